@@ -0,0 +1,111 @@
+//! A record of who changed what, appended to the `audit_log` file as one JSON object per line
+
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tracing::error;
+
+use crate::error::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditAction {
+    Upload,
+    Delete,
+    /// Moving something back out of the trash
+    Restore,
+    Rename,
+    Copy,
+    Mkdir,
+    /// Not a change, but a share link lets anyone with it download the file
+    Share,
+}
+
+/// One line of the audit log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub timestamp: DateTime<Utc>,
+    pub username: String,
+    pub action: AuditAction,
+    pub server_path: String,
+    pub key: String,
+    /// Where a rename or copy went to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination: Option<String>,
+    /// `ok`, or what went wrong
+    pub result: String,
+}
+
+impl AuditRecord {
+    pub fn new<T>(
+        username: String,
+        action: AuditAction,
+        server_path: &str,
+        key: &str,
+        result: &Result<T, Error>,
+    ) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            username,
+            action,
+            server_path: server_path.to_string(),
+            key: key.to_string(),
+            destination: None,
+            result: match result {
+                Ok(_) => "ok".to_string(),
+                Err(err) => err.to_string(),
+            },
+        }
+    }
+
+    /// Sets where a rename or copy went to
+    pub fn with_destination(self, destination: &str) -> Self {
+        Self {
+            destination: Some(destination.to_string()),
+            ..self
+        }
+    }
+}
+
+/// Appends `record` to `audit_log`, doing nothing if there isn't one.
+///
+/// The change has already happened (or not) by the time this is called, so failing to write is logged rather than returned.
+pub(crate) async fn record(audit_log: Option<&Path>, record: AuditRecord) {
+    let Some(audit_log) = audit_log else {
+        return;
+    };
+    if let Err(err) = append(audit_log, &record).await {
+        error!(
+            "Failed to write to the audit log {}: {err:?} (record was {record:?})",
+            audit_log.display()
+        );
+    }
+}
+
+async fn append(audit_log: &Path, record: &AuditRecord) -> Result<(), Error> {
+    let mut line = serde_json::to_string(record)
+        .map_err(|err| Error::InternalServerError(format!("Failed to serialize: {err}")))?;
+    line.push('\n');
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(audit_log)
+        .await?;
+    // one write per line, so lines from concurrent requests don't get mixed up
+    file.write_all(line.as_bytes()).await?;
+    // tokio writes in the background, this waits for it to land
+    file.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+/// Reads back everything in an audit log, for testing
+pub(crate) fn read_records(audit_log: &Path) -> Vec<AuditRecord> {
+    std::fs::read_to_string(audit_log)
+        .expect("Failed to read audit log")
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("Failed to parse audit record"))
+        .collect()
+}
@@ -1,33 +1,75 @@
 //! CLI Options
 
+use std::io::Write;
 use std::net::IpAddr;
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+
+use crate::config::Config;
+use crate::error::Error;
+use crate::fs::{check_available, fs_from_serverpath, FileKidFsType};
+use crate::log::LogFormat;
 
 static DEFAULT_BIND_ADDRESS: &str = "::1";
 static DEFAULT_CONFIG_PATH: &str = "filekid.json";
 
 #[derive(Parser, Debug)]
 pub struct CliOpts {
-    #[clap(short, long, env = "FILEKID_CONFIG", default_value = DEFAULT_CONFIG_PATH)]
+    #[clap(short, long, env = "FILEKID_CONFIG", default_value = DEFAULT_CONFIG_PATH, global = true)]
     pub config: PathBuf,
 
-    #[clap(long, env = "FILEKID_BIND_ADDRESS", default_value = DEFAULT_BIND_ADDRESS)]
+    #[clap(long, env = "FILEKID_BIND_ADDRESS", default_value = DEFAULT_BIND_ADDRESS, global = true)]
     pub bind_address: IpAddr,
 
-    #[clap(short, long, env = "FILEKID_DEBUG")]
+    #[clap(short, long, env = "FILEKID_DEBUG", global = true)]
     pub debug: bool,
 
-    #[clap(long, env = "FILEKID_OAUTH2_DISABLE")]
+    #[clap(long, env = "FILEKID_OAUTH2_DISABLE", global = true)]
     #[cfg(any(debug_assertions, test))]
     pub oauth2_disable: bool,
 
-    #[clap(long, env = "FILEKID_DB_DEBUG")]
+    #[clap(long, env = "FILEKID_DB_DEBUG", global = true)]
     pub db_debug: bool,
 
-    #[clap(long, env = "FILEKID_SESSION_DB_PATH")]
+    #[clap(long, env = "FILEKID_SESSION_DB_PATH", global = true)]
     pub session_db_path: Option<PathBuf>,
+
+    /// Defaults to pretty with `--debug`, JSON otherwise
+    #[clap(long, env = "FILEKID_LOG_FORMAT", value_enum, global = true)]
+    pub log_format: Option<LogFormat>,
+
+    /// Load the configuration and check the server paths the way `serve` does, then exit instead of listening
+    #[clap(long, env = "FILEKID_CHECK_CONFIG_ONLY")]
+    pub check_config_only: bool,
+
+    /// Defaults to [Command::Serve]
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Command {
+    /// Run the web server
+    #[default]
+    Serve,
+    /// Work with the configuration file
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigCommand {
+    /// Check the configuration file and server paths, then exit
+    Validate,
+    /// Write a starting configuration file to the `--config` path
+    Init {
+        /// Overwrite the file if it already exists
+        #[clap(long)]
+        force: bool,
+    },
 }
 
 impl CliOpts {
@@ -50,6 +92,9 @@ impl Default for CliOpts {
             oauth2_disable: false,
             db_debug: false,
             session_db_path: None,
+            log_format: None,
+            check_config_only: false,
+            command: None,
             bind_address: DEFAULT_BIND_ADDRESS
                 .parse()
                 .expect("Failed to parse ::1 as an IP address!"),
@@ -57,4 +102,208 @@ impl Default for CliOpts {
     }
 }
 
-impl CliOpts {}
+impl CliOpts {
+    pub fn command(&self) -> Command {
+        self.command.unwrap_or_default()
+    }
+
+    pub fn log_format(&self) -> LogFormat {
+        self.log_format
+            .unwrap_or_else(|| LogFormat::default_for(self.debug))
+    }
+}
+
+/// Loads the configuration and checks it the way startup does, writing a report on each server path to `out`
+pub async fn validate_config(cli: &CliOpts, out: &mut impl Write) -> Result<(), Error> {
+    let config = Config::new(cli)?;
+
+    let mut server_paths: Vec<_> = config.server_paths.iter().collect();
+    server_paths.sort_by_key(|(name, _)| name.as_str());
+    let mut unavailable = 0;
+    for (name, server_path) in server_paths {
+        let status = match server_path.type_ {
+            FileKidFsType::TempDir => "ok (created at startup)".to_string(),
+            _ if server_path.is_per_user() => "ok (created for each user)".to_string(),
+            _ => {
+                let available = match fs_from_serverpath(server_path) {
+                    Ok(filekidfs) => {
+                        check_available(filekidfs.as_ref(), config.available_timeout()).await
+                    }
+                    Err(err) => Err(err),
+                };
+                match available {
+                    Ok(true) => "ok".to_string(),
+                    Ok(false) => {
+                        unavailable += 1;
+                        "not available".to_string()
+                    }
+                    Err(err) => {
+                        unavailable += 1;
+                        format!("error: {err}")
+                    }
+                }
+            }
+        };
+        writeln!(out, "{name} ({:?}): {status}", server_path.type_)?;
+    }
+    if unavailable > 0 {
+        return Err(Error::Configuration(format!(
+            "{unavailable} server path(s) aren't available"
+        )));
+    }
+
+    config.startup_check().await?;
+    writeln!(out, "Configuration {} is valid", cli.config.display())?;
+    Ok(())
+}
+
+/// Loads the configuration and runs [Config::startup_check], which is everything `serve` does before it listens
+pub async fn load_config(cli: &CliOpts) -> Result<Config, Error> {
+    let config = Config::new(cli)?;
+    config.startup_check().await?;
+    Ok(config)
+}
+
+/// Writes what `--check-config-only` found to `out`, once [load_config] has passed
+pub fn config_summary(cli: &CliOpts, config: &Config, out: &mut impl Write) -> Result<(), Error> {
+    let mut server_paths: Vec<_> = config.server_paths.iter().collect();
+    server_paths.sort_by_key(|(name, _)| name.as_str());
+    writeln!(
+        out,
+        "Configuration {} is valid, {} server path(s) would be served on {}",
+        cli.config.display(),
+        server_paths.len(),
+        config.listen_addr()
+    )?;
+    for (name, server_path) in server_paths {
+        writeln!(out, "  {name} ({:?})", server_path.type_)?;
+    }
+    Ok(())
+}
+
+/// Writes [Config::template] to the `--config` path, which has to not exist yet unless `force` is set
+pub fn init_config(cli: &CliOpts, force: bool) -> Result<(), Error> {
+    if cli.config.exists() && !force {
+        return Err(Error::Configuration(format!(
+            "{} already exists, use --force to overwrite it",
+            cli.config.display()
+        )));
+    }
+    Config::template()?.to_file(&cli.config)?;
+    println!("Wrote a new configuration to {}", cli.config.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_validate_config() {
+        let mut out = Vec::new();
+        validate_config(&CliOpts::test_default(), &mut out)
+            .await
+            .expect("Example config didn't validate");
+        let out = String::from_utf8(out).expect("Report wasn't UTF-8");
+        assert!(out.contains("filekid (Local): ok"));
+        assert!(out.contains("tempdir (TempDir): ok"));
+        assert!(out.contains("Configuration files/example-config.json is valid"));
+
+        let cli = CliOpts::try_parse_from([
+            "filekid",
+            "config",
+            "validate",
+            "--config",
+            "files/example-config.json",
+        ])
+        .expect("Failed to parse args");
+        assert_eq!(
+            cli.command(),
+            Command::Config {
+                command: ConfigCommand::Validate
+            }
+        );
+        assert_eq!(cli.config, PathBuf::from("files/example-config.json"));
+        let cli = CliOpts::try_parse_from(["filekid", "--debug"]).expect("Failed to parse args");
+        assert_eq!(cli.command(), Command::Serve);
+        assert!(cli.debug);
+
+        let cli = CliOpts {
+            config: PathBuf::from("files/does-not-exist.json"),
+            ..CliOpts::default()
+        };
+        assert!(validate_config(&cli, &mut Vec::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_check_config_only() {
+        let cli = CliOpts::try_parse_from([
+            "filekid",
+            "--check-config-only",
+            "--config",
+            "files/example-config.json",
+        ])
+        .expect("Failed to parse args");
+        assert!(cli.check_config_only);
+        assert_eq!(cli.command(), Command::Serve);
+
+        let config = load_config(&cli).await.expect("Example config didn't load");
+        let mut out = Vec::new();
+        config_summary(&cli, &config, &mut out).expect("Failed to write summary");
+        let out = String::from_utf8(out).expect("Summary wasn't UTF-8");
+        assert!(out.starts_with("Configuration files/example-config.json is valid"));
+        assert!(out.contains("  filekid (Local)"));
+        assert!(out.contains("  tempdir (TempDir)"));
+
+        let cli = CliOpts {
+            config: PathBuf::from("files/does-not-exist.json"),
+            check_config_only: true,
+            ..CliOpts::default()
+        };
+        assert!(load_config(&cli).await.is_err());
+    }
+
+    #[test]
+    fn test_init_config() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let config_path = temp_dir.path().join("filekid.json");
+        let cli = CliOpts::try_parse_from([
+            "filekid",
+            "config",
+            "init",
+            "--config",
+            &config_path.display().to_string(),
+        ])
+        .expect("Failed to parse args");
+        assert_eq!(
+            cli.command(),
+            Command::Config {
+                command: ConfigCommand::Init { force: false }
+            }
+        );
+
+        init_config(&cli, false).expect("Failed to write config");
+        let config = Config::from_file(&config_path).expect("Template didn't load");
+        assert_eq!(
+            config,
+            Config::template().expect("Failed to build template")
+        );
+        assert!(config.server_paths.contains_key("temp"));
+        // the testing-only switches aren't offered to new users
+        let written = std::fs::read_to_string(&config_path).expect("Failed to read file");
+        assert!(!written.contains("oauth2_disabled"));
+        assert!(!written.contains("\"debug\""));
+
+        std::fs::write(&config_path, "{}").expect("Failed to write file");
+        assert!(matches!(
+            init_config(&cli, false),
+            Err(Error::Configuration(_))
+        ));
+        assert_eq!(
+            std::fs::read_to_string(&config_path).expect("Failed to read file"),
+            "{}"
+        );
+        init_config(&cli, true).expect("--force should overwrite");
+        Config::from_file(&config_path).expect("Template didn't load");
+    }
+}
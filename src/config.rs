@@ -1,17 +1,28 @@
 //! Config and parsing things
 
 use crate::cli::CliOpts;
+use crate::constants::{
+    AVAILABLE_CHECK_TIMEOUT_SECS, CHUNKED_UPLOAD_DIR_NAME, DEFAULT_MAX_LIST_ENTRIES,
+    DEFAULT_MAX_PREVIEW_BYTES,
+};
 use crate::error::Error;
 use crate::fs::{self, FileKidFs};
+use crate::oidc;
+use crate::web::tls_policy;
 use crate::ServerPath;
+use axum::http::{HeaderName, Uri};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::ffi::OsString;
 use std::net::IpAddr;
 
 #[cfg(test)]
 use std::net::Ipv4Addr;
 use std::num::NonZeroU16;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::{debug, warn};
 
 fn bind_address_default() -> IpAddr {
     #[allow(clippy::expect_used)]
@@ -22,17 +33,167 @@ fn bind_address_default() -> IpAddr {
 
 const DEFAULT_PORT: u16 = 6969;
 
+/// `FILEKID_PORT` overrides `port` and so on, see [Config::apply_env_overrides]
+pub const ENV_PREFIX: &str = "FILEKID_";
+/// Fields whose variables belong to command line flags instead
+const ENV_OVERRIDE_SKIP: &[&str] = &["debug", "oauth2_disabled"];
+
+/// The `FILEKID_` variables from `vars`, anything else is skipped without caring whether it's UTF-8
+fn filekid_vars(
+    vars: impl IntoIterator<Item = (OsString, OsString)>,
+) -> Result<Vec<(String, String)>, Error> {
+    vars.into_iter()
+        .filter(|(var, _)| var.as_encoded_bytes().starts_with(ENV_PREFIX.as_bytes()))
+        .map(|(var, value)| {
+            let var = var.into_string().map_err(|var| {
+                Error::Configuration(format!("{} isn't valid UTF-8", var.to_string_lossy()))
+            })?;
+            let value = value
+                .into_string()
+                .map_err(|_| Error::Configuration(format!("{var} isn't valid UTF-8")))?;
+            Ok((var, value))
+        })
+        .collect()
+}
+
 /// Defaults to 6969
 fn default_port() -> NonZeroU16 {
     #[allow(clippy::expect_used)]
     NonZeroU16::new(DEFAULT_PORT).expect("Failed to create default port from a known constant!")
 }
 
+/// Defaults to hourly
+fn default_trash_scan_interval_secs() -> u64 {
+    3600
+}
+
+/// TLS is on unless it's turned off
+fn default_tls_enabled() -> bool {
+    true
+}
+
+/// Defaults to 30 seconds
+fn default_retry_after_secs() -> u64 {
+    30
+}
+
+/// Defaults to [AVAILABLE_CHECK_TIMEOUT_SECS]
+fn default_available_timeout_secs() -> u64 {
+    AVAILABLE_CHECK_TIMEOUT_SECS
+}
+
+/// Defaults to an hour
+fn default_session_max_age_secs() -> u64 {
+    3600
+}
+
+/// Defaults to a day
+fn default_static_max_age_secs() -> u64 {
+    86400
+}
+
+/// Compression is on unless it's turned off
+fn default_compress_responses() -> bool {
+    true
+}
+
+/// Defaults to hourly
+fn default_oidc_refresh_secs() -> u64 {
+    3600
+}
+
+/// Defaults to a day
+fn default_chunked_upload_expiry_secs() -> u64 {
+    86400
+}
+
+fn default_max_list_entries() -> usize {
+    DEFAULT_MAX_LIST_ENTRIES
+}
+
+fn default_max_preview_bytes() -> u64 {
+    DEFAULT_MAX_PREVIEW_BYTES
+}
+
+fn default_reload_quiet_millis() -> u64 {
+    500
+}
+
 /// Defaults to 1GB (1024MB)
 fn default_max_upload_mb() -> usize {
     1024
 }
 
+/// The oldest TLS version the server will talk
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+/// The `SameSite` attribute of the session cookie
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CookieSameSite {
+    Strict,
+    #[default]
+    Lax,
+    /// Browsers only accept this on Secure cookies
+    None,
+}
+
+/// The formats a config file can be written in, picked by its extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    /// `.json`, or no extension at all
+    Json,
+    /// `.yaml` or `.yml`
+    Yaml,
+    /// `.toml`
+    Toml,
+}
+
+impl ConfigFormat {
+    pub fn from_path(filename: &Path) -> Result<Self, Error> {
+        match filename
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .as_deref()
+        {
+            None | Some("json") => Ok(Self::Json),
+            Some("yaml") | Some("yml") => Ok(Self::Yaml),
+            Some("toml") => Ok(Self::Toml),
+            Some(other) => Err(Error::Configuration(format!(
+                "Don't know how to read a .{other} config file {}, use .json, .yaml, .yml or .toml",
+                filename.display()
+            ))),
+        }
+    }
+
+    fn parse(self, config: &str) -> Result<Config, Error> {
+        match self {
+            Self::Json => serde_json::from_str(config).map_err(|err| err.to_string()),
+            Self::Yaml => serde_yaml_ng::from_str(config).map_err(|err| err.to_string()),
+            Self::Toml => toml::from_str(config).map_err(|err| err.to_string()),
+        }
+        .map_err(Error::Configuration)
+    }
+
+    fn serialize(self, config: &Config) -> Result<String, Error> {
+        match self {
+            Self::Json => serde_json::to_string_pretty(config)
+                .map(|config| config + "\n")
+                .map_err(|err| err.to_string()),
+            Self::Yaml => serde_yaml_ng::to_string(config).map_err(|err| err.to_string()),
+            Self::Toml => toml::to_string_pretty(config).map_err(|err| err.to_string()),
+        }
+        .map_err(|err| Error::Configuration(format!("Couldn't serialize configuration: {err}")))
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, PartialEq)]
 /// Configuration for the FileKid server.
 pub struct Config {
@@ -54,32 +215,142 @@ pub struct Config {
     pub oidc_client_id: String,
     #[serde(default)]
     pub oidc_client_secret: Option<String>,
+    /// How often (in seconds) to re-fetch the IdP's discovery document and signing keys, defaults to hourly and 0 turns it off
+    #[serde(default = "default_oidc_refresh_secs")]
+    pub oidc_refresh_secs: u64,
 
     pub static_path: Option<PathBuf>,
+    /// How long browsers can cache the CSS, JS and images under `/static`, defaults to a day
+    #[serde(default = "default_static_max_age_secs")]
+    pub static_max_age_secs: u64,
+    /// Gzip or brotli compress pages and API responses for clients that accept it, file downloads are left alone
+    #[serde(default = "default_compress_responses")]
+    pub compress_responses: bool,
 
-    /// Certificate file path
+    /// Serve HTTPS using `cert_file`/`cert_key`, turn this off to serve plain HTTP behind a TLS-terminating proxy
+    #[serde(default = "default_tls_enabled")]
+    pub tls_enabled: bool,
+    /// Certificate file path, only needed if `tls_enabled` is on
+    #[serde(default)]
     pub cert_file: PathBuf,
-    /// Certificate key file path
+    /// Certificate key file path, only needed if `tls_enabled` is on
+    #[serde(default)]
     pub cert_key: PathBuf,
+    /// The oldest TLS version to accept, `"1.2"` (the default) or `"1.3"`
+    #[serde(default)]
+    pub tls_min_version: TlsVersion,
+    /// Only offer these TLS cipher suites, by their IANA names (eg `TLS13_AES_256_GCM_SHA384`), defaults to everything rustls supports
+    #[serde(default)]
+    pub tls_cipher_suites: Option<Vec<String>>,
     /// Where to find the thing
     pub frontend_url: String,
 
     /// Debug mode is on
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
     pub debug: bool,
 
-    /// Testing-only option to disable OAuth2
-    #[serde(default)]
+    /// Testing-only option to disable OAuth2, only written out when it's on so templates don't suggest it
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
     pub(crate) oauth2_disabled: bool,
 
     /// Maximum upload size,  Defaults to 1024MB
     #[serde(default = "default_max_upload_mb")]
     pub max_upload_mb: usize,
+
+    /// How many days trashed files are kept before being permanently deleted, unset keeps them forever
+    #[serde(default)]
+    pub trash_retention_days: Option<u64>,
+    /// How often to check the trash for expired files, defaults to hourly
+    #[serde(default = "default_trash_scan_interval_secs")]
+    pub trash_scan_interval_secs: u64,
+
+    /// Whether session cookies get the Secure flag, defaults to on when `tls_enabled` is on and `frontend_url` is https
+    #[serde(default)]
+    pub cookie_secure: Option<bool>,
+    /// Sessions end after this many seconds without a request, defaults to an hour
+    #[serde(default = "default_session_max_age_secs")]
+    pub session_max_age_secs: u64,
+    /// The session cookie's `SameSite`, `"strict"`, `"lax"` (the default) or `"none"`
+    #[serde(default)]
+    pub session_same_site: CookieSameSite,
+
+    /// Stop the server after this many seconds without any requests
+    #[serde(default)]
+    pub idle_shutdown_secs: Option<u64>,
+    /// Whether requests to the health check endpoint count as activity for idle shutdown
+    #[serde(default)]
+    pub idle_shutdown_count_health_checks: bool,
+
+    /// Reject uploads that are shorter than their declared Content-Length, longer ones are always rejected
+    #[serde(default)]
+    pub strict_content_length: bool,
+
+    /// Refuse all uploads (everything else keeps working), for when things are on fire
+    #[serde(default)]
+    pub uploads_disabled: bool,
+
+    /// Add an ASCII-transliterated `filename=` to downloads of non-ASCII filenames, for clients that don't understand `filename*`
+    #[serde(default)]
+    pub ascii_filename_fallback: bool,
+
+    /// Serve Prometheus metrics on `/metrics`
+    #[serde(default)]
+    pub metrics_enabled: bool,
+    /// Serve an OpenAPI description of the JSON API on `/api/openapi.json`, and Swagger UI on `/api/docs`
+    #[serde(default)]
+    pub api_docs_enabled: bool,
+    /// Signs the links made by `/share`, which are turned off without it. Changing it breaks every link already handed out
+    #[serde(default)]
+    pub share_secret: Option<String>,
+    /// Usernames and their argon2 password hashes (`$argon2id$...`), who can log in with HTTP Basic auth when the IdP can't be reached
+    #[serde(default)]
+    pub basic_auth: Option<HashMap<String, String>>,
+
+    /// What the `Retry-After` header says when a server path's backend is down
+    #[serde(default = "default_retry_after_secs")]
+    pub retry_after_secs: u64,
+    /// How long a server path's backend gets to say it's available before it's treated as down
+    #[serde(default = "default_available_timeout_secs")]
+    pub available_timeout_secs: u64,
+
+    /// Where partial chunked uploads are kept, defaults to a directory in the system temp dir
+    #[serde(default)]
+    pub chunked_upload_dir: Option<PathBuf>,
+    /// Chunked uploads that haven't had a chunk in this long are thrown away, defaults to a day
+    #[serde(default = "default_chunked_upload_expiry_secs")]
+    pub chunked_upload_expiry_secs: u64,
+
+    /// Where the session database lives, defaults to a file in the XDG data dir. Use `:memory:` to keep sessions in memory, they're lost on restart.
+    #[serde(default)]
+    pub session_db_path: Option<PathBuf>,
+
+    /// Reload requests are held until there haven't been any for this long, so a burst of them only reloads once
+    #[serde(default = "default_reload_quiet_millis")]
+    pub reload_quiet_millis: u64,
+
+    /// Directories with more entries than this aren't listed (in browse, the API, WebDAV, trees or zips), so a huge one can't eat all the memory
+    #[serde(default = "default_max_list_entries")]
+    pub max_list_entries: usize,
+
+    /// Files bigger than this (in bytes) can't be previewed as text
+    #[serde(default = "default_max_preview_bytes")]
+    pub max_preview_bytes: u64,
+
+    /// Uploads, deletes, renames, copies, new directories and restores from the trash are recorded here, one JSON object per line, see [crate::audit]
+    #[serde(default)]
+    pub audit_log: Option<PathBuf>,
+
+    /// Behind nginx (`X-Accel-Redirect`) or Apache (`X-Sendfile`), downloads from local server paths are handed to the proxy
+    /// with this header rather than sent by FileKid. Text files being re-encoded and immutable paths are still sent by FileKid.
+    ///
+    /// It holds the file's path on disk, or for `X-Accel-Redirect` a URI under the server path's [crate::ServerPath::sendfile_prefix], which has to be set.
+    #[serde(default)]
+    pub sendfile_header: Option<String>,
 }
 
 impl Config {
     pub fn new(cli: &CliOpts) -> Result<Self, Error> {
-        let mut config = Self::from_file(&cli.config)?;
+        let mut config = Self::from_file_and_env(&cli.config)?;
 
         if cli.debug {
             config.debug = true
@@ -88,12 +359,37 @@ impl Config {
         if cli.oauth2_disable {
             config.oauth2_disabled = true
         }
+        if let Some(session_db_path) = &cli.session_db_path {
+            config.session_db_path = Some(session_db_path.clone());
+        }
 
         Ok(config)
     }
 
     /// Load the configuration from a file.
     pub fn from_file(filename: &PathBuf) -> Result<Self, Error> {
+        let mut config = Self::parse_file(filename)?;
+        config.resolve_relative_paths_to(filename);
+        Ok(config)
+    }
+
+    /// [Config::from_file] with [Config::apply_env_overrides] on top
+    pub fn from_file_and_env(filename: &PathBuf) -> Result<Self, Error> {
+        Self::from_file_with_overrides(filename, filekid_vars(std::env::vars_os())?)
+    }
+
+    /// Overrides go in before relative paths are resolved, so a path from the environment is relative to the config file too
+    pub(crate) fn from_file_with_overrides(
+        filename: &PathBuf,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, Error> {
+        let mut config = Self::parse_file(filename)?;
+        config.apply_overrides(vars)?;
+        config.resolve_relative_paths_to(filename);
+        Ok(config)
+    }
+
+    fn parse_file(filename: &PathBuf) -> Result<Self, Error> {
         if !filename.exists() {
             return Err(Error::Configuration(format!(
                 "Config file {} does not exist",
@@ -108,21 +404,197 @@ impl Config {
                 err
             ))
         })?;
-        serde_json::from_str(&config).map_err(|e| {
-            eprintln!("Failed to parse config as JSONj: {e}");
+        let format = ConfigFormat::from_path(filename)?;
+        format.parse(&config).map_err(|e| {
+            eprintln!("Failed to parse config as {format:?}: {e}");
             Error::Configuration(e.to_string())
         })
     }
+
+    fn resolve_relative_paths_to(&mut self, filename: &Path) {
+        if let Some(config_dir) = filename.parent() {
+            self.resolve_relative_paths(config_dir);
+        }
+    }
+
+    /// Makes relative local server paths relative to `config_dir` (where the config file is) rather than wherever the server was started from
+    pub fn resolve_relative_paths(&mut self, config_dir: &Path) {
+        for server_path in self.server_paths.values_mut() {
+            if server_path.type_ != fs::FileKidFsType::Local {
+                continue;
+            }
+            if let Some(path) = &mut server_path.path
+                && path.is_relative()
+            {
+                *path = config_dir.join(&*path);
+            }
+        }
+    }
+
+    /// Overrides values from the file with `FILEKID_<FIELD>` environment variables, so the order of precedence is
+    /// command line flags, then the environment, then the file, then defaults.
+    ///
+    /// Loading a file wants [Config::from_file_and_env] instead, so relative paths set here get resolved too.
+    pub fn apply_env_overrides(&mut self) -> Result<(), Error> {
+        self.apply_overrides(filekid_vars(std::env::vars_os())?)
+    }
+
+    /// [Config::apply_env_overrides] with the variables passed in, each value's tried as JSON first and then as a plain string
+    pub(crate) fn apply_overrides(
+        &mut self,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<(), Error> {
+        let serde_json::Value::Object(mut fields) =
+            serde_json::to_value(&*self).map_err(|err| {
+                Error::Configuration(format!("Couldn't serialize configuration: {err}"))
+            })?
+        else {
+            return Err(Error::Configuration(
+                "Configuration didn't serialize to an object".to_string(),
+            ));
+        };
+
+        for (var, value) in vars {
+            let Some(field) = var.strip_prefix(ENV_PREFIX).map(str::to_lowercase) else {
+                continue;
+            };
+            if ENV_OVERRIDE_SKIP.contains(&field.as_str()) || !fields.contains_key(&field) {
+                continue;
+            }
+            let candidates = serde_json::from_str(&value)
+                .ok()
+                .into_iter()
+                .chain([serde_json::Value::String(value)]);
+            let mut last_err = String::new();
+            let mut applied = false;
+            for candidate in candidates {
+                let mut attempt = fields.clone();
+                attempt.insert(field.clone(), candidate);
+                match serde_json::from_value::<Config>(serde_json::Value::Object(attempt.clone())) {
+                    Ok(_) => {
+                        fields = attempt;
+                        applied = true;
+                        break;
+                    }
+                    Err(err) => last_err = err.to_string(),
+                }
+            }
+            if !applied {
+                // not echoing the value, it might be a secret
+                return Err(Error::Configuration(format!(
+                    "Couldn't use {var} to set {field}: {last_err}"
+                )));
+            }
+            debug!("Set {} from {}", field, var);
+        }
+
+        *self = serde_json::from_value(serde_json::Value::Object(fields))
+            .map_err(|err| Error::Configuration(err.to_string()))?;
+        Ok(())
+    }
+
+    /// Write the configuration to a file, in the format its extension says, the other half of [Config::from_file]
+    pub fn to_file(&self, filename: &Path) -> Result<(), Error> {
+        let config = ConfigFormat::from_path(filename)?.serialize(self)?;
+        std::fs::write(filename, config).map_err(|err| {
+            Error::Configuration(format!(
+                "Couldn't write configuration file {}, error: {}",
+                filename.display(),
+                err
+            ))
+        })
+    }
+
+    /// A starting point for a new config file, with a tempdir server path and placeholder OIDC settings.
+    ///
+    /// JSON can't hold comments, so everything else is written out with its default value to show what can be set.
+    pub fn template() -> Result<Self, Error> {
+        serde_json::from_value(serde_json::json!({
+            "frontend_url": format!("https://localhost:{DEFAULT_PORT}"),
+            "frontend_domain": "localhost",
+            "oidc_issuer": "https://idm.example.com/oauth2/openid/filekid",
+            "oidc_client_id": "filekid",
+            "cert_file": "/path/to/fullchain.pem",
+            "cert_key": "/path/to/privkey.pem",
+            "server_paths": {
+                "temp": { "type": "tempdir" }
+            }
+        }))
+        .map_err(|err| Error::Configuration(format!("Couldn't build config template: {err}")))
+    }
     /// Check that the configuration is valid.
-    pub fn startup_check(&self) -> Result<(), Error> {
+    pub async fn startup_check(&self) -> Result<(), Error> {
+        self.check_urls()?;
+        if self.tls_enabled {
+            tls_policy(self.tls_min_version, self.tls_cipher_suites.as_deref())?;
+        }
+        match (self.cookie_secure(), self.frontend_url.starts_with("https://")) {
+            (true, false) => warn!(
+                "Session cookies are Secure but frontend_url {} isn't https, browsers won't send them back and logins will fail!",
+                self.frontend_url
+            ),
+            (false, true) => warn!(
+                "Session cookies aren't Secure but frontend_url {} is https, consider enabling cookie_secure",
+                self.frontend_url
+            ),
+            _ => {}
+        }
+        if self.session_same_site == CookieSameSite::None && !self.cookie_secure() {
+            return Err(Error::Configuration(
+                "session_same_site \"none\" needs Secure session cookies, browsers will drop them otherwise".to_string(),
+            ));
+        }
+
+        if let Some(days) = self.trash_retention_days
+            && crate::trash::retention_for_days(days).is_none()
+        {
+            return Err(Error::Configuration(format!(
+                "trash_retention_days {days} is too long"
+            )));
+        }
+
+        if let Some(sendfile_header) = &self.sendfile_header {
+            HeaderName::from_str(sendfile_header).map_err(|err| {
+                Error::Configuration(format!(
+                    "sendfile_header {sendfile_header:?} isn't a valid header name: {err}"
+                ))
+            })?;
+        }
+
+        for (username, password_hash) in self.basic_auth.iter().flatten() {
+            oidc::check_password_hash(username, password_hash)?;
+        }
+
         for (server, server_config) in self.server_paths.iter() {
+            server_config.upload_dir()?;
+            server_config.deprecation()?;
+            server_config.text_encoding()?;
+            server_config.upload_name("original", "user")?;
+            server_config.check_mime_overrides()?;
+            server_config.check_trash_dir()?;
+            server_config.check_per_user()?;
+            if let Some(sendfile_header) = &self.sendfile_header
+                && sendfile_header.eq_ignore_ascii_case("x-accel-redirect")
+                && server_config.sendfile_prefix.is_none()
+                && matches!(
+                    server_config.type_,
+                    fs::FileKidFsType::Local | fs::FileKidFsType::TempDir
+                )
+            {
+                return Err(Error::Configuration(format!(
+                    "Server path {server} needs a sendfile_prefix for X-Accel-Redirect, nginx wants an internal URI rather than a path on disk"
+                )));
+            }
+            fs::registry::check_backend_type(&server_config.type_)?;
             match server_config.type_ {
+                // everyone's directory is created when they first use it
+                _ if server_config.is_per_user() => {}
                 fs::FileKidFsType::TempDir => {
                     // it's fine!
                 }
-                fs::FileKidFsType::Local => {
+                _ => {
                     let filekid: Box<dyn FileKidFs> = fs::fs_from_serverpath(server_config)?;
-                    if !filekid.available()? {
+                    if !fs::check_available(filekid.as_ref(), self.available_timeout()).await? {
                         return Err(Error::NotFound(format!(
                             "Server path {server} ({filekid:?}) is not online"
                         )));
@@ -133,6 +605,94 @@ impl Config {
         Ok(())
     }
 
+    /// Makes sure `frontend_url` and `oidc_issuer` will work, before anything tries to use them
+    fn check_urls(&self) -> Result<(), Error> {
+        let frontend_url = Uri::from_str(&self.frontend_url).map_err(|err| {
+            Error::Configuration(format!(
+                "frontend_url {:?} isn't a valid URL: {err}",
+                self.frontend_url
+            ))
+        })?;
+        let default_port = match frontend_url.scheme_str() {
+            Some("https") => 443,
+            Some("http") => 80,
+            Some(other) => {
+                return Err(Error::Configuration(format!(
+                    "frontend_url {:?} has to be http or https, not {other}",
+                    self.frontend_url
+                )))
+            }
+            None => {
+                return Err(Error::Configuration(format!(
+                    "frontend_url {:?} needs a scheme, like https://",
+                    self.frontend_url
+                )))
+            }
+        };
+        if frontend_url.host().is_none_or(str::is_empty) {
+            return Err(Error::Configuration(format!(
+                "frontend_url {:?} doesn't have a host",
+                self.frontend_url
+            )));
+        }
+        let frontend_port = frontend_url.port_u16().unwrap_or(default_port);
+        if frontend_port != self.port.get() {
+            warn!(
+                "frontend_url {} is on port {} but the server listens on {}, which is only right behind a proxy",
+                self.frontend_url,
+                frontend_port,
+                self.port
+            );
+        }
+
+        if !self.oauth2_disabled {
+            let oidc_issuer = Uri::from_str(&self.oidc_issuer).map_err(|err| {
+                Error::Configuration(format!(
+                    "oidc_issuer {:?} isn't a valid URL: {err}",
+                    self.oidc_issuer
+                ))
+            })?;
+            if oidc_issuer.scheme_str() != Some("https") {
+                return Err(Error::Configuration(format!(
+                    "oidc_issuer {:?} has to be an https:// URL",
+                    self.oidc_issuer
+                )));
+            }
+            if oidc_issuer.host().is_none_or(str::is_empty) {
+                return Err(Error::Configuration(format!(
+                    "oidc_issuer {:?} doesn't have a host",
+                    self.oidc_issuer
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Upload handlers call this first, see [Config::uploads_disabled]
+    pub fn check_uploads_enabled(&self) -> Result<(), Error> {
+        match self.uploads_disabled {
+            true => Err(Error::NotAuthorized("uploads are disabled".to_string())),
+            false => Ok(()),
+        }
+    }
+
+    /// See [Config::available_timeout_secs]
+    pub fn available_timeout(&self) -> Duration {
+        Duration::from_secs(self.available_timeout_secs)
+    }
+
+    /// Should session cookies only be sent over HTTPS?
+    pub fn cookie_secure(&self) -> bool {
+        self.cookie_secure
+            .unwrap_or_else(|| self.tls_enabled && self.frontend_url.starts_with("https://"))
+    }
+
+    pub fn chunked_upload_dir(&self) -> PathBuf {
+        self.chunked_upload_dir
+            .clone()
+            .unwrap_or_else(|| std::env::temp_dir().join(CHUNKED_UPLOAD_DIR_NAME))
+    }
+
     pub fn listen_addr(&self) -> String {
         format!("{}:{}", self.bind_address, self.port.get())
     }
@@ -148,13 +708,43 @@ impl Config {
             oidc_issuer: "https://example.com".to_string(),
             oidc_client_id: "client_id".to_string(),
             oidc_client_secret: None,
+            oidc_refresh_secs: 3600,
             static_path: None,
+            static_max_age_secs: default_static_max_age_secs(),
+            compress_responses: true,
             cert_file: PathBuf::from("cert.pem"),
             cert_key: PathBuf::from("key.pem"),
+            tls_min_version: TlsVersion::Tls12,
+            tls_cipher_suites: None,
             frontend_url: "https://example.com".to_string(),
             debug: false,
             oauth2_disabled: false,
             max_upload_mb: 1024,
+            trash_retention_days: None,
+            trash_scan_interval_secs: 3600,
+            cookie_secure: None,
+            session_max_age_secs: 3600,
+            session_same_site: CookieSameSite::Lax,
+            idle_shutdown_secs: None,
+            idle_shutdown_count_health_checks: false,
+            strict_content_length: false,
+            tls_enabled: true,
+            ascii_filename_fallback: false,
+            uploads_disabled: false,
+            metrics_enabled: false,
+            api_docs_enabled: false,
+            share_secret: None,
+            basic_auth: None,
+            retry_after_secs: 30,
+            available_timeout_secs: AVAILABLE_CHECK_TIMEOUT_SECS,
+            chunked_upload_dir: None,
+            chunked_upload_expiry_secs: 86400,
+            session_db_path: None,
+            reload_quiet_millis: 500,
+            max_list_entries: DEFAULT_MAX_LIST_ENTRIES,
+            max_preview_bytes: DEFAULT_MAX_PREVIEW_BYTES,
+            audit_log: None,
+            sendfile_header: None,
         }
     }
 }
@@ -190,6 +780,289 @@ pub mod tests {
         Config::new(&cliopts).expect("Failed to get config from cli defaults (with switched file)");
     }
 
+    #[tokio::test]
+    async fn test_startup_check_urls() {
+        let mut config = Config::test_config();
+        config
+            .startup_check()
+            .await
+            .expect("Test config should be fine");
+
+        for frontend_url in [
+            "not a url",
+            "example.com",
+            "ftp://example.com",
+            "https://",
+            "/files",
+        ] {
+            config.frontend_url = frontend_url.to_string();
+            assert!(
+                matches!(config.startup_check().await, Err(Error::Configuration(_))),
+                "{frontend_url} should have been rejected"
+            );
+        }
+        // a different port is fine, it's probably behind a proxy
+        config.frontend_url = "https://example.com:8443/files/".to_string();
+        config
+            .startup_check()
+            .await
+            .expect("Port mismatch should only warn");
+
+        config.oidc_issuer = "http://idm.example.com/oauth2/openid/filekid".to_string();
+        let Err(Error::Configuration(message)) = config.startup_check().await else {
+            panic!("http issuer should have been rejected");
+        };
+        assert!(message.contains("oidc_issuer"));
+        config.oidc_issuer = "https://".to_string();
+        assert!(matches!(
+            config.startup_check().await,
+            Err(Error::Configuration(_))
+        ));
+        // doesn't matter if OAuth2's off
+        config.oauth2_disabled = true;
+        config
+            .startup_check()
+            .await
+            .expect("Issuer isn't used without OAuth2");
+    }
+
+    #[test]
+    fn test_relative_server_paths() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let config_dir = temp_dir.path().join("etc/filekid");
+        std::fs::create_dir_all(&config_dir).expect("Failed to create config dir");
+        let config_file = config_dir.join("filekid.json");
+        std::fs::write(
+            &config_file,
+            r#"{
+                "frontend_url": "https://example.com",
+                "frontend_domain": "example.com",
+                "oidc_client_id": "filekid",
+                "oidc_issuer": "https://idm.example.com",
+                "server_paths": {
+                    "shares": { "path": "shares", "type": "local" },
+                    "absolute": { "path": "/srv/files", "type": "local" },
+                    "temp": { "type": "tempdir" }
+                }
+            }"#,
+        )
+        .expect("Failed to write config");
+
+        let config = Config::from_file(&config_file).expect("Failed to load config");
+        let path = |name: &str| {
+            config
+                .server_paths
+                .get(name)
+                .expect("Missing server path")
+                .path
+                .clone()
+        };
+        assert_eq!(path("shares"), Some(config_dir.join("shares")));
+        assert_eq!(path("absolute"), Some(PathBuf::from("/srv/files")));
+        assert_eq!(path("temp"), None);
+
+        // paths from the environment are resolved the same way
+        let config = Config::from_file_with_overrides(
+            &config_file,
+            [(
+                "FILEKID_SERVER_PATHS".to_string(),
+                r#"{"env": { "path": "from-env", "type": "local" }}"#.to_string(),
+            )],
+        )
+        .expect("Failed to load config");
+        assert_eq!(
+            config.server_paths.get("env").and_then(|p| p.path.clone()),
+            Some(config_dir.join("from-env"))
+        );
+
+        // a config file in the current directory works like it always did
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "shares".to_string(),
+            ServerPath::test_local(Path::new("shares")),
+        );
+        config.resolve_relative_paths(Path::new(""));
+        assert_eq!(
+            config
+                .server_paths
+                .get("shares")
+                .and_then(|p| p.path.clone()),
+            Some(PathBuf::from("shares"))
+        );
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let mut config = Config::from_file(&PathBuf::from("files/example-config.json"))
+            .expect("Failed to load config");
+        let vars = |vars: &[(&str, &str)]| -> Vec<(String, String)> {
+            vars.iter()
+                .map(|(var, value)| (var.to_string(), value.to_string()))
+                .collect()
+        };
+        config
+            .apply_overrides(vars(&[
+                ("FILEKID_PORT", "9999"),
+                ("FILEKID_BIND_ADDRESS", "::"),
+                ("FILEKID_FRONTEND_URL", "https://files.example.org"),
+                (
+                    "FILEKID_OIDC_ISSUER",
+                    "https://idm.example.org/oauth2/openid/filekid",
+                ),
+                // looks like a number, but it's a string field
+                ("FILEKID_OIDC_CLIENT_SECRET", "12345"),
+                ("FILEKID_TLS_ENABLED", "false"),
+                ("FILEKID_SERVER_PATHS", r#"{"temp": {"type": "tempdir"}}"#),
+                // these belong to the command line, or aren't fields at all
+                ("FILEKID_DEBUG", "1"),
+                ("FILEKID_CONFIG", "other.json"),
+                ("PORT", "1234"),
+            ]))
+            .expect("Failed to apply overrides");
+        assert_eq!(config.port.get(), 9999);
+        assert_eq!(
+            config.bind_address,
+            "::".parse::<IpAddr>().expect("Bad address")
+        );
+        assert_eq!(config.frontend_url, "https://files.example.org");
+        assert_eq!(
+            config.oidc_issuer,
+            "https://idm.example.org/oauth2/openid/filekid"
+        );
+        assert_eq!(config.oidc_client_secret.as_deref(), Some("12345"));
+        assert!(!config.tls_enabled);
+        assert!(!config.debug);
+        assert_eq!(config.server_paths.len(), 1);
+        // untouched values come from the file
+        assert_eq!(config.max_upload_mb, 2048);
+        assert_eq!(config.frontend_domain, "example.com");
+
+        assert!(matches!(
+            config.apply_overrides(vars(&[("FILEKID_PORT", "0")])),
+            Err(Error::Configuration(_))
+        ));
+        assert!(matches!(
+            config.apply_overrides(vars(&[("FILEKID_BIND_ADDRESS", "not an address")])),
+            Err(Error::Configuration(_))
+        ));
+        assert_eq!(config.port.get(), 9999);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_filekid_vars() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let not_utf8 = || OsString::from_vec(vec![0x66, 0x6f, 0x80]);
+        assert_eq!(
+            filekid_vars([
+                (OsString::from("FILEKID_PORT"), OsString::from("9999")),
+                (OsString::from("OTHER"), not_utf8()),
+                (not_utf8(), OsString::from("value")),
+            ])
+            .expect("Failed to read variables"),
+            vec![("FILEKID_PORT".to_string(), "9999".to_string())]
+        );
+        assert_eq!(
+            filekid_vars([(OsString::from("FILEKID_PORT"), not_utf8())]),
+            Err(Error::Configuration(
+                "FILEKID_PORT isn't valid UTF-8".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_config_formats() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let write = |filename: &str, contents: &str| {
+            let path = temp_dir.path().join(filename);
+            std::fs::write(&path, contents).expect("Failed to write config");
+            Config::from_file(&path).expect("Failed to load config")
+        };
+
+        let json = write(
+            "filekid.json",
+            r#"{
+                "port": 8888,
+                "frontend_url": "https://example.com:8888",
+                "frontend_domain": "example.com",
+                "oidc_client_id": "filekid",
+                "oidc_issuer": "https://idm.example.com",
+                "server_paths": {
+                    "files": { "path": "./files", "type": "local" },
+                    "temp": { "type": "tempdir" }
+                }
+            }"#,
+        );
+        let yaml = write(
+            "filekid.yaml",
+            r#"
+port: 8888
+frontend_url: "https://example.com:8888"
+frontend_domain: example.com
+oidc_client_id: filekid
+oidc_issuer: "https://idm.example.com"
+server_paths:
+  files:
+    path: ./files
+    type: local
+  temp:
+    type: tempdir
+"#,
+        );
+        let toml = write(
+            "filekid.toml",
+            r#"
+port = 8888
+frontend_url = "https://example.com:8888"
+frontend_domain = "example.com"
+oidc_client_id = "filekid"
+oidc_issuer = "https://idm.example.com"
+
+[server_paths.files]
+path = "./files"
+type = "local"
+
+[server_paths.temp]
+type = "tempdir"
+"#,
+        );
+        assert_eq!(json, yaml);
+        assert_eq!(json, toml);
+        assert_eq!(
+            json,
+            write(
+                "filekid",
+                &serde_json::to_string(&json).expect("Failed to serialize")
+            )
+        );
+        assert_eq!(
+            json,
+            write(
+                "filekid.yml",
+                &serde_yaml_ng::to_string(&json).expect("Failed to serialize")
+            )
+        );
+
+        for filename in ["out.json", "out.yaml", "out.toml"] {
+            let path = temp_dir.path().join(filename);
+            json.to_file(&path).expect("Failed to write config");
+            assert_eq!(
+                Config::from_file(&path).expect("Failed to load config"),
+                json,
+                "{filename} didn't round-trip"
+            );
+        }
+
+        let path = temp_dir.path().join("filekid.ini");
+        std::fs::write(&path, "port=8888").expect("Failed to write config");
+        assert!(matches!(
+            Config::from_file(&path),
+            Err(Error::Configuration(_))
+        ));
+        assert!(matches!(json.to_file(&path), Err(Error::Configuration(_))));
+    }
+
     #[test]
     fn test_defaults() {
         assert_eq!(
@@ -198,10 +1071,77 @@ pub mod tests {
         );
 
         assert_eq!(default_max_upload_mb(), 1024);
+        assert_eq!(default_trash_scan_interval_secs(), 3600);
     }
 
     #[test]
-    fn test_config_startup_check() {
+    fn test_cookie_secure() {
+        let mut config = Config::test_config();
+        assert!(config.tls_enabled);
+        assert!(config.cookie_secure());
+
+        config.frontend_url = "http://localhost:6969".to_string();
+        assert!(!config.cookie_secure());
+
+        // plain HTTP for local development
+        config.tls_enabled = false;
+        assert!(!config.cookie_secure());
+
+        // behind a TLS-terminating proxy it has to be turned on
+        config.frontend_url = "https://example.com".to_string();
+        assert!(!config.cookie_secure());
+        config.cookie_secure = Some(true);
+        assert!(config.cookie_secure());
+
+        config.frontend_url = "https://example.com".to_string();
+        config.cookie_secure = Some(false);
+        assert!(!config.cookie_secure());
+    }
+
+    #[test]
+    fn test_s3_server_path() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "frontend_url": "https://filekid.example.com",
+                "frontend_domain": "filekid.example.com",
+                "cert_file": "cert.pem",
+                "cert_key": "key.pem",
+                "oidc_issuer": "https://idp.example.com",
+                "oidc_client_id": "filekid",
+                "server_paths": {
+                    "bucket": {
+                        "type": "s3",
+                        "bucket": "filekid-files",
+                        "region": "ap-southeast-2",
+                        "endpoint": "http://localhost:9000",
+                        "access_key_id": "filekid",
+                        "secret_access_key": "hunter2"
+                    }
+                }
+            }"#,
+        )
+        .expect("Failed to parse S3 config");
+
+        let server_path = config
+            .server_paths
+            .get("bucket")
+            .expect("Failed to find S3 server path");
+        assert_eq!(server_path.type_, fs::FileKidFsType::S3);
+        assert_eq!(server_path.bucket.as_deref(), Some("filekid-files"));
+        assert_eq!(server_path.region.as_deref(), Some("ap-southeast-2"));
+        assert_eq!(
+            server_path.endpoint.as_deref(),
+            Some("http://localhost:9000")
+        );
+        assert_eq!(server_path.access_key_id.as_deref(), Some("filekid"));
+        assert_eq!(server_path.secret_access_key.as_deref(), Some("hunter2"));
+
+        let filekid = fs::fs_from_serverpath(server_path).expect("Failed to build S3 backend");
+        assert_eq!(filekid.name(), "s3 (filekid-files)");
+    }
+
+    #[tokio::test]
+    async fn test_config_startup_check() {
         let mut config = Config {
             bind_address: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
             port: NonZeroU16::new(6969).expect("Failed to create default port"),
@@ -211,18 +1151,54 @@ pub mod tests {
             oidc_issuer: "https://example.com".to_string(),
             oidc_client_id: "client_id".to_string(),
             oidc_client_secret: None,
+            oidc_refresh_secs: 3600,
             static_path: None,
+            static_max_age_secs: default_static_max_age_secs(),
+            compress_responses: true,
             cert_file: PathBuf::from("cert.pem"),
             cert_key: PathBuf::from("key.pem"),
+            tls_min_version: TlsVersion::Tls12,
+            tls_cipher_suites: None,
             frontend_url: "https://example.com".to_string(),
             debug: false,
             oauth2_disabled: false,
             max_upload_mb: 1024,
+            trash_retention_days: None,
+            trash_scan_interval_secs: 3600,
+            cookie_secure: None,
+            session_max_age_secs: 3600,
+            session_same_site: CookieSameSite::Lax,
+            idle_shutdown_secs: None,
+            idle_shutdown_count_health_checks: false,
+            strict_content_length: false,
+            tls_enabled: true,
+            ascii_filename_fallback: false,
+            uploads_disabled: false,
+            metrics_enabled: false,
+            api_docs_enabled: false,
+            share_secret: None,
+            basic_auth: None,
+            retry_after_secs: 30,
+            available_timeout_secs: AVAILABLE_CHECK_TIMEOUT_SECS,
+            chunked_upload_dir: None,
+            chunked_upload_expiry_secs: 86400,
+            session_db_path: None,
+            reload_quiet_millis: 500,
+            max_list_entries: DEFAULT_MAX_LIST_ENTRIES,
+            max_preview_bytes: DEFAULT_MAX_PREVIEW_BYTES,
+            audit_log: None,
+            sendfile_header: None,
         };
 
         assert_eq!(config.listen_addr(), "127.0.0.1:6969");
 
-        assert!(config.startup_check().is_ok());
+        assert!(config.startup_check().await.is_ok());
+
+        // that many days' worth of seconds doesn't fit
+        config.trash_retention_days = Some(u64::MAX / 86400 + 1);
+        assert!(config.startup_check().await.is_err());
+        config.trash_retention_days = Some(30);
+        assert!(config.startup_check().await.is_ok());
 
         let mut server_paths = HashMap::new();
 
@@ -231,6 +1207,7 @@ pub mod tests {
             ServerPath {
                 type_: fs::FileKidFsType::TempDir,
                 path: None,
+                ..Default::default()
             },
         );
         server_paths.insert(
@@ -238,6 +1215,7 @@ pub mod tests {
             ServerPath {
                 type_: fs::FileKidFsType::Local,
                 path: Some(PathBuf::from("./")),
+                ..Default::default()
             },
         );
         config.server_paths = server_paths;
@@ -247,9 +1225,10 @@ pub mod tests {
             ServerPath {
                 type_: fs::FileKidFsType::Local,
                 path: Some(PathBuf::from("/thiswontexistIhope")),
+                ..Default::default()
             },
         );
 
-        assert!(config.startup_check().is_err());
+        assert!(config.startup_check().await.is_err());
     }
 }
@@ -2,3 +2,101 @@
 
 /// Default location on disk for the static resources
 pub const WEB_SERVER_DEFAULT_STATIC_PATH: &str = "./static";
+/// Extensions of the things browsers and crawlers ask for on their own, so missing ones are only logged when debugging
+pub const ASSET_EXTENSIONS: [&str; 9] = [
+    "ico",
+    "png",
+    "svg",
+    "css",
+    "js",
+    "map",
+    "txt",
+    "xml",
+    "webmanifest",
+];
+
+/// Responses that get compressed if the client accepts it, pages and API responses rather than downloads
+pub const COMPRESSIBLE_CONTENT_TYPES: [&str; 5] = [
+    "text/html",
+    "text/css",
+    "text/javascript",
+    "application/json",
+    "application/javascript",
+];
+
+/// How deep recursive walks of a server path go if it's not configured
+pub const DEFAULT_MAX_BROWSE_DEPTH: usize = 16;
+
+/// The most entries a directory can have before listing it is refused, if `max_list_entries` isn't configured
+pub const DEFAULT_MAX_LIST_ENTRIES: usize = 10000;
+
+/// The biggest file that can be previewed, if `max_preview_bytes` isn't configured
+pub const DEFAULT_MAX_PREVIEW_BYTES: u64 = 1024 * 1024;
+/// How much of a file is checked for null bytes before previewing it as text
+pub const PREVIEW_BINARY_CHECK_BYTES: usize = 8192;
+
+/// The most entries the tree API will return in one response
+pub const MAX_TREE_NODES: usize = 10000;
+
+/// The most results a search will return
+pub const MAX_SEARCH_RESULTS: usize = 500;
+/// How many directories deep a search will go
+pub const MAX_SEARCH_DEPTH: usize = 32;
+
+/// How long disk space figures are cached for, in seconds
+pub const DISK_SPACE_CACHE_SECS: u64 = 10;
+
+/// How long a server path's size is trusted for quota checks, in seconds, unless `size_cache_secs` is set
+pub const QUOTA_SIZE_CACHE_SECS: u64 = 30;
+
+/// How long clients can cache files from immutable server paths, a year
+pub const IMMUTABLE_MAX_AGE_SECS: u64 = 365 * 24 * 60 * 60;
+
+/// The most entries counted for each directory's size on a `?with_sizes=1` browse page
+pub const DIR_USAGE_MAX_ENTRIES: usize = 100_000;
+/// How long a `?with_sizes=1` browse page spends counting directory sizes, in seconds
+pub const DIR_USAGE_TIMEOUT_SECS: u64 = 2;
+
+/// How long share links last if they're not asked for anything else, a day
+pub const SHARE_LINK_DEFAULT_SECS: u64 = 24 * 60 * 60;
+/// The longest a share link can last, a week
+pub const SHARE_LINK_MAX_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// How many entries a browse page shows if it's not asked for a page size
+pub const DEFAULT_PAGE_SIZE: usize = 200;
+/// The most entries a browse page will show
+pub const MAX_PAGE_SIZE: usize = 5000;
+
+/// The filename that points at the newest file in a directory when a server path has `latest_alias` set
+pub const LATEST_ALIAS: &str = "latest";
+
+/// Replaced with the logged in user's username in a server path's `path`, giving everyone their own directory
+pub const USERNAME_PLACEHOLDER: &str = "{username}";
+
+/// What's served instead of the listing for directories in server paths with `serve_index` set
+pub const INDEX_FILENAME: &str = "index.html";
+
+/// Extensions refused by server paths with `reject_executables` set
+pub const EXECUTABLE_EXTENSIONS: [&str; 16] = [
+    "app", "bat", "bash", "cmd", "com", "command", "csh", "exe", "jar", "ksh", "msi", "ps1", "run",
+    "scr", "sh", "vbs",
+];
+
+/// How long to wait for a backend to say whether it's available, if `available_timeout_secs` isn't configured
+pub const AVAILABLE_CHECK_TIMEOUT_SECS: u64 = 5;
+
+/// How much of a zip download is sent at a time
+pub const ZIP_CHUNK_BYTES: usize = 64 * 1024;
+/// How many chunks a zip download can get ahead of the client
+pub const ZIP_CHANNEL_CHUNKS: usize = 4;
+
+/// Browse uploads are streamed into a hidden key starting with this, then moved into place once the whole form's been read
+pub const UPLOAD_STAGING_PREFIX: &str = ".filekid-upload-";
+
+/// Directory (relative to a server path's base) where trashed files are kept
+pub const TRASH_DIR_NAME: &str = ".filekid-trash";
+
+/// Where partial chunked uploads are kept (under the system temp dir) unless `chunked_upload_dir` is set
+pub const CHUNKED_UPLOAD_DIR_NAME: &str = "filekid-chunked-uploads";
+/// How often to look for abandoned chunked uploads
+pub const CHUNKED_UPLOAD_SCAN_SECS: u64 = 3600;
@@ -0,0 +1,255 @@
+//! Running totals of how much each server path is storing, so the browse page doesn't have to walk everything
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use tracing::{debug, warn};
+
+use crate::constants::QUOTA_SIZE_CACHE_SECS;
+use crate::error::Error;
+use crate::fs::{fs_for_user, FileKidFs};
+use crate::ServerPath;
+
+type Sizes = HashMap<String, (Instant, u64)>;
+
+/// Bytes stored per server path, kept up to date by uploads and deletes and fully recounted every [ServerPath::size_cache_secs]
+#[derive(Clone, Debug, Default)]
+pub struct DirSizeCache {
+    /// Server path name -> (when it was last fully counted, bytes)
+    sizes: Arc<Mutex<Sizes>>,
+}
+
+impl DirSizeCache {
+    fn lock(&self) -> Result<MutexGuard<'_, Sizes>, Error> {
+        self.sizes.lock().map_err(|err| {
+            Error::InternalServerError(format!("Directory size cache lock is poisoned: {err}"))
+        })
+    }
+
+    /// How many bytes are stored under the server path, `None` if it doesn't have [ServerPath::size_cache_secs] set.
+    ///
+    /// This walks the whole server path if it's not been counted yet or the last count is too old, so don't call it on an async worker.
+    pub fn get(
+        &self,
+        name: &str,
+        server_path: &ServerPath,
+        filekidfs: &dyn FileKidFs,
+    ) -> Result<Option<u64>, Error> {
+        let Some(max_age) = server_path.size_cache_secs else {
+            return Ok(None);
+        };
+        self.counted(name, Duration::from_secs(max_age), filekidfs)
+            .map(Some)
+    }
+
+    fn counted(
+        &self,
+        name: &str,
+        max_age: Duration,
+        filekidfs: &dyn FileKidFs,
+    ) -> Result<u64, Error> {
+        if let Some((counted, bytes)) = self.lock()?.get(name)
+            && counted.elapsed() < max_age
+        {
+            return Ok(*bytes);
+        }
+        debug!("Counting the size of server path {}", name);
+        let bytes = filekidfs.total_size()?;
+        self.lock()?
+            .insert(name.to_string(), (Instant::now(), bytes));
+        Ok(bytes)
+    }
+
+    /// How many more bytes fit under [ServerPath::quota_bytes], `None` if there isn't a quota.
+    ///
+    /// Like [DirSizeCache::get] this can walk the whole server path, per-user paths are always counted since they're not tracked here.
+    pub fn quota_left(
+        &self,
+        name: &str,
+        server_path: &ServerPath,
+        filekidfs: &dyn FileKidFs,
+    ) -> Result<Option<u64>, Error> {
+        let Some(quota_bytes) = server_path.quota_bytes else {
+            return Ok(None);
+        };
+        let used = match server_path.is_per_user() {
+            true => filekidfs.total_size()?,
+            false => self.counted(
+                name,
+                Duration::from_secs(server_path.size_cache_secs.unwrap_or(QUOTA_SIZE_CACHE_SECS)),
+                filekidfs,
+            )?,
+        };
+        Ok(Some(quota_bytes.saturating_sub(used)))
+    }
+
+    /// [DirSizeCache::quota_left] for `username`'s view of the server path, counted off the async workers
+    pub(crate) async fn quota_left_for(
+        &self,
+        name: &str,
+        server_path: &ServerPath,
+        username: Option<&str>,
+    ) -> Result<Option<u64>, Error> {
+        if server_path.quota_bytes.is_none() {
+            return Ok(None);
+        }
+        let (cache, name, server_path, username) = (
+            self.clone(),
+            name.to_string(),
+            server_path.clone(),
+            username.map(str::to_string),
+        );
+        tokio::task::spawn_blocking(move || {
+            let filekidfs = fs_for_user(&server_path, username.as_deref())?;
+            cache.quota_left(&name, &server_path, filekidfs.as_ref())
+        })
+        .await
+        .map_err(|err| Error::InternalServerError(format!("Quota check failed to run: {err}")))?
+    }
+
+    /// Refuses storing `incoming` bytes in place of `replaced` ones if that'd take the server path over its quota
+    pub(crate) async fn check_quota(
+        &self,
+        name: &str,
+        server_path: &ServerPath,
+        username: Option<&str>,
+        incoming: u64,
+        replaced: u64,
+    ) -> Result<(), Error> {
+        match self.quota_left_for(name, server_path, username).await? {
+            Some(left) if incoming > left.saturating_add(replaced) => {
+                Err(Error::BadRequest("quota exceeded".to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Adds a stored file to the running total, if the server path's been counted
+    pub fn add(&self, name: &str, bytes: u64) {
+        self.update(name, |total| total.saturating_add(bytes));
+    }
+
+    /// Takes a removed or replaced file off the running total, if the server path's been counted
+    pub fn subtract(&self, name: &str, bytes: u64) {
+        self.update(name, |total| total.saturating_sub(bytes));
+    }
+
+    /// Drops the running total, for changes too big to work out, so the next look recounts it
+    pub fn forget(&self, name: &str) {
+        match self.lock() {
+            Ok(mut sizes) => {
+                sizes.remove(name);
+            }
+            Err(err) => warn!("Couldn't forget the size of {}: {:?}", name, err),
+        }
+    }
+
+    fn update(&self, name: &str, change: impl FnOnce(u64) -> u64) {
+        match self.lock() {
+            Ok(mut sizes) => {
+                if let Some((_, total)) = sizes.get_mut(name) {
+                    *total = change(*total);
+                }
+            }
+            // the next full count will fix it up
+            Err(err) => warn!("Couldn't update the size of {}: {:?}", name, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::fs_from_serverpath;
+
+    #[test]
+    fn test_dir_size_cache() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::create_dir(temp_dir.path().join("sub")).expect("Failed to create dir");
+        std::fs::write(temp_dir.path().join("a.txt"), b"hello").expect("Failed to write file");
+        std::fs::write(temp_dir.path().join("sub/b.txt"), b"world!").expect("Failed to write file");
+
+        let server_path = ServerPath {
+            size_cache_secs: Some(3600),
+            ..ServerPath::test_local(temp_dir.path())
+        };
+        let filekidfs = fs_from_serverpath(&server_path).expect("Failed to get fs");
+        let cache = DirSizeCache::default();
+
+        assert_eq!(
+            cache
+                .get(
+                    "test",
+                    &ServerPath::test_local(temp_dir.path()),
+                    filekidfs.as_ref()
+                )
+                .expect("Failed to get size"),
+            None
+        );
+        // nothing to adjust until it's been counted
+        cache.add("test", 100);
+        let get = || {
+            cache
+                .get("test", &server_path, filekidfs.as_ref())
+                .expect("Failed to get size")
+        };
+        assert_eq!(get(), Some(11));
+
+        // the cached total is used, not a recount
+        std::fs::write(temp_dir.path().join("untracked.txt"), b"xyz").expect("Failed to write");
+        cache.add("test", 4);
+        assert_eq!(get(), Some(15));
+        cache.subtract("test", 5);
+        assert_eq!(get(), Some(10));
+        cache.subtract("test", 1000);
+        assert_eq!(get(), Some(0));
+
+        // stale counts get redone from scratch
+        let stale = ServerPath {
+            size_cache_secs: Some(0),
+            ..server_path.clone()
+        };
+        assert_eq!(
+            cache
+                .get("test", &stale, filekidfs.as_ref())
+                .expect("Failed to get size"),
+            Some(14)
+        );
+    }
+
+    #[test]
+    fn test_quota_left() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("a.txt"), b"hello").expect("Failed to write file");
+        // half-finished browse uploads aren't counted twice
+        std::fs::write(temp_dir.path().join(".filekid-upload-abc"), b"hello")
+            .expect("Failed to write file");
+
+        let server_path = ServerPath {
+            quota_bytes: Some(8),
+            ..ServerPath::test_local(temp_dir.path())
+        };
+        let filekidfs = fs_from_serverpath(&server_path).expect("Failed to get fs");
+        let cache = DirSizeCache::default();
+
+        assert_eq!(
+            cache
+                .quota_left(
+                    "test",
+                    &ServerPath::test_local(temp_dir.path()),
+                    filekidfs.as_ref()
+                )
+                .expect("Failed to get quota"),
+            None
+        );
+        let left = || {
+            cache
+                .quota_left("test", &server_path, filekidfs.as_ref())
+                .expect("Failed to get quota")
+        };
+        assert_eq!(left(), Some(3));
+        cache.add("test", 10);
+        assert_eq!(left(), Some(0));
+    }
+}
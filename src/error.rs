@@ -2,7 +2,11 @@
 
 use super::web::Urls;
 use askama::Template;
-use axum::response::IntoResponse;
+use axum::extract::Request;
+use axum::http::header::{ACCEPT, ALLOW, CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER};
+use axum::middleware::Next;
+use axum::response::{Html, IntoResponse};
+use axum::Json;
 use axum::{http::StatusCode, response::Response};
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
@@ -31,6 +35,34 @@ pub enum Error {
     Database(String),
     /// Template rendering failed
     TemplateRendering(String),
+    /// The server path doesn't allow this method, holds the ones it does
+    MethodNotAllowed(Vec<String>),
+    /// The backend's down for now, holds what's down and how many seconds to wait before retrying
+    ServiceUnavailable(String, u64),
+    /// The file's changed since the client last saw it, going by `If-Match`
+    PreconditionFailed(String),
+}
+
+impl Error {
+    /// A stable, machine-readable name for the variant, for API clients to match on
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Generic(_) => "Generic",
+            Error::Configuration(_) => "Configuration",
+            Error::Oidc(_) => "Oidc",
+            Error::NotFound(_) => "NotFound",
+            Error::InternalServerError(_) => "InternalServerError",
+            Error::Io(_) => "Io",
+            Error::NotAuthorized(_) => "NotAuthorized",
+            Error::InvalidFileType(_) => "InvalidFileType",
+            Error::BadRequest(_) => "BadRequest",
+            Error::Database(_) => "Database",
+            Error::TemplateRendering(_) => "TemplateRendering",
+            Error::MethodNotAllowed(_) => "MethodNotAllowed",
+            Error::ServiceUnavailable(_, _) => "ServiceUnavailable",
+            Error::PreconditionFailed(_) => "PreconditionFailed",
+        }
+    }
 }
 
 impl From<axum_oidc::error::Error> for Error {
@@ -45,6 +77,12 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl From<zip::result::ZipError> for Error {
+    fn from(e: zip::result::ZipError) -> Self {
+        Self::Io(e.to_string())
+    }
+}
+
 impl From<askama::Error> for Error {
     fn from(e: askama::Error) -> Self {
         Self::TemplateRendering(e.to_string())
@@ -71,19 +109,82 @@ impl IntoResponse for Error {
             Error::BadRequest(_) => StatusCode::BAD_REQUEST,
             Error::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Error::TemplateRendering(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            Error::ServiceUnavailable(_, _) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+        };
+        let extra_header = match &self {
+            Error::MethodNotAllowed(allowed) => Some([(ALLOW, allowed.join(", "))]),
+            Error::ServiceUnavailable(_, retry_after) => {
+                Some([(RETRY_AFTER, retry_after.to_string())])
+            }
+            _ => None,
         };
-        (
+        let mut response = (
             statuscode,
+            extra_header,
             ErrorPage {
                 error: self.to_string(),
             }
             .render()
+            .map(Html)
             .map_err(|error| {
                 log::error!("Error rendering error page: {error}");
                 Error::InternalServerError(format!("Error rendering error page: {error}"))
             }),
         )
-            .into_response()
+            .into_response();
+        // so negotiate_error_format can swap the page out for JSON
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+/// Does the `Accept` header rank JSON above HTML?
+fn prefers_json(accept: &str) -> bool {
+    let (mut json, mut html) = (0.0, 0.0);
+    for media_range in accept.split(',') {
+        let mut params = media_range.split(';').map(str::trim);
+        let media_type = params.next().unwrap_or_default().to_lowercase();
+        let quality = params
+            .find_map(|param| param.strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if media_type == "application/json" || media_type.ends_with("+json") {
+            json = quality.max(json);
+        } else if media_type == "text/html" {
+            html = quality.max(html);
+        }
+    }
+    json > html
+}
+
+/// Middleware that turns error pages into `{"error": "...", "kind": "..."}` for clients that ask for JSON
+pub(crate) async fn negotiate_error_format(request: Request, next: Next) -> Response {
+    let wants_json = request
+        .headers()
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(prefers_json);
+    let mut response = next.run(request).await;
+    if !wants_json {
+        return response;
+    }
+    match response.extensions_mut().remove::<Error>() {
+        Some(error) => {
+            let (mut parts, _) = response.into_parts();
+            parts.headers.remove(CONTENT_TYPE);
+            parts.headers.remove(CONTENT_LENGTH);
+            (
+                parts,
+                Json(serde_json::json!({
+                    "error": error.to_string(),
+                    "kind": error.kind(),
+                })),
+            )
+                .into_response()
+        }
+        None => response,
     }
 }
 
@@ -101,6 +202,14 @@ impl Display for Error {
             Error::BadRequest(e) => write!(f, "Bad request: {e}"),
             Error::TemplateRendering(e) => write!(f, "Template rendering error: {e}"),
             Error::Database(e) => write!(f, "Database error: {e}"),
+            Error::MethodNotAllowed(allowed) => {
+                write!(f, "Method not allowed, use one of: {}", allowed.join(", "))
+            }
+            Error::ServiceUnavailable(e, retry_after) => write!(
+                f,
+                "{e} is unavailable right now, please try again in {retry_after} seconds"
+            ),
+            Error::PreconditionFailed(e) => write!(f, "Precondition failed: {e}"),
         }
     }
 }
@@ -166,6 +275,46 @@ mod tests {
         assert_eq!(format!("{}", e), "Bad request: bad request");
         assert_eq!(e.clone().into_response().status(), StatusCode::BAD_REQUEST);
 
+        let e = Error::MethodNotAllowed(vec!["GET".to_string(), "HEAD".to_string()]);
+        assert_eq!(
+            format!("{}", e),
+            "Method not allowed, use one of: GET, HEAD"
+        );
+        let response = e.clone().into_response();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(
+            response
+                .headers()
+                .get(axum::http::header::ALLOW)
+                .and_then(|value| value.to_str().ok()),
+            Some("GET, HEAD")
+        );
+
+        let e = Error::ServiceUnavailable("Server path test".to_string(), 30);
+        assert_eq!(
+            format!("{}", e),
+            "Server path test is unavailable right now, please try again in 30 seconds"
+        );
+        let response = e.clone().into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok()),
+            Some("30")
+        );
+
+        let e = Error::PreconditionFailed("test.txt has changed".to_string());
+        assert_eq!(
+            format!("{}", e),
+            "Precondition failed: test.txt has changed"
+        );
+        assert_eq!(
+            e.clone().into_response().status(),
+            StatusCode::PRECONDITION_FAILED
+        );
+
         let e = Error::Database("database error".to_string());
         assert_eq!(format!("{}", e), "Database error: database error");
         assert_eq!(
@@ -185,4 +334,87 @@ mod tests {
             "OIDC error: url parsing: RelativeUrlWithoutBase"
         );
     }
+
+    #[tokio::test]
+    async fn test_negotiate_error_format() {
+        use axum::body::Body;
+        use axum::routing::get;
+        use tower::ServiceExt;
+
+        let app = axum::Router::new()
+            .route(
+                "/missing",
+                get(|| async { Err::<(), _>(Error::NotFound("nope.txt".to_string())) }),
+            )
+            .route("/fine", get(|| async { "OK" }))
+            .layer(axum::middleware::from_fn(negotiate_error_format));
+        let request = |path: &str, accept: Option<&str>| {
+            let mut request = Request::builder().uri(path);
+            if let Some(accept) = accept {
+                request = request.header(ACCEPT, accept);
+            }
+            app.clone().oneshot(
+                request
+                    .body(Body::empty())
+                    .expect("Failed to build request"),
+            )
+        };
+
+        let response = request("/missing", Some("application/json"))
+            .await
+            .expect("Failed to send request");
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok()),
+            Some("application/json")
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Failed to read body");
+        let body: serde_json::Value = serde_json::from_slice(&body).expect("Failed to parse JSON");
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "error": "File/directory not found: nope.txt",
+                "kind": "NotFound",
+            })
+        );
+
+        // browsers get the page
+        for accept in [
+            None,
+            Some("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"),
+            Some("application/json;q=0.5, text/html"),
+        ] {
+            let response = request("/missing", accept)
+                .await
+                .expect("Failed to send request");
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            assert!(response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|v| v.starts_with("text/html")));
+        }
+
+        let response = request("/fine", Some("application/json"))
+            .await
+            .expect("Failed to send request");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Failed to read body");
+        assert_eq!(&body[..], b"OK");
+    }
+
+    #[test]
+    fn test_kind() {
+        assert_eq!(Error::NotFound("x".to_string()).kind(), "NotFound");
+        assert_eq!(
+            Error::MethodNotAllowed(vec!["GET".to_string()]).kind(),
+            "MethodNotAllowed"
+        );
+    }
 }
@@ -1,32 +1,66 @@
 //! local filesystem backend
 
 use std::path::PathBuf;
+use std::time::Instant;
 
-use axum::body::Body;
+use axum::body::{Body, Bytes};
+use tokio_util::io::ReaderStream;
 use tracing::{debug, error, instrument};
 
 use crate::error::Error;
 use crate::views::FileType;
 
-use super::{FileData, FileEntry, FileKidFs};
+use super::{
+    check_list_entries, check_not_same_file, checksum_file, dir_usage_walk, disk_space_for,
+    is_hidden, key_escapes_base, list_dir_no_symlinks, remove_dir_within, resolve_download_path,
+    resolves_within, search_walk, stream_to_file, strip_executable_bits, total_size_walk,
+    write_atomically, write_range_to_disk, ChecksumAlgo, DirUsage, DiskSpace, FileData, FileEntry,
+    FileKidFs, UploadStream,
+};
 
 #[derive(Debug)]
 pub struct LocalFs {
     pub base_path: PathBuf,
+    /// Whether downloads follow symlinks, see [crate::ServerPath::download_follow_symlinks]
+    pub follow_symlinks: bool,
+    /// Whether listings include dotfiles, see [crate::ServerPath::show_hidden]
+    pub show_hidden: bool,
 }
 
 impl LocalFs {
-    /// Ensure that the thing we're looking at is in a "safe" path
+    /// Ensure that the thing we're looking at is in a "safe" path, both as written and after following symlinks
     fn is_in_basepath(&self, filename: &PathBuf) -> Result<bool, Error> {
-        Ok(self
-            .base_path
-            .join(filename)
-            .ancestors()
-            .any(|path| path == self.base_path))
+        let target = self.base_path.join(filename);
+        Ok(target.ancestors().any(|path| path == self.base_path)
+            && resolves_within(&self.base_path, &target))
+    }
+
+    /// Checks the key stays within the base path, returning where it lives on disk
+    fn checked_path(&self, key: &str) -> Result<PathBuf, Error> {
+        if key_escapes_base(key) || !self.is_in_basepath(&PathBuf::from(key))? {
+            return Err(Error::NotAuthorized(
+                "Path is outside of base path".to_string(),
+            ));
+        }
+        Ok(self.target_path_from_key(key))
     }
 
     pub fn new(base_path: PathBuf) -> Self {
-        Self { base_path }
+        Self {
+            base_path,
+            follow_symlinks: true,
+            show_hidden: false,
+        }
+    }
+
+    pub fn with_follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    pub fn with_show_hidden(mut self, show_hidden: bool) -> Self {
+        self.show_hidden = show_hidden;
+        self
     }
 }
 
@@ -40,10 +74,14 @@ impl FileKidFs for LocalFs {
         true
     }
 
-    fn available(&self) -> Result<bool, Error> {
+    async fn available(&self) -> Result<bool, Error> {
         Ok(self.base_path.exists())
     }
 
+    fn disk_space(&self) -> Result<Option<DiskSpace>, Error> {
+        disk_space_for(&self.base_path).map(Some)
+    }
+
     #[instrument(level = "debug", skip(self))]
     fn exists(&self, filepath: &str) -> Result<bool, Error> {
         let target_file = self.base_path.join(filepath);
@@ -54,7 +92,8 @@ impl FileKidFs for LocalFs {
             self.base_path.display()
         );
         if self.base_path == target_file {
-            return Ok(true);
+            // the base path's missing if whatever it lives on isn't mounted
+            return Ok(target_file.is_dir());
         }
 
         Ok(target_file.exists() && self.is_in_basepath(&PathBuf::from(filepath))?)
@@ -62,19 +101,19 @@ impl FileKidFs for LocalFs {
 
     #[instrument(level = "debug", skip(self))]
     fn get_data(&self, path: &str) -> Result<super::FileData, Error> {
-        self.is_in_basepath(&path.into())?;
+        let actual_filepath = self.checked_path(path)?;
 
-        if !self.base_path.join(path).exists() {
+        if !actual_filepath.exists() {
             return Err(Error::NotFound(format!("Can't find {path}")));
         }
 
-        let actual_filepath = self.base_path.join(path);
-
         let filename = actual_filepath
             .file_name()
             // this shouldn't trigger because we just checked the file exists
             .ok_or_else(|| Error::NotFound("File not found".to_string()))?;
 
+        let metadata = actual_filepath.metadata().ok();
+
         Ok(FileData {
             filename: filename.to_string_lossy().to_string(),
             filepath: actual_filepath
@@ -82,7 +121,8 @@ impl FileKidFs for LocalFs {
                 .unwrap_or(&self.base_path)
                 .to_path_buf(),
             // this shouldn't trigger because we just checked the file exists, but we might not be able to read it
-            size: actual_filepath.metadata().ok().map(|m| m.len()),
+            size: metadata.as_ref().map(|m| m.len()),
+            modified: metadata.and_then(|m| m.modified().ok()),
         })
     }
 
@@ -96,12 +136,29 @@ impl FileKidFs for LocalFs {
             ));
         }
 
+        let target_path =
+            resolve_download_path(&self.base_path, &target_path, self.follow_symlinks)?;
         Ok(tokio::fs::read(target_path).await?)
     }
 
     #[instrument(level = "debug", skip(self))]
     async fn read_file(&self, filepath: &str) -> Result<Body, Error> {
-        todo!()
+        let target_path = resolve_download_path(
+            &self.base_path,
+            &self.checked_path(filepath)?,
+            self.follow_symlinks,
+        )?;
+        let file = tokio::fs::File::open(target_path).await?;
+        Ok(Body::from_stream(ReaderStream::new(file)))
+    }
+
+    fn local_path(&self, filepath: &str) -> Result<Option<PathBuf>, Error> {
+        resolve_download_path(
+            &self.base_path,
+            &self.checked_path(filepath)?,
+            self.follow_symlinks,
+        )
+        .map(Some)
     }
 
     #[instrument(level = "debug", skip(contents, self))]
@@ -115,15 +172,46 @@ impl FileKidFs for LocalFs {
         }
 
         debug!("Writing to file {:?}", target_file);
-        tokio::fs::write(target_file, contents)
-            .await
-            .map_err(Error::from)
+        write_atomically(&target_file, contents).await
+    }
+
+    #[instrument(level = "debug", skip(self, stream))]
+    async fn stream_put_file<'s>(
+        &self,
+        filepath: &str,
+        stream: UploadStream<'s>,
+        declared_len: Option<u64>,
+        strict: bool,
+    ) -> Result<u64, Error> {
+        let target_file = self.checked_path(filepath)?;
+        debug!("Streaming to file {:?}", target_file);
+        stream_to_file(&target_file, stream, declared_len, strict).await
+    }
+
+    #[instrument(level = "debug", skip(contents, self))]
+    async fn put_file_range(
+        &self,
+        filepath: &str,
+        offset: u64,
+        total: u64,
+        contents: Bytes,
+        overwrite: bool,
+    ) -> Result<bool, Error> {
+        let target_file = self.checked_path(filepath)?;
+        if target_file.exists() && !overwrite {
+            return Err(Error::BadRequest(format!("{filepath} already exists")));
+        }
+        write_range_to_disk(&target_file, offset, total, contents).await
     }
 
     fn target_path_from_key(&self, key: &str) -> PathBuf {
         self.base_path.join(key)
     }
 
+    fn strip_executable(&self, filepath: &str) -> Result<(), Error> {
+        strip_executable_bits(&self.checked_path(filepath)?)
+    }
+
     #[instrument(level = "debug", skip(self))]
     fn delete_file(&self, filepath: &str) -> Result<(), Error> {
         let target_file = self.base_path.join(filepath);
@@ -136,7 +224,103 @@ impl FileKidFs for LocalFs {
     }
 
     #[instrument(level = "debug", skip(self))]
-    fn list_dir(&self, path: Option<String>) -> Result<Vec<FileEntry>, Error> {
+    fn delete_dir(&self, path: &str, recursive: bool) -> Result<(), Error> {
+        remove_dir_within(&self.base_path, &self.checked_path(path)?, path, recursive)
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    fn checksum(&self, filepath: &str, algorithm: ChecksumAlgo) -> Result<String, Error> {
+        checksum_file(
+            &resolve_download_path(
+                &self.base_path,
+                &self.checked_path(filepath)?,
+                self.follow_symlinks,
+            )?,
+            algorithm,
+        )
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    fn move_file(&self, from: &str, to: &str, overwrite: bool) -> Result<(), Error> {
+        let source = self.checked_path(from)?;
+        let destination = self.checked_path(to)?;
+        if !source.exists() {
+            return Err(Error::NotFound(from.to_string()));
+        }
+        if destination.exists() && !overwrite {
+            return Err(Error::BadRequest(format!("{to} already exists")));
+        }
+        debug!("Moving {} to {}", source.display(), destination.display());
+        std::fs::rename(source, destination).map_err(Error::from)
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    fn copy_file(&self, from: &str, to: &str, overwrite: bool) -> Result<(), Error> {
+        let source = self.checked_path(from)?;
+        let destination = self.checked_path(to)?;
+        if !source.is_file() {
+            return Err(Error::NotFound(from.to_string()));
+        }
+        if destination.exists() && !overwrite {
+            return Err(Error::BadRequest("destination exists".to_string()));
+        }
+        check_not_same_file(&source, &destination)?;
+        debug!("Copying {} to {}", source.display(), destination.display());
+        std::fs::copy(source, destination)?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    fn create_dir(&self, path: &str) -> Result<(), Error> {
+        let target = self.checked_path(path)?;
+        if target.exists() {
+            return Err(Error::BadRequest(format!("{path} already exists")));
+        }
+        if !target
+            .parent()
+            .map(|parent| parent.is_dir())
+            .unwrap_or(false)
+        {
+            return Err(Error::NotFound(format!("Parent directory of {path}")));
+        }
+        debug!("Creating directory {}", target.display());
+        std::fs::create_dir(target).map_err(Error::from)
+    }
+
+    /// Doesn't follow symlinks, so the walk can't wander outside the base path
+    #[instrument(level = "debug", skip(self))]
+    fn search(
+        &self,
+        root: Option<String>,
+        query: &str,
+        max_results: usize,
+    ) -> Result<Vec<FileEntry>, Error> {
+        if let Some(root) = &root {
+            let target = self.checked_path(root)?;
+            resolve_download_path(&self.base_path, &target, false)?;
+        }
+        search_walk(root, query, max_results, |key| {
+            list_dir_no_symlinks(&self.base_path, key)
+        })
+    }
+
+    fn total_size(&self) -> Result<u64, Error> {
+        total_size_walk(|key| list_dir_no_symlinks(&self.base_path, key))
+    }
+
+    fn dir_usage(
+        &self,
+        key: &str,
+        max_entries: usize,
+        deadline: Instant,
+    ) -> Result<DirUsage, Error> {
+        dir_usage_walk(key, max_entries, deadline, |key| {
+            list_dir_no_symlinks(&self.base_path, key)
+        })
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    fn list_dir(&self, path: Option<String>, max_entries: usize) -> Result<Vec<FileEntry>, Error> {
         let path_addition = path.clone().unwrap_or_default();
 
         let target_path = self.target_path_from_key(&path_addition);
@@ -153,7 +337,7 @@ impl FileKidFs for LocalFs {
             )));
         }
 
-        std::fs::read_dir(&target_path)
+        let entries = std::fs::read_dir(&target_path)
             .map_err(|e| {
                 error!(
                     "Failed to read dir {} from server {:?}: {}",
@@ -163,6 +347,12 @@ impl FileKidFs for LocalFs {
                 );
                 Error::from(e)
             })?
+            .filter(|entry| {
+                self.show_hidden
+                    || !entry
+                        .as_ref()
+                        .is_ok_and(|entry| is_hidden(&entry.file_name()))
+            })
             .map(|entry| {
                 entry
                     .map_err(|e| {
@@ -182,9 +372,7 @@ impl FileKidFs for LocalFs {
                                 self.base_path.display(),
                                 e
                             );
-                            Error::InternalServerError(format!(
-                                "Invalid Filename {entry:?} {e:?}"
-                            ))
+                            Error::InternalServerError(format!("Invalid Filename {entry:?} {e:?}"))
                         })?;
                         let fullpath = match &path {
                             Some(p) => format!("{p}/{filename}"),
@@ -199,32 +387,53 @@ impl FileKidFs for LocalFs {
                             Error::from(e)
                         })?;
 
+                        // follows symlinks, so links show their target's size
+                        let metadata = std::fs::metadata(entry.path()).ok();
+
                         Ok(FileEntry {
                             filename,
                             fullpath,
+                            size: metadata
+                                .as_ref()
+                                .filter(|metadata| metadata.is_file())
+                                .map(|metadata| metadata.len()),
+                            modified: metadata.and_then(|metadata| metadata.modified().ok()),
                             filetype: if filetype.is_dir() {
                                 FileType::Directory
                             } else {
                                 FileType::File
                             },
+                            dir_usage: None,
                         })
                     })
             })
-            .collect()
+            // one more than allowed is enough to know it's too many
+            .take(max_entries.saturating_add(1))
+            .collect::<Result<Vec<_>, Error>>()?;
+        check_list_entries(entries.len(), max_entries)?;
+        Ok(entries)
     }
 
-    fn is_file(&self, _key: &str) -> bool {
-        todo!()
+    #[instrument(level = "debug", skip(self))]
+    fn is_file(&self, key: &str) -> bool {
+        !key_escapes_base(key)
+            && self.is_in_basepath(&PathBuf::from(key)).unwrap_or(false)
+            && self.target_path_from_key(key).is_file()
     }
-    fn is_dir(&self, _key: &str) -> bool {
-        todo!()
+
+    #[instrument(level = "debug", skip(self))]
+    fn is_dir(&self, key: &str) -> bool {
+        !key_escapes_base(key)
+            && self.is_in_basepath(&PathBuf::from(key)).unwrap_or(false)
+            && self.target_path_from_key(key).is_dir()
     }
 }
 
 #[cfg(test)]
 mod tests {
 
-    use crate::log::setup_logging;
+    use crate::constants::DEFAULT_MAX_LIST_ENTRIES;
+    use crate::log::{setup_logging, LogFormat};
 
     #[tokio::test]
     async fn test_localfs_name() {
@@ -236,10 +445,43 @@ mod tests {
 
         let fs = LocalFs::new(temp_dir_path.clone());
 
-        assert!(fs.available().expect("Isn't available!"));
+        assert!(fs.available().await.expect("Isn't available!"));
 
         assert!(fs.name().contains(&temp_dir_path.display().to_string()));
     }
+    #[test]
+    fn test_list_dir_hidden() {
+        use super::*;
+        use tempfile::tempdir;
+
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("visible.txt"), b"hello").expect("Failed to write");
+        std::fs::write(temp_dir.path().join(".hidden"), b"hello").expect("Failed to write");
+        std::fs::create_dir(temp_dir.path().join(".git")).expect("Failed to create dir");
+
+        let filenames = |fs: &LocalFs| {
+            let mut filenames: Vec<String> = fs
+                .list_dir(None, DEFAULT_MAX_LIST_ENTRIES)
+                .expect("Failed to list dir")
+                .into_iter()
+                .map(|entry| entry.filename)
+                .collect();
+            filenames.sort();
+            filenames
+        };
+
+        let fs = LocalFs::new(temp_dir.path().to_path_buf());
+        assert_eq!(filenames(&fs), vec!["visible.txt"]);
+        // hidden entries don't count towards the limit
+        assert!(fs.list_dir(None, 1).is_ok());
+
+        let fs = fs.with_show_hidden(true);
+        assert_eq!(filenames(&fs), vec![".git", ".hidden", "visible.txt"]);
+        assert!(!filenames(&fs)
+            .iter()
+            .any(|name| name == "." || name == ".."));
+    }
+
     #[tokio::test]
     async fn test_list_dir2() {
         use super::*;
@@ -247,7 +489,7 @@ mod tests {
         use std::io::Write;
         use tempfile::tempdir;
 
-        let _ = setup_logging(true, true);
+        let _ = setup_logging(true, true, LogFormat::Pretty);
         let temp_dir = tempdir().expect("Failed to create temp dir");
         let temp_dir_path = temp_dir.path().to_path_buf();
 
@@ -258,16 +500,20 @@ mod tests {
 
         let fs = LocalFs::new(temp_dir_path.clone());
 
-        let entries = fs.list_dir(None).expect("Failed to list dir");
+        let entries = fs
+            .list_dir(None, DEFAULT_MAX_LIST_ENTRIES)
+            .expect("Failed to list dir");
         assert_eq!(entries.len(), 1);
         assert_eq!(entries[0].filename, "test.txt");
         assert_eq!(entries[0].fullpath, "test.txt");
         assert_eq!(entries[0].filetype, FileType::File);
 
-        assert!(fs.list_dir(Some("test.txt".to_string())).is_err());
+        assert!(fs
+            .list_dir(Some("test.txt".to_string()), DEFAULT_MAX_LIST_ENTRIES)
+            .is_err());
 
         let entries = fs
-            .list_dir(Some(".".to_string()))
+            .list_dir(Some(".".to_string()), DEFAULT_MAX_LIST_ENTRIES)
             .expect("Failed to list dir");
         assert_eq!(entries.len(), 1);
         assert_eq!(entries[0].filename, "test.txt");
@@ -280,7 +526,7 @@ mod tests {
         use super::*;
         use tempfile::tempdir;
 
-        let _ = setup_logging(true, true);
+        let _ = setup_logging(true, true, LogFormat::Pretty);
 
         let temp_dir = tempdir().expect("Failed to create temp dir");
         let temp_dir_path = temp_dir.path().to_path_buf();
@@ -297,7 +543,7 @@ mod tests {
         use super::*;
         use tempfile::tempdir;
 
-        let _ = setup_logging(true, true);
+        let _ = setup_logging(true, true, LogFormat::Pretty);
 
         let temp_dir = tempdir().expect("Failed to create temp dir");
         let temp_dir_path = temp_dir.path().to_path_buf();
@@ -322,7 +568,7 @@ mod tests {
         use super::*;
         use tempfile::tempdir;
 
-        let _ = setup_logging(true, true);
+        let _ = setup_logging(true, true, LogFormat::Pretty);
 
         let temp_dir = tempdir().expect("Failed to create temp dir");
         let temp_dir_path = temp_dir.path().to_path_buf();
@@ -359,24 +605,513 @@ mod tests {
         use super::*;
         use tempfile::tempdir;
 
-        let _ = setup_logging(true, true);
+        let _ = setup_logging(true, true, LogFormat::Pretty);
 
         let temp_dir = tempdir().expect("Failed to create temp dir");
         let temp_dir_path = temp_dir.path().to_path_buf();
 
         let fs = LocalFs::new(temp_dir_path.clone());
 
-        let res = fs.list_dir(None);
+        let res = fs.list_dir(None, DEFAULT_MAX_LIST_ENTRIES);
         assert!(res.is_ok());
         let entries = res.expect("failed to get file entries");
         assert_eq!(entries.len(), 0);
 
-        let res = fs.list_dir(Some(".".to_string()));
+        let res = fs.list_dir(Some(".".to_string()), DEFAULT_MAX_LIST_ENTRIES);
         assert!(res.is_ok());
         let entries = res.expect("failed to get file entries");
         assert_eq!(entries.len(), 0);
 
-        let res = fs.list_dir(Some("thiscannotexist.foo".to_string()));
+        let res = fs.list_dir(
+            Some("thiscannotexist.foo".to_string()),
+            DEFAULT_MAX_LIST_ENTRIES,
+        );
         assert!(res.is_err());
     }
+
+    #[test]
+    fn test_is_file_is_dir() {
+        use super::*;
+        use tempfile::tempdir;
+
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("test.txt"), b"Hello, world!")
+            .expect("Failed to write test file");
+        std::fs::create_dir(temp_dir.path().join("subdir")).expect("Failed to create subdir");
+
+        let fs = LocalFs::new(temp_dir.path().to_path_buf());
+
+        assert!(fs.is_file("test.txt"));
+        assert!(!fs.is_dir("test.txt"));
+
+        assert!(fs.is_dir("subdir"));
+        assert!(!fs.is_file("subdir"));
+
+        assert!(!fs.is_file("thiscannotexist.foo"));
+        assert!(!fs.is_dir("thiscannotexist.foo"));
+
+        assert!(!fs.is_dir(".."));
+        assert!(!fs.is_dir("/etc"));
+        assert!(!fs.is_file("../../../etc/passwd"));
+    }
+
+    #[test]
+    fn test_move_file() {
+        use super::*;
+        use tempfile::tempdir;
+
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("test.txt"), b"Hello, world!")
+            .expect("Failed to write test file");
+        std::fs::write(temp_dir.path().join("other.txt"), b"other")
+            .expect("Failed to write test file");
+        std::fs::create_dir(temp_dir.path().join("subdir")).expect("Failed to create subdir");
+
+        let fs = LocalFs::new(temp_dir.path().to_path_buf());
+
+        fs.move_file("test.txt", "subdir/moved.txt", false)
+            .expect("Failed to move file");
+        assert!(!fs.is_file("test.txt"));
+        assert!(fs.is_file("subdir/moved.txt"));
+
+        assert_eq!(
+            fs.move_file("subdir/moved.txt", "other.txt", false),
+            Err(Error::BadRequest("other.txt already exists".to_string()))
+        );
+        assert_eq!(
+            fs.move_file("subdir/moved.txt", "../escaped.txt", false),
+            Err(Error::NotAuthorized(
+                "Path is outside of base path".to_string()
+            ))
+        );
+        assert!(fs
+            .move_file("thiscannotexist.foo", "foo.txt", false)
+            .is_err());
+
+        fs.move_file("subdir/moved.txt", "other.txt", true)
+            .expect("Failed to move over the existing file");
+        assert!(!fs.is_file("subdir/moved.txt"));
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("other.txt")).expect("Failed to read file"),
+            b"Hello, world!"
+        );
+    }
+
+    #[test]
+    fn test_copy_file() {
+        use super::*;
+        use tempfile::tempdir;
+
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("test.txt"), b"Hello, world!")
+            .expect("Failed to write test file");
+        std::fs::write(temp_dir.path().join("other.txt"), b"other")
+            .expect("Failed to write test file");
+
+        let fs = LocalFs::new(temp_dir.path().to_path_buf());
+
+        fs.copy_file("test.txt", "copy.txt", false)
+            .expect("Failed to copy file");
+        assert!(fs.is_file("test.txt"));
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("copy.txt")).expect("Failed to read copy"),
+            b"Hello, world!"
+        );
+
+        assert_eq!(
+            fs.copy_file("test.txt", "other.txt", false),
+            Err(Error::BadRequest("destination exists".to_string()))
+        );
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("other.txt")).expect("Failed to read other"),
+            b"other"
+        );
+
+        fs.copy_file("test.txt", "other.txt", true)
+            .expect("Failed to overwrite file");
+
+        // the same file by another name, which would be truncated
+        assert_eq!(
+            fs.copy_file("test.txt", "./test.txt", true),
+            Err(Error::BadRequest(
+                "source and destination are the same".to_string()
+            ))
+        );
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("test.txt")).expect("Failed to read file"),
+            b"Hello, world!"
+        );
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("other.txt")).expect("Failed to read other"),
+            b"Hello, world!"
+        );
+
+        assert!(matches!(
+            fs.copy_file("test.txt", "../escaped.txt", true),
+            Err(Error::NotAuthorized(_))
+        ));
+        assert!(matches!(
+            fs.copy_file("/etc/passwd", "passwd", true),
+            Err(Error::NotAuthorized(_))
+        ));
+    }
+
+    #[test]
+    fn test_create_dir() {
+        use super::*;
+        use tempfile::tempdir;
+
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let fs = LocalFs::new(temp_dir.path().to_path_buf());
+
+        fs.create_dir("newdir").expect("Failed to create dir");
+        assert!(fs.is_dir("newdir"));
+        fs.create_dir("newdir/inner").expect("Failed to create dir");
+        assert!(fs.is_dir("newdir/inner"));
+
+        assert!(matches!(fs.create_dir("newdir"), Err(Error::BadRequest(_))));
+        assert!(matches!(
+            fs.create_dir("missing/inner"),
+            Err(Error::NotFound(_))
+        ));
+        assert!(!temp_dir.path().join("missing").exists());
+        assert!(matches!(
+            fs.create_dir("../escaped"),
+            Err(Error::NotAuthorized(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_put_file_range() {
+        use super::*;
+        use tempfile::tempdir;
+
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let fs = LocalFs::new(temp_dir.path().to_path_buf());
+
+        assert!(!fs
+            .put_file_range("test.txt", 5, 10, Bytes::from_static(b"world"), false)
+            .await
+            .expect("Failed to put range"));
+        assert!(!fs.is_file("test.txt"));
+        assert!(fs
+            .put_file_range("test.txt", 0, 10, Bytes::from_static(b"hello"), false)
+            .await
+            .expect("Failed to put range"));
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("test.txt")).expect("Failed to read file"),
+            b"helloworld"
+        );
+
+        assert!(matches!(
+            fs.put_file_range("test.txt", 0, 10, Bytes::from_static(b"hello"), false)
+                .await,
+            Err(Error::BadRequest(_))
+        ));
+        // replacing it needs overwrite, and nothing changes until the last range
+        assert!(!fs
+            .put_file_range("test.txt", 5, 10, Bytes::from_static(b"there"), true)
+            .await
+            .expect("Failed to put range"));
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("test.txt")).expect("Failed to read file"),
+            b"helloworld"
+        );
+        assert!(fs
+            .put_file_range("test.txt", 0, 10, Bytes::from_static(b"hello"), true)
+            .await
+            .expect("Failed to put range"));
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("test.txt")).expect("Failed to read file"),
+            b"hellothere"
+        );
+        assert!(matches!(
+            fs.put_file_range("../escaped.txt", 0, 5, Bytes::from_static(b"hello"), false)
+                .await,
+            Err(Error::NotAuthorized(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_get_file_symlinks() {
+        use super::*;
+        use tempfile::tempdir;
+
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let outside_dir = tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("real.txt"), b"hello").expect("Failed to write file");
+        std::fs::write(outside_dir.path().join("secret.txt"), b"secret")
+            .expect("Failed to write file");
+        std::os::unix::fs::symlink(
+            temp_dir.path().join("real.txt"),
+            temp_dir.path().join("link.txt"),
+        )
+        .expect("Failed to create symlink");
+        std::os::unix::fs::symlink(
+            outside_dir.path().join("secret.txt"),
+            temp_dir.path().join("escape.txt"),
+        )
+        .expect("Failed to create symlink");
+        std::os::unix::fs::symlink(temp_dir.path(), temp_dir.path().join("linkdir"))
+            .expect("Failed to create symlink");
+
+        let fs = LocalFs::new(temp_dir.path().to_path_buf());
+        assert_eq!(
+            fs.get_file("link.txt").await.expect("Failed to get file"),
+            b"hello"
+        );
+        assert_eq!(
+            fs.get_file("linkdir/real.txt")
+                .await
+                .expect("Failed to get file"),
+            b"hello"
+        );
+        let body = fs.read_file("link.txt").await.expect("Failed to read file");
+        assert_eq!(
+            axum::body::to_bytes(body, usize::MAX)
+                .await
+                .expect("Failed to read body"),
+            "hello"
+        );
+        // following never leaves the base path
+        assert!(matches!(
+            fs.get_file("escape.txt").await,
+            Err(Error::NotAuthorized(_))
+        ));
+
+        let fs = fs.with_follow_symlinks(false);
+        assert_eq!(
+            fs.get_file("real.txt").await.expect("Failed to get file"),
+            b"hello"
+        );
+        for key in ["link.txt", "linkdir/real.txt", "escape.txt"] {
+            assert!(
+                matches!(fs.get_file(key).await, Err(Error::NotAuthorized(_))),
+                "{key} should be refused"
+            );
+            assert!(
+                matches!(fs.read_file(key).await, Err(Error::NotAuthorized(_))),
+                "{key} should be refused"
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_symlink_escapes() {
+        use super::*;
+        use tempfile::tempdir;
+
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let outside_dir = tempdir().expect("Failed to create temp dir");
+        std::fs::write(outside_dir.path().join("secret.txt"), b"secret")
+            .expect("Failed to write file");
+        std::os::unix::fs::symlink(outside_dir.path(), temp_dir.path().join("escape"))
+            .expect("Failed to create symlink");
+        std::os::unix::fs::symlink(
+            outside_dir.path().join("missing.txt"),
+            temp_dir.path().join("dangling.txt"),
+        )
+        .expect("Failed to create symlink");
+        std::fs::create_dir(temp_dir.path().join("inside")).expect("Failed to create dir");
+
+        let fs = LocalFs::new(temp_dir.path().to_path_buf());
+        for result in [
+            fs.get_file("escape/secret.txt").await.map(|_| ()),
+            fs.read_file("escape/secret.txt").await.map(|_| ()),
+            fs.get_data("escape/secret.txt").map(|_| ()),
+            fs.put_file("escape/new.txt", b"nope").await,
+            fs.put_file("dangling.txt", b"nope").await,
+            fs.delete_file("escape/secret.txt"),
+            fs.copy_file("escape/secret.txt", "copied.txt", false),
+            fs.move_file("inside", "escape/moved", false),
+            fs.create_dir("escape/newdir"),
+            fs.delete_dir("escape", true),
+            fs.list_dir(Some("escape".to_string()), DEFAULT_MAX_LIST_ENTRIES)
+                .map(|_| ()),
+        ] {
+            assert!(
+                matches!(result, Err(Error::NotAuthorized(_))),
+                "got {result:?}"
+            );
+        }
+        assert!(!fs.is_file("escape/secret.txt"));
+        assert!(!fs.is_dir("escape"));
+        assert!(!fs.exists("escape/secret.txt").expect("Failed to check"));
+        assert!(outside_dir.path().join("secret.txt").exists());
+        assert!(!outside_dir.path().join("new.txt").exists());
+        assert!(!outside_dir.path().join("missing.txt").exists());
+
+        // new files and directories inside the base path are still fine
+        fs.put_file("inside/new.txt", b"yes")
+            .await
+            .expect("Failed to write a new file");
+        fs.create_dir("inside/newdir")
+            .expect("Failed to create a new dir");
+
+        // a link out of a directory stops all of it being deleted
+        std::os::unix::fs::symlink(
+            outside_dir.path(),
+            temp_dir.path().join("inside/newdir/link"),
+        )
+        .expect("Failed to create symlink");
+        assert!(matches!(
+            fs.delete_dir("inside", true),
+            Err(Error::NotAuthorized(_))
+        ));
+        assert!(temp_dir.path().join("inside/new.txt").exists());
+        assert!(outside_dir.path().join("secret.txt").exists());
+    }
+
+    #[test]
+    fn test_list_dir_max_entries() {
+        use super::*;
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        for index in 0..5 {
+            std::fs::write(temp_dir.path().join(format!("{index}.txt")), b"x")
+                .expect("Failed to write file");
+        }
+        let fs = LocalFs::new(temp_dir.path().to_path_buf());
+        assert_eq!(fs.list_dir(None, 5).expect("Failed to list dir").len(), 5);
+        assert_eq!(
+            fs.list_dir(None, 4).err(),
+            Some(Error::BadRequest(
+                "directory too large to list, it has more than 4 entries".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_dir_usage() {
+        use super::*;
+        use std::time::Duration;
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::create_dir_all(temp_dir.path().join("top/middle/bottom"))
+            .expect("Failed to create dirs");
+        std::fs::write(temp_dir.path().join("top/a.txt"), b"hello").expect("Failed to write");
+        std::fs::write(temp_dir.path().join("top/middle/b.txt"), b"world!")
+            .expect("Failed to write");
+        std::fs::write(temp_dir.path().join("top/middle/bottom/c.txt"), b"xyz")
+            .expect("Failed to write");
+        std::fs::write(temp_dir.path().join("outside.txt"), b"not counted")
+            .expect("Failed to write");
+        let fs = LocalFs::new(temp_dir.path().to_path_buf());
+        let later = Instant::now() + Duration::from_secs(60);
+
+        assert_eq!(
+            fs.dir_usage("top", 100, later).expect("Failed to count"),
+            DirUsage {
+                size: 14,
+                count: 3,
+                approximate: false,
+            }
+        );
+        assert_eq!(
+            fs.dir_usage("top/middle", 100, later)
+                .expect("Failed to count"),
+            DirUsage {
+                size: 9,
+                count: 2,
+                approximate: false,
+            }
+        );
+
+        // cut short by the entry cap or the deadline, it's a lower bound
+        let capped = fs.dir_usage("top", 2, later).expect("Failed to count");
+        assert!(capped.approximate);
+        assert!(capped.count < 3);
+        assert!(
+            fs.dir_usage("top", 100, Instant::now())
+                .expect("Failed to count")
+                .approximate
+        );
+    }
+
+    #[test]
+    fn test_search() {
+        use super::*;
+        use tempfile::tempdir;
+
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        std::fs::create_dir_all(temp_dir.path().join("photos/2024/Holiday"))
+            .expect("Failed to create dirs");
+        for file in [
+            "holiday-plan.txt",
+            "photos/HOLIDAY.jpg",
+            "photos/2024/Holiday/beach.jpg",
+            "photos/2024/other.jpg",
+        ] {
+            std::fs::write(temp_dir.path().join(file), b"").expect("Failed to write file");
+        }
+        let fs = LocalFs::new(temp_dir.path().to_path_buf());
+
+        let mut found: Vec<String> = fs
+            .search(None, "holiday", 100)
+            .expect("Failed to search")
+            .into_iter()
+            .map(|entry| entry.fullpath)
+            .collect();
+        found.sort();
+        assert_eq!(
+            found,
+            vec![
+                "holiday-plan.txt",
+                "photos/2024/Holiday",
+                "photos/HOLIDAY.jpg"
+            ]
+        );
+
+        // shallower matches are found first, and it stops at the limit
+        let found = fs.search(None, "jpg", 1).expect("Failed to search");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].fullpath, "photos/HOLIDAY.jpg");
+
+        let found = fs
+            .search(Some("photos/2024".to_string()), "jpg", 100)
+            .expect("Failed to search");
+        assert_eq!(found.len(), 2);
+
+        assert!(fs
+            .search(None, "", 100)
+            .expect("Failed to search")
+            .is_empty());
+        assert!(matches!(
+            fs.search(Some("../".to_string()), "jpg", 100),
+            Err(Error::NotAuthorized(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_search_symlinks() {
+        use super::*;
+        use tempfile::tempdir;
+
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let outside_dir = tempdir().expect("Failed to create temp dir");
+        std::fs::write(outside_dir.path().join("secret.txt"), b"secret")
+            .expect("Failed to write file");
+        std::os::unix::fs::symlink(outside_dir.path(), temp_dir.path().join("escape"))
+            .expect("Failed to create symlink");
+        // a loop, which would never finish if it was followed
+        std::os::unix::fs::symlink(temp_dir.path(), temp_dir.path().join("loop"))
+            .expect("Failed to create symlink");
+
+        let fs = LocalFs::new(temp_dir.path().to_path_buf());
+        assert!(fs
+            .search(None, "secret", 100)
+            .expect("Failed to search")
+            .is_empty());
+        assert!(fs
+            .search(None, "loop", 100)
+            .expect("Failed to search")
+            .is_empty());
+        assert!(matches!(
+            fs.search(Some("escape".to_string()), "secret", 100),
+            Err(Error::NotAuthorized(_))
+        ));
+    }
 }
@@ -1,17 +1,32 @@
-use std::path::PathBuf;
+use std::collections::{HashMap, VecDeque};
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
+use md5::Md5;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
+use axum::body::Bytes;
+use futures::stream::BoxStream;
 use futures::{Stream, TryStreamExt};
 use tokio::fs::File;
-use tokio::io::BufWriter;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
 use tokio_util::io::StreamReader;
+use tracing::{debug, warn};
+use utoipa::ToSchema;
 
+use crate::constants::{DISK_SPACE_CACHE_SECS, MAX_SEARCH_DEPTH, UPLOAD_STAGING_PREFIX};
 use crate::error::Error;
 use crate::views::browse::FileEntry;
+use crate::views::FileType;
 use crate::ServerPath;
 
 pub mod local;
+pub mod registry;
 pub mod s3;
 pub mod tempdir;
 
@@ -22,12 +37,16 @@ pub struct FileData {
     /// the parent path on disk
     pub filepath: PathBuf,
     pub size: Option<u64>,
+    pub modified: Option<SystemTime>,
 }
 
+/// The body of an upload, as it arrives
+pub type UploadStream<'s> = BoxStream<'s, Result<Bytes, std::io::Error>>;
+
 #[async_trait::async_trait]
 pub trait FileKidFs
 where
-    Self: std::fmt::Debug + Send,
+    Self: std::fmt::Debug + Send + Sync,
 {
     fn name(&self) -> String;
 
@@ -44,19 +63,138 @@ where
     async fn get_file(&self, filepath: &str) -> Result<Vec<u8>, Error>;
     async fn read_file(&self, filepath: &str) -> Result<axum::body::Body, Error>;
 
+    /// Where a file is on this machine's disk, so something else (like a reverse proxy) can read it directly. `None` if it's stored remotely.
+    fn local_path(&self, _filepath: &str) -> Result<Option<PathBuf>, Error> {
+        Ok(None)
+    }
+
     async fn put_file(&self, filepath: &str, contents: &[u8]) -> Result<(), Error>;
 
+    /// Streams an upload into a file without buffering it, returning how many bytes were written.
+    ///
+    /// The length is checked against `declared_len` with [check_content_length], and the file's removed if that fails.
+    async fn stream_put_file<'s>(
+        &self,
+        filepath: &str,
+        _stream: UploadStream<'s>,
+        _declared_len: Option<u64>,
+        _strict: bool,
+    ) -> Result<u64, Error> {
+        Err(Error::BadRequest(format!(
+            "{} doesn't support streaming uploads of {filepath}",
+            self.name()
+        )))
+    }
+
+    /// Writes `contents` at `offset` into a file that'll be `total` bytes long, returning true once every byte has arrived.
+    ///
+    /// An existing file is only replaced when the last range arrives, and only with `overwrite`.
+    async fn put_file_range(
+        &self,
+        filepath: &str,
+        offset: u64,
+        total: u64,
+        contents: Bytes,
+        overwrite: bool,
+    ) -> Result<bool, Error>;
+
     fn delete_file(&self, filepath: &str) -> Result<(), Error>;
 
-    fn list_dir(&self, path: Option<String>) -> Result<Vec<FileEntry>, Error>;
-    /// Checks if it's online/available - for S3 this would be checking if the bucket exists, local filesystem would be checking if the path exists
-    fn available(&self) -> Result<bool, Error>;
+    /// Deletes a directory, which has to be empty unless `recursive` is set
+    fn delete_dir(&self, path: &str, recursive: bool) -> Result<(), Error>;
+
+    /// Clears the execute permission bits on a stored file, where the storage has them
+    fn strip_executable(&self, _filepath: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// The hex digest of a file's contents, using `algorithm`
+    fn checksum(&self, filepath: &str, algorithm: ChecksumAlgo) -> Result<String, Error>;
+
+    /// Moves/renames a file within this filesystem, only replacing an existing destination if `overwrite` is set
+    fn move_file(&self, from: &str, to: &str, overwrite: bool) -> Result<(), Error>;
+
+    /// Copies a file within this filesystem, only replacing an existing destination if `overwrite` is set
+    fn copy_file(&self, from: &str, to: &str, overwrite: bool) -> Result<(), Error>;
+
+    /// Creates a single directory, the parent has to exist already
+    fn create_dir(&self, path: &str) -> Result<(), Error>;
+
+    /// Lists a directory, failing with [Error::BadRequest] rather than reading more than `max_entries` entries, see [check_list_entries]
+    fn list_dir(&self, path: Option<String>, max_entries: usize) -> Result<Vec<FileEntry>, Error>;
+
+    /// Finds files and directories under `root` whose names contain `query` (case-insensitively),
+    /// returning as soon as there are `max_results` of them.
+    fn search(
+        &self,
+        root: Option<String>,
+        query: &str,
+        max_results: usize,
+    ) -> Result<Vec<FileEntry>, Error> {
+        // the walk's bounded by max_results rather than the size of each directory
+        search_walk(root, query, max_results, |key| {
+            self.list_dir(key, usize::MAX)
+        })
+    }
+
+    /// The total bytes of every file in the filesystem
+    fn total_size(&self) -> Result<u64, Error> {
+        total_size_walk(|key| self.list_dir(key, usize::MAX))
+    }
+
+    /// Total bytes and number of files under the directory `key`, marked approximate if it took more than
+    /// `max_entries` entries or went past `deadline`
+    fn dir_usage(
+        &self,
+        key: &str,
+        max_entries: usize,
+        deadline: Instant,
+    ) -> Result<DirUsage, Error> {
+        dir_usage_walk(key, max_entries, deadline, |key| {
+            self.list_dir(key, usize::MAX)
+        })
+    }
+
+    /// Walks the whole filesystem for files named exactly `basename`
+    fn find_basename(&self, basename: &str) -> Result<Vec<FileEntry>, Error> {
+        Ok(self
+            .search(None, basename, usize::MAX)?
+            .into_iter()
+            .filter(|entry| entry.filetype == FileType::File && entry.filename == basename)
+            .collect())
+    }
+
+    /// One page of a directory listing (directories first, then by name), plus how many entries there are in total
+    fn list_dir_paginated(
+        &self,
+        path: Option<String>,
+        offset: usize,
+        limit: usize,
+        max_entries: usize,
+    ) -> Result<(Vec<FileEntry>, usize), Error> {
+        Ok(paginate_entries(
+            self.list_dir(path, max_entries)?,
+            offset,
+            limit,
+        ))
+    }
+
+    /// Total/used/available bytes of the storage behind this filesystem, if it has that concept
+    fn disk_space(&self) -> Result<Option<DiskSpace>, Error> {
+        Ok(None)
+    }
+
+    /// Checks if it's online/available - for S3 this would be checking if the bucket exists, local filesystem would be checking if the path exists.
+    ///
+    /// Callers should go through [check_available], so a backend that never answers can't hang them.
+    async fn available(&self) -> Result<bool, Error>;
 
     fn target_path(&self, filepath: &str, filename: &str) -> Result<String, Error> {
         if filename.is_empty() {
             return Err(Error::BadRequest("Filename is empty".to_string()));
         }
-        if filepath.trim().strip_prefix('/').unwrap_or("").is_empty() {
+        let filepath = filepath.trim().trim_matches('/');
+        if filepath.is_empty() {
             Ok(filename.to_string())
         } else {
             Ok(format!("{filepath}/{filename}"))
@@ -68,67 +206,1222 @@ where
     fn is_dir(&self, key: &str) -> bool;
 }
 
-#[derive(Deserialize, Debug, Clone, Serialize, PartialEq, Eq)]
-#[serde(rename_all = "lowercase")]
+/// A server path's `type`, anything that's not built in is looked up in the [registry::BackendRegistry]
+#[derive(Deserialize, Debug, Clone, Serialize, PartialEq, Eq, Default)]
+#[serde(from = "String", into = "String")]
 pub enum FileKidFsType {
+    #[default]
     Local,
     TempDir,
+    S3,
+    /// Registered with [registry::register_backend]
+    Other(String),
+}
+
+impl FileKidFsType {
+    pub fn as_str(&self) -> &str {
+        match self {
+            FileKidFsType::Local => "local",
+            FileKidFsType::TempDir => "tempdir",
+            FileKidFsType::S3 => "s3",
+            FileKidFsType::Other(type_) => type_,
+        }
+    }
+}
+
+impl From<String> for FileKidFsType {
+    fn from(type_: String) -> Self {
+        match type_.as_str() {
+            "local" => FileKidFsType::Local,
+            "tempdir" => FileKidFsType::TempDir,
+            "s3" => FileKidFsType::S3,
+            _ => FileKidFsType::Other(type_),
+        }
+    }
+}
+
+impl From<FileKidFsType> for String {
+    fn from(type_: FileKidFsType) -> Self {
+        type_.as_str().to_string()
+    }
 }
 
 pub fn fs_from_serverpath(server_path: &ServerPath) -> Result<Box<dyn FileKidFs>, Error> {
-    match &server_path.type_ {
-        FileKidFsType::Local => {
-            let server_path = match server_path.path {
-                Some(ref path) => path,
-                None => return Err(Error::Configuration("No path specified".to_string())),
+    if server_path.is_per_user() {
+        return Err(Error::NotAuthorized(
+            "this server path is per-user, so needs someone logged in".to_string(),
+        ));
+    }
+    registry::build_backend(server_path)
+}
+
+/// [fs_from_serverpath] for `username`, whose directory on per-user local server paths is created the first time it's needed
+pub(crate) fn fs_for_user(
+    server_path: &ServerPath,
+    username: Option<&str>,
+) -> Result<Box<dyn FileKidFs>, Error> {
+    let user_path = server_path.for_user(username)?;
+    if server_path.is_per_user()
+        && user_path.type_ == FileKidFsType::Local
+        && let Some(path) = user_path.path.as_ref()
+        && !path.exists()
+    {
+        debug!("Creating per-user directory {}", path.display());
+        std::fs::create_dir_all(path)?;
+    }
+    fs_from_serverpath(&user_path)
+}
+
+/// Creates any missing parent directories of `key`, a level at a time so the backend's own checks apply to each
+pub(crate) fn create_parent_dirs(filekidfs: &dyn FileKidFs, key: &str) -> Result<(), Error> {
+    let Some((parents, _)) = key.rsplit_once('/') else {
+        return Ok(());
+    };
+    let mut parent = String::new();
+    for segment in parents.split('/').filter(|segment| !segment.is_empty()) {
+        if !parent.is_empty() {
+            parent.push('/');
+        }
+        parent.push_str(segment);
+        if !filekidfs.is_dir(&parent) {
+            filekidfs.create_dir(&parent)?;
+        }
+    }
+    Ok(())
+}
+
+/// For server paths with `unique_basenames` set, refuses `target_path` if its filename exists somewhere else in the path
+pub(crate) fn check_unique_basename(
+    server_path: &ServerPath,
+    filekidfs: &dyn FileKidFs,
+    target_path: &str,
+) -> Result<(), Error> {
+    if !server_path.unique_basenames {
+        return Ok(());
+    }
+    let basename = target_path.rsplit('/').next().unwrap_or(target_path);
+    match filekidfs
+        .find_basename(basename)?
+        .into_iter()
+        .find(|entry| entry.fullpath != target_path)
+    {
+        Some(existing) => Err(Error::BadRequest(format!(
+            "{basename} already exists at {}",
+            existing.fullpath
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Asks `filekidfs` whether it's available, calling it unavailable if it hasn't answered within `timeout`
+pub(crate) async fn check_available(
+    filekidfs: &dyn FileKidFs,
+    timeout: Duration,
+) -> Result<bool, Error> {
+    match tokio::time::timeout(timeout, filekidfs.available()).await {
+        Ok(available) => available,
+        Err(_) => {
+            warn!(
+                "{} didn't say whether it's available within {:?}",
+                filekidfs.name(),
+                timeout
+            );
+            Ok(false)
+        }
+    }
+}
+
+/// Works out whether `err` happened because the server path's backend is down, if so it's swapped for [Error::ServiceUnavailable].
+///
+/// Handlers call this on their way out with an error, so working backends don't pay for the check.
+pub(crate) async fn explain_failure(
+    name: &str,
+    server_path: &ServerPath,
+    retry_after_secs: u64,
+    available_timeout: Duration,
+    err: Error,
+) -> Error {
+    let available = match fs_from_serverpath(server_path) {
+        Ok(filekidfs) => check_available(filekidfs.as_ref(), available_timeout).await,
+        Err(check_err) => Err(check_err),
+    };
+    let available = match available {
+        Ok(available) => available,
+        Err(check_err) => {
+            warn!("Failed to check if {} is available: {:?}", name, check_err);
+            return err;
+        }
+    };
+    match available {
+        true => err,
+        false => {
+            warn!(
+                "Server path {} is unavailable, original error: {:?}",
+                name, err
+            );
+            Error::ServiceUnavailable(format!("Server path {name}"), retry_after_secs)
+        }
+    }
+}
+
+/// Breadth-first walk over everything under `root`, `list` returns the entries of a directory key and the walk stops when `visit` returns false.
+///
+/// Goes at most [MAX_SEARCH_DEPTH] deep, and subdirectories that can't be read are skipped.
+/// Returns whether everything was walked, so it's false if `visit` stopped it or anything was skipped.
+pub(crate) fn walk_dir(
+    root: Option<String>,
+    mut list: impl FnMut(Option<String>) -> Result<Vec<FileEntry>, Error>,
+    mut visit: impl FnMut(FileEntry) -> bool,
+) -> Result<bool, Error> {
+    let mut complete = true;
+    let mut queue = VecDeque::from([(list(root)?, 1)]);
+    while let Some((entries, depth)) = queue.pop_front() {
+        for entry in entries {
+            if entry.filetype == FileType::Directory {
+                match depth < MAX_SEARCH_DEPTH {
+                    true => match list(Some(entry.fullpath.clone())) {
+                        Ok(children) => queue.push_back((children, depth + 1)),
+                        Err(err) => {
+                            warn!("Skipping {} while walking: {:?}", entry.fullpath, err);
+                            complete = false;
+                        }
+                    },
+                    false => complete = false,
+                }
+            }
+            if !visit(entry) {
+                return Ok(false);
+            }
+        }
+    }
+    Ok(complete)
+}
+
+/// [walk_dir] for [FileKidFs::search]
+pub(crate) fn search_walk(
+    root: Option<String>,
+    query: &str,
+    max_results: usize,
+    list: impl FnMut(Option<String>) -> Result<Vec<FileEntry>, Error>,
+) -> Result<Vec<FileEntry>, Error> {
+    let query = query.to_lowercase();
+    let mut results = Vec::new();
+    if query.is_empty() || max_results == 0 {
+        return Ok(results);
+    }
+
+    walk_dir(root, list, |entry| {
+        if entry.filename.to_lowercase().contains(&query) {
+            results.push(entry);
+        }
+        results.len() < max_results
+    })?;
+    Ok(results)
+}
+
+/// [walk_dir] for [FileKidFs::total_size]
+pub(crate) fn total_size_walk(
+    list: impl FnMut(Option<String>) -> Result<Vec<FileEntry>, Error>,
+) -> Result<u64, Error> {
+    let mut total: u64 = 0;
+    walk_dir(None, list, |entry| {
+        // browse uploads are counted once they're moved into place
+        if !entry.filename.starts_with(UPLOAD_STAGING_PREFIX) {
+            total = total.saturating_add(entry.size.unwrap_or(0));
+        }
+        true
+    })?;
+    Ok(total)
+}
+
+/// [walk_dir] for [FileKidFs::dir_usage], stopping after `max_entries` or at `deadline`
+pub(crate) fn dir_usage_walk(
+    key: &str,
+    max_entries: usize,
+    deadline: Instant,
+    list: impl FnMut(Option<String>) -> Result<Vec<FileEntry>, Error>,
+) -> Result<DirUsage, Error> {
+    let mut usage = DirUsage::default();
+    let mut seen: usize = 0;
+    let complete = walk_dir(Some(key.to_string()), list, |entry| {
+        if entry.filetype == FileType::File && !entry.filename.starts_with(UPLOAD_STAGING_PREFIX) {
+            usage.size = usage.size.saturating_add(entry.size.unwrap_or(0));
+            usage.count += 1;
+        }
+        seen += 1;
+        seen < max_entries && Instant::now() < deadline
+    })?;
+    usage.approximate = !complete;
+    Ok(usage)
+}
+
+/// Lists a directory on disk for [search_walk], leaving out symlinks so the walk can't be led outside `base`
+pub(crate) fn list_dir_no_symlinks(
+    base: &Path,
+    key: Option<String>,
+) -> Result<Vec<FileEntry>, Error> {
+    let key = key.unwrap_or_default();
+    let mut entries = Vec::new();
+    for entry in std::fs::read_dir(base.join(&key))? {
+        let entry = entry?;
+        // DirEntry::file_type doesn't follow symlinks
+        let file_type = entry.file_type()?;
+        let filetype = match (file_type.is_dir(), file_type.is_file()) {
+            (true, _) => FileType::Directory,
+            (_, true) => FileType::File,
+            _ => continue,
+        };
+        let metadata = entry.metadata()?;
+        let filename = entry.file_name().to_string_lossy().to_string();
+        let fullpath = match key.is_empty() {
+            true => filename.clone(),
+            false => format!("{}/{filename}", key.trim_end_matches('/')),
+        };
+        entries.push(FileEntry {
+            filename,
+            fullpath,
+            size: metadata.is_file().then_some(metadata.len()),
+            modified: metadata.modified().ok(),
+            filetype,
+            dir_usage: None,
+        });
+    }
+    Ok(entries)
+}
+
+/// What a directory listing's sorted by
+#[derive(Debug, Default, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortBy {
+    #[default]
+    Name,
+    Size,
+    Modified,
+}
+
+impl SortBy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SortBy::Name => "name",
+            SortBy::Size => "size",
+            SortBy::Modified => "modified",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SortOrder::Asc => "asc",
+            SortOrder::Desc => "desc",
+        }
+    }
+}
+
+/// Directories always come first, then entries are ordered by `sort_by`, with ties broken by name.
+///
+/// Entries without a size or modified time sort before those with one.
+pub(crate) fn sort_entries(entries: &mut [FileEntry], sort_by: SortBy, order: SortOrder) {
+    entries.sort_by(|a, b| {
+        let ordering = match sort_by {
+            SortBy::Name => a.filename.cmp(&b.filename),
+            SortBy::Size => a.size.cmp(&b.size),
+            SortBy::Modified => a.modified.cmp(&b.modified),
+        }
+        .then_with(|| a.filename.cmp(&b.filename));
+        let ordering = match order {
+            SortOrder::Asc => ordering,
+            SortOrder::Desc => ordering.reverse(),
+        };
+        a.filetype.cmp(&b.filetype).then(ordering)
+    });
+}
+
+/// Picks out one page of already-sorted entries, returning it and the total number of entries.
+pub(crate) fn page_of(
+    entries: Vec<FileEntry>,
+    offset: usize,
+    limit: usize,
+) -> (Vec<FileEntry>, usize) {
+    let total = entries.len();
+    (
+        entries.into_iter().skip(offset).take(limit).collect(),
+        total,
+    )
+}
+
+/// Sorts entries the way they're shown by default (directories first, then by name) and picks out one page of them.
+pub(crate) fn paginate_entries(
+    mut entries: Vec<FileEntry>,
+    offset: usize,
+    limit: usize,
+) -> (Vec<FileEntry>, usize) {
+    sort_entries(&mut entries, SortBy::Name, SortOrder::Asc);
+    page_of(entries, offset, limit)
+}
+
+/// An opaque token pointing at `entry`, for [page_after] to carry on from
+pub(crate) fn continuation_token(entry: &FileEntry) -> String {
+    let kind = match entry.filetype {
+        FileType::Directory => 'd',
+        FileType::File => 'f',
+    };
+    hex_digest(format!("{kind}:{}", entry.filename).as_bytes())
+}
+
+/// Unpacks a [continuation_token] into the sort key of the entry it points at
+fn parse_continuation_token(token: &str) -> Result<(FileType, String), Error> {
+    let invalid = || Error::BadRequest(format!("Invalid continuation token {token}"));
+    if !token.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    let bytes = (0..token.len())
+        .step_by(2)
+        .map(|index| {
+            token
+                .get(index..index + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(invalid)
+        })
+        .collect::<Result<Vec<u8>, Error>>()?;
+    let token = String::from_utf8(bytes).map_err(|_| invalid())?;
+    match token.split_once(':') {
+        Some(("d", filename)) => Ok((FileType::Directory, filename.to_string())),
+        Some(("f", filename)) => Ok((FileType::File, filename.to_string())),
+        _ => Err(invalid()),
+    }
+}
+
+/// Sorts entries (directories first, then by name) and picks out up to `limit` of them after the one `token` points at.
+///
+/// Unlike [page_of], entries being added or removed between requests don't shift the pages, so nothing is repeated or skipped.
+/// Returns the token for the next page, if there's anything left.
+pub(crate) fn page_after(
+    mut entries: Vec<FileEntry>,
+    token: Option<&str>,
+    limit: usize,
+) -> Result<(Vec<FileEntry>, Option<String>), Error> {
+    sort_entries(&mut entries, SortBy::Name, SortOrder::Asc);
+    let start = match token {
+        Some(token) => {
+            let (filetype, filename) = parse_continuation_token(token)?;
+            entries.partition_point(|entry| {
+                (&entry.filetype, &entry.filename) <= (&filetype, &filename)
+            })
+        }
+        None => 0,
+    };
+    let mut page: Vec<FileEntry> = entries.into_iter().skip(start).take(limit + 1).collect();
+    let next_token = match page.len() > limit {
+        true => {
+            page.truncate(limit);
+            page.last().map(continuation_token)
+        }
+        false => None,
+    };
+    Ok((page, next_token))
+}
+
+/// Matches a filename against a simple glob pattern, supporting `*` and `?` wildcards.
+pub(crate) fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // where the last `*` was seen, and how much of the name it has swallowed
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// A node in a server path's directory tree.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TreeNode {
+    pub name: String,
+    pub path: String,
+    #[serde(rename = "type")]
+    pub filetype: FileType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Only set for directories that were walked, directories past the depth limit don't have it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(no_recursion)]
+    pub children: Option<Vec<TreeNode>>,
+}
+
+/// Bounds on how much of a filesystem [build_tree] will walk.
+pub(crate) struct TreeLimits<'a> {
+    pub max_depth: usize,
+    pub max_nodes: usize,
+    /// Passed to [FileKidFs::list_dir] for each directory
+    pub max_list_entries: usize,
+    pub server_path: &'a ServerPath,
+}
+
+/// Recursively walks `path`, returning its children and whether the node limit cut the walk short.
+pub(crate) fn build_tree(
+    filekidfs: &dyn FileKidFs,
+    path: Option<String>,
+    limits: &TreeLimits,
+) -> Result<(Vec<TreeNode>, bool), Error> {
+    let mut nodes = 0;
+    let mut truncated = false;
+    let children = tree_children(filekidfs, path, 1, limits, &mut nodes, &mut truncated)?;
+    Ok((children, truncated))
+}
+
+fn tree_children(
+    filekidfs: &dyn FileKidFs,
+    path: Option<String>,
+    depth: usize,
+    limits: &TreeLimits,
+    nodes: &mut usize,
+    truncated: &mut bool,
+) -> Result<Vec<TreeNode>, Error> {
+    let mut entries = filekidfs.list_dir(path, limits.max_list_entries)?;
+    entries.retain(|entry| !limits.server_path.is_ignored(&entry.filename));
+    entries.sort_by(|a, b| a.filename.cmp(&b.filename));
+    entries.sort_by(|a, b| a.filetype.cmp(&b.filetype));
+
+    let mut children = Vec::new();
+    for entry in entries {
+        if *nodes >= limits.max_nodes {
+            *truncated = true;
+            break;
+        }
+        *nodes += 1;
+
+        let (size, grandchildren) = match entry.filetype {
+            FileType::File => (
+                filekidfs
+                    .get_data(&entry.fullpath)
+                    .ok()
+                    .and_then(|data| data.size),
+                None,
+            ),
+            FileType::Directory if depth < limits.max_depth => (
+                None,
+                Some(tree_children(
+                    filekidfs,
+                    Some(entry.fullpath.clone()),
+                    depth + 1,
+                    limits,
+                    nodes,
+                    truncated,
+                )?),
+            ),
+            FileType::Directory => (None, None),
+        };
+        children.push(TreeNode {
+            name: entry.filename,
+            path: entry.fullpath,
+            filetype: entry.filetype,
+            size,
+            children: grandchildren,
+        });
+    }
+    Ok(children)
+}
+
+/// Returns true if `key` uses `..` components to climb above the directory it gets joined to.
+pub(crate) fn key_escapes_base(key: &str) -> bool {
+    let mut depth: usize = 0;
+    for component in Path::new(key).components() {
+        match component {
+            Component::ParentDir => match depth.checked_sub(1) {
+                Some(parent) => depth = parent,
+                None => return true,
+            },
+            Component::Normal(_) => depth += 1,
+            // absolute paths are caught by the ancestor checks in the backends
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+        }
+    }
+    false
+}
+
+/// Checks a client-supplied filename is just a name, without path separators, `..`, null bytes or control characters
+pub fn sanitize_filename(filename: &str) -> Result<&str, Error> {
+    if filename.is_empty() || filename == "." || filename == ".." {
+        return Err(Error::BadRequest(format!(
+            "{filename:?} isn't a valid filename"
+        )));
+    }
+    if filename.contains(['/', '\\']) {
+        return Err(Error::BadRequest(format!(
+            "Filename {filename:?} can't contain a path separator"
+        )));
+    }
+    // null bytes are control characters too
+    if filename.chars().any(char::is_control) {
+        return Err(Error::BadRequest(format!(
+            "Filename {filename:?} can't contain control characters"
+        )));
+    }
+    Ok(filename)
+}
+
+/// Backends call this as they read a directory, so they can stop once there are more than `max_entries` entries
+pub(crate) fn check_list_entries(count: usize, max_entries: usize) -> Result<(), Error> {
+    match count > max_entries {
+        true => Err(Error::BadRequest(format!(
+            "directory too large to list, it has more than {max_entries} entries"
+        ))),
+        false => Ok(()),
+    }
+}
+
+/// Dotfiles are left out of listings unless [ServerPath::show_hidden] is set
+pub(crate) fn is_hidden(filename: &std::ffi::OsStr) -> bool {
+    filename.as_encoded_bytes().starts_with(b".")
+}
+
+/// How much is under a directory, from a walk that can be cut short
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DirUsage {
+    /// Total bytes of the files
+    pub size: u64,
+    /// How many files
+    pub count: u64,
+    /// The walk stopped early, so these are at least what's there
+    pub approximate: bool,
+}
+
+impl DirUsage {
+    /// For the browse page, eg `~1.5 MiB in 12 files`
+    pub fn describe(&self) -> String {
+        format!(
+            "{}{} in {} file{}",
+            match self.approximate {
+                true => "~",
+                false => "",
+            },
+            crate::views::human_size(self.size),
+            self.count,
+            match self.count {
+                1 => "",
+                _ => "s",
+            }
+        )
+    }
+}
+
+/// Capacity of the storage behind a server path, in bytes
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, ToSchema)]
+pub struct DiskSpace {
+    pub total: u64,
+    pub used: u64,
+    /// What's available to us, which can be less than `total - used` because of reserved blocks
+    pub available: u64,
+}
+
+/// Recent disk space lookups, so polling dashboards don't hammer the filesystem
+static DISK_SPACE_CACHE: LazyLock<Mutex<HashMap<PathBuf, (Instant, DiskSpace)>>> =
+    LazyLock::new(Default::default);
+
+/// Looks up the disk space of the filesystem holding `path`, cached for [DISK_SPACE_CACHE_SECS]
+pub(crate) fn disk_space_for(path: &Path) -> Result<DiskSpace, Error> {
+    let mut cache = DISK_SPACE_CACHE.lock().map_err(|err| {
+        Error::InternalServerError(format!("Disk space cache lock is poisoned: {err}"))
+    })?;
+    if let Some((checked, space)) = cache.get(path)
+        && checked.elapsed() < Duration::from_secs(DISK_SPACE_CACHE_SECS)
+    {
+        return Ok(*space);
+    }
+
+    let total = fs2::total_space(path)?;
+    let space = DiskSpace {
+        total,
+        used: total.saturating_sub(fs2::free_space(path)?),
+        available: fs2::available_space(path)?,
+    };
+    cache.insert(path.to_path_buf(), (Instant::now(), space));
+    Ok(space)
+}
+
+/// Works out which file on disk a download of `target` reads from.
+///
+/// If `follow_symlinks` is off then a key with a symlink anywhere under `base_path` is refused,
+/// otherwise links are followed as long as they end up back inside `base_path`.
+pub(crate) fn resolve_download_path(
+    base_path: &Path,
+    target: &Path,
+    follow_symlinks: bool,
+) -> Result<PathBuf, Error> {
+    if !follow_symlinks {
+        let relative = target
+            .strip_prefix(base_path)
+            .map_err(|_| Error::NotAuthorized("Path is outside of base path".to_string()))?;
+        let mut current = base_path.to_path_buf();
+        for component in relative.components() {
+            current.push(component);
+            if std::fs::symlink_metadata(&current)
+                .map(|metadata| metadata.file_type().is_symlink())
+                .unwrap_or(false)
+            {
+                return Err(Error::NotAuthorized(format!(
+                    "{} is a symlink and following symlinks is disabled",
+                    relative.display()
+                )));
+            }
+        }
+    }
+
+    let resolved = target.canonicalize()?;
+    if !resolved.starts_with(base_path.canonicalize()?) {
+        return Err(Error::NotAuthorized(
+            "Path is outside of base path".to_string(),
+        ));
+    }
+    Ok(resolved)
+}
+
+/// Whether `target` stays inside `base` once symlinks are resolved, so a link inside the base path can't be used to reach outside it.
+///
+/// Parts of `target` that don't exist yet (like the name of a file being uploaded) are checked through their nearest existing ancestor.
+pub(crate) fn resolves_within(base: &Path, target: &Path) -> bool {
+    let Ok(base) = base.canonicalize() else {
+        // nothing can be linked from inside a base path that isn't there, and using it will fail anyway
+        return true;
+    };
+    for ancestor in target.ancestors() {
+        if std::fs::symlink_metadata(ancestor).is_ok() {
+            return match ancestor.canonicalize() {
+                Ok(resolved) => resolved.starts_with(&base),
+                // a dangling symlink, which would be written through
+                Err(_) => false,
             };
-            Ok(Box::new(local::LocalFs::new(server_path.to_path_buf())))
         }
-        FileKidFsType::TempDir => match &server_path.path {
-            None => Err(Error::Configuration(
-                "No path specified for tempdir after startup?".to_string(),
-            )),
-            Some(path) => Ok(Box::new(tempdir::TempDir::new(path.to_owned()))),
+    }
+    false
+}
+
+/// Copying a file over itself truncates it before reading, so [FileKidFs::copy_file] refuses when both resolve to the same file
+pub(crate) fn check_not_same_file(source: &Path, destination: &Path) -> Result<(), Error> {
+    if !destination.exists() {
+        return Ok(());
+    }
+    match (source.canonicalize(), destination.canonicalize()) {
+        (Ok(source), Ok(destination)) if source == destination => Err(Error::BadRequest(
+            "source and destination are the same".to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Removes the directory `target` for [FileKidFs::delete_dir], along with everything in it if `recursive` is set.
+///
+/// Everything inside is checked to resolve within `base` first, so if anything links out of it nothing's removed.
+pub(crate) fn remove_dir_within(
+    base: &Path,
+    target: &Path,
+    key: &str,
+    recursive: bool,
+) -> Result<(), Error> {
+    // not following symlinks, a link to a directory isn't one
+    match std::fs::symlink_metadata(target) {
+        Ok(metadata) if metadata.is_dir() => {}
+        Ok(_) => return Err(Error::BadRequest(format!("{key} is not a directory"))),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Err(Error::NotFound(key.to_string()))
+        }
+        Err(err) => return Err(err.into()),
+    }
+    if base.canonicalize()? == target.canonicalize()? {
+        return Err(Error::BadRequest(
+            "The base of a server path can't be deleted".to_string(),
+        ));
+    }
+    if !recursive {
+        return std::fs::remove_dir(target).map_err(|err| match err.kind() {
+            std::io::ErrorKind::DirectoryNotEmpty => {
+                Error::BadRequest(format!("{key} isn't empty"))
+            }
+            _ => err.into(),
+        });
+    }
+    check_descendants_within(base, target)?;
+    debug!("Removing {} and everything in it", target.display());
+    std::fs::remove_dir_all(target).map_err(Error::from)
+}
+
+fn check_descendants_within(base: &Path, dir: &Path) -> Result<(), Error> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !resolves_within(base, &path) {
+            return Err(Error::NotAuthorized(format!(
+                "{} is outside of base path",
+                path.display()
+            )));
+        }
+        if std::fs::symlink_metadata(&path)?.is_dir() {
+            check_descendants_within(base, &path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Formats a digest as lowercase hex
+pub(crate) fn hex_digest(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// The hashes the checksum endpoint can work out, MD5 and CRC32 are there because S3 tooling uses them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChecksumAlgo {
+    #[default]
+    Sha256,
+    Md5,
+    Crc32,
+}
+
+impl FromStr for ChecksumAlgo {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "sha256" => Ok(Self::Sha256),
+            "md5" => Ok(Self::Md5),
+            "crc32" => Ok(Self::Crc32),
+            other => Err(Error::BadRequest(format!(
+                "Unknown checksum algorithm {other:?}, expected sha256, md5 or crc32"
+            ))),
+        }
+    }
+}
+
+/// A running [ChecksumAlgo] hash, so files only need to be read once
+pub(crate) enum Checksummer {
+    Sha256(Sha256),
+    Md5(Md5),
+    Crc32(crc32fast::Hasher),
+}
+
+impl Checksummer {
+    pub(crate) fn new(algorithm: ChecksumAlgo) -> Self {
+        match algorithm {
+            ChecksumAlgo::Sha256 => Self::Sha256(Sha256::new()),
+            ChecksumAlgo::Md5 => Self::Md5(Md5::new()),
+            ChecksumAlgo::Crc32 => Self::Crc32(crc32fast::Hasher::new()),
+        }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Md5(hasher) => hasher.update(data),
+            Self::Crc32(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Lowercase hex, CRC32 is the big-endian 8 digits everyone else uses
+    pub(crate) fn finalize(self) -> String {
+        match self {
+            Self::Sha256(hasher) => hex_digest(&hasher.finalize()),
+            Self::Md5(hasher) => hex_digest(&hasher.finalize()),
+            Self::Crc32(hasher) => format!("{:08x}", hasher.finalize()),
+        }
+    }
+}
+
+/// Streams a file through a hash without loading it all into memory
+pub(crate) fn checksum_file(path: &Path, algorithm: ChecksumAlgo) -> Result<String, Error> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Checksummer::new(algorithm);
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finalize())
+}
+
+/// Tracks which byte ranges of a partial upload have arrived.
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+struct PartialUpload {
+    total: u64,
+    /// Half-open `(start, end)` ranges, sorted and merged
+    ranges: Vec<(u64, u64)>,
+}
+
+impl PartialUpload {
+    fn add(&mut self, start: u64, end: u64) {
+        self.ranges.push((start, end));
+        self.ranges.sort();
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(self.ranges.len());
+        for (start, end) in self.ranges.drain(..) {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        self.ranges = merged;
+    }
+
+    fn is_complete(&self) -> bool {
+        self.total == 0 || self.ranges == [(0, self.total)]
+    }
+}
+
+/// Clears the user/group/other execute bits on a file on disk
+pub(crate) fn strip_executable_bits(path: &Path) -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut permissions = std::fs::metadata(path)?.permissions();
+        if permissions.mode() & 0o111 != 0 {
+            debug!("Removing execute bits from {}", path.display());
+            permissions.set_mode(permissions.mode() & !0o111);
+            std::fs::set_permissions(path, permissions)?;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// One lock per partial upload's staging file, since ranges of the same file can arrive in parallel
+static PARTIAL_UPLOAD_LOCKS: LazyLock<Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>> =
+    LazyLock::new(Default::default);
+
+fn partial_upload_lock(staging_file: &Path) -> Result<Arc<tokio::sync::Mutex<()>>, Error> {
+    let mut locks = PARTIAL_UPLOAD_LOCKS.lock().map_err(|err| {
+        Error::InternalServerError(format!("Partial upload locks are poisoned: {err}"))
+    })?;
+    Ok(locks.entry(staging_file.to_path_buf()).or_default().clone())
+}
+
+/// Forgets `staging_file`'s lock once nothing else is waiting on it
+fn release_partial_upload_lock(staging_file: &Path, lock: Arc<tokio::sync::Mutex<()>>) {
+    drop(lock);
+    match PARTIAL_UPLOAD_LOCKS.lock() {
+        Ok(mut locks) => {
+            if locks
+                .get(staging_file)
+                .is_some_and(|lock| Arc::strong_count(lock) == 1)
+            {
+                locks.remove(staging_file);
+            }
+        }
+        Err(err) => warn!("Partial upload locks are poisoned: {}", err),
+    }
+}
+
+/// Writes `data` at `offset` into a hidden staging file alongside `target`, moving it into place once all `total` bytes have arrived.
+///
+/// Returns true when the upload is complete.
+pub(crate) async fn write_range_to_disk(
+    target: &Path,
+    offset: u64,
+    total: u64,
+    data: Bytes,
+) -> Result<bool, Error> {
+    let end = offset
+        .checked_add(data.len() as u64)
+        .filter(|end| *end <= total)
+        .ok_or_else(|| Error::BadRequest("Range is outside of the total size".to_string()))?;
+
+    let filename = target
+        .file_name()
+        .ok_or_else(|| Error::BadRequest("No filename specified".to_string()))?
+        .to_string_lossy()
+        .to_string();
+    let parent = target
+        .parent()
+        .ok_or_else(|| Error::BadRequest("No parent directory".to_string()))?;
+    let staging_file = parent.join(format!(".{filename}.filekid-partial"));
+    let progress_file = parent.join(format!(".{filename}.filekid-partial.json"));
+
+    let lock = partial_upload_lock(&staging_file)?;
+    let guard = lock.lock().await;
+    let result = tokio::task::spawn_blocking({
+        let target = target.to_path_buf();
+        let staging_file = staging_file.clone();
+        move || {
+            write_range(
+                &target,
+                &staging_file,
+                &progress_file,
+                offset,
+                end,
+                total,
+                &data,
+            )
+        }
+    })
+    .await
+    .map_err(|err| Error::InternalServerError(format!("Failed to write range: {err}")));
+    drop(guard);
+    release_partial_upload_lock(&staging_file, lock);
+    result?
+}
+
+/// The blocking part of [write_range_to_disk], which holds the staging file's lock while this runs
+fn write_range(
+    target: &Path,
+    staging_file: &Path,
+    progress_file: &Path,
+    offset: u64,
+    end: u64,
+    total: u64,
+    data: &[u8],
+) -> Result<bool, Error> {
+    let mut progress = match progress_file.exists() {
+        true => serde_json::from_slice(&std::fs::read(progress_file)?).map_err(|err| {
+            Error::InternalServerError(format!(
+                "Couldn't parse partial upload progress {}: {err}",
+                progress_file.display()
+            ))
+        })?,
+        false => PartialUpload {
+            total,
+            ranges: Vec::new(),
         },
+    };
+    if progress.total != total {
+        return Err(Error::BadRequest(format!(
+            "Total size {total} doesn't match the upload in progress ({})",
+            progress.total
+        )));
+    }
+
+    // it's sized when it's created, later ranges just write into it
+    let mut file = match OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(staging_file)
+    {
+        Ok(file) => {
+            file.set_len(total)?;
+            file
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+            OpenOptions::new().write(true).open(staging_file)?
+        }
+        Err(err) => return Err(err.into()),
+    };
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(data)?;
+    progress.add(offset, end);
+
+    if progress.is_complete() {
+        std::fs::rename(staging_file, target)?;
+        if progress_file.exists() {
+            std::fs::remove_file(progress_file)?;
+        }
+        Ok(true)
+    } else {
+        std::fs::write(
+            progress_file,
+            serde_json::to_vec(&progress).map_err(|err| {
+                Error::InternalServerError(format!("Couldn't serialize upload progress: {err}"))
+            })?,
+        )?;
+        Ok(false)
+    }
+}
+
+/// Compares how many bytes arrived against a declared `Content-Length`.
+///
+/// Getting more than was declared is always an error, getting fewer is logged, and is only an error in `strict` mode.
+pub(crate) fn check_content_length(
+    declared_len: Option<u64>,
+    received: u64,
+    strict: bool,
+) -> Result<(), Error> {
+    match declared_len {
+        Some(declared_len) if received > declared_len => Err(Error::BadRequest(format!(
+            "Upload is longer than the declared Content-Length of {declared_len} bytes"
+        ))),
+        Some(declared_len) if received < declared_len => {
+            warn!(
+                "Upload declared {} bytes but only {} arrived",
+                declared_len, received
+            );
+            match strict {
+                true => Err(Error::BadRequest(format!(
+                    "Upload is shorter than the declared Content-Length of {declared_len} bytes"
+                ))),
+                false => Ok(()),
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Where a write to `filepath` goes before it's renamed into place, a hidden sibling so the rename doesn't cross filesystems
+fn temp_upload_path(filepath: &Path) -> PathBuf {
+    let name = filepath
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    filepath.with_file_name(format!(".{name}.tmp-{}", uuid::Uuid::new_v4().simple()))
+}
+
+async fn remove_temp_upload(temp_path: &Path) {
+    debug!("Cleaning up failed upload {}", temp_path.display());
+    if let Err(err) = tokio::fs::remove_file(temp_path).await
+        && err.kind() != std::io::ErrorKind::NotFound
+    {
+        warn!(
+            "Failed to clean up failed upload {}: {}",
+            temp_path.display(),
+            err
+        );
+    }
+}
+
+/// Writes `contents` next to `filepath` and renames it into place, so readers only ever see the whole file
+pub async fn write_atomically(filepath: &Path, contents: &[u8]) -> Result<(), Error> {
+    let temp_path = temp_upload_path(filepath);
+    let result = async {
+        tokio::fs::write(&temp_path, contents).await?;
+        tokio::fs::rename(&temp_path, filepath).await
+    }
+    .await;
+    if result.is_err() {
+        remove_temp_upload(&temp_path).await;
     }
+    result.map_err(Error::from)
 }
 
 // This code is from https://github.com/tokio-rs/axum/blob/f8f3a030b32d9a0fa52be6834fb142ea1c14f2d2/examples/stream-to-file/src/main.rs to stream to disk
-// Save a `Stream` to a file
-pub async fn stream_to_file<S, E>(filepath: &str, stream: S) -> Result<(), Error>
+/// Streams into `filepath`, returning how many bytes were written. The stream's written next to
+/// `filepath` and renamed into place once it's all there and passes [check_content_length],
+/// otherwise it's removed and whatever was at `filepath` is left alone.
+pub async fn stream_to_file<S, E>(
+    filepath: &Path,
+    stream: S,
+    declared_len: Option<u64>,
+    strict: bool,
+) -> Result<u64, Error>
 where
     S: Stream<Item = Result<axum::body::Bytes, E>>,
     E: Into<axum::BoxError>,
 {
-    async {
+    let temp_path = temp_upload_path(filepath);
+    let result = async {
         // Convert the stream into an `AsyncRead`.
-        let body_with_io_error =
-            stream.map_err(|err| std::io::Error::other(err));
-        let body_reader = StreamReader::new(body_with_io_error);
+        let body_with_io_error = stream.map_err(|err| std::io::Error::other(err));
+        let body_reader = StreamReader::new(body_with_io_error)
+            // reading one byte past the declared length is enough to know it's too long
+            .take(declared_len.map_or(u64::MAX, |len| len.saturating_add(1)));
         futures::pin_mut!(body_reader);
 
         // Create the file. `File` implements `AsyncWrite`.
-        // let path = std::path::Path::new(UPLOADS_DIRECTORY).join(path);
-        let mut file = BufWriter::new(File::create(filepath).await?);
+        let mut file = BufWriter::new(File::create(&temp_path).await?);
 
         // Copy the body into the file.
-        tokio::io::copy(&mut body_reader, &mut file).await?;
+        let written = tokio::io::copy(&mut body_reader, &mut file).await?;
+        file.flush().await?;
 
-        Ok::<_, std::io::Error>(())
+        Ok::<_, std::io::Error>(written)
     }
     .await
     .map_err(Error::from)
+    .and_then(|written| check_content_length(declared_len, written, strict).map(|_| written));
+    let result = match result {
+        Ok(written) => tokio::fs::rename(&temp_path, filepath)
+            .await
+            .map(|_| written)
+            .map_err(Error::from),
+        Err(err) => Err(err),
+    };
+
+    if result.is_err() {
+        remove_temp_upload(&temp_path).await;
+    }
+    result
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_fs_for_user() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let server_path = ServerPath::test_local(&temp_dir.path().join("homes/{username}"));
+        assert!(server_path.is_per_user());
+
+        let alice = fs_for_user(&server_path, Some("alice")).expect("Failed to get alice's fs");
+        let bob = fs_for_user(&server_path, Some("bob")).expect("Failed to get bob's fs");
+        assert_eq!(
+            alice.target_path_from_key("test.txt"),
+            temp_dir.path().join("homes/alice/test.txt")
+        );
+        assert_eq!(
+            bob.target_path_from_key("test.txt"),
+            temp_dir.path().join("homes/bob/test.txt")
+        );
+        // created on first use
+        assert!(temp_dir.path().join("homes/alice").is_dir());
+        assert!(temp_dir.path().join("homes/bob").is_dir());
+
+        for username in ["..", ".", "", "../bob", "alice/../bob", "a\0b"] {
+            assert!(
+                matches!(
+                    fs_for_user(&server_path, Some(username)),
+                    Err(Error::NotAuthorized(_))
+                ),
+                "{username:?}"
+            );
+        }
+        assert!(!temp_dir.path().join("bob").exists());
+
+        // without a user there's nowhere to go
+        assert!(matches!(
+            fs_for_user(&server_path, None),
+            Err(Error::NotAuthorized(_))
+        ));
+        assert!(matches!(
+            fs_from_serverpath(&server_path),
+            Err(Error::NotAuthorized(_))
+        ));
+        assert!(!temp_dir.path().join("homes/{username}").exists());
+
+        // ordinary server paths are the same for everyone
+        let shared = ServerPath::test_local(temp_dir.path());
+        assert_eq!(
+            fs_for_user(&shared, Some("alice"))
+                .expect("Failed to get fs")
+                .target_path_from_key("test.txt"),
+            temp_dir.path().join("test.txt")
+        );
+    }
+
     #[test]
     fn test_fs_from_serverpath_local() {
         let server_path = ServerPath {
             type_: FileKidFsType::Local,
             path: Some(PathBuf::from("/some/local/path")),
+            ..Default::default()
         };
         let fs = fs_from_serverpath(&server_path);
         assert!(fs.is_ok());
@@ -143,6 +1436,7 @@ mod tests {
         let server_path = ServerPath {
             type_: FileKidFsType::TempDir,
             path: Some(PathBuf::from("/some/tempdir/path")),
+            ..Default::default()
         };
         let fs = fs_from_serverpath(&server_path);
         assert!(fs.is_ok());
@@ -157,16 +1451,409 @@ mod tests {
         let server_path = ServerPath {
             type_: FileKidFsType::Local,
             path: None,
+            ..Default::default()
         };
         let fs = fs_from_serverpath(&server_path);
         assert!(fs.is_err());
     }
 
+    #[test]
+    fn test_target_path() {
+        let fs = tempdir::TempDir::new(PathBuf::from("/some/tempdir/path"));
+        assert_eq!(fs.target_path("", "foo.txt"), Ok("foo.txt".to_string()));
+        assert_eq!(fs.target_path("/", "foo.txt"), Ok("foo.txt".to_string()));
+        assert_eq!(
+            fs.target_path("subdir", "foo.txt"),
+            Ok("subdir/foo.txt".to_string())
+        );
+        assert_eq!(
+            fs.target_path("/subdir/", "foo.txt"),
+            Ok("subdir/foo.txt".to_string())
+        );
+        assert!(fs.target_path("subdir", "").is_err());
+    }
+
+    #[test]
+    fn test_partial_upload_ranges() {
+        let mut progress = PartialUpload {
+            total: 10,
+            ranges: Vec::new(),
+        };
+        progress.add(5, 8);
+        progress.add(0, 3);
+        assert_eq!(progress.ranges, vec![(0, 3), (5, 8)]);
+        assert!(!progress.is_complete());
+        progress.add(2, 6);
+        assert_eq!(progress.ranges, vec![(0, 8)]);
+        progress.add(8, 10);
+        assert!(progress.is_complete());
+    }
+
+    #[tokio::test]
+    async fn test_write_range_to_disk() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let target = temp_dir.path().join("assembled.txt");
+
+        // the second half arrives first
+        assert!(
+            !write_range_to_disk(&target, 6, 12, Bytes::from_static(b"world!"))
+                .await
+                .expect("Failed to write range")
+        );
+        assert!(!target.exists());
+        assert!(
+            write_range_to_disk(&target, 8, 12, Bytes::from_static(b"world!"))
+                .await
+                .is_err(),
+            "range past the total should fail"
+        );
+        assert!(
+            write_range_to_disk(&target, 0, 13, Bytes::from_static(b"hello "))
+                .await
+                .is_err(),
+            "mismatched total should fail"
+        );
+        assert!(
+            write_range_to_disk(&target, 0, 12, Bytes::from_static(b"hello "))
+                .await
+                .expect("Failed to write range")
+        );
+
+        assert_eq!(
+            std::fs::read(&target).expect("Failed to read assembled file"),
+            b"hello world!"
+        );
+        // the staging files are gone
+        assert_eq!(
+            std::fs::read_dir(temp_dir.path())
+                .expect("Failed to read temp dir")
+                .count(),
+            1
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_write_range_to_disk_parallel() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let target = temp_dir.path().join("parallel.bin");
+        let expected: Vec<u8> = (0..=255).collect();
+
+        let tasks: Vec<_> = expected
+            .chunks(16)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let target = target.clone();
+                let chunk = Bytes::copy_from_slice(chunk);
+                tokio::spawn(async move {
+                    write_range_to_disk(&target, index as u64 * 16, 256, chunk).await
+                })
+            })
+            .collect();
+        let mut completed = 0;
+        for task in tasks {
+            if task
+                .await
+                .expect("Range task failed")
+                .expect("Failed to write range")
+            {
+                completed += 1;
+            }
+        }
+        assert_eq!(completed, 1);
+        assert_eq!(
+            std::fs::read(&target).expect("Failed to read assembled file"),
+            expected
+        );
+        // nothing's waiting on the upload's lock any more
+        let staging_file = temp_dir.path().join(".parallel.bin.filekid-partial");
+        assert!(!PARTIAL_UPLOAD_LOCKS
+            .lock()
+            .expect("Poisoned")
+            .contains_key(&staging_file));
+    }
+
+    #[test]
+    fn test_checksum_file() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("hello.txt");
+        std::fs::write(&path, b"hello world\n").expect("Failed to write file");
+        for (algorithm, expected) in [
+            (
+                ChecksumAlgo::Sha256,
+                "a948904f2f0f479b8f8197694b30184b0d2ed1c1cd2a1ec0fb85d299a192a447",
+            ),
+            (ChecksumAlgo::Md5, "6f5902ac237024bdd0c176cb93063dc4"),
+            (ChecksumAlgo::Crc32, "af083b2d"),
+        ] {
+            assert_eq!(
+                checksum_file(&path, algorithm).expect("Failed to hash file"),
+                expected,
+                "{algorithm:?}"
+            );
+        }
+        assert_eq!("MD5".parse::<ChecksumAlgo>(), Ok(ChecksumAlgo::Md5));
+        assert!(matches!(
+            "sha1".parse::<ChecksumAlgo>(),
+            Err(Error::BadRequest(_))
+        ));
+    }
+
+    /// Any temporary files left over from writes
+    fn temp_uploads(dir: &Path) -> Vec<String> {
+        std::fs::read_dir(dir)
+            .expect("Failed to read dir")
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .filter(|name| name.contains(".tmp-"))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_write_atomically() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let target = temp_dir.path().join("upload.txt");
+
+        write_atomically(&target, b"hello world")
+            .await
+            .expect("Failed to write");
+        assert_eq!(
+            std::fs::read(&target).expect("Failed to read file"),
+            b"hello world"
+        );
+        assert_eq!(temp_uploads(temp_dir.path()), Vec::<String>::new());
+
+        // the rename fails if there's a directory in the way
+        let blocked = temp_dir.path().join("blocked");
+        std::fs::create_dir_all(blocked.join("inside")).expect("Failed to create dir");
+        assert!(write_atomically(&blocked, b"hello world").await.is_err());
+        assert!(blocked.join("inside").is_dir());
+        assert_eq!(temp_uploads(temp_dir.path()), Vec::<String>::new());
+
+        // a stream that breaks halfway leaves nothing behind
+        let broken = futures::stream::iter(vec![
+            Ok(Bytes::from_static(b"hello ")),
+            Err(std::io::Error::other("connection reset")),
+        ]);
+        assert!(stream_to_file(&target, broken, None, false).await.is_err());
+        assert_eq!(
+            std::fs::read(&target).expect("Failed to read file"),
+            b"hello world"
+        );
+        let partial = temp_dir.path().join("partial.txt");
+        let broken = futures::stream::iter(vec![
+            Ok(Bytes::from_static(b"hello ")),
+            Err(std::io::Error::other("connection reset")),
+        ]);
+        assert!(stream_to_file(&partial, broken, None, false).await.is_err());
+        assert!(!partial.exists());
+        assert_eq!(temp_uploads(temp_dir.path()), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn test_stream_to_file_lengths() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let target = temp_dir.path().join("upload.txt");
+        let stream = || {
+            futures::stream::iter(vec![
+                Ok::<_, std::io::Error>(Bytes::from_static(b"hello ")),
+                Ok(Bytes::from_static(b"world")),
+            ])
+        };
+
+        assert_eq!(
+            stream_to_file(&target, stream(), Some(11), true)
+                .await
+                .expect("Failed to stream"),
+            11
+        );
+        assert_eq!(
+            std::fs::read(&target).expect("Failed to read file"),
+            b"hello world"
+        );
+
+        // too long is always rejected
+        let new_target = temp_dir.path().join("new.txt");
+        for strict in [true, false] {
+            assert!(matches!(
+                stream_to_file(&new_target, stream(), Some(5), strict).await,
+                Err(Error::BadRequest(_))
+            ));
+            assert!(!new_target.exists(), "failed upload wasn't cleaned up");
+        }
+
+        // too short is only rejected in strict mode, and the old file's kept
+        assert!(matches!(
+            stream_to_file(&target, stream(), Some(20), true).await,
+            Err(Error::BadRequest(_))
+        ));
+        assert_eq!(
+            std::fs::read(&target).expect("Failed to read file"),
+            b"hello world"
+        );
+        assert_eq!(temp_uploads(temp_dir.path()), Vec::<String>::new());
+        assert_eq!(
+            stream_to_file(&target, stream(), Some(20), false)
+                .await
+                .expect("Failed to stream"),
+            11
+        );
+        assert_eq!(
+            stream_to_file(&target, stream(), None, true)
+                .await
+                .expect("Failed to stream"),
+            11
+        );
+    }
+
+    #[test]
+    fn test_list_dir_paginated() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        for dir in ["zdir", "adir"] {
+            std::fs::create_dir(temp_dir.path().join(dir)).expect("Failed to create dir");
+        }
+        for file in ["c.txt", "a.txt", "b.txt"] {
+            std::fs::write(temp_dir.path().join(file), b"").expect("Failed to write file");
+        }
+        let fs = local::LocalFs::new(temp_dir.path().to_path_buf());
+        let page = |offset: usize, limit: usize| {
+            let (entries, total) = fs
+                .list_dir_paginated(None, offset, limit, usize::MAX)
+                .expect("Failed to list dir");
+            (
+                entries
+                    .into_iter()
+                    .map(|entry| entry.filename)
+                    .collect::<Vec<String>>(),
+                total,
+            )
+        };
+
+        // directories come first, then files, each sorted by name
+        assert_eq!(
+            page(0, 100),
+            (
+                vec!["adir", "zdir", "a.txt", "b.txt", "c.txt"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+                5
+            )
+        );
+        assert_eq!(page(0, 2).0, vec!["adir", "zdir"]);
+        assert_eq!(page(2, 2).0, vec!["a.txt", "b.txt"]);
+        // the last page is short
+        assert_eq!(page(4, 2), (vec!["c.txt".to_string()], 5));
+        // past the end is empty, but still knows the total
+        assert_eq!(page(5, 2), (vec![], 5));
+        assert_eq!(page(50, 2), (vec![], 5));
+        assert_eq!(page(0, 0), (vec![], 5));
+    }
+
+    #[test]
+    fn test_sort_entries() {
+        let epoch = SystemTime::UNIX_EPOCH;
+        let entry =
+            |filename: &str, filetype: FileType, size: Option<u64>, modified: u64| FileEntry {
+                filename: filename.to_string(),
+                fullpath: filename.to_string(),
+                filetype,
+                size,
+                modified: Some(epoch + Duration::from_secs(modified)),
+                dir_usage: None,
+            };
+        let mut entries = vec![
+            entry("big.iso", FileType::File, Some(5000), 10),
+            entry("zdir", FileType::Directory, None, 5),
+            entry("a.txt", FileType::File, Some(10), 30),
+            entry("adir", FileType::Directory, None, 50),
+            entry("middle.bin", FileType::File, Some(200), 20),
+        ];
+        let mut sorted = |sort_by: SortBy, order: SortOrder| {
+            sort_entries(&mut entries, sort_by, order);
+            entries
+                .iter()
+                .map(|entry| entry.filename.as_str())
+                .collect::<Vec<&str>>()
+                .join(",")
+        };
+
+        assert_eq!(
+            sorted(SortBy::Name, SortOrder::Asc),
+            "adir,zdir,a.txt,big.iso,middle.bin"
+        );
+        assert_eq!(
+            sorted(SortBy::Name, SortOrder::Desc),
+            "zdir,adir,middle.bin,big.iso,a.txt"
+        );
+        // directories don't have sizes, so they fall back to name order
+        assert_eq!(
+            sorted(SortBy::Size, SortOrder::Asc),
+            "adir,zdir,a.txt,middle.bin,big.iso"
+        );
+        assert_eq!(
+            sorted(SortBy::Size, SortOrder::Desc),
+            "zdir,adir,big.iso,middle.bin,a.txt"
+        );
+        assert_eq!(
+            sorted(SortBy::Modified, SortOrder::Asc),
+            "zdir,adir,big.iso,middle.bin,a.txt"
+        );
+        assert_eq!(
+            sorted(SortBy::Modified, SortOrder::Desc),
+            "adir,zdir,a.txt,middle.bin,big.iso"
+        );
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.tmp", "foo.tmp"));
+        assert!(glob_match("*", ".hidden"));
+        assert!(glob_match(".git", ".git"));
+        assert!(glob_match("foo?.txt", "foo1.txt"));
+        assert!(glob_match("*a*b*", "xxaxxbxx"));
+        assert!(!glob_match("*.tmp", "foo.txt"));
+        assert!(!glob_match("foo?.txt", "foo.txt"));
+        assert!(!glob_match(".git", ".github"));
+    }
+
+    #[test]
+    fn test_key_escapes_base() {
+        assert!(!key_escapes_base("test.txt"));
+        assert!(!key_escapes_base("foo/../test.txt"));
+        assert!(!key_escapes_base("./foo/bar"));
+        assert!(key_escapes_base("../test.txt"));
+        assert!(key_escapes_base("foo/../../test.txt"));
+    }
+
+    #[test]
+    fn test_sanitize_filename() {
+        for filename in ["report.pdf", "two..dots.txt", ".hidden", "résumé 日本.txt"] {
+            assert_eq!(sanitize_filename(filename), Ok(filename));
+        }
+        for filename in [
+            "",
+            ".",
+            "..",
+            "../passwd",
+            "foo/bar.txt",
+            "..\\windows.ini",
+            "null\0byte.txt",
+            "bell\u{7}.txt",
+            "new\nline.txt",
+        ] {
+            assert!(
+                matches!(sanitize_filename(filename), Err(Error::BadRequest(_))),
+                "{filename:?} should have been rejected"
+            );
+        }
+    }
+
     #[test]
     fn test_fs_from_serverpath_tempdir_no_path() {
         let server_path = ServerPath {
             type_: FileKidFsType::TempDir,
             path: None,
+            ..Default::default()
         };
         let fs = fs_from_serverpath(&server_path);
         assert!(fs.is_err());
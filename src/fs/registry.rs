@@ -0,0 +1,226 @@
+//! Which backend each server path `type` is built with, so crates embedding FileKid can add their own
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, LazyLock, RwLock};
+
+use tracing::debug;
+
+use super::{local, s3, tempdir, FileKidFs, FileKidFsType};
+use crate::error::Error;
+use crate::ServerPath;
+
+/// Builds a backend for a server path, see [BackendRegistry::register]
+pub type BackendFactory =
+    dyn Fn(&ServerPath) -> Result<Box<dyn FileKidFs>, Error> + Send + Sync + 'static;
+
+/// Server path types and how to build their backends, starts out with `local`, `tempdir` and `s3`
+#[derive(Clone)]
+pub struct BackendRegistry {
+    factories: HashMap<String, Arc<BackendFactory>>,
+}
+
+impl Debug for BackendRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BackendRegistry")
+            .field("types", &self.types())
+            .finish()
+    }
+}
+
+impl Default for BackendRegistry {
+    fn default() -> Self {
+        let mut registry = Self {
+            factories: HashMap::new(),
+        };
+        registry.insert(FileKidFsType::Local.as_str(), |server_path| {
+            let Some(path) = server_path.path.as_ref() else {
+                return Err(Error::Configuration("No path specified".to_string()));
+            };
+            Ok(Box::new(
+                local::LocalFs::new(path.to_path_buf())
+                    .with_follow_symlinks(server_path.download_follow_symlinks())
+                    .with_show_hidden(server_path.show_hidden),
+            ))
+        });
+        registry.insert(FileKidFsType::TempDir.as_str(), |server_path| {
+            let Some(path) = server_path.path.as_ref() else {
+                return Err(Error::Configuration(
+                    "No path specified for tempdir after startup?".to_string(),
+                ));
+            };
+            Ok(Box::new(
+                tempdir::TempDir::new(path.to_owned())
+                    .with_follow_symlinks(server_path.download_follow_symlinks())
+                    .with_show_hidden(server_path.show_hidden),
+            ))
+        });
+        registry.insert(FileKidFsType::S3.as_str(), |server_path| {
+            Ok(Box::new(s3::S3Fs::new(server_path)?))
+        });
+        registry
+    }
+}
+
+impl BackendRegistry {
+    fn insert(
+        &mut self,
+        type_: &str,
+        factory: impl Fn(&ServerPath) -> Result<Box<dyn FileKidFs>, Error> + Send + Sync + 'static,
+    ) {
+        self.factories.insert(type_.to_string(), Arc::new(factory));
+    }
+
+    /// Adds a backend for server paths with `type = "<type_>"`, the built-in types can't be replaced
+    pub fn register(
+        &mut self,
+        type_: &str,
+        factory: impl Fn(&ServerPath) -> Result<Box<dyn FileKidFs>, Error> + Send + Sync + 'static,
+    ) -> Result<(), Error> {
+        if self.contains(type_) {
+            return Err(Error::Configuration(format!(
+                "Backend type {type_} is already registered"
+            )));
+        }
+        debug!("Registering backend type {}", type_);
+        self.insert(type_, factory);
+        Ok(())
+    }
+
+    pub fn contains(&self, type_: &str) -> bool {
+        self.factories.contains_key(type_)
+    }
+
+    /// The registered type names, sorted
+    pub fn types(&self) -> Vec<String> {
+        let mut types: Vec<String> = self.factories.keys().cloned().collect();
+        types.sort();
+        types
+    }
+
+    /// Builds the backend for `server_path` with whatever's registered for its type
+    pub fn build(&self, server_path: &ServerPath) -> Result<Box<dyn FileKidFs>, Error> {
+        match self.factories.get(server_path.type_.as_str()) {
+            Some(factory) => factory(server_path),
+            None => Err(self.unknown(&server_path.type_)),
+        }
+    }
+
+    /// Refuses types nothing's registered for
+    pub fn check(&self, type_: &FileKidFsType) -> Result<(), Error> {
+        match self.contains(type_.as_str()) {
+            true => Ok(()),
+            false => Err(self.unknown(type_)),
+        }
+    }
+
+    fn unknown(&self, type_: &FileKidFsType) -> Error {
+        Error::Configuration(format!(
+            "Unknown server path type {}, expected one of: {}",
+            type_.as_str(),
+            self.types().join(", ")
+        ))
+    }
+}
+
+static BACKENDS: LazyLock<RwLock<BackendRegistry>> = LazyLock::new(Default::default);
+
+fn with_backends<T>(f: impl FnOnce(&BackendRegistry) -> T) -> Result<T, Error> {
+    let backends = BACKENDS.read().map_err(|err| {
+        Error::InternalServerError(format!("Backend registry lock is poisoned: {err}"))
+    })?;
+    Ok(f(&backends))
+}
+
+/// Adds a backend type to the registry every server path is built from, do this before loading the configuration
+pub fn register_backend(
+    type_: &str,
+    factory: impl Fn(&ServerPath) -> Result<Box<dyn FileKidFs>, Error> + Send + Sync + 'static,
+) -> Result<(), Error> {
+    BACKENDS
+        .write()
+        .map_err(|err| {
+            Error::InternalServerError(format!("Backend registry lock is poisoned: {err}"))
+        })?
+        .register(type_, factory)
+}
+
+/// Builds `server_path`'s backend from the global registry
+pub(crate) fn build_backend(server_path: &ServerPath) -> Result<Box<dyn FileKidFs>, Error> {
+    with_backends(|backends| backends.build(server_path))?
+}
+
+/// Checks something's registered for `type_` in the global registry
+pub(crate) fn check_backend_type(type_: &FileKidFsType) -> Result<(), Error> {
+    with_backends(|backends| backends.check(type_))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::fs_from_serverpath;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_custom_backend() {
+        let mut registry = BackendRegistry::default();
+        assert_eq!(registry.types(), vec!["local", "s3", "tempdir"]);
+        assert!(registry
+            .register("local", |_| Err(Error::Generic("unused".to_string())))
+            .is_err());
+
+        // a stand-in for something like a database blob store
+        registry
+            .register("blobs", |server_path| {
+                Ok(Box::new(tempdir::TempDir::new(
+                    server_path.path.clone().unwrap_or_default(),
+                )))
+            })
+            .expect("Failed to register backend");
+        assert_eq!(registry.types(), vec!["blobs", "local", "s3", "tempdir"]);
+
+        let server_path: ServerPath =
+            serde_json::from_str(r#"{"type": "blobs", "path": "/blobs"}"#)
+                .expect("Failed to parse server path");
+        assert_eq!(server_path.type_, FileKidFsType::Other("blobs".to_string()));
+        assert!(registry.check(&server_path.type_).is_ok());
+        assert_eq!(
+            registry
+                .build(&server_path)
+                .expect("Failed to build backend")
+                .name(),
+            "tempdir (/blobs)"
+        );
+
+        // the global registry hasn't heard of it until it's registered there
+        assert!(fs_from_serverpath(&server_path).is_err());
+        register_backend("blobs", |server_path| {
+            Ok(Box::new(local::LocalFs::new(
+                server_path
+                    .path
+                    .clone()
+                    .unwrap_or_else(|| PathBuf::from("/")),
+            )))
+        })
+        .expect("Failed to register backend");
+        assert_eq!(
+            fs_from_serverpath(&server_path)
+                .expect("Failed to build backend")
+                .name(),
+            "local:/blobs"
+        );
+        assert!(check_backend_type(&server_path.type_).is_ok());
+    }
+
+    #[test]
+    fn test_unknown_backend() {
+        let registry = BackendRegistry::default();
+        let type_ = FileKidFsType::from("nope".to_string());
+        assert_eq!(
+            registry.check(&type_),
+            Err(Error::Configuration(
+                "Unknown server path type nope, expected one of: local, s3, tempdir".to_string()
+            ))
+        );
+    }
+}
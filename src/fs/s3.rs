@@ -1 +1,1258 @@
 //! S3 backend
+
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::LazyLock;
+use std::time::SystemTime;
+
+use aws_config::environment::EnvironmentVariableCredentialsProvider;
+use aws_sdk_s3::config::{BehaviorVersion, Credentials, Region};
+use aws_sdk_s3::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use aws_sdk_s3::Client;
+use axum::body::{Body, Bytes};
+use futures::StreamExt;
+use tokio::runtime::{Handle, Runtime, RuntimeFlavor};
+use tokio::sync::mpsc;
+use tracing::{debug, error, instrument, warn};
+
+use crate::error::Error;
+use crate::views::browse::FileEntry;
+use crate::views::FileType;
+use crate::ServerPath;
+
+use super::{
+    check_content_length, check_list_entries, key_escapes_base, ChecksumAlgo, Checksummer,
+    FileData, FileKidFs, UploadStream,
+};
+
+/// The region used when a server path doesn't specify one
+const DEFAULT_REGION: &str = "us-east-1";
+
+/// Streamed uploads are sent in parts this big, S3 won't take parts under 5MiB (other than the last one)
+const MULTIPART_PART_SIZE: usize = 5 * 1024 * 1024;
+
+/// S3 requests run on their own runtime, so the sync parts of [FileKidFs] can block on them
+/// without stalling the web server's runtime, and connections are pooled in one place.
+static S3_RUNTIME: LazyLock<Option<Runtime>> = LazyLock::new(|| {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .thread_name("filekid-s3")
+        .enable_all()
+        .build()
+        .inspect_err(|err| error!("Failed to start the S3 runtime: {err}"))
+        .ok()
+});
+
+/// Downloads only read this many chunks ahead of the client
+const READ_CHANNEL_CHUNKS: usize = 4;
+
+fn s3_runtime() -> Result<&'static Runtime, Error> {
+    S3_RUNTIME
+        .as_ref()
+        .ok_or_else(|| Error::InternalServerError("The S3 runtime isn't running".to_string()))
+}
+
+/// Runs an S3 request on [S3_RUNTIME], blocking until it's done
+fn block_on<T: Send + 'static>(
+    future: impl Future<Output = Result<T, Error>> + Send + 'static,
+) -> Result<T, Error> {
+    let runtime = s3_runtime()?;
+    let failed_to_run = |err: &dyn std::fmt::Display| {
+        Error::InternalServerError(format!("S3 request failed to run: {err}"))
+    };
+    match Handle::try_current() {
+        // tells the caller's runtime to hand this worker's other tasks to another one while it waits
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            let task = runtime.spawn(future);
+            tokio::task::block_in_place(|| handle.block_on(task))
+                .map_err(|err| failed_to_run(&err))?
+        }
+        // block_in_place panics on a current-thread runtime, the request's on S3_RUNTIME's threads so
+        // waiting on a plain channel can't deadlock, it just holds up this thread like any sync call
+        Ok(_) => {
+            let (sender, receiver) = std::sync::mpsc::sync_channel(1);
+            runtime.spawn(async move {
+                // the receiver's only gone if the caller has, so there's no one to tell
+                let _ = sender.send(future.await);
+            });
+            receiver.recv().map_err(|err| failed_to_run(&err))?
+        }
+        Err(_) => runtime.block_on(future),
+    }
+}
+
+/// Runs an S3 request on [S3_RUNTIME] from an async context
+async fn run<T: Send + 'static>(
+    future: impl Future<Output = Result<T, Error>> + Send + 'static,
+) -> Result<T, Error> {
+    s3_runtime()?
+        .spawn(future)
+        .await
+        .map_err(|err| Error::InternalServerError(format!("S3 request failed to run: {err}")))?
+}
+
+/// Turns an S3 SDK error into one of ours, missing things become [Error::NotFound]
+fn s3_error<E>(key: &str, err: &SdkError<E>) -> Error
+where
+    E: ProvideErrorMetadata + std::error::Error + Send + Sync + 'static,
+{
+    let status = err
+        .raw_response()
+        .map(|response| response.status().as_u16());
+    if status == Some(404) || matches!(err.code(), Some("NoSuchKey" | "NotFound")) {
+        return Error::NotFound(key.to_string());
+    }
+    error!("S3 request for {key} failed: {}", DisplayErrorContext(err));
+    Error::InternalServerError(format!("S3 request for {key} failed"))
+}
+
+/// Percent-encodes an object key for the `x-amz-copy-source` header, leaving `/` alone
+fn encode_copy_source(bucket: &str, key: &str) -> String {
+    let mut source = format!("{bucket}/");
+    for byte in key.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                source.push(byte as char)
+            }
+            _ => source.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    source
+}
+
+#[derive(Clone)]
+pub struct S3Fs {
+    client: Client,
+    bucket: String,
+}
+
+impl std::fmt::Debug for S3Fs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3Fs")
+            .field("bucket", &self.bucket)
+            .finish()
+    }
+}
+
+impl S3Fs {
+    /// Builds a client from the bucket/region/endpoint/credentials settings of a server path.
+    ///
+    /// If no credentials are configured, they're read from the usual `AWS_*` environment variables.
+    pub fn new(server_path: &ServerPath) -> Result<Self, Error> {
+        let bucket = server_path
+            .bucket
+            .clone()
+            .filter(|bucket| !bucket.is_empty())
+            .ok_or_else(|| Error::Configuration("No bucket specified".to_string()))?;
+
+        let mut config = aws_sdk_s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new(
+                server_path
+                    .region
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_REGION.to_string()),
+            ));
+
+        match (&server_path.access_key_id, &server_path.secret_access_key) {
+            (Some(access_key_id), Some(secret_access_key)) => {
+                config = config.credentials_provider(Credentials::new(
+                    access_key_id,
+                    secret_access_key,
+                    None,
+                    None,
+                    "filekid-config",
+                ));
+            }
+            (None, None) => {
+                config = config.credentials_provider(EnvironmentVariableCredentialsProvider::new());
+            }
+            _ => {
+                return Err(Error::Configuration(format!(
+                    "Bucket {bucket} needs both access_key_id and secret_access_key set"
+                )));
+            }
+        }
+
+        if let Some(endpoint) = &server_path.endpoint {
+            // things like MinIO don't do virtual-hosted buckets
+            config = config.endpoint_url(endpoint).force_path_style(true);
+        }
+
+        Ok(Self {
+            client: Client::from_conf(config.build()),
+            bucket,
+        })
+    }
+
+    /// Turns a filekid key into an object key, refusing anything that tries to climb out of the bucket
+    fn object_key(&self, key: &str) -> Result<String, Error> {
+        if key_escapes_base(key) {
+            return Err(Error::NotAuthorized(format!(
+                "Path '{key}' is outside of base path"
+            )));
+        }
+        Ok(key
+            .split('/')
+            .filter(|part| !part.is_empty() && *part != ".")
+            .collect::<Vec<&str>>()
+            .join("/"))
+    }
+
+    /// Directories are prefixes ending in `/`, the root of the bucket is an empty prefix
+    fn dir_prefix(object_key: &str) -> String {
+        match object_key.is_empty() {
+            true => String::new(),
+            false => format!("{object_key}/"),
+        }
+    }
+
+    /// Returns the size and last modified time of an object
+    async fn head_object(
+        &self,
+        object_key: String,
+    ) -> Result<(Option<u64>, Option<SystemTime>), Error> {
+        let (client, bucket) = (self.client.clone(), self.bucket.clone());
+        run(async move {
+            client
+                .head_object()
+                .bucket(bucket)
+                .key(&object_key)
+                .send()
+                .await
+                .map(|head| {
+                    (
+                        head.content_length().map(|len| len.max(0) as u64),
+                        head.last_modified()
+                            .and_then(|modified| SystemTime::try_from(*modified).ok()),
+                    )
+                })
+                .map_err(|err| s3_error(&object_key, &err))
+        })
+        .await
+    }
+
+    fn object_exists(&self, object_key: &str) -> Result<bool, Error> {
+        if object_key.is_empty() {
+            return Ok(false);
+        }
+        let (client, bucket, object_key) = (
+            self.client.clone(),
+            self.bucket.clone(),
+            object_key.to_string(),
+        );
+        block_on(async move {
+            match client
+                .head_object()
+                .bucket(bucket)
+                .key(&object_key)
+                .send()
+                .await
+            {
+                Ok(_) => Ok(true),
+                Err(err) => match s3_error(&object_key, &err) {
+                    Error::NotFound(_) => Ok(false),
+                    err => Err(err),
+                },
+            }
+        })
+    }
+
+    fn prefix_exists(&self, object_key: &str) -> Result<bool, Error> {
+        if object_key.is_empty() {
+            return Ok(true);
+        }
+        let (client, bucket, prefix) = (
+            self.client.clone(),
+            self.bucket.clone(),
+            Self::dir_prefix(object_key),
+        );
+        block_on(async move {
+            client
+                .list_objects_v2()
+                .bucket(bucket)
+                .prefix(&prefix)
+                .max_keys(1)
+                .send()
+                .await
+                .map(|res| res.key_count().unwrap_or_default() > 0)
+                .map_err(|err| s3_error(&prefix, &err))
+        })
+    }
+
+    fn copy_object(&self, from: String, to: String) -> Result<(), Error> {
+        let (client, bucket) = (self.client.clone(), self.bucket.clone());
+        block_on(async move {
+            client
+                .copy_object()
+                .bucket(&bucket)
+                .copy_source(encode_copy_source(&bucket, &from))
+                .key(&to)
+                .send()
+                .await
+                .map(|_| ())
+                .map_err(|err| s3_error(&from, &err))
+        })
+    }
+
+    async fn put_object(&self, object_key: String, data: Bytes) -> Result<(), Error> {
+        let (client, bucket) = (self.client.clone(), self.bucket.clone());
+        debug!("Writing to s3://{}/{}", self.bucket, object_key);
+        run(async move {
+            client
+                .put_object()
+                .bucket(bucket)
+                .key(&object_key)
+                .body(ByteStream::from(data))
+                .send()
+                .await
+                .map(|_| ())
+                .map_err(|err| s3_error(&object_key, &err))
+        })
+        .await
+    }
+
+    /// Starts a multipart upload, returning its upload ID
+    async fn create_multipart_upload(&self, object_key: String) -> Result<String, Error> {
+        let (client, bucket) = (self.client.clone(), self.bucket.clone());
+        debug!(
+            "Starting multipart upload to s3://{}/{}",
+            self.bucket, object_key
+        );
+        run(async move {
+            client
+                .create_multipart_upload()
+                .bucket(bucket)
+                .key(&object_key)
+                .send()
+                .await
+                .map_err(|err| s3_error(&object_key, &err))?
+                .upload_id()
+                .map(str::to_string)
+                .ok_or_else(|| {
+                    Error::InternalServerError(format!(
+                        "S3 didn't return an upload ID for {object_key}"
+                    ))
+                })
+        })
+        .await
+    }
+
+    async fn upload_part(
+        &self,
+        object_key: String,
+        upload_id: String,
+        part_number: i32,
+        data: Bytes,
+    ) -> Result<CompletedPart, Error> {
+        let (client, bucket) = (self.client.clone(), self.bucket.clone());
+        run(async move {
+            let part = client
+                .upload_part()
+                .bucket(bucket)
+                .key(&object_key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(ByteStream::from(data))
+                .send()
+                .await
+                .map_err(|err| s3_error(&object_key, &err))?;
+            Ok(CompletedPart::builder()
+                .set_e_tag(part.e_tag().map(str::to_string))
+                .part_number(part_number)
+                .build())
+        })
+        .await
+    }
+
+    async fn complete_multipart_upload(
+        &self,
+        object_key: String,
+        upload_id: String,
+        parts: Vec<CompletedPart>,
+    ) -> Result<(), Error> {
+        let (client, bucket) = (self.client.clone(), self.bucket.clone());
+        run(async move {
+            client
+                .complete_multipart_upload()
+                .bucket(bucket)
+                .key(&object_key)
+                .upload_id(upload_id)
+                .multipart_upload(
+                    CompletedMultipartUpload::builder()
+                        .set_parts(Some(parts))
+                        .build(),
+                )
+                .send()
+                .await
+                .map(|_| ())
+                .map_err(|err| s3_error(&object_key, &err))
+        })
+        .await
+    }
+
+    /// Throws away the parts of a failed upload, otherwise S3 keeps (and bills for) them
+    async fn abort_multipart_upload(&self, object_key: String, upload_id: String) {
+        let (client, bucket) = (self.client.clone(), self.bucket.clone());
+        warn!(
+            "Aborting multipart upload to s3://{}/{}",
+            self.bucket, object_key
+        );
+        if let Err(err) = run(async move {
+            client
+                .abort_multipart_upload()
+                .bucket(bucket)
+                .key(&object_key)
+                .upload_id(upload_id)
+                .send()
+                .await
+                .map(|_| ())
+                .map_err(|err| s3_error(&object_key, &err))
+        })
+        .await
+        {
+            error!("Failed to abort multipart upload: {err:?}");
+        }
+    }
+
+    /// Every object key starting with `prefix`, however deep
+    fn keys_under(&self, prefix: String) -> Result<Vec<String>, Error> {
+        let (client, bucket) = (self.client.clone(), self.bucket.clone());
+        block_on(async move {
+            let mut keys = Vec::new();
+            let mut continuation_token = None;
+            loop {
+                let res = client
+                    .list_objects_v2()
+                    .bucket(&bucket)
+                    .prefix(&prefix)
+                    .set_continuation_token(continuation_token)
+                    .send()
+                    .await
+                    .map_err(|err| s3_error(&prefix, &err))?;
+                keys.extend(
+                    res.contents()
+                        .iter()
+                        .filter_map(|object| object.key().map(str::to_string)),
+                );
+                match res.next_continuation_token() {
+                    Some(token) => continuation_token = Some(token.to_string()),
+                    None => break,
+                }
+            }
+            Ok(keys)
+        })
+    }
+
+    fn delete_object(&self, object_key: String) -> Result<(), Error> {
+        let (client, bucket) = (self.client.clone(), self.bucket.clone());
+        block_on(async move {
+            client
+                .delete_object()
+                .bucket(bucket)
+                .key(&object_key)
+                .send()
+                .await
+                .map(|_| ())
+                .map_err(|err| s3_error(&object_key, &err))
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl FileKidFs for S3Fs {
+    fn name(&self) -> String {
+        format!("s3 ({})", self.bucket)
+    }
+
+    fn has_stream_put_file(&self) -> bool {
+        true
+    }
+
+    fn target_path_from_key(&self, key: &str) -> PathBuf {
+        PathBuf::from(key)
+    }
+
+    /// Checks the bucket exists and that we're allowed to look at it
+    async fn available(&self) -> Result<bool, Error> {
+        let (client, bucket) = (self.client.clone(), self.bucket.clone());
+        run(async move {
+            match client.head_bucket().bucket(&bucket).send().await {
+                Ok(_) => Ok(true),
+                Err(err) => {
+                    warn!(
+                        "S3 bucket {bucket} isn't available: {}",
+                        DisplayErrorContext(&err)
+                    );
+                    Ok(false)
+                }
+            }
+        })
+        .await
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    fn exists(&self, filepath: &str) -> Result<bool, Error> {
+        let object_key = self.object_key(filepath)?;
+        Ok(self.object_exists(&object_key)? || self.prefix_exists(&object_key)?)
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    fn get_data(&self, path: &str) -> Result<FileData, Error> {
+        let object_key = self.object_key(path)?;
+        let (size, modified) = block_on({
+            let fs = self.clone();
+            let object_key = object_key.clone();
+            async move { fs.head_object(object_key).await }
+        })?;
+        let key_path = PathBuf::from(&object_key);
+        Ok(FileData {
+            filename: key_path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .ok_or_else(|| Error::NotFound(path.to_string()))?,
+            filepath: key_path.parent().map(PathBuf::from).unwrap_or_default(),
+            size,
+            modified,
+        })
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn get_file(&self, filepath: &str) -> Result<Vec<u8>, Error> {
+        let object_key = self.object_key(filepath)?;
+        let (client, bucket) = (self.client.clone(), self.bucket.clone());
+        run(async move {
+            let object = client
+                .get_object()
+                .bucket(bucket)
+                .key(&object_key)
+                .send()
+                .await
+                .map_err(|err| s3_error(&object_key, &err))?;
+            let data = object.body.collect().await.map_err(|err| {
+                error!("Failed to read S3 object {object_key}: {err}");
+                Error::InternalServerError(format!("Failed to read {object_key}"))
+            })?;
+            Ok(data.into_bytes().to_vec())
+        })
+        .await
+    }
+
+    /// Streams the object to the client as it arrives from S3, rather than holding all of it in memory
+    #[instrument(level = "debug", skip(self))]
+    async fn read_file(&self, filepath: &str) -> Result<Body, Error> {
+        let object_key = self.object_key(filepath)?;
+        let (client, bucket) = (self.client.clone(), self.bucket.clone());
+        // the request's made up front, so a missing object is a 404 rather than a broken body
+        let mut object = run({
+            let object_key = object_key.clone();
+            async move {
+                client
+                    .get_object()
+                    .bucket(bucket)
+                    .key(&object_key)
+                    .send()
+                    .await
+                    .map(|object| object.body)
+                    .map_err(|err| s3_error(&object_key, &err))
+            }
+        })
+        .await?;
+
+        // the connection belongs to the S3 runtime, so it's read there and handed over a channel
+        let (tx, mut rx) = mpsc::channel(READ_CHANNEL_CHUNKS);
+        s3_runtime()?.spawn(async move {
+            while let Some(chunk) = object.next().await {
+                let chunk = chunk.map_err(|err| {
+                    error!("Failed to read S3 object {object_key}: {err}");
+                    std::io::Error::other(err)
+                });
+                let failed = chunk.is_err();
+                // erroring the body cuts the connection, so the client knows it's not got the whole thing
+                if tx.send(chunk).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok(Body::from_stream(futures::stream::poll_fn(move |cx| {
+            rx.poll_recv(cx)
+        })))
+    }
+
+    #[instrument(level = "debug", skip(self, contents))]
+    async fn put_file(&self, filepath: &str, contents: &[u8]) -> Result<(), Error> {
+        let object_key = self.object_key(filepath)?;
+        self.put_object(object_key, Bytes::copy_from_slice(contents))
+            .await
+    }
+
+    /// Uploads arriving in more than one [MULTIPART_PART_SIZE] go up as a multipart upload,
+    /// which is aborted if anything goes wrong. Smaller ones are a single `PutObject`.
+    #[instrument(level = "debug", skip(self, stream))]
+    async fn stream_put_file<'s>(
+        &self,
+        filepath: &str,
+        mut stream: UploadStream<'s>,
+        declared_len: Option<u64>,
+        strict: bool,
+    ) -> Result<u64, Error> {
+        let object_key = self.object_key(filepath)?;
+        let mut buffer: Vec<u8> = Vec::with_capacity(MULTIPART_PART_SIZE);
+        let mut upload_id: Option<String> = None;
+        let mut parts: Vec<CompletedPart> = Vec::new();
+        let mut received: u64 = 0;
+
+        let result = async {
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk.map_err(|err| {
+                    error!("Failed to read upload of {object_key}: {err}");
+                    Error::BadRequest(format!("Failed to read upload: {err}"))
+                })?;
+                received += chunk.len() as u64;
+                if declared_len.is_some_and(|len| received > len) {
+                    check_content_length(declared_len, received, strict)?;
+                }
+                buffer.extend_from_slice(&chunk);
+
+                while buffer.len() >= MULTIPART_PART_SIZE {
+                    let id = match &upload_id {
+                        Some(id) => id.clone(),
+                        None => {
+                            let id = self.create_multipart_upload(object_key.clone()).await?;
+                            upload_id = Some(id.clone());
+                            id
+                        }
+                    };
+                    let rest = buffer.split_off(MULTIPART_PART_SIZE);
+                    let part = std::mem::replace(&mut buffer, rest);
+                    let part_number = parts.len() as i32 + 1;
+                    parts.push(
+                        self.upload_part(object_key.clone(), id, part_number, Bytes::from(part))
+                            .await?,
+                    );
+                }
+            }
+            check_content_length(declared_len, received, strict)?;
+
+            let last_part = Bytes::from(std::mem::take(&mut buffer));
+            match &upload_id {
+                None => self.put_object(object_key.clone(), last_part).await,
+                Some(id) => {
+                    if !last_part.is_empty() {
+                        let part_number = parts.len() as i32 + 1;
+                        parts.push(
+                            self.upload_part(
+                                object_key.clone(),
+                                id.clone(),
+                                part_number,
+                                last_part,
+                            )
+                            .await?,
+                        );
+                    }
+                    self.complete_multipart_upload(
+                        object_key.clone(),
+                        id.clone(),
+                        std::mem::take(&mut parts),
+                    )
+                    .await
+                }
+            }
+        }
+        .await;
+
+        if let Err(err) = result {
+            if let Some(id) = upload_id {
+                self.abort_multipart_upload(object_key, id).await;
+            }
+            return Err(err);
+        }
+        Ok(received)
+    }
+
+    async fn put_file_range(
+        &self,
+        _filepath: &str,
+        _offset: u64,
+        _total: u64,
+        _contents: Bytes,
+        _overwrite: bool,
+    ) -> Result<bool, Error> {
+        Err(Error::BadRequest(
+            "Partial uploads aren't supported on S3 server paths".to_string(),
+        ))
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    fn delete_file(&self, filepath: &str) -> Result<(), Error> {
+        let object_key = self.object_key(filepath)?;
+        if !self.object_exists(&object_key)? {
+            return Err(Error::NotFound(filepath.to_string()));
+        }
+        self.delete_object(object_key)
+    }
+
+    /// Directories are prefixes, so this deletes every object under it (including the empty directory marker)
+    #[instrument(level = "debug", skip(self))]
+    fn delete_dir(&self, path: &str, recursive: bool) -> Result<(), Error> {
+        let object_key = self.object_key(path)?;
+        if object_key.is_empty() {
+            return Err(Error::BadRequest(
+                "The base of a server path can't be deleted".to_string(),
+            ));
+        }
+        let prefix = Self::dir_prefix(&object_key);
+        let keys = self.keys_under(prefix.clone())?;
+        if keys.is_empty() {
+            return Err(Error::NotFound(path.to_string()));
+        }
+        if !recursive && keys.iter().any(|key| *key != prefix) {
+            return Err(Error::BadRequest(format!("{path} isn't empty")));
+        }
+        debug!(
+            "Deleting {} objects under s3://{}/{}",
+            keys.len(),
+            self.bucket,
+            prefix
+        );
+        keys.into_iter().try_for_each(|key| self.delete_object(key))
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    fn checksum(&self, filepath: &str, algorithm: ChecksumAlgo) -> Result<String, Error> {
+        let object_key = self.object_key(filepath)?;
+        let (client, bucket) = (self.client.clone(), self.bucket.clone());
+        block_on(async move {
+            let mut object = client
+                .get_object()
+                .bucket(bucket)
+                .key(&object_key)
+                .send()
+                .await
+                .map_err(|err| s3_error(&object_key, &err))?;
+            let mut hasher = Checksummer::new(algorithm);
+            while let Some(chunk) = object.body.next().await {
+                let chunk = chunk.map_err(|err| {
+                    error!("Failed to read S3 object {object_key}: {err}");
+                    Error::InternalServerError(format!("Failed to read {object_key}"))
+                })?;
+                hasher.update(&chunk);
+            }
+            Ok(hasher.finalize())
+        })
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    fn move_file(&self, from: &str, to: &str, overwrite: bool) -> Result<(), Error> {
+        let source = self.object_key(from)?;
+        let destination = self.object_key(to)?;
+        if !self.object_exists(&source)? {
+            return Err(Error::NotFound(from.to_string()));
+        }
+        if !overwrite && self.exists(to)? {
+            return Err(Error::BadRequest(format!("{to} already exists")));
+        }
+        // S3 doesn't have renames, so copy then delete the original
+        self.copy_object(source.clone(), destination)?;
+        self.delete_object(source)
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    fn copy_file(&self, from: &str, to: &str, overwrite: bool) -> Result<(), Error> {
+        let source = self.object_key(from)?;
+        let destination = self.object_key(to)?;
+        if !self.object_exists(&source)? {
+            return Err(Error::NotFound(from.to_string()));
+        }
+        if !overwrite && self.exists(to)? {
+            return Err(Error::BadRequest("destination exists".to_string()));
+        }
+        self.copy_object(source, destination)
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    fn create_dir(&self, path: &str) -> Result<(), Error> {
+        let object_key = self.object_key(path)?;
+        if object_key.is_empty() || self.exists(path)? {
+            return Err(Error::BadRequest(format!("{path} already exists")));
+        }
+        let parent = object_key
+            .rsplit_once('/')
+            .map(|(parent, _)| parent)
+            .unwrap_or_default();
+        if !self.prefix_exists(parent)? {
+            return Err(Error::NotFound(format!(
+                "Parent directory of {path} doesn't exist"
+            )));
+        }
+        // an empty object with a trailing slash is how S3 tools represent an empty directory
+        let (client, bucket, marker) = (
+            self.client.clone(),
+            self.bucket.clone(),
+            Self::dir_prefix(&object_key),
+        );
+        block_on(async move {
+            client
+                .put_object()
+                .bucket(bucket)
+                .key(&marker)
+                .body(ByteStream::from_static(b""))
+                .send()
+                .await
+                .map(|_| ())
+                .map_err(|err| s3_error(&marker, &err))
+        })
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    fn list_dir(&self, path: Option<String>, max_entries: usize) -> Result<Vec<FileEntry>, Error> {
+        let prefix = Self::dir_prefix(&self.object_key(&path.clone().unwrap_or_default())?);
+        let (client, bucket) = (self.client.clone(), self.bucket.clone());
+
+        let (directories, files) = block_on({
+            let prefix = prefix.clone();
+            async move {
+                let mut directories = Vec::new();
+                let mut files = Vec::new();
+                let mut continuation_token = None;
+                loop {
+                    let res = client
+                        .list_objects_v2()
+                        .bucket(&bucket)
+                        .prefix(&prefix)
+                        .delimiter("/")
+                        .set_continuation_token(continuation_token)
+                        .send()
+                        .await
+                        .map_err(|err| s3_error(&prefix, &err))?;
+                    directories.extend(
+                        res.common_prefixes()
+                            .iter()
+                            .filter_map(|common| common.prefix().map(str::to_string)),
+                    );
+                    files.extend(res.contents().iter().filter_map(|object| {
+                        object.key().map(|key| {
+                            (
+                                key.to_string(),
+                                object.size().map(|size| size.max(0) as u64),
+                                object
+                                    .last_modified()
+                                    .and_then(|modified| SystemTime::try_from(*modified).ok()),
+                            )
+                        })
+                    }));
+                    check_list_entries(directories.len() + files.len(), max_entries)?;
+                    match res.next_continuation_token() {
+                        Some(token) => continuation_token = Some(token.to_string()),
+                        None => break,
+                    }
+                }
+                Ok((directories, files))
+            }
+        })?;
+
+        if !prefix.is_empty() && directories.is_empty() && files.is_empty() {
+            return Err(Error::BadRequest(format!(
+                "{} is not a directory",
+                path.unwrap_or_default()
+            )));
+        }
+
+        let entry = |object_key: &str,
+                     filetype: FileType,
+                     size: Option<u64>,
+                     modified: Option<SystemTime>| {
+            let filename = object_key
+                .strip_prefix(&prefix)
+                .unwrap_or(object_key)
+                .trim_end_matches('/')
+                .to_string();
+            let fullpath = match &path {
+                Some(p) => format!("{p}/{filename}"),
+                None => filename.clone(),
+            };
+            FileEntry {
+                filename,
+                fullpath,
+                filetype,
+                size,
+                modified,
+                dir_usage: None,
+            }
+        };
+
+        Ok(directories
+            .iter()
+            .map(|object_key| entry(object_key, FileType::Directory, None, None))
+            .chain(
+                files
+                    .iter()
+                    // skip the directory's own marker object
+                    .filter(|(object_key, _, _)| *object_key != prefix)
+                    .map(|(object_key, size, modified)| {
+                        entry(object_key, FileType::File, *size, *modified)
+                    }),
+            )
+            .collect())
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    fn is_file(&self, key: &str) -> bool {
+        self.object_key(key)
+            .and_then(|object_key| self.object_exists(&object_key))
+            .unwrap_or(false)
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    fn is_dir(&self, key: &str) -> bool {
+        self.object_key(key)
+            .and_then(|object_key| self.prefix_exists(&object_key))
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::FileKidFsType;
+    use axum::extract::{Path, Query, State};
+    use axum::http::{Method, StatusCode};
+    use axum::response::IntoResponse;
+    use axum::routing::any;
+    use axum::Router;
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::{Arc, Mutex};
+
+    fn test_serverpath() -> ServerPath {
+        ServerPath {
+            type_: FileKidFsType::S3,
+            bucket: Some("filekid".to_string()),
+            // nothing listens here, so requests fail fast
+            endpoint: Some("http://127.0.0.1:1".to_string()),
+            access_key_id: Some("access".to_string()),
+            secret_access_key: Some("secret".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_new() {
+        let fs = S3Fs::new(&test_serverpath()).expect("Failed to build S3 backend");
+        assert_eq!(fs.name(), "s3 (filekid)");
+        assert!(!format!("{fs:?}").contains("secret"));
+
+        let mut no_bucket = test_serverpath();
+        no_bucket.bucket = None;
+        assert!(matches!(
+            S3Fs::new(&no_bucket),
+            Err(Error::Configuration(_))
+        ));
+
+        let mut half_credentials = test_serverpath();
+        half_credentials.secret_access_key = None;
+        assert!(matches!(
+            S3Fs::new(&half_credentials),
+            Err(Error::Configuration(_))
+        ));
+    }
+
+    #[test]
+    fn test_object_key() {
+        let fs = S3Fs::new(&test_serverpath()).expect("Failed to build S3 backend");
+        assert_eq!(fs.object_key("/foo//bar/").expect("Failed"), "foo/bar");
+        assert_eq!(fs.object_key("./foo").expect("Failed"), "foo");
+        assert_eq!(fs.object_key("").expect("Failed"), "");
+        assert!(matches!(
+            fs.object_key("../foo"),
+            Err(Error::NotAuthorized(_))
+        ));
+        assert!(matches!(
+            fs.delete_file("foo/../../bar"),
+            Err(Error::NotAuthorized(_))
+        ));
+        assert_eq!(S3Fs::dir_prefix(""), "");
+        assert_eq!(S3Fs::dir_prefix("foo"), "foo/");
+    }
+
+    #[test]
+    fn test_encode_copy_source() {
+        assert_eq!(
+            encode_copy_source("bucket", "dir/hello world+1.txt"),
+            "bucket/dir/hello%20world%2B1.txt"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_bucket_is_unavailable() {
+        let fs = S3Fs::new(&test_serverpath()).expect("Failed to build S3 backend");
+        assert!(!fs.available().await.expect("available() shouldn't error"));
+        assert!(!fs.is_file("foo.txt"));
+    }
+
+    #[tokio::test]
+    async fn test_slow_bucket_is_unavailable() {
+        // accepts connections but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind");
+        let addr = listener.local_addr().expect("Failed to get address");
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                held.push(stream);
+            }
+        });
+        let fs = S3Fs::new(&ServerPath {
+            endpoint: Some(format!("http://{addr}")),
+            ..test_serverpath()
+        })
+        .expect("Failed to build S3 backend");
+
+        let started = std::time::Instant::now();
+        assert!(
+            !crate::fs::check_available(&fs, std::time::Duration::from_millis(200))
+                .await
+                .expect("check_available() shouldn't error")
+        );
+        assert!(started.elapsed() < std::time::Duration::from_secs(2));
+    }
+
+    type Parts = BTreeMap<u32, Vec<u8>>;
+
+    /// Just enough of the S3 API to check multipart uploads, objects are stored by key
+    #[derive(Clone, Default)]
+    struct FakeS3 {
+        objects: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+        /// upload ID -> part number -> part
+        uploads: Arc<Mutex<HashMap<String, Parts>>>,
+        aborted: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl FakeS3 {
+        async fn handle(
+            State(fake): State<FakeS3>,
+            method: Method,
+            Path((_bucket, key)): Path<(String, String)>,
+            Query(query): Query<HashMap<String, String>>,
+            body: Bytes,
+        ) -> axum::response::Response {
+            let upload_id = query.get("uploadId").cloned();
+            match (method, upload_id) {
+                (Method::POST, None) if query.contains_key("uploads") => {
+                    let upload_id = format!("upload-{key}");
+                    fake.uploads
+                        .lock()
+                        .expect("poisoned")
+                        .insert(upload_id.clone(), BTreeMap::new());
+                    format!(
+                        "<InitiateMultipartUploadResult><Bucket>filekid</Bucket><Key>{key}</Key><UploadId>{upload_id}</UploadId></InitiateMultipartUploadResult>"
+                    )
+                    .into_response()
+                }
+                (Method::PUT, Some(upload_id)) => {
+                    let part_number: u32 = query
+                        .get("partNumber")
+                        .and_then(|part| part.parse().ok())
+                        .expect("no part number");
+                    fake.uploads
+                        .lock()
+                        .expect("poisoned")
+                        .get_mut(&upload_id)
+                        .expect("unknown upload")
+                        .insert(part_number, body.to_vec());
+                    ([("ETag", format!("\"part{part_number}\""))], "").into_response()
+                }
+                (Method::POST, Some(upload_id)) => {
+                    let parts = fake
+                        .uploads
+                        .lock()
+                        .expect("poisoned")
+                        .remove(&upload_id)
+                        .expect("unknown upload");
+                    fake.objects
+                        .lock()
+                        .expect("poisoned")
+                        .insert(key.clone(), parts.into_values().flatten().collect());
+                    format!(
+                        "<CompleteMultipartUploadResult><Bucket>filekid</Bucket><Key>{key}</Key><ETag>\"done\"</ETag></CompleteMultipartUploadResult>"
+                    )
+                    .into_response()
+                }
+                (Method::DELETE, Some(upload_id)) => {
+                    fake.uploads.lock().expect("poisoned").remove(&upload_id);
+                    fake.aborted.lock().expect("poisoned").push(upload_id);
+                    StatusCode::NO_CONTENT.into_response()
+                }
+                (Method::GET | Method::HEAD, None) => {
+                    match fake.objects.lock().expect("poisoned").get(&key) {
+                        Some(object) => object.clone().into_response(),
+                        None => (
+                            StatusCode::NOT_FOUND,
+                            "<Error><Code>NoSuchKey</Code><Message>Not found</Message></Error>",
+                        )
+                            .into_response(),
+                    }
+                }
+                (Method::PUT, None) => {
+                    fake.objects
+                        .lock()
+                        .expect("poisoned")
+                        .insert(key, body.to_vec());
+                    ([("ETag", "\"object\"")], "").into_response()
+                }
+                _ => StatusCode::NOT_IMPLEMENTED.into_response(),
+            }
+        }
+
+        /// Starts the fake on a random port, returning a server path pointing at it
+        async fn start(&self) -> ServerPath {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+                .await
+                .expect("Failed to bind");
+            let addr = listener.local_addr().expect("Failed to get address");
+            let app = Router::new()
+                .route("/{bucket}/{*key}", any(Self::handle))
+                .layer(axum::extract::DefaultBodyLimit::disable())
+                .with_state(self.clone());
+            tokio::spawn(async move { axum::serve(listener, app).await });
+
+            ServerPath {
+                endpoint: Some(format!("http://{addr}")),
+                ..test_serverpath()
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_stream_put_file_multipart() {
+        let fake = FakeS3::default();
+        let fs = S3Fs::new(&fake.start().await).expect("Failed to build S3 backend");
+
+        // two full parts and a bit, in awkwardly sized chunks
+        let data: Vec<u8> = (0..(MULTIPART_PART_SIZE * 2 + 1234))
+            .map(|index| (index % 251) as u8)
+            .collect();
+        let chunks: Vec<Result<Bytes, std::io::Error>> = data
+            .chunks(1024 * 1024 - 7)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        let written = fs
+            .stream_put_file(
+                "dir/big.bin",
+                futures::stream::iter(chunks).boxed(),
+                Some(data.len() as u64),
+                true,
+            )
+            .await
+            .expect("Failed to stream upload");
+        assert_eq!(written, data.len() as u64);
+        assert_eq!(
+            fake.objects.lock().expect("poisoned").get("dir/big.bin"),
+            Some(&data)
+        );
+        assert!(fake.uploads.lock().expect("poisoned").is_empty());
+
+        // small uploads skip the multipart dance
+        fs.stream_put_file(
+            "small.txt",
+            futures::stream::iter(vec![Ok(Bytes::from_static(b"hello"))]).boxed(),
+            None,
+            false,
+        )
+        .await
+        .expect("Failed to stream upload");
+        assert_eq!(
+            fake.objects.lock().expect("poisoned").get("small.txt"),
+            Some(&b"hello".to_vec())
+        );
+        assert!(fake.aborted.lock().expect("poisoned").is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_read_file_round_trip() {
+        let fake = FakeS3::default();
+        let fs = S3Fs::new(&fake.start().await).expect("Failed to build S3 backend");
+
+        fs.put_file("dir/hello.txt", b"hello world")
+            .await
+            .expect("Failed to put file");
+        let body = fs
+            .read_file("dir/hello.txt")
+            .await
+            .expect("Failed to read file");
+        let contents = axum::body::to_bytes(body, usize::MAX)
+            .await
+            .expect("Failed to read body");
+        assert_eq!(contents, Bytes::from_static(b"hello world"));
+
+        assert!(matches!(
+            fs.read_file("dir/nope.txt").await,
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_sync_calls_on_a_runtime_worker() {
+        // the fake runs on this runtime's only worker, so it has to keep going while the sync calls wait
+        let fake = FakeS3::default();
+        let fs = S3Fs::new(&fake.start().await).expect("Failed to build S3 backend");
+
+        fs.put_file("hello.txt", b"hello world")
+            .await
+            .expect("Failed to put file");
+        assert!(fs.is_file("hello.txt"));
+        assert!(!fs.is_file("nope.txt"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_stream_put_file_aborts() {
+        let fake = FakeS3::default();
+        let fs = S3Fs::new(&fake.start().await).expect("Failed to build S3 backend");
+
+        // the client goes away after the first part's been sent
+        let chunks: Vec<Result<Bytes, std::io::Error>> = vec![
+            Ok(Bytes::from(vec![0u8; MULTIPART_PART_SIZE + 10])),
+            Err(std::io::Error::other("connection reset")),
+        ];
+        assert!(fs
+            .stream_put_file(
+                "broken.bin",
+                futures::stream::iter(chunks).boxed(),
+                None,
+                false
+            )
+            .await
+            .is_err());
+        assert_eq!(
+            *fake.aborted.lock().expect("poisoned"),
+            vec!["upload-broken.bin".to_string()]
+        );
+        assert!(fake.uploads.lock().expect("poisoned").is_empty());
+        assert!(!fake
+            .objects
+            .lock()
+            .expect("poisoned")
+            .contains_key("broken.bin"));
+
+        // too long for the declared length
+        let chunks: Vec<Result<Bytes, std::io::Error>> =
+            vec![Ok(Bytes::from(vec![0u8; MULTIPART_PART_SIZE * 2]))];
+        assert!(matches!(
+            fs.stream_put_file(
+                "long.bin",
+                futures::stream::iter(chunks).boxed(),
+                Some(10),
+                false
+            )
+            .await,
+            Err(Error::BadRequest(_))
+        ));
+        assert!(!fake
+            .objects
+            .lock()
+            .expect("poisoned")
+            .contains_key("long.bin"));
+    }
+}
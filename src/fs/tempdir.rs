@@ -1,27 +1,66 @@
 //! Tempdir module, only works while the instance is up
 
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::time::Instant;
 
+use axum::body::Bytes;
 use tracing::*;
 
+use crate::config::Config;
 use crate::error::Error;
+use crate::fs::FileKidFsType;
 use crate::views::browse::FileEntry;
 
-use super::FileKidFs;
+use super::{
+    check_list_entries, check_not_same_file, checksum_file, dir_usage_walk, disk_space_for,
+    is_hidden, key_escapes_base, list_dir_no_symlinks, remove_dir_within, resolve_download_path,
+    resolves_within, search_walk, stream_to_file, strip_executable_bits, total_size_walk,
+    write_atomically, write_range_to_disk, ChecksumAlgo, DirUsage, DiskSpace, FileKidFs,
+    UploadStream,
+};
 
 #[derive(Debug)]
-pub(crate) struct TempDir(PathBuf);
+pub(crate) struct TempDir {
+    path: PathBuf,
+    follow_symlinks: bool,
+    show_hidden: bool,
+}
 
 impl TempDir {
     pub fn new(path: PathBuf) -> Self {
-        Self(path)
+        Self {
+            path,
+            follow_symlinks: true,
+            show_hidden: false,
+        }
+    }
+
+    /// Whether downloads follow symlinks, see [crate::ServerPath::download_follow_symlinks]
+    pub fn with_follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    /// Whether listings include dotfiles, see [crate::ServerPath::show_hidden]
+    pub fn with_show_hidden(mut self, show_hidden: bool) -> Self {
+        self.show_hidden = show_hidden;
+        self
     }
 
-    /// Ensure that the thing we're looking at is in a "safe" path
+    /// Ensure that the thing we're looking at is in a "safe" path, both as written and after following symlinks
     #[instrument(level = "debug", skip(self))]
     fn is_in_basepath(&self, key: &str) -> Result<bool, Error> {
+        if key_escapes_base(key) {
+            debug!("key {} escapes the base path", key);
+            return Ok(false);
+        }
+        if !resolves_within(&self.path, &self.target_path_from_key(key)) {
+            debug!("key {} resolves outside the base path", key);
+            return Ok(false);
+        }
         Ok(self.target_path_from_key(key).ancestors().any(|path| {
-            if path == self.0 {
+            if path == self.path {
                 debug!(
                     "filename: {} matches parent path {} (key={})",
                     key,
@@ -35,18 +74,106 @@ impl TempDir {
     }
 }
 
+/// Keeps the directories backing `tempdir` server paths alive, creating and cleaning them up as the config changes.
+#[derive(Debug, Default)]
+pub struct LiveTempDirs(HashMap<String, tempfile::TempDir>);
+
+impl LiveTempDirs {
+    /// Brings the live tempdirs in line with `config`, filling in the path of each tempdir server path.
+    ///
+    /// `previous` is the configuration being replaced (if any), so changes in a server path's type can be reported.
+    pub fn sync(&mut self, config: &mut Config, previous: Option<&Config>) -> Result<(), Error> {
+        for (server, server_config) in config.server_paths.iter_mut() {
+            if let Some(previous_config) =
+                previous.and_then(|previous| previous.server_paths.get(server))
+                && previous_config.type_ != server_config.type_
+            {
+                info!(
+                    "Server path {} changed type from {:?} to {:?}",
+                    server, previous_config.type_, server_config.type_
+                );
+            }
+
+            match server_config.type_ {
+                FileKidFsType::TempDir => {
+                    let tempdir = match self.0.remove(server) {
+                        Some(tempdir) => tempdir,
+                        None => {
+                            let tempdir = tempfile::tempdir()?;
+                            info!(
+                                "Created tempdir {} for server path {}",
+                                tempdir.path().display(),
+                                server
+                            );
+                            tempdir
+                        }
+                    };
+                    server_config.path = Some(tempdir.path().to_path_buf());
+                    self.0.insert(server.clone(), tempdir);
+                }
+                _ => {
+                    if let Some(tempdir) = self.0.remove(server) {
+                        info!(
+                            "Removing tempdir {} as server path {} is now {:?}",
+                            tempdir.path().display(),
+                            server,
+                            server_config.type_
+                        );
+                        tempdir.close()?;
+                    }
+                }
+            }
+        }
+
+        let removed: Vec<String> = self
+            .0
+            .keys()
+            .filter(|server| !config.server_paths.contains_key(*server))
+            .cloned()
+            .collect();
+        for server in removed {
+            if let Some(tempdir) = self.0.remove(&server) {
+                info!(
+                    "Removing tempdir {} as server path {} no longer exists",
+                    tempdir.path().display(),
+                    server
+                );
+                tempdir.close()?;
+            }
+        }
+        Ok(())
+    }
+}
+
 #[async_trait::async_trait]
 impl FileKidFs for TempDir {
+    fn has_stream_put_file(&self) -> bool {
+        true
+    }
+
     fn name(&self) -> String {
-        format!("tempdir ({})", self.0.display())
+        format!("tempdir ({})", self.path.display())
     }
 
     fn target_path_from_key(&self, key: &str) -> PathBuf {
-        self.0.join(key)
+        self.path.join(key)
     }
 
-    fn available(&self) -> Result<bool, crate::error::Error> {
-        Ok(self.0.exists())
+    fn strip_executable(&self, filepath: &str) -> Result<(), Error> {
+        if !self.is_in_basepath(filepath)? {
+            return Err(Error::NotAuthorized(format!(
+                "Path {filepath} is outside of parent path"
+            )));
+        }
+        strip_executable_bits(&self.target_path_from_key(filepath))
+    }
+
+    async fn available(&self) -> Result<bool, crate::error::Error> {
+        Ok(self.path.exists())
+    }
+
+    fn disk_space(&self) -> Result<Option<DiskSpace>, Error> {
+        disk_space_for(&self.path).map(Some)
     }
 
     #[instrument(level = "debug", skip(self))]
@@ -66,16 +193,22 @@ impl FileKidFs for TempDir {
         debug!(
             "Checking if {} is in base path {}",
             target.display(),
-            self.0.display()
+            self.path.display()
         );
 
-        self.is_in_basepath(path)?;
+        if !self.is_in_basepath(path)? {
+            return Err(Error::NotAuthorized(format!(
+                "Path '{path}' is outside of base path"
+            )));
+        }
 
         if let Some(filename) = target.file_name() {
+            let metadata = target.metadata()?;
             Ok(super::FileData {
                 filename: filename.to_string_lossy().to_string(),
-                filepath: target.parent().unwrap_or(&self.0).to_path_buf(),
-                size: Some(target.metadata()?.len()),
+                filepath: target.parent().unwrap_or(&self.path).to_path_buf(),
+                size: Some(metadata.len()),
+                modified: metadata.modified().ok(),
             })
         } else {
             Err(crate::error::Error::Generic(
@@ -91,12 +224,44 @@ impl FileKidFs for TempDir {
             )));
         }
 
-        Ok(tokio::fs::read(&self.target_path_from_key(filepath)).await?)
+        let target_path = resolve_download_path(
+            &self.path,
+            &self.target_path_from_key(filepath),
+            self.follow_symlinks,
+        )?;
+        Ok(tokio::fs::read(target_path).await?)
     }
 
     #[instrument(level = "debug", skip(self))]
-    async fn read_file(&self, _filepath: &str) -> Result<axum::body::Body, Error> {
-        todo!("read_file hasn't beem implemented for TempDir yet");
+    async fn read_file(&self, filepath: &str) -> Result<axum::body::Body, Error> {
+        if !self.is_in_basepath(filepath)? {
+            return Err(Error::NotAuthorized(format!(
+                "Path '{filepath}' is outside of base path"
+            )));
+        }
+        let target_path = resolve_download_path(
+            &self.path,
+            &self.target_path_from_key(filepath),
+            self.follow_symlinks,
+        )?;
+        let file = tokio::fs::File::open(target_path).await?;
+        Ok(axum::body::Body::from_stream(
+            tokio_util::io::ReaderStream::new(file),
+        ))
+    }
+
+    fn local_path(&self, filepath: &str) -> Result<Option<PathBuf>, Error> {
+        if !self.is_in_basepath(filepath)? {
+            return Err(Error::NotAuthorized(format!(
+                "Path '{filepath}' is outside of base path"
+            )));
+        }
+        resolve_download_path(
+            &self.path,
+            &self.target_path_from_key(filepath),
+            self.follow_symlinks,
+        )
+        .map(Some)
     }
 
     #[instrument(level = "debug", skip(self, contents))]
@@ -104,8 +269,7 @@ impl FileKidFs for TempDir {
         if self.is_in_basepath(filepath)? {
             let target_path = self.target_path_from_key(filepath);
             debug!("Writing to '{}'", target_path.display());
-            tokio::fs::write(target_path, contents).await?;
-            Ok(())
+            write_atomically(&target_path, contents).await
         } else {
             Err(crate::error::Error::NotAuthorized(format!(
                 "Path {filepath} is outside of parent path"
@@ -113,19 +277,204 @@ impl FileKidFs for TempDir {
         }
     }
 
+    #[instrument(level = "debug", skip(self, stream))]
+    async fn stream_put_file<'s>(
+        &self,
+        filepath: &str,
+        stream: UploadStream<'s>,
+        declared_len: Option<u64>,
+        strict: bool,
+    ) -> Result<u64, Error> {
+        if !self.is_in_basepath(filepath)? {
+            return Err(Error::NotAuthorized(format!(
+                "Path {filepath} is outside of parent path"
+            )));
+        }
+        stream_to_file(
+            &self.target_path_from_key(filepath),
+            stream,
+            declared_len,
+            strict,
+        )
+        .await
+    }
+
+    #[instrument(level = "debug", skip(self, contents))]
+    async fn put_file_range(
+        &self,
+        filepath: &str,
+        offset: u64,
+        total: u64,
+        contents: Bytes,
+        overwrite: bool,
+    ) -> Result<bool, Error> {
+        if !self.is_in_basepath(filepath)? {
+            return Err(Error::NotAuthorized(format!(
+                "Path {filepath} is outside of parent path"
+            )));
+        }
+        let target_path = self.target_path_from_key(filepath);
+        if target_path.exists() && !overwrite {
+            return Err(Error::BadRequest(format!("{filepath} already exists")));
+        }
+        write_range_to_disk(&target_path, offset, total, contents).await
+    }
+
     #[instrument(level = "debug", skip(self))]
     fn delete_file(&self, filepath: &str) -> Result<(), crate::error::Error> {
-        todo!("tempdir delete file functionality")
+        if !self.is_in_basepath(filepath)? {
+            return Err(Error::NotAuthorized(format!(
+                "Path '{filepath}' is outside of base path"
+            )));
+        }
+        let target_path = self.target_path_from_key(filepath);
+        debug!("Deleting {}", target_path.display());
+        std::fs::remove_file(target_path).map_err(Error::from)
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    fn delete_dir(&self, path: &str, recursive: bool) -> Result<(), Error> {
+        if !self.is_in_basepath(path)? {
+            return Err(Error::NotAuthorized(format!(
+                "Path '{path}' is outside of base path"
+            )));
+        }
+        remove_dir_within(
+            &self.path,
+            &self.target_path_from_key(path),
+            path,
+            recursive,
+        )
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    fn checksum(&self, filepath: &str, algorithm: ChecksumAlgo) -> Result<String, Error> {
+        if !self.is_in_basepath(filepath)? {
+            return Err(Error::NotAuthorized(format!(
+                "Path '{filepath}' is outside of base path"
+            )));
+        }
+        checksum_file(
+            &resolve_download_path(
+                &self.path,
+                &self.target_path_from_key(filepath),
+                self.follow_symlinks,
+            )?,
+            algorithm,
+        )
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    fn move_file(&self, from: &str, to: &str, overwrite: bool) -> Result<(), Error> {
+        for key in [from, to] {
+            if !self.is_in_basepath(key)? {
+                return Err(Error::NotAuthorized(format!(
+                    "Path '{key}' is outside of base path"
+                )));
+            }
+        }
+        let source = self.target_path_from_key(from);
+        let destination = self.target_path_from_key(to);
+        if !source.exists() {
+            return Err(Error::NotFound(from.to_string()));
+        }
+        if destination.exists() && !overwrite {
+            return Err(Error::BadRequest(format!("{to} already exists")));
+        }
+        debug!("Moving {} to {}", source.display(), destination.display());
+        std::fs::rename(source, destination).map_err(Error::from)
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    fn copy_file(&self, from: &str, to: &str, overwrite: bool) -> Result<(), Error> {
+        for key in [from, to] {
+            if !self.is_in_basepath(key)? {
+                return Err(Error::NotAuthorized(format!(
+                    "Path '{key}' is outside of base path"
+                )));
+            }
+        }
+        let source = self.target_path_from_key(from);
+        let destination = self.target_path_from_key(to);
+        if !source.is_file() {
+            return Err(Error::NotFound(from.to_string()));
+        }
+        if destination.exists() && !overwrite {
+            return Err(Error::BadRequest("destination exists".to_string()));
+        }
+        check_not_same_file(&source, &destination)?;
+        debug!("Copying {} to {}", source.display(), destination.display());
+        std::fs::copy(source, destination)?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    fn create_dir(&self, path: &str) -> Result<(), Error> {
+        if !self.is_in_basepath(path)? {
+            return Err(Error::NotAuthorized(format!(
+                "Path '{path}' is outside of base path"
+            )));
+        }
+        let target = self.target_path_from_key(path);
+        if target.exists() {
+            return Err(Error::BadRequest(format!("{path} already exists")));
+        }
+        if !target
+            .parent()
+            .map(|parent| parent.is_dir())
+            .unwrap_or(false)
+        {
+            return Err(Error::NotFound(format!("Parent directory of {path}")));
+        }
+        debug!("Creating directory {}", target.display());
+        std::fs::create_dir(target).map_err(Error::from)
+    }
+
+    /// Doesn't follow symlinks, so the walk can't wander outside the base path
+    #[instrument(level = "debug", skip(self))]
+    fn search(
+        &self,
+        root: Option<String>,
+        query: &str,
+        max_results: usize,
+    ) -> Result<Vec<FileEntry>, Error> {
+        if let Some(root) = &root {
+            if !self.is_in_basepath(root)? {
+                return Err(Error::NotAuthorized(
+                    "Path is outside of base path".to_string(),
+                ));
+            }
+            resolve_download_path(&self.path, &self.target_path_from_key(root), false)?;
+        }
+        search_walk(root, query, max_results, |key| {
+            list_dir_no_symlinks(&self.path, key)
+        })
+    }
+
+    fn total_size(&self) -> Result<u64, Error> {
+        total_size_walk(|key| list_dir_no_symlinks(&self.path, key))
+    }
+
+    fn dir_usage(
+        &self,
+        key: &str,
+        max_entries: usize,
+        deadline: Instant,
+    ) -> Result<DirUsage, Error> {
+        dir_usage_walk(key, max_entries, deadline, |key| {
+            list_dir_no_symlinks(&self.path, key)
+        })
     }
 
     #[instrument(level = "debug", skip(self))]
     fn list_dir(
         &self,
         path: Option<String>,
+        max_entries: usize,
     ) -> Result<Vec<crate::views::browse::FileEntry>, Error> {
         let path_addition = path.unwrap_or_default();
 
-        let target_path = self.0.join(&path_addition);
+        let target_path = self.path.join(&path_addition);
         if !target_path.is_dir() {
             return Err(Error::BadRequest(format!(
                 "{path_addition} is not a directory"
@@ -138,22 +487,29 @@ impl FileKidFs for TempDir {
         if let Ok(readdir) = target_path.read_dir() {
             for direntry in readdir {
                 let direntry = direntry.map_err(Error::from)?;
+                if !self.show_hidden && is_hidden(&direntry.file_name()) {
+                    continue;
+                }
                 let mut fileentry = FileEntry::try_from(direntry)?;
                 fileentry.fullpath = format!("{path_addition}/{}", fileentry.filename)
                     .trim_start_matches("/")
                     .to_string();
                 res.push(fileentry);
+                check_list_entries(res.len(), max_entries)?;
             }
         }
 
         Ok(res)
     }
 
-    fn is_file(&self, _key: &str) -> bool {
-        todo!()
+    #[instrument(level = "debug", skip(self))]
+    fn is_file(&self, key: &str) -> bool {
+        self.is_in_basepath(key).unwrap_or(false) && self.target_path_from_key(key).is_file()
     }
-    fn is_dir(&self, _key: &str) -> bool {
-        todo!()
+
+    #[instrument(level = "debug", skip(self))]
+    fn is_dir(&self, key: &str) -> bool {
+        self.is_in_basepath(key).unwrap_or(false) && self.target_path_from_key(key).is_dir()
     }
 }
 
@@ -165,8 +521,9 @@ mod tests {
     use tokio::io::AsyncWriteExt;
 
     use super::*;
+    use crate::constants::DEFAULT_MAX_LIST_ENTRIES;
     use crate::fs::FileKidFs;
-    use crate::log::setup_logging;
+    use crate::log::{setup_logging, LogFormat};
     use crate::views::FileType;
 
     #[test]
@@ -185,11 +542,11 @@ mod tests {
 
         assert!(fs.name().contains(&temp_dir_path.display().to_string()));
 
-        assert!(fs.available().expect("Isn't available!"));
+        assert!(fs.available().await.expect("Isn't available!"));
     }
     #[tokio::test]
     async fn test_list_dir() {
-        let _ = setup_logging(true, true);
+        let _ = setup_logging(true, true, LogFormat::Pretty);
 
         let temp_dir = tempdir().expect("Failed to create temp dir");
         let temp_dir_path = temp_dir.path().to_path_buf();
@@ -203,18 +560,20 @@ mod tests {
 
         let fs = TempDir::new(temp_dir_path);
 
-        let entries = fs.list_dir(None).expect("Failed to list dir");
+        let entries = fs
+            .list_dir(None, DEFAULT_MAX_LIST_ENTRIES)
+            .expect("Failed to list dir");
         assert_eq!(entries.len(), 1);
         assert_eq!(entries[0].filename, "test.txt");
         assert_eq!(entries[0].fullpath, "test.txt");
         assert_eq!(entries[0].filetype, FileType::File);
 
-        let bad_test = fs.list_dir(Some("test.txt".to_string()));
+        let bad_test = fs.list_dir(Some("test.txt".to_string()), DEFAULT_MAX_LIST_ENTRIES);
         dbg!(&bad_test);
         assert!(bad_test.is_err());
 
         let entries = fs
-            .list_dir(Some(".".to_string()))
+            .list_dir(Some(".".to_string()), DEFAULT_MAX_LIST_ENTRIES)
             .expect("Failed to list dir");
         assert_eq!(entries.len(), 1);
         assert_eq!(entries[0].filename, "test.txt");
@@ -222,13 +581,42 @@ mod tests {
         assert_eq!(entries[0].filetype, FileType::File);
     }
 
+    #[test]
+    fn test_list_dir_hidden() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("visible.txt"), b"hello").expect("Failed to write");
+        std::fs::write(temp_dir.path().join(".hidden"), b"hello").expect("Failed to write");
+        std::fs::create_dir(temp_dir.path().join(".git")).expect("Failed to create dir");
+
+        let filenames = |fs: &TempDir| {
+            let mut filenames: Vec<String> = fs
+                .list_dir(None, DEFAULT_MAX_LIST_ENTRIES)
+                .expect("Failed to list dir")
+                .into_iter()
+                .map(|entry| entry.filename)
+                .collect();
+            filenames.sort();
+            filenames
+        };
+
+        let fs = TempDir::new(temp_dir.path().to_path_buf());
+        assert_eq!(filenames(&fs), vec!["visible.txt"]);
+        assert!(fs.list_dir(None, 1).is_ok());
+
+        let fs = fs.with_show_hidden(true);
+        assert_eq!(filenames(&fs), vec![".git", ".hidden", "visible.txt"]);
+        assert!(!filenames(&fs)
+            .iter()
+            .any(|name| name == "." || name == ".."));
+    }
+
     #[test]
     fn test_get_data() {
         use super::*;
 
         use tempfile::tempdir;
 
-        let _ = setup_logging(true, true);
+        let _ = setup_logging(true, true, LogFormat::Pretty);
 
         let temp_dir = tempdir().expect("Failed to create temp dir");
         let temp_dir_path = temp_dir.path().to_path_buf();
@@ -245,7 +633,7 @@ mod tests {
         use std::io::Write;
         use tempfile::tempdir;
 
-        let _ = setup_logging(true, true);
+        let _ = setup_logging(true, true, LogFormat::Pretty);
 
         let temp_dir = tempdir().expect("Failed to create temp dir");
         let temp_dir_path = temp_dir.path().to_path_buf();
@@ -269,7 +657,7 @@ mod tests {
         use super::*;
         use tempfile::tempdir;
 
-        let _ = setup_logging(true, true);
+        let _ = setup_logging(true, true, LogFormat::Pretty);
 
         let temp_dir = tempdir().expect("Failed to create temp dir");
         let temp_dir_path = temp_dir.path().to_path_buf();
@@ -306,4 +694,206 @@ mod tests {
 
         assert!(outside_res.is_err());
     }
+
+    #[test]
+    fn test_is_file_is_dir() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("test.txt"), b"Hello, world!")
+            .expect("Failed to write test file");
+        std::fs::create_dir(temp_dir.path().join("subdir")).expect("Failed to create subdir");
+
+        let fs = TempDir::new(temp_dir.path().to_path_buf());
+
+        assert!(fs.is_file("test.txt"));
+        assert!(!fs.is_dir("test.txt"));
+
+        assert!(fs.is_dir("subdir"));
+        assert!(!fs.is_file("subdir"));
+
+        assert!(!fs.is_file("thiscannotexist.foo"));
+        assert!(!fs.is_dir("thiscannotexist.foo"));
+
+        // the parent of the tempdir definitely exists, but it's outside the base path
+        assert!(!fs.is_dir(".."));
+        assert!(!fs.is_dir("subdir/../.."));
+        assert!(!fs.is_file("../../../etc/passwd"));
+    }
+
+    #[test]
+    fn test_move_file() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("test.txt"), b"Hello, world!")
+            .expect("Failed to write test file");
+
+        let fs = TempDir::new(temp_dir.path().to_path_buf());
+
+        fs.move_file("test.txt", "renamed.txt", false)
+            .expect("Failed to move file");
+        assert!(!fs.is_file("test.txt"));
+        assert!(fs.is_file("renamed.txt"));
+
+        std::fs::write(temp_dir.path().join("other.txt"), b"other")
+            .expect("Failed to write test file");
+        assert!(matches!(
+            fs.move_file("renamed.txt", "other.txt", false),
+            Err(Error::BadRequest(_))
+        ));
+        assert!(matches!(
+            fs.move_file("renamed.txt", "../../escaped.txt", false),
+            Err(Error::NotAuthorized(_))
+        ));
+
+        fs.move_file("renamed.txt", "other.txt", true)
+            .expect("Failed to move over the existing file");
+        assert!(!fs.is_file("renamed.txt"));
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("other.txt")).expect("Failed to read file"),
+            b"Hello, world!"
+        );
+    }
+
+    #[test]
+    fn test_delete_file() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("test.txt"), b"Hello, world!")
+            .expect("Failed to write test file");
+
+        let fs = TempDir::new(temp_dir.path().to_path_buf());
+
+        fs.delete_file("test.txt").expect("Failed to delete file");
+        assert!(!fs.is_file("test.txt"));
+        assert!(fs.delete_file("test.txt").is_err());
+        assert!(matches!(
+            fs.delete_file("../../etc/passwd"),
+            Err(Error::NotAuthorized(_))
+        ));
+    }
+
+    #[test]
+    fn test_live_tempdirs_type_change() {
+        let _ = setup_logging(true, true, LogFormat::Pretty);
+
+        let local_dir = tempdir().expect("Failed to create temp dir");
+
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "changing".to_string(),
+            crate::ServerPath {
+                path: None,
+                type_: FileKidFsType::TempDir,
+                ..Default::default()
+            },
+        );
+
+        let mut live_tempdirs = LiveTempDirs::default();
+        live_tempdirs
+            .sync(&mut config, None)
+            .expect("Failed to sync tempdirs");
+        let tempdir_path = config.server_paths["changing"]
+            .path
+            .clone()
+            .expect("Tempdir path wasn't set");
+        assert!(tempdir_path.is_dir());
+
+        // syncing the same config again keeps the same tempdir
+        let mut same_config = Config::test_config();
+        same_config.server_paths = config.server_paths.clone();
+        live_tempdirs
+            .sync(&mut same_config, Some(&config))
+            .expect("Failed to sync tempdirs");
+        assert_eq!(
+            same_config.server_paths["changing"].path,
+            Some(tempdir_path.clone())
+        );
+
+        // now it's changed to a local path, so the tempdir should go away
+        let mut new_config = Config::test_config();
+        new_config.server_paths.insert(
+            "changing".to_string(),
+            crate::ServerPath::test_local(local_dir.path()),
+        );
+        live_tempdirs
+            .sync(&mut new_config, Some(&same_config))
+            .expect("Failed to sync tempdirs");
+        assert!(!tempdir_path.exists());
+
+        let filekidfs = crate::fs::fs_from_serverpath(&new_config.server_paths["changing"])
+            .expect("Failed to build the new backend");
+        assert_eq!(
+            filekidfs.name(),
+            format!("local:{}", local_dir.path().display())
+        );
+
+        // and back to a tempdir gets a fresh one
+        live_tempdirs
+            .sync(&mut config, Some(&new_config))
+            .expect("Failed to sync tempdirs");
+        let new_tempdir_path = config.server_paths["changing"]
+            .path
+            .clone()
+            .expect("Tempdir path wasn't set");
+        assert!(new_tempdir_path.is_dir());
+        assert_ne!(new_tempdir_path, tempdir_path);
+
+        // removing the server path entirely cleans up too
+        live_tempdirs
+            .sync(&mut Config::test_config(), Some(&config))
+            .expect("Failed to sync tempdirs");
+        assert!(!new_tempdir_path.exists());
+    }
+
+    #[test]
+    fn test_copy_file() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("test.txt"), b"Hello, world!")
+            .expect("Failed to write test file");
+
+        let fs = TempDir::new(temp_dir.path().to_path_buf());
+
+        fs.copy_file("test.txt", "copy.txt", false)
+            .expect("Failed to copy file");
+        assert!(fs.is_file("test.txt"));
+        assert!(fs.is_file("copy.txt"));
+
+        assert_eq!(
+            fs.copy_file("test.txt", "copy.txt", false),
+            Err(Error::BadRequest("destination exists".to_string()))
+        );
+        fs.copy_file("test.txt", "copy.txt", true)
+            .expect("Failed to overwrite file");
+        assert_eq!(
+            fs.copy_file("test.txt", "./test.txt", true),
+            Err(Error::BadRequest(
+                "source and destination are the same".to_string()
+            ))
+        );
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("test.txt")).expect("Failed to read file"),
+            b"Hello, world!"
+        );
+
+        assert!(matches!(
+            fs.copy_file("test.txt", "../../escaped.txt", true),
+            Err(Error::NotAuthorized(_))
+        ));
+    }
+
+    #[test]
+    fn test_create_dir() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let fs = TempDir::new(temp_dir.path().to_path_buf());
+
+        fs.create_dir("newdir").expect("Failed to create dir");
+        assert!(fs.is_dir("newdir"));
+
+        assert!(matches!(fs.create_dir("newdir"), Err(Error::BadRequest(_))));
+        assert!(matches!(
+            fs.create_dir("missing/inner"),
+            Err(Error::NotFound(_))
+        ));
+        assert!(matches!(
+            fs.create_dir("../../escaped"),
+            Err(Error::NotAuthorized(_))
+        ));
+    }
 }
@@ -0,0 +1,190 @@
+//! Shutting the server down when nobody's using it
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use tokio::sync::mpsc::Sender;
+use tracing::{debug, error, info};
+
+use crate::web::Urls;
+use crate::{SendableConfig, WebServerControl, WebState};
+
+/// How often to check whether idle shutdown has been turned on, when it's off
+const IDLE_DISABLED_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Tracks when the last request came in
+#[derive(Clone, Debug)]
+pub struct ActivityTracker {
+    started: Instant,
+    /// Milliseconds since `started` of the last request
+    last_request_ms: Arc<AtomicU64>,
+}
+
+impl Default for ActivityTracker {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            last_request_ms: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+impl ActivityTracker {
+    pub fn touch(&self) {
+        self.last_request_ms
+            .store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// How long it's been since the last request
+    pub fn idle_for(&self) -> Duration {
+        self.started.elapsed().saturating_sub(Duration::from_millis(
+            self.last_request_ms.load(Ordering::Relaxed),
+        ))
+    }
+}
+
+/// Middleware that records each request as activity, health checks only count if configured to
+pub(crate) async fn track_activity(
+    State(state): State<WebState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if ![
+        Urls::HealthCheck.as_ref(),
+        Urls::Ready.as_ref(),
+        Urls::Metrics.as_ref(),
+    ]
+    .contains(&request.uri().path())
+        || state
+            .configuration
+            .read()
+            .await
+            .idle_shutdown_count_health_checks
+    {
+        state.activity.touch();
+    }
+    next.run(request).await
+}
+
+/// Sends [WebServerControl::Stop] once there haven't been any requests for `idle_shutdown_secs`
+pub(crate) async fn idle_shutdown_task(
+    configuration: SendableConfig,
+    activity: ActivityTracker,
+    web_tx: Sender<WebServerControl>,
+) {
+    loop {
+        let Some(idle_limit) = configuration
+            .read()
+            .await
+            .idle_shutdown_secs
+            .map(Duration::from_secs)
+        else {
+            tokio::time::sleep(IDLE_DISABLED_CHECK_INTERVAL).await;
+            continue;
+        };
+
+        let idle_for = activity.idle_for();
+        if idle_for >= idle_limit {
+            info!(
+                "No requests for {} seconds, shutting down",
+                idle_for.as_secs()
+            );
+            if let Err(err) = web_tx.send(WebServerControl::Stop).await {
+                error!("Failed to send idle shutdown: {:?}", err);
+            }
+            return;
+        }
+        debug!("Idle for {:?} of {:?}", idle_for, idle_limit);
+        tokio::time::sleep(idle_limit - idle_for).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use tokio::sync::RwLock;
+
+    #[tokio::test]
+    async fn test_idle_shutdown_task() {
+        let mut config = Config::test_config();
+        config.idle_shutdown_secs = Some(1);
+        let activity = ActivityTracker::default();
+        let (web_tx, mut web_rx) = tokio::sync::mpsc::channel(1);
+
+        let started = Instant::now();
+        let _task = tokio::spawn(idle_shutdown_task(
+            Arc::new(RwLock::new(config)),
+            activity.clone(),
+            web_tx,
+        ));
+
+        // keep it busy for a bit
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_millis(400)).await;
+            activity.touch();
+        }
+        assert!(web_rx.try_recv().is_err(), "stopped while active");
+
+        let message = tokio::time::timeout(Duration::from_secs(5), web_rx.recv())
+            .await
+            .expect("Idle shutdown didn't trigger");
+        assert_eq!(message, Some(WebServerControl::Stop));
+        assert!(started.elapsed() >= Duration::from_millis(2200));
+    }
+
+    #[tokio::test]
+    async fn test_track_activity() {
+        use axum::body::Body;
+        use axum::routing::get;
+        use axum::Router;
+        use tower::ServiceExt;
+
+        let state = WebState::test_webstate_with_config(Config::test_config()).await;
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .route(Urls::HealthCheck.as_ref(), get(|| async { "ok" }))
+            .layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                track_activity,
+            ))
+            .with_state(state.clone());
+        let request = |uri: &str| {
+            Request::builder()
+                .uri(uri)
+                .body(Body::empty())
+                .expect("Failed to build request")
+        };
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let _ = app
+            .clone()
+            .oneshot(request(Urls::HealthCheck.as_ref()))
+            .await
+            .expect("Failed to send request");
+        assert!(state.activity.idle_for() >= Duration::from_millis(200));
+
+        let _ = app
+            .clone()
+            .oneshot(request("/"))
+            .await
+            .expect("Failed to send request");
+        assert!(state.activity.idle_for() < Duration::from_millis(200));
+
+        state
+            .configuration
+            .write()
+            .await
+            .idle_shutdown_count_health_checks = true;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let _ = app
+            .oneshot(request(Urls::HealthCheck.as_ref()))
+            .await
+            .expect("Failed to send request");
+        assert!(state.activity.idle_for() < Duration::from_millis(200));
+    }
+}
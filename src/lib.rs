@@ -14,37 +14,449 @@
 #![deny(clippy::unwrap_used)]
 #![forbid(unsafe_code)]
 
+pub mod audit;
 pub mod cli;
 pub mod config;
 pub mod constants;
+pub mod dir_size;
 pub mod error;
 pub mod fs;
+pub mod idle;
 pub mod log;
+pub mod metrics;
 pub mod oidc;
 pub(crate) mod prelude;
 pub(crate) mod session_store;
+pub mod text;
+pub(crate) mod trash;
 pub mod views;
 pub mod web;
 
 #[cfg(test)]
 use axum::extract::State;
 
+use axum::body::Bytes;
 use config::Config;
 use error::Error;
 use fs::FileKidFsType;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
-#[derive(Deserialize, Debug, Clone, Serialize, PartialEq)]
+#[derive(Deserialize, Debug, Clone, Serialize, PartialEq, Default)]
 /// A server path.
 pub struct ServerPath {
-    /// The path on disk, can be relative or absolute.
+    /// The path on disk, can be relative or absolute. A [constants::USERNAME_PLACEHOLDER] in it gives every user their own directory.
     #[serde(default)]
     pub path: Option<PathBuf>,
     #[serde(rename = "type")]
     pub type_: FileKidFsType,
+    /// How deep recursive walks (like the tree API) will go, defaults to [constants::DEFAULT_MAX_BROWSE_DEPTH]
+    #[serde(default)]
+    pub max_browse_depth: Option<usize>,
+    /// Filename patterns (supporting `*` and `?` wildcards) to hide from listings
+    #[serde(default)]
+    pub ignore_patterns: Vec<String>,
+    /// The bucket for `s3` server paths
+    #[serde(default)]
+    pub bucket: Option<String>,
+    /// The region for `s3` server paths, defaults to `us-east-1`
+    #[serde(default)]
+    pub region: Option<String>,
+    /// A custom endpoint URL for `s3` server paths, eg for MinIO
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// If the access key isn't set, `s3` server paths use the `AWS_ACCESS_KEY_ID`/`AWS_SECRET_ACCESS_KEY` environment variables
+    #[serde(default)]
+    pub access_key_id: Option<String>,
+    #[serde(default)]
+    pub secret_access_key: Option<String>,
+    /// Rewrite line endings of text uploads, binaries are left alone
+    #[serde(default)]
+    pub normalize_text_eol: Option<text::Eol>,
+    /// Whether downloads follow symlinks (as long as they stay inside the path), defaults to true
+    #[serde(default)]
+    pub download_follow_symlinks: Option<bool>,
+    /// Only allow browsing and downloads
+    #[serde(default)]
+    pub read_only: bool,
+    /// Heading for the browse page, defaults to the server path's name
+    #[serde(default)]
+    pub title: Option<String>,
+    /// Shown under the heading on the browse page
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Files here never change, so they get strong content-based ETags and can be cached forever
+    #[serde(default)]
+    pub immutable: bool,
+    /// If set, only these HTTP methods (eg `["GET", "HEAD"]`) are served for this path, everything else gets a `405`
+    #[serde(default)]
+    pub allowed_methods: Option<Vec<String>>,
+    /// Uploads from the browse page land in this directory (relative to the base path), wherever the user's browsing
+    #[serde(default)]
+    pub upload_dir: Option<String>,
+    /// Requests for `<dir>/latest` serve the most recently modified file in `<dir>`, handy for CI artifacts
+    #[serde(default)]
+    pub latest_alias: bool,
+    /// Refuse uploads whose filename already exists anywhere else under this path
+    #[serde(default)]
+    pub unique_basenames: bool,
+    /// RFC 3339 timestamp for when this path's content goes away, downloads carry `Deprecation` and `Sunset` headers
+    #[serde(default)]
+    pub deprecation: Option<String>,
+    /// Refuse uploads that look runnable (a `#!` line or an executable extension), and strip execute bits from stored files
+    #[serde(default)]
+    pub reject_executables: bool,
+    /// Show how much this path's storing on the browse page, fully recounted this often (in seconds) and kept up to date by uploads and deletes in between
+    #[serde(default)]
+    pub size_cache_secs: Option<u64>,
+    /// Most bytes this path can store, uploads that would take it over are refused. On per-user paths it's for each user.
+    #[serde(default)]
+    pub quota_bytes: Option<u64>,
+    /// Allow downloading directories as zip files
+    #[serde(default)]
+    pub zip_downloads: bool,
+    /// Text files here are in this encoding (eg `shift_jis`, `latin1`) and get converted to UTF-8 when they're served
+    #[serde(default)]
+    pub text_encoding: Option<String>,
+    /// Largest single file (in bytes) that can be uploaded here, on top of the global `max_upload_mb` request limit
+    #[serde(default)]
+    pub max_file_bytes: Option<u64>,
+    /// Whether files can be deleted, separately from [ServerPath::read_only] so users can add files but not remove them, defaults to true
+    #[serde(default)]
+    pub deletions_allowed: Option<bool>,
+    /// Where browse uploads are stored, relative to the directory they're uploaded to, eg `{date}/{user}/{original}`.
+    ///
+    /// Variables are `{date}` (`YYYY-MM-DD`), `{user}`, `{original}` (the uploaded filename) and `{id}` (random).
+    #[serde(default)]
+    pub upload_name_template: Option<String>,
+    /// Content types to serve by file extension (eg `{"md": "text/plain"}`), checked before guessing from the extension
+    #[serde(default)]
+    pub mime_overrides: HashMap<String, String>,
+    /// Browsing a directory with an `index.html` in it serves that instead of the listing, for hosting static sites
+    #[serde(default)]
+    pub serve_index: bool,
+    /// What the home page calls this server path, the key in `server_paths` is still what goes in URLs
+    #[serde(default)]
+    pub display_name: Option<String>,
+    /// Directory (relative to the base path) that deletes move things into rather than removing them, unless they ask to be permanent
+    #[serde(default)]
+    pub trash_dir: Option<String>,
+    /// List files and directories whose names start with `.` on local and tempdir paths, browse can override it with `?show_hidden=1`
+    #[serde(default)]
+    pub show_hidden: bool,
+    /// Send `<file>.br` or `<file>.gz` instead of the file to clients that accept that encoding, if it's there
+    #[serde(default)]
+    pub prefer_precompressed: bool,
+    /// The internal location (eg `/internal/builds`) nginx serves this path's files from, for [config::Config::sendfile_header].
+    ///
+    /// `X-Accel-Redirect` needs a URI, so the file's key goes on the end of this. Without it the header holds the path on disk, which suits Apache's `X-Sendfile`.
+    #[serde(default)]
+    pub sendfile_prefix: Option<String>,
+}
+
+impl ServerPath {
+    /// Should this filename be hidden from listings?
+    pub fn is_ignored(&self, filename: &str) -> bool {
+        self.ignore_patterns
+            .iter()
+            .any(|pattern| fs::glob_match(pattern, filename))
+    }
+
+    /// Applies [ServerPath::normalize_text_eol] to a whole uploaded file
+    pub(crate) fn normalize_upload(&self, data: Bytes) -> Bytes {
+        match self.normalize_text_eol.and_then(|eol| eol.normalize(&data)) {
+            Some(normalized) => Bytes::from(normalized),
+            None => data,
+        }
+    }
+
+    /// Handlers that change things call this before touching the filesystem
+    pub fn check_writable(&self) -> Result<(), Error> {
+        match self.read_only {
+            true => Err(Error::NotAuthorized("server path is read-only".to_string())),
+            false => Ok(()),
+        }
+    }
+
+    /// Delete handlers call this instead of [ServerPath::check_writable]
+    pub fn check_deletable(&self) -> Result<(), Error> {
+        self.check_writable()?;
+        match self.deletions_allowed() {
+            true => Ok(()),
+            false => Err(Error::NotAuthorized(
+                "deleting files isn't allowed on this server path".to_string(),
+            )),
+        }
+    }
+
+    pub fn deletions_allowed(&self) -> bool {
+        self.deletions_allowed.unwrap_or(true)
+    }
+
+    /// Upload handlers call this with the start of the file when [ServerPath::reject_executables] is set
+    pub fn check_not_executable(&self, filepath: &str, contents: &[u8]) -> Result<(), Error> {
+        if !self.reject_executables {
+            return Ok(());
+        }
+        if contents.starts_with(b"#!") {
+            return Err(Error::BadRequest(format!(
+                "{filepath} looks like a script, uploads starting with #! aren't allowed"
+            )));
+        }
+        let extension = std::path::Path::new(filepath)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        match extension {
+            Some(extension) if constants::EXECUTABLE_EXTENSIONS.contains(&extension.as_str()) => {
+                Err(Error::BadRequest(format!(
+                    "{filepath} has an executable extension, .{extension} files aren't allowed"
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Upload handlers call this with the size of each file once it's been read
+    pub fn check_file_size(&self, filename: &str, size: u64) -> Result<(), Error> {
+        match self.max_file_bytes {
+            Some(max_file_bytes) if size > max_file_bytes => Err(Error::BadRequest(format!(
+                "{filename} is {size} bytes, files here can be at most {max_file_bytes} bytes"
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Renders [ServerPath::upload_name_template] for an upload, or just returns the original name without one
+    pub fn upload_name(&self, original: &str, username: &str) -> Result<String, Error> {
+        let Some(template) = &self.upload_name_template else {
+            return Ok(original.to_string());
+        };
+        // values can't add directories of their own
+        let flatten = |value: &str| value.replace(['/', '\\'], "_");
+
+        let mut rendered = String::new();
+        let mut rest = template.as_str();
+        while let Some(start) = rest.find('{') {
+            let Some(length) = rest[start..].find('}') else {
+                break;
+            };
+            rendered.push_str(&rest[..start]);
+            match &rest[start + 1..start + length] {
+                "date" => rendered.push_str(&chrono::Utc::now().format("%Y-%m-%d").to_string()),
+                "user" => rendered.push_str(&flatten(username)),
+                "original" => rendered.push_str(&flatten(original)),
+                "id" => rendered.extend(std::iter::repeat_with(fastrand::alphanumeric).take(8)),
+                variable => {
+                    return Err(Error::Configuration(format!(
+                        "Unknown variable {{{variable}}} in upload_name_template {template}"
+                    )));
+                }
+            }
+            rest = &rest[start + length + 1..];
+        }
+        rendered.push_str(rest);
+
+        let mut segments = Vec::new();
+        for segment in rendered.split('/') {
+            let segment = segment
+                .chars()
+                .filter(|c| !c.is_control())
+                .collect::<String>();
+            match segment.trim() {
+                "" | "." => {}
+                ".." => {
+                    return Err(Error::BadRequest(format!(
+                        "Upload name {rendered} would be outside of the base path"
+                    )));
+                }
+                segment => segments.push(segment.to_string()),
+            }
+        }
+        match segments.is_empty() {
+            true => Err(Error::BadRequest(format!(
+                "Upload name template {template} gave an empty name for {original}"
+            ))),
+            false => Ok(segments.join("/")),
+        }
+    }
+
+    /// What to show people for this server path, `key` being its key in `server_paths`
+    pub fn label<'a>(&'a self, key: &'a str) -> &'a str {
+        self.display_name.as_deref().unwrap_or(key)
+    }
+
+    /// Handlers call this with the method they serve, so paths can be locked down regardless of [ServerPath::read_only]
+    pub fn check_method(&self, method: &axum::http::Method) -> Result<(), Error> {
+        match &self.allowed_methods {
+            Some(allowed)
+                if !allowed
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(method.as_str())) =>
+            {
+                Err(Error::MethodNotAllowed(
+                    allowed
+                        .iter()
+                        .map(|allowed| allowed.to_ascii_uppercase())
+                        .collect(),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The key of [ServerPath::upload_dir], refusing anything that'd climb out of the base path
+    pub fn upload_dir(&self) -> Result<Option<String>, Error> {
+        match &self.upload_dir {
+            Some(upload_dir) if fs::key_escapes_base(upload_dir) => Err(Error::Configuration(
+                format!("upload_dir {upload_dir} is outside of the base path"),
+            )),
+            Some(upload_dir) => Ok(Some(upload_dir.trim_matches('/').to_string())
+                .filter(|upload_dir| !upload_dir.is_empty())),
+            None => Ok(None),
+        }
+    }
+
+    /// Parses [ServerPath::deprecation], checked at startup so a typo doesn't only show up on download
+    pub fn deprecation(&self) -> Result<Option<chrono::DateTime<chrono::Utc>>, Error> {
+        self.deprecation
+            .as_deref()
+            .map(|deprecation| {
+                chrono::DateTime::parse_from_rfc3339(deprecation)
+                    .map(|date| date.to_utc())
+                    .map_err(|err| {
+                        Error::Configuration(format!(
+                            "deprecation {deprecation} isn't an RFC 3339 timestamp: {err}"
+                        ))
+                    })
+            })
+            .transpose()
+    }
+
+    /// Looks up [ServerPath::text_encoding], checked at startup so a typo doesn't only show up on download
+    pub fn text_encoding(&self) -> Result<Option<&'static encoding_rs::Encoding>, Error> {
+        self.text_encoding
+            .as_deref()
+            .map(text::encoding_for_label)
+            .transpose()
+    }
+
+    /// Checks every [ServerPath::mime_overrides] value is a real content type, at startup rather than on download
+    pub fn check_mime_overrides(&self) -> Result<(), Error> {
+        for (extension, mime_type) in self.mime_overrides.iter() {
+            mime_guess::mime::Mime::from_str(mime_type).map_err(|err| {
+                Error::Configuration(format!(
+                    "mime_overrides has an invalid content type for {extension}: {mime_type:?} ({err})"
+                ))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Whether [ServerPath::path] has a [constants::USERNAME_PLACEHOLDER], so it's different for everyone
+    pub fn is_per_user(&self) -> bool {
+        self.path.as_ref().is_some_and(|path| {
+            path.to_string_lossy()
+                .contains(constants::USERNAME_PLACEHOLDER)
+        })
+    }
+
+    /// Only local server paths can be per-user, the others don't have anywhere to put the username
+    pub fn check_per_user(&self) -> Result<(), Error> {
+        match self.is_per_user() && self.type_ != FileKidFsType::Local {
+            true => Err(Error::Configuration(format!(
+                "only local server paths can have {} in their path",
+                constants::USERNAME_PLACEHOLDER
+            ))),
+            false => Ok(()),
+        }
+    }
+
+    /// This server path as `username` sees it, with [constants::USERNAME_PLACEHOLDER] in [ServerPath::path] filled in
+    pub fn for_user(&self, username: Option<&str>) -> Result<ServerPath, Error> {
+        if !self.is_per_user() {
+            return Ok(self.clone());
+        }
+        let username = username.ok_or_else(|| {
+            Error::NotAuthorized(
+                "this server path is per-user, so needs someone logged in".to_string(),
+            )
+        })?;
+        // the username becomes one directory name, so it can't climb out or point somewhere else
+        let username = fs::sanitize_filename(username).map_err(|_| {
+            Error::NotAuthorized(format!("{username:?} can't be used as a directory name"))
+        })?;
+        Ok(ServerPath {
+            path: self.path.as_ref().map(|path| {
+                PathBuf::from(
+                    path.to_string_lossy()
+                        .replace(constants::USERNAME_PLACEHOLDER, username),
+                )
+            }),
+            ..self.clone()
+        })
+    }
+
+    /// The key of [ServerPath::trash_dir], if deletes go to the trash
+    pub fn trash(&self) -> Option<&str> {
+        self.trash_dir
+            .as_deref()
+            .map(|trash_dir| trash_dir.trim_matches('/'))
+            .filter(|trash_dir| !trash_dir.is_empty())
+    }
+
+    /// Checks [ServerPath::trash_dir] stays inside the base path
+    pub fn check_trash_dir(&self) -> Result<(), Error> {
+        match (&self.trash_dir, self.trash()) {
+            (Some(trash_dir), None) => Err(Error::Configuration(format!(
+                "trash_dir {trash_dir:?} can't be the base path"
+            ))),
+            (_, Some(trash_dir)) if fs::key_escapes_base(trash_dir) => Err(Error::Configuration(
+                format!("trash_dir {trash_dir:?} is outside of the base path"),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// The content type to serve a file as, from [ServerPath::mime_overrides] or guessed from the extension
+    pub fn mime_type(&self, filepath: &str) -> String {
+        let extension = std::path::Path::new(filepath)
+            .extension()
+            .and_then(|extension| extension.to_str());
+        if let Some(extension) = extension
+            && let Some((_, mime_type)) = self
+                .mime_overrides
+                .iter()
+                .find(|(key, _)| key.trim_start_matches('.').eq_ignore_ascii_case(extension))
+        {
+            return mime_type.clone();
+        }
+        mime_guess::from_path(filepath)
+            .first_or_octet_stream()
+            .to_string()
+    }
+
+    pub fn download_follow_symlinks(&self) -> bool {
+        self.download_follow_symlinks.unwrap_or(true)
+    }
+
+    pub fn max_browse_depth(&self) -> usize {
+        self.max_browse_depth
+            .unwrap_or(constants::DEFAULT_MAX_BROWSE_DEPTH)
+    }
+
+    #[cfg(test)]
+    /// A local server path, for testing
+    pub(crate) fn test_local(path: &std::path::Path) -> Self {
+        Self {
+            path: Some(path.to_path_buf()),
+            type_: FileKidFsType::Local,
+            ..Default::default()
+        }
+    }
 }
 
 pub enum WebMessage {
@@ -70,6 +482,12 @@ pub struct WebState {
     pub web_tx: tokio::sync::mpsc::Sender<WebServerControl>,
 
     pub config_filepath: PathBuf,
+
+    /// When the last request came in, for idle shutdown
+    pub activity: idle::ActivityTracker,
+
+    /// How much each server path is storing, see [ServerPath::size_cache_secs]
+    pub dir_sizes: dir_size::DirSizeCache,
 }
 
 impl WebState {
@@ -83,6 +501,8 @@ impl WebState {
             configuration,
             web_tx,
             config_filepath,
+            activity: idle::ActivityTracker::default(),
+            dir_sizes: dir_size::DirSizeCache::default(),
         })
     }
 
@@ -98,6 +518,14 @@ impl WebState {
             .expect("Failed to get state")
     }
 
+    #[cfg(test)]
+    pub(crate) async fn test_webstate_with_config(config: Config) -> Self {
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        Self::new(tx, Arc::new(RwLock::new(config)), PathBuf::from("test"))
+            .await
+            .expect("Failed to get state")
+    }
+
     #[cfg(test)]
     pub(crate) fn to_state(&self) -> State<Self> {
         State(self.clone())
@@ -1,14 +1,63 @@
 //! log configuration and setup module
 
 use std::env;
+use std::io::Write;
 
 use env_logger::{Builder, Target};
-use log::LevelFilter;
+use log::{LevelFilter, Record};
+
+/// How log lines are written
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Readable, with timestamps and the module each line's from
+    Pretty,
+    /// One JSON object per line, for log pipelines
+    Json,
+    /// Just the level and message
+    Compact,
+}
+
+impl LogFormat {
+    /// Pretty when debugging, JSON otherwise
+    pub fn default_for(debug: bool) -> Self {
+        match debug {
+            true => Self::Pretty,
+            false => Self::Json,
+        }
+    }
+}
+
+/// A log line for [LogFormat::Json]
+fn json_record(timestamp: &str, record: &Record) -> serde_json::Value {
+    serde_json::json!({
+        "timestamp": timestamp,
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    })
+}
 
 /// Sets up logging
-pub fn setup_logging(debug: bool, db_debug: bool) -> Result<(), log::SetLoggerError> {
+pub fn setup_logging(
+    debug: bool,
+    db_debug: bool,
+    format: LogFormat,
+) -> Result<(), log::SetLoggerError> {
     let mut builder = Builder::from_default_env();
 
+    match format {
+        LogFormat::Pretty => {}
+        LogFormat::Json => {
+            builder.format(|buf, record| {
+                let timestamp = buf.timestamp_millis().to_string();
+                writeln!(buf, "{}", json_record(&timestamp, record))
+            });
+        }
+        LogFormat::Compact => {
+            builder.format(|buf, record| writeln!(buf, "{} {}", record.level(), record.args()));
+        }
+    }
+
     let level = if debug && env::var("RUST_LOG").is_err() {
         LevelFilter::Debug
     } else {
@@ -47,20 +96,45 @@ pub fn setup_logging(debug: bool, db_debug: bool) -> Result<(), log::SetLoggerEr
 
 #[cfg(test)]
 mod tests {
-    use super::setup_logging;
+    use super::{json_record, setup_logging, LogFormat};
 
     #[test]
     fn test_setup_logging() {
-        let test1 = setup_logging(false, true);
+        let test1 = setup_logging(false, true, LogFormat::Pretty);
         dbg!(&test1);
         assert!(test1.is_ok());
         // it'll probably throw an error because we're trying to re-init the logger, but we're in test so it's OK.
-        let test2 = setup_logging(true, true);
+        let test2 = setup_logging(true, true, LogFormat::Pretty);
         dbg!(&test2);
         assert!(test2.is_ok());
 
-        let test3 = setup_logging(true, false);
+        let test3 = setup_logging(true, false, LogFormat::Pretty);
         dbg!(&test3);
         assert!(test3.is_ok());
+
+        for format in [LogFormat::Pretty, LogFormat::Json, LogFormat::Compact] {
+            assert!(setup_logging(false, false, format).is_ok(), "{format:?}");
+        }
+    }
+
+    #[test]
+    fn test_log_format() {
+        assert_eq!(LogFormat::default_for(true), LogFormat::Pretty);
+        assert_eq!(LogFormat::default_for(false), LogFormat::Json);
+
+        let record = log::Record::builder()
+            .level(log::Level::Warn)
+            .target("filekid::web")
+            .args(format_args!("hello \"world\""))
+            .build();
+        assert_eq!(
+            json_record("2024-01-02T03:04:05.678Z", &record),
+            serde_json::json!({
+                "timestamp": "2024-01-02T03:04:05.678Z",
+                "level": "WARN",
+                "target": "filekid::web",
+                "message": "hello \"world\"",
+            })
+        );
     }
 }
@@ -1,12 +1,10 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
-use std::str::FromStr;
 use std::sync::Arc;
 
 use clap::Parser;
-use filekid::cli::CliOpts;
+use filekid::cli::{
+    config_summary, init_config, load_config, validate_config, CliOpts, Command, ConfigCommand,
+};
 use filekid::error::Error;
-use filekid::fs::FileKidFsType;
 use filekid::log::setup_logging;
 use filekid::web::run_web_server;
 use tokio::sync::RwLock;
@@ -15,31 +13,30 @@ use tokio::sync::RwLock;
 async fn main() -> Result<(), filekid::error::Error> {
     let cli = CliOpts::parse();
 
-    setup_logging(cli.debug, cli.db_debug).map_err(|err| Error::Generic(err.to_string()))?;
+    setup_logging(cli.debug, cli.db_debug, cli.log_format())
+        .map_err(|err| Error::Generic(err.to_string()))?;
 
-    let mut config = filekid::config::Config::new(&cli)?;
-    config.startup_check()?;
+    match cli.command() {
+        Command::Serve => {}
+        Command::Config { command } => {
+            return match command {
+                ConfigCommand::Validate => validate_config(&cli, &mut std::io::stdout()).await,
+                ConfigCommand::Init { force } => init_config(&cli, force),
+            };
+        }
+    }
 
-    let (web_tx, web_rx) = tokio::sync::mpsc::channel(1);
+    let config = load_config(&cli).await?;
+    if cli.check_config_only {
+        return config_summary(&cli, &config, &mut std::io::stdout());
+    }
 
-    let mut live_tempdirs: HashMap<String, tempfile::TempDir> = HashMap::new();
+    let (web_tx, web_rx) = tokio::sync::mpsc::channel(1);
 
-    for (server, server_config) in config.server_paths.iter_mut() {
-        if let FileKidFsType::TempDir = server_config.type_ {
-            let tempdir = tempfile::tempdir()?;
-            server_config.path = Some(tempdir.path().to_path_buf());
-            live_tempdirs.insert(server.clone(), tempdir);
-        }
-    }
+    #[cfg(unix)]
+    let _sighup_task = filekid::web::reload_on_sighup(web_tx.clone())?;
 
     let sendable_config = Arc::new(RwLock::new(config));
 
-    run_web_server(
-        PathBuf::from_str("filekid.json").expect("Failed to parse filekid.json"),
-        sendable_config,
-        cli.session_db_path.map(|p| format!("sqlite://{}?mode=rwc", p.display())),
-        web_tx,
-        web_rx,
-    )
-    .await
+    run_web_server(cli.config.clone(), sendable_config, None, web_tx, web_rx).await
 }
@@ -0,0 +1,172 @@
+//! Prometheus metrics, served on [Urls::Metrics] when `metrics_enabled` is set
+
+use std::sync::LazyLock;
+use std::time::Instant;
+
+use axum::body::HttpBody;
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use axum::http::{Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use metrics::{counter, histogram};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use tracing::error;
+
+use crate::error::Error;
+use crate::web::Urls;
+use crate::WebState;
+
+pub const REQUESTS_TOTAL: &str = "filekid_requests_total";
+pub const REQUEST_DURATION_SECONDS: &str = "filekid_request_duration_seconds";
+pub const BYTES_DOWNLOADED_TOTAL: &str = "filekid_bytes_downloaded_total";
+pub const BYTES_UPLOADED_TOTAL: &str = "filekid_bytes_uploaded_total";
+pub const DOWNLOAD_DURATION_SECONDS: &str = "filekid_download_duration_seconds";
+pub const UPLOAD_DURATION_SECONDS: &str = "filekid_upload_duration_seconds";
+
+/// The recorder's process-wide, so it's installed the first time anything asks for it
+static PROMETHEUS: LazyLock<Result<PrometheusHandle, Error>> = LazyLock::new(|| {
+    PrometheusBuilder::new()
+        .install_recorder()
+        .map_err(|err| Error::Configuration(format!("Failed to set up metrics: {err}")))
+});
+
+/// Gets the Prometheus recorder going, until then the `metrics` macros don't record anything
+pub fn install() -> Result<(), Error> {
+    PROMETHEUS.as_ref().map(|_| ()).map_err(Clone::clone)
+}
+
+fn handle() -> Result<PrometheusHandle, Error> {
+    PROMETHEUS.clone()
+}
+
+/// Middleware that counts requests and times them, plus bytes for uploads and downloads
+pub(crate) async fn record_metrics(
+    State(state): State<WebState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !state.configuration.read().await.metrics_enabled {
+        return next.run(request).await;
+    }
+    if let Err(err) = install() {
+        error!("Not recording metrics: {:?}", err);
+        return next.run(request).await;
+    }
+    // grouping by route rather than URL keeps the number of series sane
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let method = request.method().clone();
+    let upload_bytes = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .or_else(|| request.body().size_hint().exact());
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let status = response.status();
+    counter!(
+        REQUESTS_TOTAL,
+        "route" => route.clone(),
+        "method" => method.to_string(),
+        "status" => status.as_u16().to_string(),
+    )
+    .increment(1);
+    histogram!(REQUEST_DURATION_SECONDS, "route" => route.clone()).record(elapsed);
+
+    if status.is_success() {
+        if route.starts_with(Urls::GetFile.as_ref()) && method == Method::GET {
+            histogram!(DOWNLOAD_DURATION_SECONDS).record(elapsed);
+            if let Some(bytes) = response.body().size_hint().exact() {
+                counter!(BYTES_DOWNLOADED_TOTAL).increment(bytes);
+            }
+        } else if route.starts_with(Urls::Upload.as_ref()) {
+            histogram!(UPLOAD_DURATION_SECONDS).record(elapsed);
+            if let Some(bytes) = upload_bytes {
+                counter!(BYTES_UPLOADED_TOTAL).increment(bytes);
+            }
+        }
+    }
+    response
+}
+
+/// Renders everything in the Prometheus text format, for scrapers
+pub(crate) async fn metrics_get(State(state): State<WebState>) -> Result<Response, Error> {
+    if !state.configuration.read().await.metrics_enabled {
+        return Err(Error::NotFound(Urls::Metrics.as_ref().to_string()));
+    }
+    Ok((
+        StatusCode::OK,
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        handle()?.render(),
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use axum::body::Body;
+    use axum::routing::get;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_record_metrics() {
+        let mut config = Config::test_config();
+        config.metrics_enabled = true;
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let app = axum::Router::new()
+            .route("/metrics_test/{thing}", get(|| async { "OK" }))
+            .route(Urls::Metrics.as_ref(), get(metrics_get))
+            .layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                record_metrics,
+            ))
+            .with_state(state.clone());
+        let get = |uri: &str| {
+            app.clone().oneshot(
+                Request::builder()
+                    .uri(uri)
+                    .body(Body::empty())
+                    .expect("Failed to build request"),
+            )
+        };
+
+        for thing in ["one", "two"] {
+            let response = get(&format!("/metrics_test/{thing}"))
+                .await
+                .expect("Failed to send request");
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let response = get(Urls::Metrics.as_ref())
+            .await
+            .expect("Failed to get metrics");
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Failed to read body");
+        let body = String::from_utf8_lossy(&body);
+        assert!(
+            body.lines().any(|line| line
+                == "filekid_requests_total{route=\"/metrics_test/{thing}\",method=\"GET\",status=\"200\"} 2"),
+            "{body}"
+        );
+
+        // it's off unless asked for
+        state.configuration.write().await.metrics_enabled = false;
+        let response = get(Urls::Metrics.as_ref())
+            .await
+            .expect("Failed to get metrics");
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
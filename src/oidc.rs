@@ -1,7 +1,25 @@
 //! OIDC handling for the web server.
 
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, RwLock, Weak};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use argon2::password_hash::{PasswordHash, PasswordVerifier};
+use argon2::{Algorithm, Argon2};
+use axum::extract::{Request, State};
+use axum::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use axum::http::{HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
 use axum_oidc::{AdditionalClaims, EmptyAdditionalClaims, OidcClaims};
-use tracing::{debug, error, instrument};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures::future::BoxFuture;
+use openidconnect::{IdTokenClaims, IssuerUrl, StandardClaims, SubjectIdentifier};
+use tower::{Layer, Service, ServiceExt};
+use tracing::{debug, error, info, instrument};
 
 use axum_oidc::error::MiddlewareError;
 use tokio::sync::mpsc::Sender;
@@ -15,6 +33,11 @@ pub(crate) struct OidcErrorHandler {
 }
 
 const RELOAD_TIME: u64 = 1000;
+/// The issuer on claims made for HTTP Basic logins
+const BASIC_AUTH_ISSUER: &str = "urn:filekid:basic-auth";
+/// An argon2 hash with the default parameters that no password's expected to match, see [BasicAuth::verify]
+const BASIC_AUTH_DUMMY_HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$ZmlsZWtpZGR1bW15c2FsdA$740zwuMf1HG4Vmlpj2i8cGJLmOogYEuGR1WjhQ1ZQc0";
+const BASIC_AUTH_CHALLENGE: &str = "Basic realm=\"filekid\", charset=\"UTF-8\"";
 
 impl OidcErrorHandler {
     pub fn new(web_tx: Option<Sender<WebServerControl>>) -> Self {
@@ -33,6 +56,137 @@ impl OidcErrorHandler {
     }
 }
 
+/// Wraps a layer (the OIDC auth layer) so it can be swapped for a freshly discovered one while the server's running,
+/// which is how rotated IdP signing keys get picked up without a restart.
+pub(crate) struct RefreshableLayer<L> {
+    current: Arc<RwLock<L>>,
+}
+
+// a derive would want L: Clone too
+impl<L> Clone for RefreshableLayer<L> {
+    fn clone(&self) -> Self {
+        Self {
+            current: self.current.clone(),
+        }
+    }
+}
+
+impl<L: Clone> RefreshableLayer<L> {
+    pub(crate) fn new(layer: L) -> Self {
+        Self {
+            current: Arc::new(RwLock::new(layer)),
+        }
+    }
+
+    pub(crate) fn current(&self) -> L {
+        match self.current.read() {
+            Ok(layer) => layer.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// For the refresh task, so it stops once the app that uses the layer's gone
+    fn downgrade(&self) -> Weak<RwLock<L>> {
+        Arc::downgrade(&self.current)
+    }
+}
+
+fn replace_layer<L>(current: &RwLock<L>, layer: L) {
+    match current.write() {
+        Ok(mut current) => *current = layer,
+        Err(poisoned) => *poisoned.into_inner() = layer,
+    }
+}
+
+impl<L, S> Layer<S> for RefreshableLayer<L> {
+    type Service = RefreshableService<L, S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RefreshableService {
+            layer: self.clone(),
+            inner,
+        }
+    }
+}
+
+/// Wraps each request with whichever layer's current, see [RefreshableLayer]
+pub(crate) struct RefreshableService<L, S> {
+    layer: RefreshableLayer<L>,
+    inner: S,
+}
+
+impl<L, S: Clone> Clone for RefreshableService<L, S> {
+    fn clone(&self) -> Self {
+        Self {
+            layer: self.layer.clone(),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<L, S, R> Service<R> for RefreshableService<L, S>
+where
+    L: Layer<S> + Clone,
+    S: Clone,
+    L::Service: Service<R> + Send + 'static,
+    <L::Service as Service<R>>::Future: Send,
+    R: Send + 'static,
+{
+    type Response = <L::Service as Service<R>>::Response;
+    type Error = <L::Service as Service<R>>::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    // the wrapped service is built per request, oneshot waits for it to be ready
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        let service = self.layer.current().layer(self.inner.clone());
+        Box::pin(service.oneshot(request))
+    }
+}
+
+/// Swaps in the result of `discover`, keeping the current layer if that fails
+async fn refresh_layer<L, F, Fut>(current: &RwLock<L>, discover: &F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<L, Error>>,
+{
+    match discover().await {
+        Ok(layer) => {
+            replace_layer(current, layer);
+            info!("Refreshed OIDC discovery document and signing keys");
+        }
+        Err(err) => error!(
+            "Failed to refresh OIDC discovery, keeping the last good signing keys: {}",
+            err
+        ),
+    }
+}
+
+/// Re-runs `discover` every `interval` and swaps in the result, until the layer's no longer in use
+pub(crate) async fn refresh_task<L, F, Fut>(
+    layer: RefreshableLayer<L>,
+    interval: Duration,
+    discover: F,
+) where
+    L: Clone,
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<L, Error>>,
+{
+    let current = layer.downgrade();
+    drop(layer);
+    loop {
+        tokio::time::sleep(interval).await;
+        let Some(current) = current.upgrade() else {
+            debug!("OIDC layer's gone, stopping refreshes");
+            return;
+        };
+        refresh_layer(&current, &discover).await;
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct User {
     username: String,
@@ -74,9 +228,158 @@ pub(crate) fn check_login(
     }
 }
 
+/// Makes sure a [crate::config::Config::basic_auth] password is an argon2 hash, and not the password itself
+pub(crate) fn check_password_hash(username: &str, password_hash: &str) -> Result<(), Error> {
+    let parsed = PasswordHash::new(password_hash).map_err(|err| {
+        Error::Configuration(format!(
+            "basic_auth password for {username} isn't a password hash: {err}"
+        ))
+    })?;
+    match Algorithm::try_from(parsed.algorithm) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(Error::Configuration(format!(
+            "basic_auth password for {username} has to be an argon2 hash, not {}",
+            parsed.algorithm
+        ))),
+    }
+}
+
+/// Who can log in with HTTP Basic auth, see [basic_auth_fallback]
+#[derive(Clone, Debug)]
+pub(crate) struct BasicAuth {
+    credentials: Arc<HashMap<String, String>>,
+    /// OIDC discovery failed at startup, so there's no IdP to send people without credentials to
+    required: bool,
+}
+
+impl BasicAuth {
+    pub(crate) fn new(credentials: HashMap<String, String>, required: bool) -> Self {
+        Self {
+            credentials: Arc::new(credentials),
+            required,
+        }
+    }
+
+    /// The username, if `header` is Basic credentials that match
+    async fn verify(&self, header: &HeaderValue) -> Result<String, Error> {
+        let invalid = || Error::NotAuthorized("invalid username or password".to_string());
+        let encoded = header
+            .to_str()
+            .ok()
+            .and_then(|header| header.strip_prefix("Basic "))
+            .ok_or_else(invalid)?;
+        let decoded = STANDARD
+            .decode(encoded.trim())
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .ok_or_else(invalid)?;
+        let (username, password) = decoded.split_once(':').ok_or_else(invalid)?;
+        // unknown usernames are checked against a dummy hash, so they take as long as a wrong password
+        let (known, password_hash) = match self.credentials.get(username) {
+            Some(password_hash) => (true, password_hash.clone()),
+            None => (false, BASIC_AUTH_DUMMY_HASH.to_string()),
+        };
+        let password = password.to_string();
+
+        // argon2's slow on purpose, keep it off the runtime
+        let verified = tokio::task::spawn_blocking(move || {
+            PasswordHash::new(&password_hash).is_ok_and(|parsed| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &parsed)
+                    .is_ok()
+            })
+        })
+        .await
+        .map_err(|err| Error::InternalServerError(format!("Password check failed: {err}")))?;
+        match known && verified {
+            true => Ok(username.to_string()),
+            false => Err(invalid()),
+        }
+    }
+}
+
+/// Claims for someone who logged in with HTTP Basic auth, so handlers can't tell them from an OIDC login
+fn basic_auth_claims(username: &str) -> Result<OidcClaims<EmptyAdditionalClaims>, Error> {
+    let issuer = IssuerUrl::new(BASIC_AUTH_ISSUER.to_string())
+        .map_err(|err| Error::InternalServerError(format!("Invalid issuer URL: {err}")))?;
+    Ok(OidcClaims::<EmptyAdditionalClaims>(IdTokenClaims::new(
+        issuer,
+        vec![],
+        chrono::Utc::now() + chrono::Duration::hours(1),
+        chrono::Utc::now(),
+        StandardClaims::new(SubjectIdentifier::new(username.to_string())),
+        EmptyAdditionalClaims {},
+    )))
+}
+
+fn basic_auth_challenge(message: &str) -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(WWW_AUTHENTICATE, BASIC_AUTH_CHALLENGE)],
+        message.to_string(),
+    )
+        .into_response()
+}
+
+/// Logs requests without OIDC claims in with HTTP Basic auth, if they've got credentials from [crate::config::Config::basic_auth].
+///
+/// It sits inside the OIDC auth layer, so an OIDC session always wins, and outside the login layer so Basic logins don't get sent to the IdP.
+/// When discovery failed at startup there's no OIDC at all, and requests without credentials get a Basic challenge.
+pub(crate) async fn basic_auth_fallback(
+    State(basic_auth): State<BasicAuth>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if request
+        .extensions()
+        .get::<OidcClaims<EmptyAdditionalClaims>>()
+        .is_some()
+    {
+        return next.run(request).await;
+    }
+    let Some(header) = request.headers().get(AUTHORIZATION).cloned() else {
+        return match basic_auth.required {
+            true => basic_auth_challenge("You must be logged in to view this page!"),
+            false => next.run(request).await,
+        };
+    };
+    let username = match basic_auth.verify(&header).await {
+        Ok(username) => username,
+        Err(err) => {
+            info!("HTTP Basic login failed: {}", err);
+            return basic_auth_challenge("Invalid username or password");
+        }
+    };
+    match basic_auth_claims(&username) {
+        Ok(claims) => {
+            debug!("{} logged in with HTTP Basic auth", username);
+            request.extensions_mut().insert(claims);
+            next.run(request).await
+        }
+        Err(err) => err.into_response(),
+    }
+}
+
+#[cfg(test)]
+/// An argon2 hash of `password`, with cheap parameters so tests are quick
+pub(crate) fn test_password_hash(password: &str) -> String {
+    use argon2::password_hash::{PasswordHasher, SaltString};
+    use argon2::{Params, Version};
+
+    #[allow(clippy::expect_used)]
+    let salt = SaltString::from_b64("c29tZXNhbHRzb21lc2FsdA").expect("Failed to parse salt");
+    #[allow(clippy::expect_used)]
+    let params = Params::new(8, 1, 1, None).expect("Failed to build argon2 params");
+    #[allow(clippy::expect_used)]
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Failed to hash password")
+        .to_string()
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::log::setup_logging;
+    use crate::log::{setup_logging, LogFormat};
     use crate::views::oidc::{test_user_claims, OIDC_TEST_USERNAME};
 
     use super::*;
@@ -84,7 +387,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_oidc_error_handler() {
-        setup_logging(true, true).expect("Failed to set up logging");
+        setup_logging(true, true, LogFormat::Pretty).expect("Failed to set up logging");
 
         let (tx, mut rx) = channel(1);
         let handler = OidcErrorHandler::new(Some(tx));
@@ -104,4 +407,219 @@ mod tests {
         let user = check_login(Some(claims)).expect("Failed to check login");
         assert_eq!(user.username(), OIDC_TEST_USERNAME);
     }
+
+    #[tokio::test]
+    async fn test_refreshable_layer() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use axum::routing::get;
+        use axum::{Extension, Router};
+
+        // Extension stands in for the auth layer, the "keys" it carries are what the handler sees
+        let layer = RefreshableLayer::new(Extension("old keys"));
+        let app = Router::new()
+            .route(
+                "/",
+                get(|Extension(keys): Extension<&'static str>| async move { keys }),
+            )
+            .layer(layer.clone());
+        let keys = |app: Router| async move {
+            let response = app
+                .oneshot(
+                    axum::http::Request::builder()
+                        .uri("/")
+                        .body(axum::body::Body::empty())
+                        .expect("Failed to build request"),
+                )
+                .await
+                .expect("Failed to send request");
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .expect("Failed to read body");
+            String::from_utf8_lossy(&body).to_string()
+        };
+        assert_eq!(keys(app.clone()).await, "old keys");
+
+        // a fake discovery endpoint, down the first time and then serving rotated keys
+        let calls = Arc::new(AtomicUsize::new(0));
+        let discover = {
+            let calls = calls.clone();
+            move || {
+                let call = calls.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    match call {
+                        0 => Err(Error::Oidc("discovery is down".to_string())),
+                        _ => Ok(Extension("rotated keys")),
+                    }
+                }
+            }
+        };
+        let task = tokio::spawn(refresh_task(
+            layer.clone(),
+            Duration::from_millis(10),
+            discover,
+        ));
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while calls.load(Ordering::SeqCst) < 2 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("Refresh never ran");
+        assert_eq!(keys(app.clone()).await, "rotated keys");
+
+        // once the app's gone the refreshes stop
+        drop(app);
+        drop(layer);
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .expect("Refresh task didn't stop")
+            .expect("Refresh task failed");
+    }
+
+    #[tokio::test]
+    async fn test_refresh_layer_keeps_last_good() {
+        let current = RwLock::new("good");
+        refresh_layer(&current, &|| async {
+            Err::<&str, _>(Error::Oidc("nope".to_string()))
+        })
+        .await;
+        assert_eq!(*current.read().expect("Poisoned"), "good");
+        refresh_layer(&current, &|| async { Ok("newer") }).await;
+        assert_eq!(*current.read().expect("Poisoned"), "newer");
+    }
+
+    #[test]
+    fn test_check_password_hash() {
+        assert!(check_password_hash("dummy", BASIC_AUTH_DUMMY_HASH).is_ok());
+        assert!(check_password_hash("alice", &test_password_hash("hunter2")).is_ok());
+        assert!(check_password_hash("alice", "hunter2").is_err());
+        assert!(check_password_hash(
+            "alice",
+            "$pbkdf2-sha256$i=1000$c29tZXNhbHQ$dGhpc2lzbm90YWhhc2hidXRpdHNiYXNlNjQ"
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_basic_auth_unknown_user() {
+        let basic_auth = BasicAuth::new(
+            HashMap::from([("alice".to_string(), test_password_hash("hunter2"))]),
+            false,
+        );
+        let header = |credentials: &str| {
+            HeaderValue::from_str(&format!("Basic {}", STANDARD.encode(credentials)))
+                .expect("Invalid header")
+        };
+        // the dummy hash is checked for unknown users, but matching it doesn't get anyone in
+        assert!(basic_auth
+            .verify(&header("nobody:not anyone's password"))
+            .await
+            .is_err());
+        assert_eq!(
+            basic_auth
+                .verify(&header("alice:hunter2"))
+                .await
+                .expect("Failed to verify"),
+            "alice"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_basic_auth_fallback() {
+        use axum::body::Body;
+        use axum::routing::get;
+        use axum::{Extension, Router};
+
+        let basic_auth = BasicAuth::new(
+            HashMap::from([("alice".to_string(), test_password_hash("hunter2"))]),
+            false,
+        );
+        let whoami = |claims: Option<OidcClaims<EmptyAdditionalClaims>>| async move {
+            check_login(claims).map(|user| user.username())
+        };
+        let app =
+            Router::new()
+                .route("/", get(whoami))
+                .layer(axum::middleware::from_fn_with_state(
+                    basic_auth.clone(),
+                    basic_auth_fallback,
+                ));
+        let request = |authorization: Option<&str>| {
+            let mut request = axum::http::Request::builder().uri("/");
+            if let Some(authorization) = authorization {
+                request = request.header(
+                    AUTHORIZATION,
+                    format!("Basic {}", STANDARD.encode(authorization)),
+                );
+            }
+            request
+                .body(Body::empty())
+                .expect("Failed to build request")
+        };
+        let body = |response: Response| async move {
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .expect("Failed to read body");
+            String::from_utf8_lossy(&body).to_string()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request(Some("alice:hunter2")))
+            .await
+            .expect("Failed to send request");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await, "alice");
+
+        for authorization in ["alice:hunter3", "bob:hunter2", "alice"] {
+            let response = app
+                .clone()
+                .oneshot(request(Some(authorization)))
+                .await
+                .expect("Failed to send request");
+            assert_eq!(
+                response.status(),
+                StatusCode::UNAUTHORIZED,
+                "{authorization}"
+            );
+            assert_eq!(
+                response.headers().get(WWW_AUTHENTICATE),
+                Some(&HeaderValue::from_static(BASIC_AUTH_CHALLENGE))
+            );
+        }
+
+        // no credentials goes on to the OIDC login, unless there's no OIDC
+        let response = app
+            .clone()
+            .oneshot(request(None))
+            .await
+            .expect("Failed to send request");
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let required =
+            Router::new()
+                .route("/", get(whoami))
+                .layer(axum::middleware::from_fn_with_state(
+                    BasicAuth::new(HashMap::new(), true),
+                    basic_auth_fallback,
+                ));
+        let response = required
+            .oneshot(request(None))
+            .await
+            .expect("Failed to send request");
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // an OIDC login wins, whatever Basic credentials come with it
+        let app = app.layer(Extension(test_user_claims()));
+        for authorization in ["alice:hunter2", "alice:hunter3"] {
+            let response = app
+                .clone()
+                .oneshot(request(Some(authorization)))
+                .await
+                .expect("Failed to send request");
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(body(response).await, OIDC_TEST_USERNAME);
+        }
+    }
 }
@@ -1,5 +1,7 @@
 //! Web session store things
 
+use std::path::{Path, PathBuf};
+
 use etcetera::app_strategy::Xdg;
 use etcetera::{AppStrategy, AppStrategyArgs};
 use tower_sessions::cookie::time::Duration;
@@ -10,10 +12,48 @@ use tower_sessions_sqlx_store::sqlx::SqlitePool;
 use tower_sessions_sqlx_store::SqliteStore;
 use tracing::{debug, info};
 
+use crate::config::{Config, CookieSameSite};
 use crate::error::Error;
 
-/// Returns a path to the database file, creating the directory if it doesn't exist
-async fn db_dir() -> Result<String, Error> {
+/// How sessions are stored and their cookies set up, see [Config::session_max_age_secs], [Config::session_same_site], [Config::cookie_secure] and [Config::session_db_path]
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct SessionConfig {
+    /// Sessions expire after this long without a request
+    pub max_age_secs: u64,
+    pub same_site: CookieSameSite,
+    /// Sets the Secure flag on the session cookie, which stops it being sent over plain HTTP
+    pub secure: bool,
+    pub db_path: Option<PathBuf>,
+}
+
+impl From<&Config> for SessionConfig {
+    fn from(config: &Config) -> Self {
+        Self {
+            max_age_secs: config.session_max_age_secs,
+            same_site: config.session_same_site,
+            secure: config.cookie_secure(),
+            db_path: config.session_db_path.clone(),
+        }
+    }
+}
+
+impl From<CookieSameSite> for SameSite {
+    fn from(same_site: CookieSameSite) -> Self {
+        match same_site {
+            CookieSameSite::Strict => SameSite::Strict,
+            CookieSameSite::Lax => SameSite::Lax,
+            CookieSameSite::None => SameSite::None,
+        }
+    }
+}
+
+/// The configuration value that keeps sessions in memory
+pub(crate) const SESSION_DB_MEMORY: &str = ":memory:";
+
+pub(crate) const SQLITE_MEMORY: &str = "sqlite::memory:";
+
+/// Where the database lives when [Config::session_db_path] isn't set
+fn xdg_data_dir() -> Result<PathBuf, Error> {
     let app_strategy = Xdg::new(AppStrategyArgs {
         top_level_domain: "com".to_string(),
         author: "Terminal Outcomes".to_string(),
@@ -24,41 +64,53 @@ async fn db_dir() -> Result<String, Error> {
             "Couldn't identify way of generating a database dir! Error was: {err}"
         ))
     })?;
+    Ok(app_strategy.data_dir())
+}
 
-    if !app_strategy.data_dir().exists() {
-        info!(
-            "Creating DB data dir: {}",
-            app_strategy.data_dir().display()
-        );
-        tokio::fs::create_dir(app_strategy.data_dir())
-            .await
-            .map_err(|err| {
-                Error::Configuration(format!(
-                    "Couldn't create data dir {}! Error was: {}",
-                    app_strategy.data_dir().display(),
-                    err
-                ))
-            })?;
+/// Turns the configured database path into a sqlite URL, falling back to a file in the XDG data dir
+pub(crate) fn db_url(session_db_path: Option<&Path>) -> Result<String, Error> {
+    match session_db_path {
+        Some(path) if path.as_os_str() == SESSION_DB_MEMORY => Ok(SQLITE_MEMORY.to_string()),
+        Some(path) => Ok(format!("sqlite://{}?mode=rwc", path.display())),
+        None => Ok(format!(
+            "sqlite://{}/filekid.sqlite?mode=rwc",
+            xdg_data_dir()?.to_string_lossy()
+        )),
     }
+}
 
-    Ok(format!(
-        "sqlite://{}/filekid.sqlite?mode=rwc",
-        app_strategy.data_dir().to_string_lossy()
-    ))
+/// Creates the XDG data dir if it doesn't exist, when it's going to be used
+async fn create_db_dir() -> Result<(), Error> {
+    let data_dir = xdg_data_dir()?;
+    if !data_dir.exists() {
+        info!("Creating DB data dir: {}", data_dir.display());
+        tokio::fs::create_dir(&data_dir).await.map_err(|err| {
+            Error::Configuration(format!(
+                "Couldn't create data dir {}! Error was: {}",
+                data_dir.display(),
+                err
+            ))
+        })?;
+    }
+    Ok(())
 }
+
 pub(crate) type DeletionTask =
     tokio::task::JoinHandle<Result<(), tower_sessions::session_store::Error>>;
 
-#[cfg(test)]
-pub(crate) const SQLITE_MEMORY: &str = "sqlite::memory:";
-
-/// Returns a session store and a task that will delete expired sessions periodically
+/// Returns a session store and a task that will delete expired sessions periodically, `database_url` overrides [SessionConfig::db_path]
 pub(crate) async fn build(
-    database_path: Option<String>,
+    database_url: Option<String>,
+    session_config: &SessionConfig,
 ) -> Result<(DeletionTask, SessionManagerLayer<SqliteStore>), Error> {
-    let database_path = match database_path {
+    let database_path = match database_url {
         Some(val) => val,
-        None => db_dir().await?,
+        None => {
+            if session_config.db_path.is_none() {
+                create_db_dir().await?;
+            }
+            db_url(session_config.db_path.as_deref())?
+        }
     };
     debug!("Sqlite database path: {}", database_path);
 
@@ -78,11 +130,13 @@ pub(crate) async fn build(
     );
 
     let session_layer = SessionManagerLayer::new(session_store)
-        .with_secure(true)
+        .with_secure(session_config.secure)
         .with_http_only(true) // is the default but it's nice to explicitly call it out
         .with_path("/")
-        .with_same_site(SameSite::Lax)
-        .with_expiry(Expiry::OnInactivity(Duration::seconds(3600)));
+        .with_same_site(session_config.same_site.into())
+        .with_expiry(Expiry::OnInactivity(Duration::seconds(
+            session_config.max_age_secs.try_into().unwrap_or(i64::MAX),
+        )));
 
     Ok((deletion_task, session_layer))
 }
@@ -91,10 +145,145 @@ pub(crate) async fn build(
 mod tests {
     use super::*;
 
+    use axum::body::Body;
+    use axum::http::header::SET_COOKIE;
+    use axum::http::Request;
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+    use tower_sessions::Session;
+
     #[tokio::test]
     async fn test_build() {
-        build(Some(SQLITE_MEMORY.to_string()))
+        build(
+            Some(SQLITE_MEMORY.to_string()),
+            &SessionConfig::from(&Config::test_config()),
+        )
+        .await
+        .expect("Failed to build session store");
+    }
+
+    /// Makes a request that starts a session, returning the Set-Cookie header
+    async fn session_cookie(session_config: &SessionConfig) -> String {
+        let (_deletion_task, session_layer) =
+            build(Some(SQLITE_MEMORY.to_string()), session_config)
+                .await
+                .expect("Failed to build session store");
+        let app = Router::new()
+            .route(
+                "/",
+                get(|session: Session| async move {
+                    session
+                        .insert("hello", "world")
+                        .await
+                        .expect("Failed to insert into session");
+                    "OK"
+                }),
+            )
+            .layer(session_layer);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .body(Body::empty())
+                    .expect("Failed to build request"),
+            )
+            .await
+            .expect("Failed to make request");
+        response
+            .headers()
+            .get(SET_COOKIE)
+            .expect("No session cookie was set")
+            .to_str()
+            .expect("Cookie wasn't a string")
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_cookie_secure_follows_frontend_url() {
+        let mut config = crate::config::Config::test_config();
+        config.frontend_url = "https://example.com".to_string();
+        assert!(session_cookie(&SessionConfig::from(&config))
+            .await
+            .contains("Secure"));
+
+        config.frontend_url = "http://example.com".to_string();
+        assert!(!session_cookie(&SessionConfig::from(&config))
+            .await
+            .contains("Secure"));
+    }
+
+    #[tokio::test]
+    async fn test_cookie_secure_follows_tls_enabled() {
+        let mut config = crate::config::Config::test_config();
+        config.frontend_url = "https://example.com".to_string();
+        config.tls_enabled = false;
+        assert_eq!(config.cookie_secure, None);
+        assert!(!session_cookie(&SessionConfig::from(&config))
+            .await
+            .contains("Secure"));
+    }
+
+    #[tokio::test]
+    async fn test_session_config() {
+        let cookie = session_cookie(&SessionConfig::from(&Config::test_config())).await;
+        assert!(cookie.contains("SameSite=Lax"));
+        assert!(cookie.contains("Max-Age=3600"));
+
+        let mut config = Config::test_config();
+        config.session_max_age_secs = 60;
+        config.session_same_site = CookieSameSite::Strict;
+        config.cookie_secure = Some(false);
+        let cookie = session_cookie(&SessionConfig::from(&config)).await;
+        assert!(cookie.contains("SameSite=Strict"));
+        assert!(cookie.contains("Max-Age=60"));
+        assert!(!cookie.contains("Secure"));
+
+        // browsers throw away SameSite=None cookies that aren't Secure
+        config.session_same_site = CookieSameSite::None;
+        assert!(matches!(
+            config.startup_check().await,
+            Err(Error::Configuration(_))
+        ));
+        config.cookie_secure = Some(true);
+        let cookie = session_cookie(&SessionConfig::from(&config)).await;
+        assert!(cookie.contains("SameSite=None"));
+        assert!(cookie.contains("Secure"));
+    }
+
+    #[tokio::test]
+    async fn test_session_db_path() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let db_path = temp_dir.path().join("sessions.sqlite");
+        assert_eq!(
+            db_url(Some(&db_path)).expect("Failed to get URL"),
+            format!("sqlite://{}?mode=rwc", db_path.display())
+        );
+        assert_eq!(
+            db_url(Some(Path::new(SESSION_DB_MEMORY))).expect("Failed to get URL"),
+            SQLITE_MEMORY
+        );
+        assert_eq!(
+            db_url(None).expect("Failed to get URL"),
+            format!(
+                "sqlite://{}/filekid.sqlite?mode=rwc",
+                xdg_data_dir()
+                    .expect("Failed to get XDG dir")
+                    .to_string_lossy()
+            )
+        );
+
+        let mut config = Config::test_config();
+        config.session_db_path = Some(db_path.clone());
+        build(None, &SessionConfig::from(&config))
             .await
             .expect("Failed to build session store");
+        assert!(db_path.exists());
+
+        config.session_db_path = Some(PathBuf::from(SESSION_DB_MEMORY));
+        build(None, &SessionConfig::from(&config))
+            .await
+            .expect("Failed to build in-memory session store");
     }
 }
@@ -0,0 +1,106 @@
+//! Text handling for uploads and downloads
+
+use encoding_rs::Encoding;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+/// How many bytes are sniffed when deciding if an upload is text
+const SNIFF_BYTES: usize = 8192;
+
+/// Line endings that text uploads can be normalized to
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Eol {
+    Lf,
+    Crlf,
+    /// Leave line endings alone
+    None,
+}
+
+impl Eol {
+    /// Rewrites the line endings in `data` if it looks like text, binaries are returned untouched.
+    pub fn normalize(self, data: &[u8]) -> Option<Vec<u8>> {
+        if self == Eol::None || !looks_like_text(data) {
+            return None;
+        }
+        let mut normalized = Vec::with_capacity(data.len());
+        let mut bytes = data.iter().peekable();
+        while let Some(&byte) = bytes.next() {
+            if byte == b'\r' && bytes.peek() == Some(&&b'\n') {
+                continue;
+            }
+            if byte == b'\n' && self == Eol::Crlf {
+                normalized.push(b'\r');
+            }
+            normalized.push(byte);
+        }
+        Some(normalized)
+    }
+}
+
+/// Looks up an encoding by its WHATWG label, eg `shift_jis` or `latin1`
+pub fn encoding_for_label(label: &str) -> Result<&'static Encoding, Error> {
+    Encoding::for_label(label.trim().as_bytes())
+        .ok_or_else(|| Error::Configuration(format!("Unknown text encoding {label:?}")))
+}
+
+/// Decodes text in `encoding` to UTF-8, ignoring any byte order mark since the encoding's been forced
+pub fn to_utf8(data: &[u8], encoding: &'static Encoding) -> Vec<u8> {
+    encoding
+        .decode_without_bom_handling(data)
+        .0
+        .into_owned()
+        .into_bytes()
+}
+
+/// Sniffs the start of some data to guess if it's text - it has to be UTF-8 without any NUL bytes.
+pub fn looks_like_text(data: &[u8]) -> bool {
+    let sample = &data[..data.len().min(SNIFF_BYTES)];
+    if sample.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(sample) {
+        Ok(_) => true,
+        // the sample might have cut a multi-byte character in half
+        Err(err) => err.error_len().is_none() && data.len() > SNIFF_BYTES,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_looks_like_text() {
+        assert!(looks_like_text(b"hello\r\nworld\r\n"));
+        assert!(looks_like_text("héllo wörld".as_bytes()));
+        assert!(looks_like_text(b""));
+        assert!(!looks_like_text(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"));
+        assert!(!looks_like_text(b"\xff\xfe\r\n"));
+
+        // a multi-byte character straddling the end of the sample is fine
+        let mut long = vec![b'a'; SNIFF_BYTES - 1];
+        long.extend_from_slice("é".as_bytes());
+        assert!(looks_like_text(&long));
+    }
+
+    #[test]
+    fn test_normalize() {
+        let mixed = b"one\r\ntwo\nthree\r\n";
+        assert_eq!(
+            Eol::Lf.normalize(mixed),
+            Some(b"one\ntwo\nthree\n".to_vec())
+        );
+        assert_eq!(
+            Eol::Crlf.normalize(mixed),
+            Some(b"one\r\ntwo\r\nthree\r\n".to_vec())
+        );
+        assert_eq!(Eol::None.normalize(mixed), None);
+        assert_eq!(Eol::Lf.normalize(b"\0binary\r\n"), None);
+        assert_eq!(
+            serde_json::from_str::<Eol>("\"crlf\"").expect("Failed to parse Eol"),
+            Eol::Crlf
+        );
+    }
+}
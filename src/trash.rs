@@ -0,0 +1,304 @@
+//! The trash, where deletes on server paths with a `trash_dir` move things to, and retention handling that
+//! permanently deletes items that have sat in a server path's trash for too long.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::constants::TRASH_DIR_NAME;
+use crate::error::Error;
+use crate::fs::{create_parent_dirs, key_escapes_base, sanitize_filename, FileKidFs};
+use crate::SendableConfig;
+
+const SECONDS_PER_DAY: u64 = 86400;
+
+/// Escaped in a trashed key so the whole key fits in one filename
+const TRASHED_KEY: &AsciiSet = &CONTROLS.add(b'/').add(b'%');
+
+/// Whether `key` is the trash directory or something in it, which are deleted for real
+pub(crate) fn in_trash(trash_dir: &str, key: &str) -> bool {
+    let key = key.trim_matches('/');
+    key == trash_dir
+        || key
+            .strip_prefix(trash_dir)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Moves `key` into `trash_dir` as `<unix timestamp>-<escaped key>`, returning the name it's got in there
+#[instrument(level = "debug", skip(filekidfs))]
+pub(crate) fn move_to_trash(
+    filekidfs: &dyn FileKidFs,
+    trash_dir: &str,
+    key: &str,
+) -> Result<String, Error> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|err| Error::InternalServerError(format!("Clock is before the epoch: {err}")))?
+        .as_secs();
+    let name = format!(
+        "{now}-{}",
+        utf8_percent_encode(key.trim_matches('/'), TRASHED_KEY)
+    );
+    let trashed_key = format!("{trash_dir}/{name}");
+    create_parent_dirs(filekidfs, &trashed_key)?;
+    filekidfs.move_file(key, &trashed_key, false)?;
+    Ok(name)
+}
+
+/// Moves `name` out of `trash_dir` back to where it was deleted from, returning its key
+#[instrument(level = "debug", skip(filekidfs))]
+pub(crate) fn restore(
+    filekidfs: &dyn FileKidFs,
+    trash_dir: &str,
+    name: &str,
+) -> Result<String, Error> {
+    let not_trashed = || Error::BadRequest(format!("{name} didn't come from the trash"));
+    let encoded_key = sanitize_filename(name)?
+        .split_once('-')
+        .filter(|(timestamp, _)| timestamp.parse::<u64>().is_ok())
+        .map(|(_, encoded_key)| encoded_key)
+        .ok_or_else(not_trashed)?;
+    let key = percent_decode_str(encoded_key)
+        .decode_utf8()
+        .map_err(|_| not_trashed())?
+        .to_string();
+    if key.is_empty() || in_trash(trash_dir, &key) {
+        return Err(not_trashed());
+    }
+    if key_escapes_base(&key) {
+        return Err(Error::NotAuthorized(format!(
+            "{key} is outside of the base path"
+        )));
+    }
+    if filekidfs.exists(&key)? {
+        return Err(Error::BadRequest(format!("{key} already exists")));
+    }
+    create_parent_dirs(filekidfs, &key)?;
+    filekidfs.move_file(&format!("{trash_dir}/{name}"), &key, false)?;
+    Ok(key)
+}
+
+/// Works out when an item was trashed, trashed items are named `<unix timestamp>-<original name>`
+/// and anything else falls back to the modified time.
+fn trashed_at(path: &Path) -> Result<SystemTime, Error> {
+    let timestamp = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .and_then(|name| {
+            name.split_once('-')
+                .and_then(|(prefix, _)| prefix.parse::<u64>().ok())
+        });
+    match timestamp {
+        Some(timestamp) => Ok(UNIX_EPOCH + Duration::from_secs(timestamp)),
+        None => Ok(path.symlink_metadata()?.modified()?),
+    }
+}
+
+/// How long things stay in the trash for `trash_retention_days`, none if that many seconds doesn't fit in a u64
+pub(crate) fn retention_for_days(days: u64) -> Option<Duration> {
+    days.checked_mul(SECONDS_PER_DAY).map(Duration::from_secs)
+}
+
+/// Permanently deletes everything in `base_path`'s trash directory older than `retention`, returning what was purged.
+#[instrument(level = "debug")]
+pub(crate) fn purge_trash(
+    base_path: &Path,
+    trash_dir: &str,
+    retention: Duration,
+) -> Result<Vec<PathBuf>, Error> {
+    let trash_dir = base_path.join(trash_dir);
+    if !trash_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    // don't follow a trash dir that's been swapped out for a link to somewhere else
+    let canonical_base = base_path.canonicalize()?;
+    let canonical_trash = trash_dir.canonicalize()?;
+    if canonical_trash == canonical_base || !canonical_trash.starts_with(&canonical_base) {
+        return Err(Error::NotAuthorized(format!(
+            "Trash dir {} is outside of base path {}",
+            trash_dir.display(),
+            base_path.display()
+        )));
+    }
+
+    let now = SystemTime::now();
+    let mut purged = Vec::new();
+    for entry in std::fs::read_dir(&canonical_trash)? {
+        let path = entry?.path();
+        let age = now
+            .duration_since(trashed_at(&path)?)
+            .unwrap_or(Duration::ZERO);
+        if age < retention {
+            continue;
+        }
+        debug!("Purging {} from trash, age {:?}", path.display(), age);
+        // symlinks are removed rather than followed
+        if path.symlink_metadata()?.is_dir() {
+            std::fs::remove_dir_all(&path)?;
+        } else {
+            std::fs::remove_file(&path)?;
+        }
+        purged.push(path);
+    }
+    Ok(purged)
+}
+
+/// Runs forever, periodically purging old items from every server path's trash.
+pub(crate) async fn purge_trash_task(configuration: SendableConfig) {
+    loop {
+        let config_reader = configuration.read().await;
+        let interval = Duration::from_secs(config_reader.trash_scan_interval_secs.max(1));
+        // startup_check refuses retentions that are too long
+        let retention = config_reader
+            .trash_retention_days
+            .and_then(retention_for_days);
+        let base_paths: Vec<(String, PathBuf, String)> = config_reader
+            .server_paths
+            .iter()
+            .filter_map(|(name, server_path)| {
+                let trash_dir = server_path.trash().unwrap_or(TRASH_DIR_NAME).to_string();
+                server_path
+                    .path
+                    .clone()
+                    .map(|path| (name.clone(), path, trash_dir))
+            })
+            .collect();
+        drop(config_reader);
+
+        match retention {
+            None => debug!("Trash retention is not configured, skipping purge"),
+            Some(retention) => {
+                for (name, base_path, trash_dir) in base_paths {
+                    // deleting a big tree takes a while, keep it off the runtime
+                    let purged = tokio::task::spawn_blocking(move || {
+                        purge_trash(&base_path, &trash_dir, retention)
+                    })
+                    .await
+                    .unwrap_or_else(|err| {
+                        Err(Error::InternalServerError(format!(
+                            "Trash purge task failed: {err}"
+                        )))
+                    });
+                    match purged {
+                        Ok(purged) if purged.is_empty() => {}
+                        Ok(purged) => {
+                            for path in purged {
+                                info!(
+                                    "Purged {} from trash on server path {}",
+                                    path.display(),
+                                    name
+                                );
+                            }
+                        }
+                        Err(Error::NotAuthorized(err)) => warn!("{}", err),
+                        Err(err) => {
+                            error!("Failed to purge trash for server path {}: {}", name, err)
+                        }
+                    }
+                }
+            }
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retention_for_days() {
+        assert_eq!(retention_for_days(0), Some(Duration::ZERO));
+        assert_eq!(retention_for_days(2), Some(Duration::from_secs(2 * 86400)));
+        assert_eq!(retention_for_days(u64::MAX), None);
+    }
+
+    #[test]
+    fn test_purge_trash() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let trash_dir = temp_dir.path().join(TRASH_DIR_NAME);
+        std::fs::create_dir(&trash_dir).expect("Failed to create trash dir");
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        let old_item = trash_dir.join(format!("{}-old.txt", now - 40 * SECONDS_PER_DAY));
+        let recent_item = trash_dir.join(format!("{}-recent.txt", now - SECONDS_PER_DAY));
+        std::fs::write(&old_item, b"old").expect("Failed to write old item");
+        std::fs::write(&recent_item, b"recent").expect("Failed to write recent item");
+
+        // no timestamp prefix, so this falls back to the modified time
+        let old_dir = trash_dir.join("olddir");
+        std::fs::create_dir(&old_dir).expect("Failed to create old dir");
+        std::fs::write(old_dir.join("inner.txt"), b"inner").expect("Failed to write inner file");
+        std::fs::File::open(&old_dir)
+            .expect("Failed to open old dir")
+            .set_modified(SystemTime::now() - Duration::from_secs(40 * SECONDS_PER_DAY))
+            .expect("Failed to set modified time");
+
+        let purged = purge_trash(
+            temp_dir.path(),
+            TRASH_DIR_NAME,
+            Duration::from_secs(30 * SECONDS_PER_DAY),
+        )
+        .expect("Failed to purge trash");
+
+        assert_eq!(purged.len(), 2);
+        assert!(!old_item.exists());
+        assert!(!old_dir.exists());
+        assert!(recent_item.exists());
+    }
+
+    #[test]
+    fn test_purge_trash_no_trash_dir() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let purged = purge_trash(temp_dir.path(), TRASH_DIR_NAME, Duration::ZERO)
+            .expect("Failed to purge trash");
+        assert!(purged.is_empty());
+    }
+
+    #[test]
+    fn test_move_to_trash_and_restore() {
+        use crate::fs::local::LocalFs;
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::create_dir(temp_dir.path().join("docs")).expect("Failed to create dir");
+        std::fs::write(temp_dir.path().join("docs/a%b.txt"), b"hello")
+            .expect("Failed to write file");
+        let filekidfs = LocalFs::new(temp_dir.path().to_path_buf());
+
+        let name = move_to_trash(&filekidfs, "trash/bin", "docs/a%b.txt").expect("Failed to trash");
+        assert!(name.ends_with("-docs%2Fa%25b.txt"), "{name}");
+        assert!(!temp_dir.path().join("docs/a%b.txt").exists());
+        assert!(temp_dir.path().join("trash/bin").join(&name).is_file());
+        assert!(trashed_at(&temp_dir.path().join("trash/bin").join(&name)).is_ok());
+
+        // restoring puts back the directories it was in
+        std::fs::remove_dir(temp_dir.path().join("docs")).expect("Failed to remove dir");
+        assert_eq!(
+            restore(&filekidfs, "trash/bin", &name).expect("Failed to restore"),
+            "docs/a%b.txt"
+        );
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("docs/a%b.txt")).expect("Failed to read file"),
+            b"hello"
+        );
+
+        assert!(matches!(
+            restore(&filekidfs, "trash/bin", "not-trashed.txt"),
+            Err(Error::BadRequest(_))
+        ));
+        assert!(matches!(
+            restore(&filekidfs, "trash/bin", "1-..%2F..%2Fescape.txt"),
+            Err(Error::NotAuthorized(_))
+        ));
+
+        assert!(in_trash("trash/bin", "/trash/bin/"));
+        assert!(in_trash("trash/bin", "trash/bin/123-a.txt"));
+        assert!(!in_trash("trash/bin", "trash/binder"));
+    }
+}
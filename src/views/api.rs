@@ -0,0 +1,919 @@
+//! JSON API endpoints, for clients that want to render things themselves.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use axum::extract::{Path, Query, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::Json;
+use utoipa::{IntoParams, OpenApi, ToSchema};
+
+use super::prelude::*;
+use crate::constants::{DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE, MAX_TREE_NODES};
+use crate::fs::{
+    build_tree, fs_for_user, page_after, ChecksumAlgo, DiskSpace, FileKidFs, TreeLimits, TreeNode,
+};
+use crate::oidc::check_login;
+use crate::views::browse::FileEntry;
+use crate::views::FileType;
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct TreeResponse {
+    server_path: String,
+    /// The key the tree starts from, empty for the root of the server path
+    path: String,
+    /// Set if the walk stopped early because there were too many entries
+    truncated: bool,
+    children: Vec<TreeNode>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/tree/{server_path}/",
+    params(("server_path" = String, Path, description = "The name of the server path")),
+    responses((status = 200, body = TreeResponse))
+)]
+pub(crate) async fn tree_nopath(
+    State(state): State<WebState>,
+    Path(server_path): Path<String>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+) -> Result<Json<TreeResponse>, Error> {
+    tree_get(State(state), Path((server_path, None)), claims).await
+}
+
+/// Returns the (depth and size-bounded) recursive directory tree of a server path.
+#[utoipa::path(
+    get,
+    path = "/api/tree/{server_path}/{filepath}",
+    params(("server_path" = String, Path, description = "The name of the server path"), ("filepath" = String, Path, description = "The key in the server path, slashes and all")),
+    responses(
+        (status = 200, body = TreeResponse),
+        (status = 404, description = "The server path or directory doesn't exist")
+    )
+)]
+#[instrument(level = "debug", skip(state, claims))]
+pub(crate) async fn tree_get(
+    State(state): State<WebState>,
+    Path((server_path, filepath)): Path<(String, Option<String>)>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+) -> Result<Json<TreeResponse>, Error> {
+    let user = check_login(claims)?;
+    debug!("User {} requested a tree", user.username());
+
+    let server_reader = state.configuration.read().await;
+
+    let server_path_object = match server_reader.server_paths.get(&server_path) {
+        None => {
+            error!("Couldn't find server path {}", server_path);
+            return Err(Error::NotFound(server_path));
+        }
+        Some(p) => p,
+    };
+    server_path_object.check_method(&Method::GET)?;
+
+    let filekidfs = fs_for_user(server_path_object, Some(&user.username()))?;
+
+    let filepath = filepath
+        .map(|p| p.trim_matches('/').to_string())
+        .filter(|p| !p.is_empty());
+
+    if let Some(filepath) = &filepath
+        && !filekidfs.is_dir(filepath)
+    {
+        return Err(Error::NotFound(filepath.to_string()));
+    }
+
+    let (children, truncated) = build_tree(
+        filekidfs.as_ref(),
+        filepath.clone(),
+        &TreeLimits {
+            max_depth: server_path_object.max_browse_depth(),
+            max_nodes: MAX_TREE_NODES,
+            max_list_entries: server_reader.max_list_entries,
+            server_path: server_path_object,
+        },
+    )?;
+
+    Ok(Json(TreeResponse {
+        server_path,
+        path: filepath.unwrap_or_default(),
+        truncated,
+        children,
+    }))
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct ListQuery {
+    limit: Option<usize>,
+    /// The `next_token` from the previous page
+    token: Option<String>,
+    /// Comma-separated [ListField]s to include, defaults to [DEFAULT_LIST_FIELDS]
+    fields: Option<String>,
+}
+
+/// The attributes of an entry that a listing can include
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ListField {
+    Name,
+    Path,
+    Type,
+    Size,
+    Modified,
+    Mime,
+    /// Means reading every file, so it's only worked out when asked for
+    Hash,
+}
+
+/// What a listing includes when `fields` isn't set
+const DEFAULT_LIST_FIELDS: &[ListField] = &[
+    ListField::Name,
+    ListField::Path,
+    ListField::Type,
+    ListField::Size,
+    ListField::Modified,
+];
+
+impl FromStr for ListField {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "name" => Ok(Self::Name),
+            "path" => Ok(Self::Path),
+            "type" => Ok(Self::Type),
+            "size" => Ok(Self::Size),
+            "modified" => Ok(Self::Modified),
+            "mime" => Ok(Self::Mime),
+            "hash" => Ok(Self::Hash),
+            other => Err(Error::BadRequest(format!(
+                "Unknown field {other:?}, expected name, path, type, size, modified, mime or hash"
+            ))),
+        }
+    }
+}
+
+impl ListQuery {
+    fn fields(&self) -> Result<Vec<ListField>, Error> {
+        match self.fields.as_deref() {
+            None => Ok(DEFAULT_LIST_FIELDS.to_vec()),
+            Some(fields) => fields
+                .split(',')
+                .map(str::trim)
+                .filter(|field| !field.is_empty())
+                .map(ListField::from_str)
+                .collect(),
+        }
+    }
+}
+
+/// Fields that weren't asked for are left out, ones that don't apply (like the size of a directory) are `null`
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub(crate) struct ListEntry {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filename: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fullpath: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    is_dir: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<Option<u64>>,
+    /// RFC3339, in UTC
+    #[serde(skip_serializing_if = "Option::is_none")]
+    modified: Option<Option<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mime: Option<Option<String>>,
+    /// The SHA-256 of the file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<Option<String>>,
+}
+
+impl ListEntry {
+    /// Picks the requested `fields` out of `entry`, hashing files if [ListField::Hash] is one of them
+    fn new(
+        filekidfs: &dyn FileKidFs,
+        entry: &FileEntry,
+        fields: &[ListField],
+    ) -> Result<Self, Error> {
+        let is_dir = entry.filetype == FileType::Directory;
+        let mut list_entry = Self::default();
+        for field in fields {
+            match field {
+                ListField::Name => list_entry.filename = Some(entry.filename.clone()),
+                ListField::Path => list_entry.fullpath = Some(entry.fullpath.clone()),
+                ListField::Type => list_entry.is_dir = Some(is_dir),
+                ListField::Size => list_entry.size = Some(entry.size),
+                ListField::Modified => {
+                    list_entry.modified = Some(entry.modified.map(|modified| {
+                        chrono::DateTime::<chrono::Utc>::from(modified)
+                            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+                    }))
+                }
+                ListField::Mime => {
+                    list_entry.mime = Some((!is_dir).then(|| {
+                        mime_guess::from_path(&entry.filename)
+                            .first_or_octet_stream()
+                            .to_string()
+                    }))
+                }
+                ListField::Hash => {
+                    list_entry.sha256 = Some(match is_dir {
+                        true => None,
+                        false => Some(filekidfs.checksum(&entry.fullpath, ChecksumAlgo::Sha256)?),
+                    })
+                }
+            }
+        }
+        Ok(list_entry)
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct ListResponse {
+    server_path: String,
+    path: String,
+    entries: Vec<ListEntry>,
+    /// Pass this back as `token` to get the next page, `null` once there's nothing left
+    next_token: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/list/{server_path}/",
+    params(("server_path" = String, Path, description = "The name of the server path"), ListQuery),
+    responses((status = 200, body = ListResponse))
+)]
+pub(crate) async fn list_nopath(
+    State(state): State<WebState>,
+    Path(server_path): Path<String>,
+    Query(query): Query<ListQuery>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+) -> Result<Json<ListResponse>, Error> {
+    list_get(
+        State(state),
+        Path((server_path, None)),
+        Query(query),
+        claims,
+    )
+    .await
+}
+
+/// Returns one page of a directory listing, use `next_token` to walk through big directories.
+#[utoipa::path(
+    get,
+    path = "/api/list/{server_path}/{filepath}",
+    params(("server_path" = String, Path, description = "The name of the server path"), ("filepath" = String, Path, description = "The key in the server path, slashes and all"), ListQuery),
+    responses(
+        (status = 200, body = ListResponse),
+        (status = 400, description = "A bad `token` or `fields`, or the directory's too big to list"),
+        (status = 404, description = "The server path or directory doesn't exist")
+    )
+)]
+#[instrument(level = "debug", skip(state, claims))]
+pub(crate) async fn list_get(
+    State(state): State<WebState>,
+    Path((server_path, filepath)): Path<(String, Option<String>)>,
+    Query(query): Query<ListQuery>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+) -> Result<Json<ListResponse>, Error> {
+    let user = check_login(claims)?;
+    debug!("User {} requested a listing", user.username());
+
+    let server_reader = state.configuration.read().await;
+
+    let server_path_object = match server_reader.server_paths.get(&server_path) {
+        None => {
+            error!("Couldn't find server path {}", server_path);
+            return Err(Error::NotFound(server_path));
+        }
+        Some(p) => p,
+    };
+    server_path_object.check_method(&Method::GET)?;
+
+    let filekidfs = fs_for_user(server_path_object, Some(&user.username()))?;
+
+    let filepath = filepath
+        .map(|p| p.trim_matches('/').to_string())
+        .filter(|p| !p.is_empty());
+
+    if let Some(filepath) = &filepath
+        && !filekidfs.is_dir(filepath)
+    {
+        return Err(Error::NotFound(filepath.to_string()));
+    }
+
+    let mut entries = filekidfs.list_dir(filepath.clone(), server_reader.max_list_entries)?;
+    entries.retain(|entry| !server_path_object.is_ignored(&entry.filename));
+    let fields = query.fields()?;
+    let (entries, next_token) = page_after(
+        entries,
+        query.token.as_deref(),
+        query
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE),
+    )?;
+
+    // hashing big files takes a while, so keep it off the async workers
+    let entries = tokio::task::spawn_blocking(move || {
+        entries
+            .iter()
+            .map(|entry| ListEntry::new(filekidfs.as_ref(), entry, &fields))
+            .collect::<Result<Vec<_>, Error>>()
+    })
+    .await
+    .map_err(|err| Error::InternalServerError(format!("Listing task failed to run: {err}")))??;
+
+    Ok(Json(ListResponse {
+        server_path,
+        path: filepath.unwrap_or_default(),
+        entries,
+        next_token,
+    }))
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct DiskSpaceResponse {
+    /// `null` for server paths whose backend doesn't know about disk space
+    #[schema(value_type = BTreeMap<String, DiskSpace>)]
+    server_paths: BTreeMap<String, Option<DiskSpace>>,
+}
+
+/// Returns the total/used/available bytes for each server path, for dashboards to poll.
+#[utoipa::path(get, path = "/api/space", responses((status = 200, body = DiskSpaceResponse)))]
+#[instrument(level = "debug", skip(state, claims))]
+pub(crate) async fn disk_space_get(
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+) -> Result<Json<DiskSpaceResponse>, Error> {
+    let user = check_login(claims)?;
+    debug!("User {} requested disk space", user.username());
+
+    let server_reader = state.configuration.read().await;
+
+    let server_paths = server_reader
+        .server_paths
+        .iter()
+        .map(|(name, server_path)| {
+            let space = fs_for_user(server_path, Some(&user.username()))
+                .and_then(|filekidfs| filekidfs.disk_space())
+                .unwrap_or_else(|err| {
+                    error!("Failed to get disk space for {}: {:?}", name, err);
+                    None
+                });
+            (name.to_owned(), space)
+        })
+        .collect();
+
+    Ok(Json(DiskSpaceResponse { server_paths }))
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct ChecksumQuery {
+    /// `sha256` (the default), `md5` or `crc32`
+    algo: Option<String>,
+}
+
+/// Only the requested algorithm's field is included
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub(crate) struct ChecksumResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    md5: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crc32: Option<String>,
+}
+
+/// Returns the SHA-256 (or MD5/CRC32, with `?algo=`) of a file, so clients can verify what they've got without downloading it again.
+#[utoipa::path(
+    get,
+    path = "/checksum/{server_path}/{filepath}",
+    params(("server_path" = String, Path, description = "The name of the server path"), ("filepath" = String, Path, description = "The key in the server path, slashes and all"), ChecksumQuery),
+    responses(
+        (status = 200, body = ChecksumResponse),
+        (status = 404, description = "The server path or file doesn't exist")
+    )
+)]
+#[instrument(level = "debug", skip(state, claims))]
+pub(crate) async fn checksum_get(
+    State(state): State<WebState>,
+    Path((server_path, filepath)): Path<(String, String)>,
+    Query(query): Query<ChecksumQuery>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+) -> Result<Json<ChecksumResponse>, Error> {
+    let user = check_login(claims)?;
+    let algorithm = match query.algo.as_deref() {
+        Some(algo) => ChecksumAlgo::from_str(algo)?,
+        None => ChecksumAlgo::default(),
+    };
+
+    let server_reader = state.configuration.read().await;
+
+    let server_path_object = match server_reader.server_paths.get(&server_path) {
+        None => {
+            error!("Couldn't find server path {}", server_path);
+            return Err(Error::NotFound(server_path));
+        }
+        Some(p) => p,
+    };
+    server_path_object.check_method(&Method::GET)?;
+
+    let filekidfs = fs_for_user(server_path_object, Some(&user.username()))?;
+
+    if !filekidfs.is_file(&filepath) {
+        return Err(Error::NotFound(filepath));
+    }
+
+    debug!(
+        "User {} requested the checksum of {} on {}",
+        user.username(),
+        filepath,
+        server_path
+    );
+    // hashing big files takes a while, so keep it off the async workers
+    let checksum = tokio::task::spawn_blocking(move || filekidfs.checksum(&filepath, algorithm))
+        .await
+        .map_err(|err| {
+            Error::InternalServerError(format!("Checksum task failed to run: {err}"))
+        })??;
+
+    Ok(Json(match algorithm {
+        ChecksumAlgo::Sha256 => ChecksumResponse {
+            sha256: Some(checksum),
+            ..Default::default()
+        },
+        ChecksumAlgo::Md5 => ChecksumResponse {
+            md5: Some(checksum),
+            ..Default::default()
+        },
+        ChecksumAlgo::Crc32 => ChecksumResponse {
+            crc32: Some(checksum),
+            ..Default::default()
+        },
+    }))
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct StatResponse {
+    filename: String,
+    size: Option<u64>,
+    /// RFC3339, in UTC
+    modified: Option<String>,
+    is_dir: bool,
+}
+
+/// Returns the metadata of a file or directory, so scripts can poll for changes.
+#[utoipa::path(
+    get,
+    path = "/stat/{server_path}/{filepath}",
+    params(("server_path" = String, Path, description = "The name of the server path"), ("filepath" = String, Path, description = "The key in the server path, slashes and all")),
+    responses(
+        (status = 200, body = StatResponse),
+        (status = 404, description = "The server path or key doesn't exist")
+    )
+)]
+#[instrument(level = "debug", skip(state, claims))]
+pub(crate) async fn stat_get(
+    State(state): State<WebState>,
+    Path((server_path, filepath)): Path<(String, String)>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+) -> Result<Json<StatResponse>, Error> {
+    let user = check_login(claims)?;
+
+    let server_reader = state.configuration.read().await;
+
+    let server_path_object = match server_reader.server_paths.get(&server_path) {
+        None => {
+            error!("Couldn't find server path {}", server_path);
+            return Err(Error::NotFound(server_path));
+        }
+        Some(p) => p,
+    };
+    server_path_object.check_method(&Method::GET)?;
+
+    let filekidfs = fs_for_user(server_path_object, Some(&user.username()))?;
+
+    if !filekidfs.exists(&filepath)? {
+        return Err(Error::NotFound(filepath));
+    }
+    debug!(
+        "User {} requested the stat of {} on {}",
+        user.username(),
+        filepath,
+        server_path
+    );
+
+    let data = filekidfs.get_data(&filepath)?;
+    Ok(Json(StatResponse {
+        filename: data.filename,
+        size: data.size,
+        modified: data.modified.map(|modified| {
+            chrono::DateTime::<chrono::Utc>::from(modified)
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+        }),
+        is_dir: filekidfs.is_dir(&filepath),
+    }))
+}
+
+/// The OpenAPI description of the endpoints in this module, served by [openapi_get]
+#[derive(OpenApi)]
+#[openapi(paths(
+    tree_nopath,
+    tree_get,
+    list_nopath,
+    list_get,
+    disk_space_get,
+    checksum_get,
+    stat_get
+))]
+pub(crate) struct ApiDoc;
+
+/// Serves [ApiDoc] if [crate::config::Config::api_docs_enabled] is set
+pub(crate) async fn openapi_get(
+    State(state): State<WebState>,
+) -> Result<Json<utoipa::openapi::OpenApi>, Error> {
+    if !state.configuration.read().await.api_docs_enabled {
+        return Err(Error::NotFound(Urls::ApiDocs.as_ref().to_string()));
+    }
+    Ok(Json(ApiDoc::openapi()))
+}
+
+/// Hides Swagger UI unless [crate::config::Config::api_docs_enabled] is set
+pub(crate) async fn require_api_docs(
+    State(state): State<WebState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, Error> {
+    if !state.configuration.read().await.api_docs_enabled {
+        return Err(Error::NotFound(request.uri().path().to_string()));
+    }
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::views::oidc::test_user_claims;
+
+    fn fixture_tree() -> tempfile::TempDir {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::create_dir_all(temp_dir.path().join("sub/deeper")).expect("Failed to create dirs");
+        std::fs::write(temp_dir.path().join("a.txt"), b"hello").expect("Failed to write file");
+        std::fs::write(temp_dir.path().join("ignored.tmp"), b"nope").expect("Failed to write file");
+        std::fs::write(temp_dir.path().join("sub/b.txt"), b"hi").expect("Failed to write file");
+        std::fs::write(temp_dir.path().join("sub/deeper/c.txt"), b"c")
+            .expect("Failed to write file");
+        temp_dir
+    }
+
+    async fn fixture_state(
+        temp_dir: &tempfile::TempDir,
+        max_browse_depth: Option<usize>,
+    ) -> WebState {
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "test".to_string(),
+            ServerPath {
+                max_browse_depth,
+                ignore_patterns: vec!["*.tmp".to_string()],
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        WebState::test_webstate_with_config(config).await
+    }
+
+    #[tokio::test]
+    async fn test_tree_get() {
+        let temp_dir = fixture_tree();
+        let state = fixture_state(&temp_dir, None).await;
+
+        let Json(tree) = tree_nopath(
+            state.to_state(),
+            Path("test".to_string()),
+            Some(test_user_claims()),
+        )
+        .await
+        .expect("Failed to get tree");
+
+        assert_eq!(
+            serde_json::to_value(&tree).expect("Failed to serialize tree"),
+            serde_json::json!({
+                "server_path": "test",
+                "path": "",
+                "truncated": false,
+                "children": [
+                    {
+                        "name": "sub",
+                        "path": "sub",
+                        "type": "directory",
+                        "children": [
+                            {
+                                "name": "deeper",
+                                "path": "sub/deeper",
+                                "type": "directory",
+                                "children": [
+                                    {"name": "c.txt", "path": "sub/deeper/c.txt", "type": "file", "size": 1}
+                                ]
+                            },
+                            {"name": "b.txt", "path": "sub/b.txt", "type": "file", "size": 2}
+                        ]
+                    },
+                    {"name": "a.txt", "path": "a.txt", "type": "file", "size": 5}
+                ]
+            })
+        );
+
+        let Json(subtree) = tree_get(
+            state.to_state(),
+            Path(("test".to_string(), Some("sub/deeper".to_string()))),
+            Some(test_user_claims()),
+        )
+        .await
+        .expect("Failed to get subtree");
+        assert_eq!(subtree.path, "sub/deeper");
+        assert_eq!(subtree.children.len(), 1);
+
+        assert!(tree_get(
+            state.to_state(),
+            Path(("test".to_string(), Some("a.txt".to_string()))),
+            Some(test_user_claims()),
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tree_get_depth_limit() {
+        let temp_dir = fixture_tree();
+        let state = fixture_state(&temp_dir, Some(1)).await;
+
+        let Json(tree) = tree_nopath(
+            state.to_state(),
+            Path("test".to_string()),
+            Some(test_user_claims()),
+        )
+        .await
+        .expect("Failed to get tree");
+
+        assert_eq!(tree.children.len(), 2);
+        assert_eq!(tree.children[0].name, "sub");
+        assert!(tree.children[0].children.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_tree_get_node_limit() {
+        let temp_dir = fixture_tree();
+        let filekidfs = crate::fs::local::LocalFs::new(temp_dir.path().to_path_buf());
+        let server_path = ServerPath::test_local(temp_dir.path());
+
+        let (children, truncated) = build_tree(
+            &filekidfs,
+            None,
+            &TreeLimits {
+                max_depth: 16,
+                max_nodes: 2,
+                max_list_entries: usize::MAX,
+                server_path: &server_path,
+            },
+        )
+        .expect("Failed to build tree");
+        assert!(truncated);
+        assert_eq!(children.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_disk_space_get() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let mut config = Config::test_config();
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(temp_dir.path()));
+        config.server_paths.insert(
+            "bucket".to_string(),
+            ServerPath {
+                type_: crate::fs::FileKidFsType::S3,
+                bucket: Some("filekid".to_string()),
+                ..Default::default()
+            },
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let Json(response) = disk_space_get(state.to_state(), Some(test_user_claims()))
+            .await
+            .expect("Failed to get disk space");
+
+        let space = response
+            .server_paths
+            .get("test")
+            .expect("Missing test server path")
+            .expect("Local paths should have disk space");
+        assert!(space.total > 0);
+        assert!(space.used <= space.total);
+        assert!(space.available <= space.total);
+        assert_eq!(response.server_paths.get("bucket"), Some(&None));
+
+        assert!(disk_space_get(state.to_state(), None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_checksum_get() {
+        let temp_dir = fixture_tree();
+        let state = fixture_state(&temp_dir, None).await;
+
+        let checksum = |filepath: &str, algo: Option<&str>| {
+            checksum_get(
+                state.to_state(),
+                Path(("test".to_string(), filepath.to_string())),
+                Query(ChecksumQuery {
+                    algo: algo.map(str::to_string),
+                }),
+                Some(test_user_claims()),
+            )
+        };
+
+        let Json(response) = checksum("a.txt", None)
+            .await
+            .expect("Failed to get checksum");
+        // sha256 of "hello"
+        assert_eq!(
+            serde_json::to_value(&response).expect("Failed to serialize"),
+            serde_json::json!({
+                "sha256": "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+            })
+        );
+
+        for (algo, expected) in [
+            (
+                "sha256",
+                serde_json::json!({"sha256": "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"}),
+            ),
+            (
+                "md5",
+                serde_json::json!({"md5": "5d41402abc4b2a76b9719d911017c592"}),
+            ),
+            ("crc32", serde_json::json!({"crc32": "3610a686"})),
+        ] {
+            let Json(response) = checksum("a.txt", Some(algo))
+                .await
+                .expect("Failed to get checksum");
+            assert_eq!(
+                serde_json::to_value(&response).expect("Failed to serialize"),
+                expected
+            );
+        }
+        assert!(matches!(
+            checksum("a.txt", Some("sha1")).await,
+            Err(Error::BadRequest(_))
+        ));
+
+        assert_eq!(
+            checksum("sub", None).await.err(),
+            Some(Error::NotFound("sub".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stat_get() {
+        let temp_dir = fixture_tree();
+        let state = fixture_state(&temp_dir, None).await;
+
+        let stat = |filepath: &str| {
+            stat_get(
+                state.to_state(),
+                Path(("test".to_string(), filepath.to_string())),
+                Some(test_user_claims()),
+            )
+        };
+
+        let Json(response) = stat("a.txt").await.expect("Failed to stat file");
+        let response = serde_json::to_value(&response).expect("Failed to serialize");
+        let modified = response["modified"]
+            .as_str()
+            .expect("modified should be set for a fresh file");
+        assert!(chrono::DateTime::parse_from_rfc3339(modified).is_ok());
+        assert_eq!(
+            response,
+            serde_json::json!({
+                "filename": "a.txt",
+                "size": 5,
+                "modified": modified,
+                "is_dir": false,
+            })
+        );
+
+        let Json(response) = stat("sub").await.expect("Failed to stat dir");
+        assert!(response.is_dir);
+        assert_eq!(response.filename, "sub");
+
+        assert_eq!(
+            stat("missing.txt").await.err(),
+            Some(Error::NotFound("missing.txt".to_string()))
+        );
+        assert!(matches!(
+            stat("../etc/passwd").await,
+            Err(Error::NotAuthorized(_)) | Err(Error::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_list_continuation_tokens() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        for dirname in ["dir_b", "dir_a"] {
+            std::fs::create_dir(temp_dir.path().join(dirname)).expect("Failed to create dir");
+        }
+        for filename in ["e.txt", "a.txt", "d.txt", "c.txt", "b.txt", "skip.tmp"] {
+            std::fs::write(temp_dir.path().join(filename), b"x").expect("Failed to write file");
+        }
+        let state = fixture_state(&temp_dir, None).await;
+        let list = |token: Option<String>| {
+            list_nopath(
+                state.to_state(),
+                Path("test".to_string()),
+                Query(ListQuery {
+                    limit: Some(2),
+                    token,
+                    ..Default::default()
+                }),
+                Some(test_user_claims()),
+            )
+        };
+
+        let mut seen = Vec::new();
+        let mut token = None;
+        let mut pages = 0;
+        loop {
+            let Json(page) = list(token).await.expect("Failed to list");
+            assert!(page.entries.len() <= 2);
+            seen.extend(page.entries.into_iter().filter_map(|entry| entry.filename));
+            pages += 1;
+            if pages == 2 {
+                // things changing behind the cursor don't shift what comes next
+                std::fs::write(temp_dir.path().join("0.txt"), b"x").expect("Failed to write");
+                std::fs::remove_file(temp_dir.path().join("a.txt")).expect("Failed to remove");
+            }
+            token = page.next_token;
+            if token.is_none() {
+                break;
+            }
+        }
+        assert_eq!(
+            seen,
+            vec!["dir_a", "dir_b", "a.txt", "b.txt", "c.txt", "d.txt", "e.txt"]
+        );
+        assert_eq!(pages, 4);
+
+        assert!(matches!(
+            list(Some("not a token".to_string())).await,
+            Err(Error::BadRequest(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_list_fields() {
+        let temp_dir = fixture_tree();
+        let state = fixture_state(&temp_dir, None).await;
+        let list = |fields: &str| {
+            list_nopath(
+                state.to_state(),
+                Path("test".to_string()),
+                Query(ListQuery {
+                    fields: Some(fields.to_string()),
+                    ..Default::default()
+                }),
+                Some(test_user_claims()),
+            )
+        };
+
+        let Json(page) = list("name,size").await.expect("Failed to list");
+        assert_eq!(
+            serde_json::to_value(&page.entries).expect("Failed to serialize"),
+            serde_json::json!([
+                {"filename": "sub", "size": null},
+                {"filename": "a.txt", "size": 5},
+            ])
+        );
+
+        let Json(page) = list("name,hash,mime").await.expect("Failed to list");
+        assert_eq!(
+            serde_json::to_value(&page.entries).expect("Failed to serialize"),
+            serde_json::json!([
+                {"filename": "sub", "mime": null, "sha256": null},
+                {
+                    "filename": "a.txt",
+                    "mime": "text/plain",
+                    // sha256 of "hello"
+                    "sha256": "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+                },
+            ])
+        );
+
+        assert!(matches!(
+            list("name,owner").await,
+            Err(Error::BadRequest(_))
+        ));
+    }
+}
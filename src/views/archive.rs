@@ -0,0 +1,415 @@
+//! Downloading whole directories as zip files, streamed out while they're being built
+
+use std::io::Write;
+
+use axum::body::{Body, Bytes};
+use axum::extract::Path;
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::http::{HeaderMap, HeaderValue};
+use axum::response::Response;
+use futures::StreamExt;
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use tracing::warn;
+use zip::write::{SimpleFileOptions, StreamWriter};
+use zip::{CompressionMethod, ZipWriter};
+
+use super::{check_login, prelude::*, FileType};
+use crate::constants::{ZIP_CHANNEL_CHUNKS, ZIP_CHUNK_BYTES};
+use crate::fs::{fs_for_user, walk_dir, FileKidFs};
+use crate::views::browse::content_disposition;
+
+type ChunkTx = mpsc::Sender<Result<Bytes, std::io::Error>>;
+type ArchiveWriter = ZipWriter<StreamWriter<ChunkSender>>;
+
+/// Hands the archive to the response body in [ZIP_CHUNK_BYTES] pieces, so nothing big builds up in memory.
+///
+/// Sends fail once the client's gone, which stops the archive being built.
+struct ChunkSender {
+    tx: ChunkTx,
+    buffer: Vec<u8>,
+}
+
+impl Write for ChunkSender {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= ZIP_CHUNK_BYTES {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = Bytes::from(std::mem::take(&mut self.buffer));
+        self.tx
+            .blocking_send(Ok(chunk))
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "client went away"))
+    }
+}
+
+/// Walks `root` and writes everything that's not ignored into a zip, runs on a blocking thread
+fn write_archive(
+    handle: &Handle,
+    filekidfs: &dyn FileKidFs,
+    server_path: &ServerPath,
+    root: Option<&str>,
+    max_list_entries: usize,
+    writer: ChunkSender,
+) -> Result<(), Error> {
+    let prefix = root.map(|root| format!("{root}/")).unwrap_or_default();
+    let mut zip = ZipWriter::new_stream(writer);
+    let mut failure = None;
+
+    let complete = walk_dir(
+        root.map(str::to_string),
+        |key| filekidfs.list_dir(key, max_list_entries),
+        |entry| {
+            if entry
+                .fullpath
+                .split('/')
+                .any(|part| server_path.is_ignored(part))
+            {
+                return true;
+            }
+            let name = entry
+                .fullpath
+                .strip_prefix(&prefix)
+                .unwrap_or(&entry.fullpath)
+                .to_string();
+            let result = match entry.filetype {
+                FileType::Directory => zip
+                    .add_directory(name, SimpleFileOptions::default())
+                    .map_err(Error::from),
+                FileType::File => add_file(
+                    handle,
+                    filekidfs,
+                    &mut zip,
+                    &entry.fullpath,
+                    name,
+                    entry.size,
+                ),
+            };
+            match result {
+                Ok(()) => true,
+                Err(err) => {
+                    failure = Some(err);
+                    false
+                }
+            }
+        },
+    )?;
+    if let Some(err) = failure {
+        return Err(err);
+    }
+    // leaving the zip unfinished means the client gets a broken download rather than a short one
+    if !complete {
+        return Err(Error::InternalServerError(format!(
+            "Couldn't walk all of {}, it's too deep or something in it couldn't be listed",
+            root.unwrap_or("/")
+        )));
+    }
+    zip.finish()?.flush()?;
+    Ok(())
+}
+
+fn add_file(
+    handle: &Handle,
+    filekidfs: &dyn FileKidFs,
+    zip: &mut ArchiveWriter,
+    key: &str,
+    name: String,
+    size: Option<u64>,
+) -> Result<(), Error> {
+    // things like symlinks that lead outside the server path can't be read, so they're left out
+    let body = match handle.block_on(filekidfs.read_file(key)) {
+        Ok(body) => body,
+        Err(err) => {
+            warn!("Leaving {} out of the zip: {:?}", key, err);
+            return Ok(());
+        }
+    };
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .large_file(size.unwrap_or(u64::MAX) >= u64::from(u32::MAX));
+    zip.start_file(name, options)?;
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = handle.block_on(stream.next()) {
+        zip.write_all(&chunk.map_err(|err| Error::Io(err.to_string()))?)?;
+    }
+    // get each file's tail out to the client rather than waiting for the next one
+    zip.flush()?;
+    Ok(())
+}
+
+pub(crate) async fn zip_nopath(
+    State(state): State<WebState>,
+    Path(server_path): Path<String>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+) -> Result<Response, Error> {
+    zip_get(State(state), Path((server_path, None)), claims).await
+}
+
+/// Downloads a directory as a zip, which starts arriving straight away rather than once it's all been built
+#[instrument(level = "debug", skip(state, claims))]
+pub(crate) async fn zip_get(
+    State(state): State<WebState>,
+    Path((server_path, filepath)): Path<(String, Option<String>)>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+) -> Result<Response, Error> {
+    let user = check_login(claims)?;
+    let server_reader = state.configuration.read().await;
+    let server_path_object = match server_reader.server_paths.get(&server_path) {
+        None => {
+            error!("Couldn't find server path {}", server_path);
+            return Err(Error::NotFound(server_path));
+        }
+        Some(p) => p.clone(),
+    };
+    server_path_object.check_method(&Method::GET)?;
+    if !server_path_object.zip_downloads {
+        return Err(Error::NotAuthorized(
+            "zip downloads are turned off for this server path".to_string(),
+        ));
+    }
+    let ascii_fallback = server_reader.ascii_filename_fallback;
+    let max_list_entries = server_reader.max_list_entries;
+    drop(server_reader);
+
+    let filekidfs = fs_for_user(&server_path_object, Some(&user.username()))?;
+    let root = filepath
+        .map(|p| p.trim_matches('/').to_string())
+        .filter(|p| !p.is_empty());
+    let exists = match &root {
+        Some(root) => filekidfs.is_dir(root),
+        None => filekidfs.exists("")?,
+    };
+    if !exists {
+        return Err(Error::NotFound(root.unwrap_or_default()));
+    }
+
+    let archive_name = format!(
+        "{}.zip",
+        root.as_deref()
+            .and_then(|root| root.rsplit('/').next())
+            .unwrap_or(&server_path)
+    );
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/zip"));
+    let disposition = content_disposition("attachment", &archive_name, ascii_fallback);
+    headers.insert(
+        CONTENT_DISPOSITION,
+        HeaderValue::from_str(&disposition).map_err(|err| {
+            Error::InternalServerError(format!(
+                "Failed to build Content-Disposition {disposition}: {err}"
+            ))
+        })?,
+    );
+
+    // a small channel means the walk only gets a few chunks ahead of the client
+    let (tx, mut rx) = mpsc::channel(ZIP_CHANNEL_CHUNKS);
+    let handle = Handle::current();
+    tokio::task::spawn_blocking(move || {
+        let writer = ChunkSender {
+            tx: tx.clone(),
+            buffer: Vec::with_capacity(ZIP_CHUNK_BYTES),
+        };
+        match write_archive(
+            &handle,
+            filekidfs.as_ref(),
+            &server_path_object,
+            root.as_deref(),
+            max_list_entries,
+            writer,
+        ) {
+            Ok(()) => debug!("Finished zipping {}", archive_name),
+            Err(_) if tx.is_closed() => debug!("Client stopped downloading {}", archive_name),
+            Err(err) => {
+                error!("Failed to zip {}: {:?}", archive_name, err);
+                // erroring the body cuts the connection, so the client knows it's not got the whole thing
+                let _ = tx.blocking_send(Err(std::io::Error::other(err.to_string())));
+            }
+        }
+    });
+
+    // there's no Content-Length, so HTTP/1.1 clients get it chunked as it's sent
+    let body = Body::from_stream(futures::stream::poll_fn(move |cx| rx.poll_recv(cx)));
+    Ok((headers, body).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::constants::MAX_SEARCH_DEPTH;
+    use crate::views::oidc::test_user_claims;
+
+    #[tokio::test]
+    async fn test_zip_get_streams() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let base = temp_dir.path().join("builds");
+        std::fs::create_dir_all(base.join("sub")).expect("Failed to create dirs");
+        // noise doesn't compress, so the archive's a lot bigger than one chunk
+        let mut seed: u32 = 1;
+        let mut noise = |len: usize| {
+            (0..len)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    (seed >> 16) as u8
+                })
+                .collect::<Vec<u8>>()
+        };
+        for index in 0..8 {
+            std::fs::write(base.join(format!("file{index}.bin")), noise(256 * 1024))
+                .expect("Failed to write file");
+        }
+        std::fs::write(base.join("sub/small.txt"), b"hello").expect("Failed to write file");
+        std::fs::write(base.join("skip.tmp"), b"nope").expect("Failed to write file");
+
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "test".to_string(),
+            ServerPath {
+                zip_downloads: true,
+                ignore_patterns: vec!["*.tmp".to_string()],
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        config
+            .server_paths
+            .insert("nozip".to_string(), ServerPath::test_local(temp_dir.path()));
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let response = zip_get(
+            state.to_state(),
+            Path(("test".to_string(), Some("builds".to_string()))),
+            Some(test_user_claims()),
+        )
+        .await
+        .expect("Failed to start zip");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE),
+            Some(&HeaderValue::from_static("application/zip"))
+        );
+        assert_eq!(
+            response.headers().get(CONTENT_DISPOSITION),
+            Some(&HeaderValue::from_static(
+                "attachment; filename=\"builds.zip\""
+            ))
+        );
+
+        let mut stream = response.into_body().into_data_stream();
+        let first = stream
+            .next()
+            .await
+            .expect("No data")
+            .expect("Failed to read chunk");
+        assert!(first.starts_with(b"PK\x03\x04"));
+        assert!(first.len() < 256 * 1024);
+
+        let mut archive = first.to_vec();
+        while let Some(chunk) = stream.next().await {
+            archive.extend_from_slice(&chunk.expect("Failed to read chunk"));
+        }
+        assert!(archive.len() > 8 * 256 * 1024);
+
+        let mut archive =
+            zip::ZipArchive::new(std::io::Cursor::new(archive)).expect("Failed to read zip");
+        let mut names = archive.file_names().map(str::to_string).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names.len(), 10);
+        assert!(names.contains(&"sub/".to_string()));
+        assert!(!names.iter().any(|name| name.ends_with(".tmp")));
+        let mut small = String::new();
+        std::io::Read::read_to_string(
+            &mut archive.by_name("sub/small.txt").expect("Missing file"),
+            &mut small,
+        )
+        .expect("Failed to read file");
+        assert_eq!(small, "hello");
+
+        assert!(matches!(
+            zip_get(
+                state.to_state(),
+                Path(("nozip".to_string(), None)),
+                Some(test_user_claims())
+            )
+            .await,
+            Err(Error::NotAuthorized(_))
+        ));
+        assert!(matches!(
+            zip_get(
+                state.to_state(),
+                Path(("test".to_string(), Some("missing".to_string()))),
+                Some(test_user_claims())
+            )
+            .await,
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_zip_get_requires_login() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "test".to_string(),
+            ServerPath {
+                zip_downloads: true,
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+
+        assert!(matches!(
+            zip_get(state.to_state(), Path(("test".to_string(), None)), None).await,
+            Err(Error::NotAuthorized(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_zip_get_incomplete_walk() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        // one level deeper than the walk goes
+        let deep = (0..=MAX_SEARCH_DEPTH)
+            .map(|depth| format!("d{depth}"))
+            .collect::<Vec<_>>()
+            .join("/");
+        std::fs::create_dir_all(temp_dir.path().join(&deep)).expect("Failed to create dirs");
+        std::fs::write(temp_dir.path().join(deep).join("lost.txt"), b"hello")
+            .expect("Failed to write file");
+
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "test".to_string(),
+            ServerPath {
+                zip_downloads: true,
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let response = zip_get(
+            state.to_state(),
+            Path(("test".to_string(), None)),
+            Some(test_user_claims()),
+        )
+        .await
+        .expect("Failed to start zip");
+        let mut stream = response.into_body().into_data_stream();
+        let mut failed = false;
+        while let Some(chunk) = stream.next().await {
+            if chunk.is_err() {
+                failed = true;
+                break;
+            }
+        }
+        assert!(
+            failed,
+            "A zip that's missing things should fail the download"
+        );
+    }
+}
@@ -1,21 +1,215 @@
 //! This module contains the browse endpoint, which allows users to browse the files on the server.
 use std::fs::DirEntry;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use axum::body::Bytes;
-use axum::extract::{Multipart, Path};
-use axum::http::header::CONTENT_TYPE;
-use axum::http::HeaderMap;
+use axum::extract::{Multipart, Path, Query};
+use axum::http::header::{
+    ACCEPT_ENCODING, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_ENCODING,
+    CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, VARY,
+};
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
 use axum::response::{Html, Redirect, Response};
+use axum::Form;
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, TryStreamExt};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
 use tracing::{debug, warn};
 
-use super::{prelude::*, FileType};
-use crate::fs::fs_from_serverpath;
-use crate::oidc::check_login;
+use super::{check_if_match, content_etag, file_etag, http_date, human_size, prelude::*, FileType};
+use crate::audit::{self, AuditAction, AuditRecord};
+use crate::constants::{
+    DEFAULT_PAGE_SIZE, DIR_USAGE_MAX_ENTRIES, DIR_USAGE_TIMEOUT_SECS, IMMUTABLE_MAX_AGE_SECS,
+    INDEX_FILENAME, LATEST_ALIAS, MAX_PAGE_SIZE, UPLOAD_STAGING_PREFIX,
+};
+use crate::fs::{
+    check_content_length, check_unique_basename, create_parent_dirs, explain_failure, fs_for_user,
+    fs_from_serverpath, key_escapes_base, page_of, sanitize_filename, sort_entries, DirUsage,
+    FileKidFs, SortBy, SortOrder,
+};
+use crate::oidc::{check_login, User};
+use crate::text::to_utf8;
+
+const DEPRECATION: HeaderName = HeaderName::from_static("deprecation");
+/// What's escaped in the path or URI handed to the reverse proxy by [crate::config::Config::sendfile_header], `/` isn't
+const SENDFILE_PATH: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'#').add(b'%').add(b'?');
+const SUNSET: HeaderName = HeaderName::from_static("sunset");
+
+/// Builds a `Content-Disposition` value, non-ASCII filenames are sent as an RFC 5987 `filename*`.
+///
+/// If `ascii_fallback` is set, those also get a transliterated `filename=` for older clients.
+pub(crate) fn content_disposition(
+    disposition: &str,
+    filename: &str,
+    ascii_fallback: bool,
+) -> String {
+    let quoted = |name: &str| {
+        name.chars()
+            .filter(|c| !c.is_control())
+            .map(|c| match c {
+                '"' | '\\' => format!("\\{c}"),
+                c => c.to_string(),
+            })
+            .collect::<String>()
+    };
+    if filename.is_ascii() {
+        return format!("{disposition}; filename=\"{}\"", quoted(filename));
+    }
+
+    let mut encoded = String::new();
+    for byte in filename.bytes() {
+        match byte {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'!'
+            | b'#'
+            | b'$'
+            | b'&'
+            | b'+'
+            | b'-'
+            | b'.'
+            | b'^'
+            | b'_'
+            | b'`'
+            | b'|'
+            | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    match ascii_fallback {
+        true => format!(
+            "{disposition}; filename=\"{}\"; filename*=UTF-8''{encoded}",
+            quoted(&deunicode::deunicode(filename))
+        ),
+        false => format!("{disposition}; filename*=UTF-8''{encoded}"),
+    }
+}
+
+/// Hands back `result`, unless it failed because the server path's backend is down, see [explain_failure]
+async fn explained<T>(
+    state: &WebState,
+    server_path: &str,
+    result: Result<T, Error>,
+) -> Result<T, Error> {
+    match result {
+        Ok(value) => Ok(value),
+        // these are about the request, not the backend
+        Err(
+            err @ (Error::NotAuthorized(_)
+            | Error::BadRequest(_)
+            | Error::MethodNotAllowed(_)
+            | Error::InvalidFileType(_)),
+        ) => Err(err),
+        Err(err) => {
+            let server_reader = state.configuration.read().await;
+            match server_reader.server_paths.get(server_path) {
+                Some(server_path_object) => Err(explain_failure(
+                    server_path,
+                    server_path_object,
+                    server_reader.retry_after_secs,
+                    server_reader.available_timeout(),
+                    err,
+                )
+                .await),
+                None => Err(err),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct GetFileQuery {
+    /// `?download=1` asks the browser to save the file instead of showing it
+    download: Option<String>,
+}
+
+impl GetFileQuery {
+    fn download(&self) -> bool {
+        matches!(self.download.as_deref(), Some("1" | "true"))
+    }
+}
 
 pub(crate) async fn get_file(
     State(state): State<WebState>,
     Path((server_path, filepath)): Path<(String, String)>,
-) -> Result<impl IntoResponse, Error> {
+    Query(query): Query<GetFileQuery>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    request_headers: HeaderMap,
+) -> Result<Response, Error> {
+    let result = serve_file(
+        State(state.clone()),
+        Path((server_path.clone(), filepath)),
+        request_headers,
+        query.download(),
+        false,
+        claims.map(|claims| User::from(claims).username()),
+    )
+    .await;
+    explained(&state, &server_path, result).await
+}
+
+/// The headers [get_file] would send, without reading the file where that can be helped
+pub(crate) async fn head_file(
+    State(state): State<WebState>,
+    Path((server_path, filepath)): Path<(String, String)>,
+    Query(query): Query<GetFileQuery>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    request_headers: HeaderMap,
+) -> Result<Response, Error> {
+    let result = serve_file(
+        State(state.clone()),
+        Path((server_path.clone(), filepath)),
+        request_headers,
+        query.download(),
+        true,
+        claims.map(|claims| User::from(claims).username()),
+    )
+    .await;
+    explained(&state, &server_path, result).await
+}
+
+/// Whether the client's copy is still good, going by `If-None-Match` or failing that `If-Modified-Since`
+fn not_modified(
+    request_headers: &HeaderMap,
+    etag: Option<&str>,
+    modified: Option<DateTime<Utc>>,
+) -> bool {
+    // weak comparison, the W/ prefix doesn't matter
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if let Some(if_none_match) = request_headers
+        .get(IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+    {
+        return etag.is_some_and(|etag| {
+            if_none_match
+                .split(',')
+                .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+        });
+    }
+    match (
+        modified,
+        request_headers
+            .get(IF_MODIFIED_SINCE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| DateTime::parse_from_rfc2822(value).ok()),
+    ) {
+        (Some(modified), Some(since)) => modified.timestamp() <= since.timestamp(),
+        _ => false,
+    }
+}
+
+pub(crate) async fn serve_file(
+    State(state): State<WebState>,
+    Path((server_path, filepath)): Path<(String, String)>,
+    request_headers: HeaderMap,
+    download: bool,
+    head: bool,
+    username: Option<String>,
+) -> Result<Response, Error> {
     let server_reader = state.configuration.read().await;
     let server_path_object = match server_reader.server_paths.get(&server_path) {
         None => {
@@ -24,18 +218,48 @@ pub(crate) async fn get_file(
         }
         Some(p) => p,
     };
+    server_path_object.check_method(match head {
+        true => &Method::HEAD,
+        false => &Method::GET,
+    })?;
 
-    let filekidfs = fs_from_serverpath(server_path_object)?;
+    let filekidfs = fs_for_user(server_path_object, username.as_deref())?;
 
     if !filekidfs.exists(&filepath)? {
+        // a real file called "latest" wins over the alias
+        if server_path_object.latest_alias
+            && let Some(parent) = latest_alias_parent(&filepath)
+        {
+            let newest = newest_file(
+                server_path_object,
+                filekidfs.list_dir(parent, server_reader.max_list_entries)?,
+            )
+            .ok_or_else(|| Error::NotFound(filepath.to_string()))?;
+            return Ok(Redirect::temporary(&format!(
+                "{}/{}/{}",
+                Urls::GetFile.as_ref(),
+                server_path,
+                newest.fullpath
+            ))
+            .into_response());
+        }
         error!("Couldn't find file!");
         return Err(Error::NotFound(filepath.to_string()));
     }
+    if filekidfs.is_dir(&filepath) {
+        return Ok(Redirect::permanent(&format!(
+            "{}/{}/{}",
+            Urls::Browse.as_ref(),
+            server_path,
+            filepath
+        ))
+        .into_response());
+    }
 
-    let mime_type = mime_guess::from_path(&filepath)
-        .first_or_octet_stream()
-        .to_string();
+    let mime_type = server_path_object.mime_type(&filepath);
     let mut headers = HeaderMap::new();
+    // there's no Range support, so clients shouldn't try to resume
+    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("none"));
     headers.insert(
         CONTENT_TYPE,
         mime_type.parse().map_err(|err| {
@@ -49,11 +273,245 @@ pub(crate) async fn get_file(
             ))
         })?,
     );
-    Ok((
-        StatusCode::OK,
-        headers,
-        filekidfs.get_file(&filepath).await?,
-    ))
+    let filename = filepath.rsplit('/').next().unwrap_or(&filepath);
+    let disposition = content_disposition(
+        match download {
+            true => "attachment",
+            false => "inline",
+        },
+        filename,
+        server_reader.ascii_filename_fallback,
+    );
+    headers.insert(
+        CONTENT_DISPOSITION,
+        HeaderValue::from_str(&disposition).map_err(|err| {
+            Error::InternalServerError(format!(
+                "Failed to build Content-Disposition {disposition}: {err}"
+            ))
+        })?,
+    );
+    if let Some(sunset) = server_path_object.deprecation()? {
+        // RFC 9745 wants a structured field date, RFC 8594 an HTTP-date
+        for (name, value) in [
+            (DEPRECATION, format!("@{}", sunset.timestamp())),
+            (SUNSET, http_date(sunset)),
+        ] {
+            let header_value = HeaderValue::from_str(&value).map_err(|err| {
+                Error::InternalServerError(format!("Failed to build {name} {value}: {err}"))
+            })?;
+            headers.insert(name, header_value);
+        }
+    }
+    let encoding = match mime_type.starts_with("text/") {
+        true => server_path_object.text_encoding()?,
+        false => None,
+    };
+    // re-encoded text and content-hashed immutable files need the real thing
+    let precompressed = match server_path_object.prefer_precompressed
+        && encoding.is_none()
+        && !server_path_object.immutable
+    {
+        true => {
+            headers.insert(VARY, HeaderValue::from_static("accept-encoding"));
+            precompressed_variant(&request_headers, filekidfs.as_ref(), &filepath)
+        }
+        false => None,
+    };
+    // the variant's what gets sent, so it's what the validators and length describe
+    let filepath = match precompressed {
+        Some((variant, content_encoding)) => {
+            debug!("Sending {} for {}", variant, filepath);
+            headers.insert(CONTENT_ENCODING, HeaderValue::from_static(content_encoding));
+            variant
+        }
+        None => filepath,
+    };
+    let data = filekidfs.get_data(&filepath)?;
+    let modified = data.modified.map(DateTime::<Utc>::from);
+    if let Some(modified) = modified {
+        headers.insert(
+            LAST_MODIFIED,
+            HeaderValue::from_str(&http_date(modified)).map_err(|err| {
+                Error::InternalServerError(format!("Failed to build Last-Modified: {err}"))
+            })?,
+        );
+    }
+    // immutable paths get an ETag from the content further down
+    if !server_path_object.immutable
+        && let Some(etag) = file_etag(&data)
+    {
+        headers.insert(
+            ETAG,
+            HeaderValue::from_str(&etag).map_err(|err| {
+                Error::InternalServerError(format!("Failed to build ETag {etag}: {err}"))
+            })?,
+        );
+    }
+    let etag = headers
+        .get(ETAG)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    if !server_path_object.immutable && not_modified(&request_headers, etag.as_deref(), modified) {
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
+
+    if encoding.is_none() && !server_path_object.immutable {
+        if head {
+            if let Some(size) = data.size {
+                headers.insert(CONTENT_LENGTH, HeaderValue::from(size));
+            }
+            return Ok((StatusCode::OK, headers).into_response());
+        }
+        if let Some(sendfile_header) = &server_reader.sendfile_header
+            && let Some(local_path) = filekidfs.local_path(&filepath)?
+        {
+            // the reverse proxy sends the file (and its length), so the body's empty
+            let location = match &server_path_object.sendfile_prefix {
+                Some(prefix) => format!(
+                    "{}/{}",
+                    prefix.trim_end_matches('/'),
+                    utf8_percent_encode(&filepath, SENDFILE_PATH)
+                ),
+                None => {
+                    utf8_percent_encode(&local_path.to_string_lossy(), SENDFILE_PATH).to_string()
+                }
+            };
+            headers.insert(
+                HeaderName::from_str(sendfile_header).map_err(|err| {
+                    Error::InternalServerError(format!(
+                        "Invalid sendfile_header {sendfile_header}: {err}"
+                    ))
+                })?,
+                HeaderValue::from_str(&location).map_err(|err| {
+                    Error::InternalServerError(format!(
+                        "Failed to build {sendfile_header} {location}: {err}"
+                    ))
+                })?,
+            );
+            return Ok((StatusCode::OK, headers).into_response());
+        }
+        // nothing needs the whole file, so stream it, saying how big it is so clients can show progress
+        if let Some(size) = data.size {
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(size));
+        }
+        let body = filekidfs.read_file(&filepath).await?;
+        return Ok((StatusCode::OK, headers, body).into_response());
+    }
+
+    let mut contents = filekidfs.get_file(&filepath).await?;
+    if let Some(encoding) = encoding {
+        contents = to_utf8(&contents, encoding);
+        let content_type = format!("{mime_type}; charset=utf-8");
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_str(&content_type).map_err(|err| {
+                Error::InternalServerError(format!(
+                    "Failed to build Content-Type {content_type}: {err}"
+                ))
+            })?,
+        );
+    }
+
+    if server_path_object.immutable {
+        // hashing the content means the ETag only changes if the bytes do, unlike size/mtime
+        let etag = content_etag(&contents);
+        headers.insert(
+            ETAG,
+            etag.parse().map_err(|err| {
+                Error::InternalServerError(format!("Failed to build ETag {etag}: {err}"))
+            })?,
+        );
+        headers.insert(
+            CACHE_CONTROL,
+            HeaderValue::from_str(&format!(
+                "public, max-age={IMMUTABLE_MAX_AGE_SECS}, immutable"
+            ))
+            .map_err(|err| {
+                Error::InternalServerError(format!("Failed to build Cache-Control: {err}"))
+            })?,
+        );
+        if not_modified(&request_headers, Some(&etag), modified) {
+            return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+        }
+    }
+
+    if head {
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(contents.len()));
+        return Ok((StatusCode::OK, headers).into_response());
+    }
+    Ok((StatusCode::OK, headers, contents).into_response())
+}
+
+/// How much the `Accept-Encoding` header wants `encoding`, 0 if it's not there or refused
+fn encoding_quality(accept_encoding: &str, encoding: &str) -> f32 {
+    accept_encoding
+        .split(',')
+        .filter_map(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            match params.next()?.eq_ignore_ascii_case(encoding) {
+                true => Some(
+                    params
+                        .find_map(|param| param.strip_prefix("q="))
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .unwrap_or(1.0),
+                ),
+                false => None,
+            }
+        })
+        .fold(0.0, f32::max)
+}
+
+/// The precompressed `.br` or `.gz` file next to `filepath` the client would most like, and its `Content-Encoding`.
+///
+/// Brotli wins a tie, being smaller.
+fn precompressed_variant(
+    request_headers: &HeaderMap,
+    filekidfs: &dyn FileKidFs,
+    filepath: &str,
+) -> Option<(String, &'static str)> {
+    let accept_encoding = request_headers
+        .get(ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())?;
+    let mut variants: Vec<(f32, String, &'static str)> = [("br", "br"), ("gzip", "gz")]
+        .into_iter()
+        .map(|(encoding, extension)| {
+            (
+                encoding_quality(accept_encoding, encoding),
+                format!("{filepath}.{extension}"),
+                encoding,
+            )
+        })
+        .filter(|(quality, variant, _)| *quality > 0.0 && filekidfs.is_file(variant))
+        .collect();
+    // stable, so brotli stays first on a tie
+    variants.sort_by(|a, b| b.0.total_cmp(&a.0));
+    variants
+        .into_iter()
+        .next()
+        .map(|(_, variant, encoding)| (variant, encoding))
+}
+
+/// If `filepath` is a request for the latest alias, the directory to look in (`None` being the root)
+fn latest_alias_parent(filepath: &str) -> Option<Option<String>> {
+    match filepath.trim_end_matches('/').rsplit_once('/') {
+        Some((parent, LATEST_ALIAS)) => Some(Some(parent.to_string())),
+        None if filepath.trim_end_matches('/') == LATEST_ALIAS => Some(None),
+        _ => None,
+    }
+}
+
+/// The most recently modified, non-ignored file in `entries`
+fn newest_file(server_path: &ServerPath, entries: Vec<FileEntry>) -> Option<FileEntry> {
+    entries
+        .into_iter()
+        .filter(|entry| {
+            entry.filetype == FileType::File && !server_path.is_ignored(&entry.filename)
+        })
+        .max_by(|a, b| {
+            a.modified
+                .cmp(&b.modified)
+                .then_with(|| a.filename.cmp(&b.filename))
+        })
 }
 
 #[derive(Template)]
@@ -62,8 +520,84 @@ pub(crate) struct BrowsePage {
     server_path: String,
     entries: Vec<FileEntry>,
     parent_path: String,
+    /// Where the upload form sends files
+    upload_path: String,
     current_path: String,
     username: String,
+    /// Hides the upload/delete controls
+    read_only: bool,
+    uploads_disabled: bool,
+    /// Hides the delete buttons
+    deletions_allowed: bool,
+    /// Shows the "Download as zip" link
+    zip_downloads: bool,
+    title: String,
+    description: Option<String>,
+    /// "X of Y used", if the server path has [ServerPath::size_cache_secs] set
+    used: Option<String>,
+    page: usize,
+    per_page: usize,
+    total_pages: usize,
+    total_entries: usize,
+    sort: SortBy,
+    order: SortOrder,
+    /// Carries the sort options, `show_hidden` and `with_sizes` through the page links
+    sort_query: String,
+    /// Whether directories have their sizes counted
+    with_sizes: bool,
+}
+
+impl BrowsePage {
+    /// Links to sort by `sort_by`, clicking the current sort again flips the order
+    fn sort_link(&self, sort_by: SortBy) -> String {
+        let order = match (self.sort == sort_by, self.order) {
+            (true, SortOrder::Asc) => SortOrder::Desc,
+            _ => SortOrder::Asc,
+        };
+        format!(
+            "?sort={}&order={}&per_page={}{}",
+            sort_by.as_str(),
+            order.as_str(),
+            self.per_page,
+            match self.with_sizes {
+                true => "&with_sizes=1",
+                false => "",
+            }
+        )
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct BrowseQuery {
+    /// Starts at 1
+    page: Option<usize>,
+    per_page: Option<usize>,
+    sort: Option<SortBy>,
+    order: Option<SortOrder>,
+    /// `?show_hidden=1` (or `0`) overrides [ServerPath::show_hidden] for this listing
+    show_hidden: Option<String>,
+    /// `?with_sizes=1` counts what's in each directory, which means walking them
+    with_sizes: Option<String>,
+}
+
+impl BrowseQuery {
+    fn show_hidden(&self) -> Option<bool> {
+        match self.show_hidden.as_deref() {
+            Some("1" | "true") => Some(true),
+            Some("0" | "false") => Some(false),
+            _ => None,
+        }
+    }
+
+    fn with_sizes(&self) -> bool {
+        matches!(self.with_sizes.as_deref(), Some("1" | "true"))
+    }
+
+    fn per_page(&self) -> usize {
+        self.per_page
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE)
+    }
 }
 
 impl From<BrowsePage> for Result<Response, Error>
@@ -80,6 +614,11 @@ pub struct FileEntry {
     pub filename: String,
     pub fullpath: String,
     pub filetype: FileType,
+    /// Only set for files
+    pub size: Option<u64>,
+    pub modified: Option<SystemTime>,
+    /// Only set for directories on `?with_sizes=1` browse pages
+    pub dir_usage: Option<DirUsage>,
 }
 
 impl FileEntry {
@@ -100,6 +639,14 @@ impl FileEntry {
             ),
         }
     }
+
+    /// Saves the file rather than opening it in the browser, there's nothing to download for directories
+    pub fn download_url(&self, server_path: &impl ToString) -> Option<String> {
+        match self.filetype {
+            FileType::Directory => None,
+            FileType::File => Some(format!("{}?download=1", self.url(server_path))),
+        }
+    }
 }
 
 impl TryFrom<DirEntry> for FileEntry {
@@ -112,10 +659,17 @@ impl TryFrom<DirEntry> for FileEntry {
             .ok_or_else(|| Error::Generic("Couldn't get filename".to_string()))?;
         let filename = filename.to_string_lossy().to_string();
         let filetype = FileType::try_from(&path)?;
+        let metadata = path.metadata().ok();
         Ok(Self {
             filename,
             fullpath: path.to_string_lossy().to_string(),
+            size: metadata
+                .as_ref()
+                .filter(|metadata| metadata.is_file())
+                .map(|metadata| metadata.len()),
+            modified: metadata.and_then(|metadata| metadata.modified().ok()),
             filetype,
+            dir_usage: None,
         })
     }
 }
@@ -123,16 +677,45 @@ impl TryFrom<DirEntry> for FileEntry {
 pub(crate) async fn browse_nopath(
     State(state): State<WebState>,
     Path(server_path): Path<String>,
+    query: Query<BrowseQuery>,
     claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    request_headers: HeaderMap,
 ) -> Result<Response, Error> {
-    browse(State(state), Path((server_path, None)), claims).await
+    browse(
+        State(state),
+        Path((server_path, None)),
+        query,
+        claims,
+        request_headers,
+    )
+    .await
 }
 
-// /// Browse the files in a server path.
+/// Browse the files in a server path.
 pub(crate) async fn browse(
     State(state): State<WebState>,
     Path((server_path, filepath)): Path<(String, Option<String>)>,
+    query: Query<BrowseQuery>,
     claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    request_headers: HeaderMap,
+) -> Result<Response, Error> {
+    let result = browse_dir(
+        State(state.clone()),
+        Path((server_path.clone(), filepath)),
+        query,
+        claims,
+        request_headers,
+    )
+    .await;
+    explained(&state, &server_path, result).await
+}
+
+async fn browse_dir(
+    State(state): State<WebState>,
+    Path((server_path, filepath)): Path<(String, Option<String>)>,
+    Query(query): Query<BrowseQuery>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    request_headers: HeaderMap,
 ) -> Result<Response, Error> {
     let user = check_login(claims)?;
     debug!("User {} logged in", user.username());
@@ -146,8 +729,15 @@ pub(crate) async fn browse(
         }
         Some(p) => p,
     };
+    server_path_object.check_method(&Method::GET)?;
 
-    let filekidfs = fs_from_serverpath(server_path_object)?;
+    let listed_path = ServerPath {
+        show_hidden: query
+            .show_hidden()
+            .unwrap_or(server_path_object.show_hidden),
+        ..server_path_object.clone()
+    };
+    let filekidfs = fs_for_user(&listed_path, Some(&user.username()))?;
 
     let target_filepath = filepath
         .clone()
@@ -162,6 +752,45 @@ pub(crate) async fn browse(
         );
         return Err(Error::NotFound(filepath.unwrap_or("".into())));
     }
+    // mistaken links to files still work
+    if !target_filepath.is_empty() && filekidfs.is_file(&target_filepath) {
+        return Ok(Redirect::permanent(&format!(
+            "{}/{}/{}",
+            Urls::GetFile.as_ref(),
+            server_path,
+            target_filepath
+        ))
+        .into_response());
+    }
+
+    if server_path_object.serve_index {
+        let index = match target_filepath.trim_end_matches('/') {
+            "" => INDEX_FILENAME.to_string(),
+            dir => format!("{dir}/{INDEX_FILENAME}"),
+        };
+        if filekidfs.is_file(&index) {
+            // relative links in the page only work from a URL ending in a slash
+            if let Some(filepath) = filepath.as_ref().filter(|path| !path.ends_with('/')) {
+                return Ok(Redirect::permanent(&format!(
+                    "{}/{}/{}/",
+                    Urls::Browse.as_ref(),
+                    server_path,
+                    filepath
+                ))
+                .into_response());
+            }
+            drop(server_reader);
+            return serve_file(
+                State(state),
+                Path((server_path, index)),
+                request_headers,
+                false,
+                false,
+                Some(user.username()),
+            )
+            .await;
+        }
+    }
 
     let parent_path = match &filepath {
         Some(p) => {
@@ -172,37 +801,294 @@ pub(crate) async fn browse(
         None => "".to_string(),
     };
 
-    let mut entries: Vec<FileEntry> = filekidfs.list_dir(filepath.clone())?;
-    // sort by filename
-    entries.sort_by(|a, b| a.filename.cmp(&b.filename));
-    // sort by type to put directories first
-    entries.sort_by(|a, b| a.filetype.cmp(&b.filetype));
+    let per_page = query.per_page();
+    let page = query.page.unwrap_or(1).max(1);
+    let offset = (page - 1).saturating_mul(per_page);
+
+    let default_order = query.sort.is_none() && query.order.is_none();
+    let (entries, total_entries) =
+        match default_order && server_path_object.ignore_patterns.is_empty() {
+            true => filekidfs.list_dir_paginated(
+                filepath.clone(),
+                offset,
+                per_page,
+                server_reader.max_list_entries,
+            )?,
+            // ignored entries have to be dropped before paging, or the counts are off
+            false => {
+                let mut entries =
+                    filekidfs.list_dir(filepath.clone(), server_reader.max_list_entries)?;
+                entries.retain(|entry| !server_path_object.is_ignored(&entry.filename));
+                sort_entries(
+                    &mut entries,
+                    query.sort.unwrap_or_default(),
+                    query.order.unwrap_or_default(),
+                );
+                page_of(entries, offset, per_page)
+            }
+        };
+
+    let title = server_path_object
+        .title
+        .clone()
+        .unwrap_or_else(|| server_path.clone());
+
+    let upload_path = server_path_object
+        .upload_dir()?
+        .unwrap_or_else(|| parent_path.clone());
+
+    // one count shared by everyone's directories would be wrong for all of them
+    let used = match server_path_object
+        .size_cache_secs
+        .filter(|_| !server_path_object.is_per_user())
+    {
+        None => None,
+        Some(_) => {
+            let dir_sizes = state.dir_sizes.clone();
+            let (name, server_path_object) = (server_path.clone(), server_path_object.clone());
+            // the first count walks the whole path
+            tokio::task::spawn_blocking(move || -> Result<Option<String>, Error> {
+                let filekidfs = fs_from_serverpath(&server_path_object)?;
+                let used = dir_sizes.get(&name, &server_path_object, filekidfs.as_ref())?;
+                let total = filekidfs.disk_space()?.map(|space| space.total);
+                Ok(used.map(|used| match total {
+                    Some(total) => format!("{} of {} used", human_size(used), human_size(total)),
+                    None => format!("{} used", human_size(used)),
+                }))
+            })
+            .await
+            .map_err(|err| {
+                Error::InternalServerError(format!("Directory size task failed to run: {err}"))
+            })??
+        }
+    };
+
+    let mut sort_query = match default_order {
+        true => String::new(),
+        false => format!(
+            "&sort={}&order={}",
+            query.sort.unwrap_or_default().as_str(),
+            query.order.unwrap_or_default().as_str()
+        ),
+    };
+    if let Some(show_hidden) = query.show_hidden() {
+        sort_query.push_str(&format!("&show_hidden={}", u8::from(show_hidden)));
+    }
+    let with_sizes = query.with_sizes();
+    let entries = match with_sizes {
+        false => entries,
+        true => {
+            sort_query.push_str("&with_sizes=1");
+            let username = user.username();
+            tokio::task::spawn_blocking(move || -> Result<Vec<FileEntry>, Error> {
+                let filekidfs = fs_for_user(&listed_path, Some(&username))?;
+                Ok(with_dir_usage(filekidfs.as_ref(), entries))
+            })
+            .await
+            .map_err(|err| {
+                Error::InternalServerError(format!("Directory size task failed to run: {err}"))
+            })??
+        }
+    };
 
     BrowsePage {
         server_path,
         entries,
         parent_path,
+        upload_path,
         current_path: filepath.unwrap_or("".to_string()),
         username: user.username(),
+        read_only: server_path_object.read_only,
+        uploads_disabled: server_reader.uploads_disabled,
+        deletions_allowed: server_path_object.deletions_allowed(),
+        zip_downloads: server_path_object.zip_downloads,
+        title,
+        description: server_path_object.description.clone(),
+        used,
+        page,
+        per_page,
+        total_pages: total_entries.div_ceil(per_page).max(1),
+        total_entries,
+        sort: query.sort.unwrap_or_default(),
+        order: query.order.unwrap_or_default(),
+        sort_query,
+        with_sizes,
     }
     .into()
 }
 
+/// Fills in [FileEntry::dir_usage] for the directories, they share [DIR_USAGE_TIMEOUT_SECS] so a page of huge ones can't take forever
+fn with_dir_usage(filekidfs: &dyn FileKidFs, mut entries: Vec<FileEntry>) -> Vec<FileEntry> {
+    let deadline = Instant::now() + Duration::from_secs(DIR_USAGE_TIMEOUT_SECS);
+    for entry in entries
+        .iter_mut()
+        .filter(|entry| entry.filetype == FileType::Directory)
+    {
+        match filekidfs.dir_usage(&entry.fullpath, DIR_USAGE_MAX_ENTRIES, deadline) {
+            Ok(usage) => entry.dir_usage = Some(usage),
+            Err(err) => warn!("Couldn't count the size of {}: {:?}", entry.fullpath, err),
+        }
+    }
+    entries
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct CreateDirForm {
+    server_path: String,
+    current_path: String,
+    dirname: String,
+}
+
+/// Creates a new directory inside the one being browsed
+#[instrument(level = "debug", skip(state, claims))]
+pub(crate) async fn create_dir_post(
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    Form(form): Form<CreateDirForm>,
+) -> Result<Redirect, Error> {
+    let user = check_login(claims)?;
+
+    if form.dirname.is_empty() || form.dirname.contains('/') || form.dirname.contains("..") {
+        return Err(Error::BadRequest(format!(
+            "Invalid directory name {:?}",
+            form.dirname
+        )));
+    }
+
+    let server_reader = state.configuration.read().await;
+
+    let server_path_object = match server_reader.server_paths.get(&form.server_path) {
+        None => {
+            error!("Couldn't find server path {}", form.server_path);
+            return Err(Error::NotFound(form.server_path));
+        }
+        Some(p) => p,
+    };
+    server_path_object.check_method(&Method::POST)?;
+
+    server_path_object.check_writable()?;
+    let filekidfs = fs_for_user(server_path_object, Some(&user.username()))?;
+
+    let current_path = form.current_path.trim_matches('/');
+    let new_dir = filekidfs.target_path(current_path, &form.dirname)?;
+    let result = filekidfs.create_dir(&new_dir);
+    audit::record(
+        server_reader.audit_log.as_deref(),
+        AuditRecord::new(
+            user.username(),
+            AuditAction::Mkdir,
+            &form.server_path,
+            &new_dir,
+            &result,
+        ),
+    )
+    .await;
+    result?;
+    debug!(
+        "User {} created directory {} on {}",
+        user.username(),
+        new_dir,
+        form.server_path
+    );
+
+    Ok(Redirect::to(&format!(
+        "{}/{}/{}",
+        Urls::Browse.as_ref(),
+        form.server_path,
+        current_path
+    )))
+}
+
 pub(crate) async fn upload_nopath(
     State(state): State<WebState>,
     Path(server_path): Path<String>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
     multipart: Multipart,
 ) -> Result<Redirect, Error> {
-    upload_file(State(state), Path((server_path, None)), multipart).await
+    upload_file(State(state), Path((server_path, None)), claims, multipart).await
 }
 
-#[instrument(level = "debug", skip(state, multipart))]
+#[instrument(level = "debug", skip(state, claims, multipart))]
 pub(crate) async fn upload_file(
     State(state): State<WebState>,
     Path((server_path, filepath)): Path<(String, Option<String>)>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    multipart: Multipart,
+) -> Result<Redirect, Error> {
+    let result = store_upload(
+        State(state.clone()),
+        Path((server_path.clone(), filepath)),
+        claims,
+        multipart,
+    )
+    .await;
+    explained(&state, &server_path, result).await
+}
+
+/// A browse upload's file field, held while the rest of the form's read
+enum ReceivedUpload<'a> {
+    /// Read into memory, for server paths that check or change the content before it's stored
+    Buffered(Bytes),
+    Staged {
+        staged: StagedUpload<'a>,
+        size: u64,
+    },
+}
+
+impl ReceivedUpload<'_> {
+    fn size(&self) -> u64 {
+        match self {
+            Self::Buffered(data) => data.len() as u64,
+            Self::Staged { size, .. } => *size,
+        }
+    }
+}
+
+/// A file field streamed into a hidden key (see [UPLOAD_STAGING_PREFIX]), which is removed again unless [StagedUpload::store] moves it into place
+struct StagedUpload<'a> {
+    filekidfs: &'a dyn FileKidFs,
+    key: String,
+    stored: bool,
+}
+
+impl<'a> StagedUpload<'a> {
+    fn new(filekidfs: &'a dyn FileKidFs) -> Self {
+        Self {
+            filekidfs,
+            key: format!("{UPLOAD_STAGING_PREFIX}{}", uuid::Uuid::new_v4().simple()),
+            stored: false,
+        }
+    }
+
+    /// Moves the staged file over `target_path`, replacing whatever's already there in one step
+    fn store(mut self, target_path: &str) -> Result<(), Error> {
+        self.filekidfs.move_file(&self.key, target_path, true)?;
+        self.stored = true;
+        Ok(())
+    }
+}
+
+impl Drop for StagedUpload<'_> {
+    fn drop(&mut self) {
+        if self.stored || !self.filekidfs.is_file(&self.key) {
+            return;
+        }
+        debug!("Removing staged upload {}", self.key);
+        if let Err(err) = self.filekidfs.delete_file(&self.key) {
+            warn!("Failed to remove staged upload {}: {:?}", self.key, err);
+        }
+    }
+}
+
+async fn store_upload(
+    State(state): State<WebState>,
+    Path((server_path, filepath)): Path<(String, Option<String>)>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
     mut multipart: Multipart,
 ) -> Result<Redirect, Error> {
+    let user = check_login(claims)?;
     let server_reader = state.configuration.read().await;
+    server_reader.check_uploads_enabled()?;
 
     let server_path_object = match server_reader.server_paths.get(&server_path) {
         None => {
@@ -211,17 +1097,26 @@ pub(crate) async fn upload_file(
         }
         Some(p) => p,
     };
+    server_path_object.check_method(&Method::POST)?;
 
-    let filekidfs = fs_from_serverpath(server_path_object)?;
+    server_path_object.check_writable()?;
+    let filekidfs = fs_for_user(server_path_object, Some(&user.username()))?;
+    let strict = server_reader.strict_content_length;
 
-    let mut uploaded_filename: Option<String> = None;
-    let mut uploaded_data: Option<Bytes> = None;
-    // let mut overwrite: bool = false;
+    // the content checks need the whole file up front, so those paths don't stream
+    let streamed = filekidfs.has_stream_put_file()
+        && server_path_object.normalize_text_eol.is_none()
+        && !server_path_object.reject_executables;
 
-    const FIELD_NAMES: [&str; 2] = ["file", "overwrite"];
+    let mut uploaded_filename: Option<String> = None;
+    let mut uploaded_data: Option<ReceivedUpload> = None;
+    let mut overwrite: bool = false;
+    // the ETag of the file the client means to overwrite
+    let mut if_match: Option<String> = None;
 
-    let stripped_filepath = filepath.clone().unwrap_or_default();
+    const FIELD_NAMES: [&str; 3] = ["file", "overwrite", "if_match"];
 
+    // collect everything first, so the overwrite field can arrive before or after the file
     while let Ok(Some(field)) = multipart.next_field().await {
         if let Some(field_name) = field.name() {
             if !FIELD_NAMES.contains(&field_name) {
@@ -234,32 +1129,81 @@ pub(crate) async fn upload_file(
 
             if field_name == "file" {
                 let file_name = match field.file_name() {
-                    Some(name) => name.to_owned(),
+                    Some(name) => sanitize_filename(name)?.to_owned(),
                     None => {
                         warn!("File upload attempted without a filename - ignoring");
                         continue;
                     }
                 };
 
-                let full_path = [stripped_filepath.clone(), file_name.clone()].join("/");
+                // parts don't usually declare a length, but check it if they do
+                let part_len = field
+                    .headers()
+                    .get(CONTENT_LENGTH)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.parse::<u64>().ok());
 
-                if filekidfs.exists(&full_path)? {
-                    warn!("File {} already exists - ignoring", file_name);
-                    continue;
-                }
-
-                let data = field.bytes().await.map_err(|err| {
-                    error!("Failed to read file data: {:?}", err);
-                    Error::InternalServerError("Failed to read file data".to_string())
-                })?;
+                // a later file field replaces an earlier one
+                drop(uploaded_data.take());
+                let data = match streamed {
+                    true => {
+                        let staged = StagedUpload::new(filekidfs.as_ref());
+                        // streamed uploads aren't buffered, so the size limit is checked as bytes arrive
+                        let max_file_bytes = server_path_object.max_file_bytes.unwrap_or(u64::MAX);
+                        let received = Arc::new(AtomicU64::new(0));
+                        let stream = field
+                            .map_err(std::io::Error::other)
+                            .and_then({
+                                let received = received.clone();
+                                move |chunk| {
+                                    let total = received
+                                        .fetch_add(chunk.len() as u64, Ordering::Relaxed)
+                                        + chunk.len() as u64;
+                                    futures::future::ready(match total > max_file_bytes {
+                                        true => Err(std::io::Error::other("upload is too big")),
+                                        false => Ok(chunk),
+                                    })
+                                }
+                            })
+                            .boxed();
+                        let size = filekidfs
+                            .stream_put_file(&staged.key, stream, part_len, strict)
+                            .await
+                            .map_err(|err| {
+                                server_path_object
+                                    .check_file_size(&file_name, received.load(Ordering::Relaxed))
+                                    .err()
+                                    .unwrap_or(err)
+                            })?;
+                        ReceivedUpload::Staged { staged, size }
+                    }
+                    false => {
+                        let data = field.bytes().await.map_err(|err| {
+                            error!("Failed to read file data: {:?}", err);
+                            Error::InternalServerError("Failed to read file data".to_string())
+                        })?;
+                        check_content_length(part_len, data.len() as u64, strict)?;
+                        ReceivedUpload::Buffered(data)
+                    }
+                };
 
-                debug!("Length of `{}` is {} bytes", file_name, data.len());
+                debug!("Length of `{}` is {} bytes", file_name, data.size());
+                server_path_object.check_file_size(&file_name, data.size())?;
 
                 uploaded_filename = Some(file_name);
                 uploaded_data = Some(data);
             } else if field_name == "overwrite" {
-                // overwrite = true;
-                // TODO: handle the overwrite field
+                let value = field.text().await.map_err(|err| {
+                    error!("Failed to read overwrite field: {:?}", err);
+                    Error::BadRequest("Failed to read overwrite field".to_string())
+                })?;
+                // checkboxes send "on"
+                overwrite = matches!(value.trim(), "true" | "on" | "1");
+            } else if field_name == "if_match" {
+                if_match = Some(field.text().await.map_err(|err| {
+                    error!("Failed to read if_match field: {:?}", err);
+                    Error::BadRequest("Failed to read if_match field".to_string())
+                })?);
             }
         }
     }
@@ -267,14 +1211,97 @@ pub(crate) async fn upload_file(
     // have we got a file?
     match (uploaded_filename, uploaded_data) {
         (Some(uploaded_file), Some(uploaded_data)) => {
-            let filepath = filepath.unwrap_or("".to_string());
+            let filepath = match server_path_object.upload_dir()? {
+                Some(upload_dir) => {
+                    if !filekidfs.is_dir(&upload_dir) {
+                        error!(
+                            "upload_dir {} doesn't exist in server path {}",
+                            upload_dir, server_path
+                        );
+                        return Err(Error::NotFound(upload_dir));
+                    }
+                    upload_dir
+                }
+                None => filepath.unwrap_or("".to_string()),
+            };
+            let stored_name = server_path_object.upload_name(&uploaded_file, &user.username())?;
+            let target_path = filekidfs.target_path(&filepath, &stored_name)?;
+
+            let result = async {
+                if key_escapes_base(&target_path) {
+                    return Err(Error::NotAuthorized(format!(
+                        "{target_path} is outside of the base path"
+                    )));
+                }
 
-            filekidfs
-                .put_file(
-                    &filekidfs.target_path(&filepath, &uploaded_file)?,
-                    &uploaded_data,
+                check_if_match(
+                    if_match.as_deref(),
+                    server_path_object,
+                    filekidfs.as_ref(),
+                    &target_path,
                 )
                 .await?;
+                let mut replaced_size = 0;
+                if filekidfs.exists(&target_path)? {
+                    if !overwrite {
+                        warn!("File {} already exists, not overwriting", target_path);
+                        return Err(Error::BadRequest(format!("{uploaded_file} already exists")));
+                    }
+                    if !filekidfs.is_file(&target_path) {
+                        return Err(Error::BadRequest(format!(
+                            "{uploaded_file} exists and isn't a file"
+                        )));
+                    }
+                    debug!("Overwriting {}", target_path);
+                    replaced_size = filekidfs.get_data(&target_path)?.size.unwrap_or(0);
+                }
+                check_unique_basename(server_path_object, filekidfs.as_ref(), &target_path)?;
+                state
+                    .dir_sizes
+                    .check_quota(
+                        &server_path,
+                        server_path_object,
+                        Some(&user.username()),
+                        uploaded_data.size(),
+                        replaced_size,
+                    )
+                    .await?;
+
+                let size = match uploaded_data {
+                    ReceivedUpload::Buffered(uploaded_data) => {
+                        server_path_object.check_not_executable(&target_path, &uploaded_data)?;
+                        let uploaded_data = server_path_object.normalize_upload(uploaded_data);
+                        create_parent_dirs(filekidfs.as_ref(), &target_path)?;
+                        filekidfs.put_file(&target_path, &uploaded_data).await?;
+                        uploaded_data.len() as u64
+                    }
+                    ReceivedUpload::Staged { staged, size } => {
+                        create_parent_dirs(filekidfs.as_ref(), &target_path)?;
+                        staged.store(&target_path)?;
+                        size
+                    }
+                };
+                state.dir_sizes.subtract(&server_path, replaced_size);
+                state.dir_sizes.add(&server_path, size);
+                if server_path_object.reject_executables {
+                    filekidfs.strip_executable(&target_path)?;
+                }
+                Ok(())
+            }
+            .await;
+            audit::record(
+                server_reader.audit_log.as_deref(),
+                AuditRecord::new(
+                    user.username(),
+                    AuditAction::Upload,
+                    &server_path,
+                    &target_path,
+                    &result,
+                ),
+            )
+            .await;
+            result?;
+
             Ok(Redirect::to(&format!(
                 "{}/{}/{}",
                 Urls::Browse.as_ref(),
@@ -288,3 +1315,2164 @@ pub(crate) async fn upload_file(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::views::oidc::test_user_claims;
+
+    /// Builds a multipart upload from `(name, filename, data)` fields
+    async fn test_multipart(fields: &[(&str, Option<&str>, &[u8])]) -> Multipart {
+        use axum::extract::FromRequest;
+
+        let boundary = "filekidtestboundary";
+        let mut body = Vec::new();
+        for (name, filename, data) in fields {
+            body.extend_from_slice(
+                format!("--{boundary}\r\nContent-Disposition: form-data; name=\"{name}\"")
+                    .as_bytes(),
+            );
+            if let Some(filename) = filename {
+                body.extend_from_slice(format!("; filename=\"{filename}\"").as_bytes());
+            }
+            body.extend_from_slice(b"\r\n\r\n");
+            body.extend_from_slice(data);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+
+        let request = axum::http::Request::builder()
+            .method("POST")
+            .header(
+                CONTENT_TYPE,
+                format!("multipart/form-data; boundary={boundary}"),
+            )
+            .body(axum::body::Body::from(body))
+            .expect("Failed to build request");
+        Multipart::from_request(request, &())
+            .await
+            .expect("Failed to build multipart")
+    }
+
+    async fn test_state(temp_dir: &tempfile::TempDir) -> WebState {
+        let mut config = Config::test_config();
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(temp_dir.path()));
+        WebState::test_webstate_with_config(config).await
+    }
+
+    #[tokio::test]
+    async fn test_create_dir_post() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::create_dir(temp_dir.path().join("subdir")).expect("Failed to create subdir");
+        let state = test_state(&temp_dir).await;
+
+        let form = |current_path: &str, dirname: &str| CreateDirForm {
+            server_path: "test".to_string(),
+            current_path: current_path.to_string(),
+            dirname: dirname.to_string(),
+        };
+
+        let response = create_dir_post(
+            state.to_state(),
+            Some(test_user_claims()),
+            Form(form("subdir", "newdir")),
+        )
+        .await
+        .expect("Failed to create dir")
+        .into_response();
+        assert_eq!(
+            response
+                .headers()
+                .get("location")
+                .expect("Failed to get location header"),
+            "/browse/test/subdir"
+        );
+        assert!(temp_dir.path().join("subdir/newdir").is_dir());
+
+        let _ = create_dir_post(
+            state.to_state(),
+            Some(test_user_claims()),
+            Form(form("", "toplevel")),
+        )
+        .await
+        .expect("Failed to create dir");
+        assert!(temp_dir.path().join("toplevel").is_dir());
+
+        for bad_name in ["", "a/b", "..", "../escape"] {
+            assert!(matches!(
+                create_dir_post(
+                    state.to_state(),
+                    Some(test_user_claims()),
+                    Form(form("subdir", bad_name)),
+                )
+                .await,
+                Err(Error::BadRequest(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upload_file_overwrite() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("test.txt"), b"original")
+            .expect("Failed to write test file");
+        let state = test_state(&temp_dir).await;
+
+        // colliding without overwrite is an error, and the file's left alone
+        let multipart = test_multipart(&[("file", Some("test.txt"), b"replaced")]).await;
+        assert_eq!(
+            upload_file(
+                state.to_state(),
+                Path(("test".to_string(), None)),
+                Some(test_user_claims()),
+                multipart
+            )
+            .await
+            .err(),
+            Some(Error::BadRequest("test.txt already exists".to_string()))
+        );
+        let multipart = test_multipart(&[
+            ("file", Some("test.txt"), b"replaced"),
+            ("overwrite", None, b"false"),
+        ])
+        .await;
+        assert!(upload_file(
+            state.to_state(),
+            Path(("test".to_string(), None)),
+            Some(test_user_claims()),
+            multipart
+        )
+        .await
+        .is_err());
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("test.txt")).expect("Failed to read file"),
+            b"original"
+        );
+
+        // overwrite can come after the file...
+        let multipart = test_multipart(&[
+            ("file", Some("test.txt"), b"replaced"),
+            ("overwrite", None, b"true"),
+        ])
+        .await;
+        assert!(upload_file(
+            state.to_state(),
+            Path(("test".to_string(), None)),
+            Some(test_user_claims()),
+            multipart
+        )
+        .await
+        .is_ok());
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("test.txt")).expect("Failed to read file"),
+            b"replaced"
+        );
+
+        // ...or before it
+        let multipart = test_multipart(&[
+            ("overwrite", None, b"on"),
+            ("file", Some("test.txt"), b"replaced again"),
+        ])
+        .await;
+        assert!(upload_file(
+            state.to_state(),
+            Path(("test".to_string(), None)),
+            Some(test_user_claims()),
+            multipart
+        )
+        .await
+        .is_ok());
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("test.txt")).expect("Failed to read file"),
+            b"replaced again"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_only() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("test.txt"), b"hello").expect("Failed to write file");
+
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "test".to_string(),
+            ServerPath {
+                read_only: true,
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+        let read_only = Some(Error::NotAuthorized("server path is read-only".to_string()));
+
+        // browsing and downloading still work
+        let response = browse_nopath(
+            state.to_state(),
+            Path("test".to_string()),
+            Query(BrowseQuery::default()),
+            Some(test_user_claims()),
+            HeaderMap::new(),
+        )
+        .await
+        .expect("Failed to browse");
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Failed to read body");
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains("test.txt"));
+        assert!(!body.contains("multipart/form-data"));
+        assert!(!body.contains(Urls::Delete.as_ref()));
+
+        let response = get_file(
+            state.to_state(),
+            Path(("test".to_string(), "test.txt".to_string())),
+            Query(GetFileQuery::default()),
+            None,
+            HeaderMap::new(),
+        )
+        .await
+        .expect("Failed to get file")
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // but changing things doesn't
+        let multipart = test_multipart(&[("file", Some("new.txt"), b"nope")]).await;
+        assert_eq!(
+            upload_file(
+                state.to_state(),
+                Path(("test".to_string(), None)),
+                Some(test_user_claims()),
+                multipart
+            )
+            .await
+            .err(),
+            read_only
+        );
+        assert_eq!(
+            create_dir_post(
+                state.to_state(),
+                Some(test_user_claims()),
+                Form(CreateDirForm {
+                    server_path: "test".to_string(),
+                    current_path: "".to_string(),
+                    dirname: "newdir".to_string(),
+                }),
+            )
+            .await
+            .err(),
+            read_only
+        );
+        assert!(!temp_dir.path().join("new.txt").exists());
+        assert!(!temp_dir.path().join("newdir").exists());
+    }
+
+    #[tokio::test]
+    async fn test_browse_title_description() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "test".to_string(),
+            ServerPath {
+                title: Some("Shared Files".to_string()),
+                description: Some("Things <everyone> needs".to_string()),
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        config
+            .server_paths
+            .insert("plain".to_string(), ServerPath::test_local(temp_dir.path()));
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let body = |response: Response| async {
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .expect("Failed to read body");
+            String::from_utf8_lossy(&body).to_string()
+        };
+
+        let response = browse_nopath(
+            state.to_state(),
+            Path("test".to_string()),
+            Query(BrowseQuery::default()),
+            Some(test_user_claims()),
+            HeaderMap::new(),
+        )
+        .await
+        .expect("Failed to browse");
+        let page = body(response).await;
+        assert!(page.contains("<h1>Shared Files/</h1>"));
+        assert!(page.contains("Things &#60;everyone&#62; needs"));
+
+        let response = browse_nopath(
+            state.to_state(),
+            Path("plain".to_string()),
+            Query(BrowseQuery::default()),
+            Some(test_user_claims()),
+            HeaderMap::new(),
+        )
+        .await
+        .expect("Failed to browse");
+        let page = body(response).await;
+        assert!(page.contains("<h1>plain/</h1>"));
+        assert!(!page.contains("class=\"description\""));
+    }
+
+    #[tokio::test]
+    async fn test_get_file_immutable() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("test.txt"), b"hello").expect("Failed to write file");
+
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "immutable".to_string(),
+            ServerPath {
+                immutable: true,
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        config.server_paths.insert(
+            "normal".to_string(),
+            ServerPath::test_local(temp_dir.path()),
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let get = |server_path: &str| {
+            get_file(
+                state.to_state(),
+                Path((server_path.to_string(), "test.txt".to_string())),
+                Query(GetFileQuery::default()),
+                None,
+                HeaderMap::new(),
+            )
+        };
+
+        let response = get("immutable")
+            .await
+            .expect("Failed to get file")
+            .into_response();
+        assert_eq!(
+            response.headers().get(ETAG).expect("Missing ETag"),
+            // sha256 of "hello", strong validators don't start with W/
+            "\"2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824\""
+        );
+        assert_eq!(
+            response
+                .headers()
+                .get(CACHE_CONTROL)
+                .expect("Missing Cache-Control"),
+            "public, max-age=31536000, immutable"
+        );
+
+        let response = get("normal")
+            .await
+            .expect("Failed to get file")
+            .into_response();
+        // everything else gets one from the size and modified time
+        assert!(response
+            .headers()
+            .get(ETAG)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|etag| etag.starts_with('"')));
+        assert!(response.headers().get(CACHE_CONTROL).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_browse_pagination() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        for index in 0..5 {
+            std::fs::write(temp_dir.path().join(format!("file{index}.txt")), b"")
+                .expect("Failed to write file");
+        }
+        let state = test_state(&temp_dir).await;
+
+        let page = |page: usize| {
+            let state = state.clone();
+            async move {
+                let response = browse_nopath(
+                    state.to_state(),
+                    Path("test".to_string()),
+                    Query(BrowseQuery {
+                        page: Some(page),
+                        per_page: Some(2),
+                        ..Default::default()
+                    }),
+                    Some(test_user_claims()),
+                    HeaderMap::new(),
+                )
+                .await
+                .expect("Failed to browse");
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .expect("Failed to read body");
+                String::from_utf8_lossy(&body).to_string()
+            }
+        };
+
+        let first = page(1).await;
+        assert!(first.contains("file0.txt") && first.contains("file1.txt"));
+        assert!(!first.contains("file2.txt"));
+        assert!(first.contains("Page 1 of 3"));
+        assert!(first.contains("?page=2&amp;per_page=2") || first.contains("?page=2&per_page=2"));
+        assert!(!first.contains("Previous"));
+
+        let last = page(3).await;
+        assert!(last.contains("file4.txt"));
+        assert!(!last.contains("file3.txt"));
+        assert!(last.contains("Previous"));
+        assert!(!last.contains("Next"));
+    }
+
+    #[tokio::test]
+    async fn test_allowed_methods() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "mirror".to_string(),
+            ServerPath {
+                allowed_methods: Some(vec!["GET".to_string(), "head".to_string()]),
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let response = browse_nopath(
+            state.to_state(),
+            Path("mirror".to_string()),
+            Query(BrowseQuery::default()),
+            Some(test_user_claims()),
+            HeaderMap::new(),
+        )
+        .await
+        .expect("Failed to browse");
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let multipart = test_multipart(&[("file", Some("test.txt"), b"hello")]).await;
+        let response = upload_file(
+            state.to_state(),
+            Path(("mirror".to_string(), None)),
+            Some(test_user_claims()),
+            multipart,
+        )
+        .await
+        .expect_err("POST should be refused")
+        .into_response();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(
+            response
+                .headers()
+                .get(axum::http::header::ALLOW)
+                .and_then(|value| value.to_str().ok()),
+            Some("GET, HEAD")
+        );
+        assert!(!temp_dir.path().join("test.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_upload_dir() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::create_dir_all(temp_dir.path().join("inbox")).expect("Failed to create inbox");
+        std::fs::create_dir_all(temp_dir.path().join("elsewhere/deeper"))
+            .expect("Failed to create dir");
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "test".to_string(),
+            ServerPath {
+                upload_dir: Some("/inbox/".to_string()),
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+
+        // the form on the browse page points at the inbox
+        let response = browse(
+            state.to_state(),
+            Path(("test".to_string(), Some("elsewhere/deeper".to_string()))),
+            Query(BrowseQuery::default()),
+            Some(test_user_claims()),
+            HeaderMap::new(),
+        )
+        .await
+        .expect("Failed to browse");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Failed to read body");
+        assert!(String::from_utf8_lossy(&body).contains("/upload/test/inbox\""));
+
+        let multipart = test_multipart(&[("file", Some("letter.txt"), b"hello")]).await;
+        let _ = upload_file(
+            state.to_state(),
+            Path(("test".to_string(), Some("elsewhere/deeper".to_string()))),
+            Some(test_user_claims()),
+            multipart,
+        )
+        .await
+        .expect("Failed to upload");
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("inbox/letter.txt")).expect("Failed to read"),
+            b"hello"
+        );
+        assert!(!temp_dir.path().join("elsewhere/deeper/letter.txt").exists());
+
+        // it's not allowed to point outside the base path
+        let server_path = ServerPath {
+            upload_dir: Some("../outside".to_string()),
+            ..ServerPath::test_local(temp_dir.path())
+        };
+        assert!(matches!(
+            server_path.upload_dir(),
+            Err(Error::Configuration(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_browse_sort() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::create_dir(temp_dir.path().join("folder")).expect("Failed to create dir");
+        for (filename, size, age_secs) in [
+            ("small.txt", 1, 100),
+            ("large.txt", 1000, 300),
+            ("medium.txt", 100, 200),
+        ] {
+            let file = std::fs::File::create(temp_dir.path().join(filename))
+                .expect("Failed to create file");
+            file.set_len(size).expect("Failed to set length");
+            file.set_modified(SystemTime::now() - std::time::Duration::from_secs(age_secs))
+                .expect("Failed to set modified time");
+        }
+        let state = test_state(&temp_dir).await;
+
+        let listing = |sort: Option<SortBy>, order: Option<SortOrder>| {
+            let state = state.clone();
+            async move {
+                let response = browse_nopath(
+                    state.to_state(),
+                    Path("test".to_string()),
+                    Query(BrowseQuery {
+                        sort,
+                        order,
+                        ..Default::default()
+                    }),
+                    Some(test_user_claims()),
+                    HeaderMap::new(),
+                )
+                .await
+                .expect("Failed to browse");
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .expect("Failed to read body");
+                let body = String::from_utf8_lossy(&body).to_string();
+                // the names in the order they appear on the page
+                let mut names = ["folder", "small.txt", "medium.txt", "large.txt"]
+                    .into_iter()
+                    .map(|name| {
+                        (
+                            body.find(&format!("/test/{name}\""))
+                                .expect("missing entry"),
+                            name,
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                names.sort();
+                names
+                    .into_iter()
+                    .map(|(_, name)| name)
+                    .collect::<Vec<&str>>()
+                    .join(",")
+            }
+        };
+
+        assert_eq!(
+            listing(None, None).await,
+            "folder,large.txt,medium.txt,small.txt"
+        );
+        assert_eq!(
+            listing(Some(SortBy::Name), Some(SortOrder::Desc)).await,
+            "folder,small.txt,medium.txt,large.txt"
+        );
+        assert_eq!(
+            listing(Some(SortBy::Size), None).await,
+            "folder,small.txt,medium.txt,large.txt"
+        );
+        assert_eq!(
+            listing(Some(SortBy::Size), Some(SortOrder::Desc)).await,
+            "folder,large.txt,medium.txt,small.txt"
+        );
+        assert_eq!(
+            listing(Some(SortBy::Modified), Some(SortOrder::Asc)).await,
+            "folder,large.txt,medium.txt,small.txt"
+        );
+        assert_eq!(
+            listing(Some(SortBy::Modified), Some(SortOrder::Desc)).await,
+            "folder,small.txt,medium.txt,large.txt"
+        );
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        assert_eq!(
+            content_disposition("inline", "report \"final\".pdf", true),
+            "inline; filename=\"report \\\"final\\\".pdf\""
+        );
+        assert_eq!(
+            content_disposition("attachment", "Привет мир.txt", false),
+            "attachment; filename*=UTF-8''%D0%9F%D1%80%D0%B8%D0%B2%D0%B5%D1%82%20%D0%BC%D0%B8%D1%80.txt"
+        );
+        assert_eq!(
+            content_disposition("attachment", "Привет мир.txt", true),
+            "attachment; filename=\"Privet mir.txt\"; filename*=UTF-8''%D0%9F%D1%80%D0%B8%D0%B2%D0%B5%D1%82%20%D0%BC%D0%B8%D1%80.txt"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_file_ascii_filename_fallback() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("Отчёт.txt"), b"hello").expect("Failed to write file");
+        let state = test_state(&temp_dir).await;
+
+        let disposition = |state: WebState| async move {
+            get_file(
+                state.to_state(),
+                Path(("test".to_string(), "Отчёт.txt".to_string())),
+                Query(GetFileQuery::default()),
+                None,
+                HeaderMap::new(),
+            )
+            .await
+            .expect("Failed to get file")
+            .into_response()
+            .headers()
+            .get(CONTENT_DISPOSITION)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+            .expect("No Content-Disposition")
+        };
+
+        let header = disposition(state.clone()).await;
+        assert!(header.contains("filename*=UTF-8''"));
+        assert!(!header.contains("filename=\""));
+
+        state.configuration.write().await.ascii_filename_fallback = true;
+        let header = disposition(state).await;
+        assert!(header.contains("filename*=UTF-8''"));
+        assert!(header.contains("filename=\"Otchiot.txt\""), "{header}");
+    }
+
+    #[tokio::test]
+    async fn test_upload_file_uploads_disabled() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let state = test_state(&temp_dir).await;
+        state.configuration.write().await.uploads_disabled = true;
+
+        let multipart = test_multipart(&[("file", Some("test.txt"), b"hello")]).await;
+        assert_eq!(
+            upload_file(
+                state.to_state(),
+                Path(("test".to_string(), None)),
+                Some(test_user_claims()),
+                multipart
+            )
+            .await
+            .err(),
+            Some(Error::NotAuthorized("uploads are disabled".to_string()))
+        );
+        assert!(!temp_dir.path().join("test.txt").exists());
+
+        // the form's gone, but creating folders is still there
+        let response = browse_nopath(
+            state.to_state(),
+            Path("test".to_string()),
+            Query(BrowseQuery::default()),
+            Some(test_user_claims()),
+            HeaderMap::new(),
+        )
+        .await
+        .expect("Failed to browse");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Failed to read body");
+        let body = String::from_utf8_lossy(&body);
+        assert!(!body.contains(Urls::Upload.as_ref()));
+        assert!(body.contains(Urls::CreateDir.as_ref()));
+    }
+
+    #[tokio::test]
+    async fn test_get_file_download() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("Отчёт.pdf"), b"%PDF").expect("Failed to write file");
+        let state = test_state(&temp_dir).await;
+
+        let disposition = |download: Option<&str>| {
+            let state = state.clone();
+            let query = GetFileQuery {
+                download: download.map(str::to_string),
+            };
+            async move {
+                get_file(
+                    state.to_state(),
+                    Path(("test".to_string(), "Отчёт.pdf".to_string())),
+                    Query(query),
+                    None,
+                    HeaderMap::new(),
+                )
+                .await
+                .expect("Failed to get file")
+                .headers()
+                .get(CONTENT_DISPOSITION)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+                .expect("No Content-Disposition")
+            }
+        };
+
+        let header = disposition(Some("1")).await;
+        assert_eq!(
+            header,
+            "attachment; filename*=UTF-8''%D0%9E%D1%82%D1%87%D1%91%D1%82.pdf"
+        );
+        for download in [None, Some("0")] {
+            let header = disposition(download).await;
+            assert!(header.starts_with("inline;"), "{header}");
+            assert!(!header.contains("attachment"));
+        }
+
+        let entry = FileEntry {
+            filename: "Отчёт.pdf".to_string(),
+            fullpath: "Отчёт.pdf".to_string(),
+            filetype: FileType::File,
+            size: None,
+            modified: None,
+            dir_usage: None,
+        };
+        assert_eq!(
+            entry.download_url(&"test"),
+            Some(format!(
+                "{}/test/Отчёт.pdf?download=1",
+                Urls::GetFile.as_ref()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_file_sendfile_header() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("big file.bin"), vec![0u8; 4096])
+            .expect("Failed to write file");
+        let get = |sendfile_header: &str, sendfile_prefix: Option<&str>| {
+            let mut config = Config::test_config();
+            config.sendfile_header = Some(sendfile_header.to_string());
+            config.server_paths.insert(
+                "test".to_string(),
+                ServerPath {
+                    sendfile_prefix: sendfile_prefix.map(str::to_string),
+                    ..ServerPath::test_local(temp_dir.path())
+                },
+            );
+            async move {
+                let state = WebState::test_webstate_with_config(config).await;
+                get_file(
+                    state.to_state(),
+                    Path(("test".to_string(), "big file.bin".to_string())),
+                    Query(GetFileQuery::default()),
+                    None,
+                    HeaderMap::new(),
+                )
+                .await
+                .expect("Failed to get file")
+            }
+        };
+
+        // Apache wants the path on disk
+        let response = get("X-Sendfile", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let expected = temp_dir
+            .path()
+            .canonicalize()
+            .expect("Failed to canonicalize")
+            .join("big%20file.bin");
+        assert_eq!(
+            response
+                .headers()
+                .get("x-sendfile")
+                .and_then(|value| value.to_str().ok()),
+            Some(expected.to_string_lossy().as_ref())
+        );
+        assert!(response.headers().get(CONTENT_LENGTH).is_none());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Failed to read body");
+        assert!(body.is_empty());
+
+        // nginx wants a URI in an internal location
+        let response = get("X-Accel-Redirect", Some("/internal/test/")).await;
+        assert_eq!(
+            response
+                .headers()
+                .get("x-accel-redirect")
+                .and_then(|value| value.to_str().ok()),
+            Some("/internal/test/big%20file.bin")
+        );
+
+        let mut config = Config::test_config();
+        config.sendfile_header = Some("X-Accel-Redirect".to_string());
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(temp_dir.path()));
+        assert!(matches!(
+            config.startup_check().await,
+            Err(Error::Configuration(message)) if message.contains("sendfile_prefix")
+        ));
+
+        let mut config = Config::test_config();
+        config.sendfile_header = Some("not a header".to_string());
+        assert!(matches!(
+            config.startup_check().await,
+            Err(Error::Configuration(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_get_file_latest_alias() {
+        use axum::http::header::LOCATION;
+        use std::time::{Duration, SystemTime};
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let builds = temp_dir.path().join("builds");
+        std::fs::create_dir_all(builds.join("empty")).expect("Failed to create dirs");
+        let now = SystemTime::now();
+        for (filename, age) in [("old.zip", 300), ("newest.zip", 10), ("middle.zip", 100)] {
+            let file = std::fs::File::create(builds.join(filename)).expect("Failed to create file");
+            file.set_modified(now - Duration::from_secs(age))
+                .expect("Failed to set mtime");
+        }
+
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "ci".to_string(),
+            ServerPath {
+                latest_alias: true,
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        config
+            .server_paths
+            .insert("plain".to_string(), ServerPath::test_local(temp_dir.path()));
+        let state = WebState::test_webstate_with_config(config).await;
+        let get = |server_path: &str, filepath: &str| {
+            get_file(
+                state.to_state(),
+                Path((server_path.to_string(), filepath.to_string())),
+                Query(GetFileQuery::default()),
+                None,
+                HeaderMap::new(),
+            )
+        };
+
+        let response = get("ci", "builds/latest")
+            .await
+            .expect("Failed to get latest");
+        assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(
+            response
+                .headers()
+                .get(LOCATION)
+                .and_then(|value| value.to_str().ok()),
+            Some("/get/ci/builds/newest.zip")
+        );
+
+        assert_eq!(
+            get("ci", "builds/empty/latest").await.err(),
+            Some(Error::NotFound("builds/empty/latest".to_string()))
+        );
+        assert_eq!(
+            get("plain", "builds/latest").await.err(),
+            Some(Error::NotFound("builds/latest".to_string()))
+        );
+
+        // a real file called latest is served as-is
+        std::fs::write(builds.join("latest"), b"real").expect("Failed to write file");
+        let response = get("ci", "builds/latest")
+            .await
+            .expect("Failed to get latest");
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_upload_file_unique_basenames() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::create_dir_all(temp_dir.path().join("albums/one")).expect("Failed to create dir");
+        std::fs::create_dir_all(temp_dir.path().join("albums/two")).expect("Failed to create dir");
+        std::fs::write(temp_dir.path().join("albums/one/song.mp3"), b"one")
+            .expect("Failed to write file");
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "test".to_string(),
+            ServerPath {
+                unique_basenames: true,
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+        let upload = |filepath: &str, filename: &'static str, overwrite: bool| {
+            let state = state.clone();
+            let filepath = filepath.to_string();
+            async move {
+                let mut fields: Vec<(&str, Option<&str>, &[u8])> =
+                    vec![("file", Some(filename), b"two")];
+                if overwrite {
+                    fields.push(("overwrite", None, b"on"));
+                }
+                upload_file(
+                    state.to_state(),
+                    Path(("test".to_string(), Some(filepath))),
+                    Some(test_user_claims()),
+                    test_multipart(&fields).await,
+                )
+                .await
+            }
+        };
+
+        assert_eq!(
+            upload("albums/two", "song.mp3", false).await.err(),
+            Some(Error::BadRequest(
+                "song.mp3 already exists at albums/one/song.mp3".to_string()
+            ))
+        );
+        assert!(!temp_dir.path().join("albums/two/song.mp3").exists());
+
+        // replacing the file in place and new names are fine
+        let _ = upload("albums/one", "song.mp3", true)
+            .await
+            .expect("Failed to overwrite");
+        let _ = upload("albums/two", "other.mp3", false)
+            .await
+            .expect("Failed to upload");
+        assert!(temp_dir.path().join("albums/two/other.mp3").exists());
+    }
+
+    #[tokio::test]
+    async fn test_get_file_deprecation() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("test.txt"), b"hello").expect("Failed to write file");
+
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "going".to_string(),
+            ServerPath {
+                deprecation: Some("2030-01-02T03:04:05+10:00".to_string()),
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        config.server_paths.insert(
+            "staying".to_string(),
+            ServerPath::test_local(temp_dir.path()),
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+        let get = |server_path: &str| {
+            get_file(
+                state.to_state(),
+                Path((server_path.to_string(), "test.txt".to_string())),
+                Query(GetFileQuery::default()),
+                None,
+                HeaderMap::new(),
+            )
+        };
+
+        let response = get("going").await.expect("Failed to get file");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(SUNSET).and_then(|v| v.to_str().ok()),
+            Some("Tue, 01 Jan 2030 17:04:05 GMT")
+        );
+        assert_eq!(
+            response
+                .headers()
+                .get(DEPRECATION)
+                .and_then(|v| v.to_str().ok()),
+            Some("@1893517445")
+        );
+
+        let response = get("staying").await.expect("Failed to get file");
+        assert!(response.headers().get(SUNSET).is_none());
+        assert!(response.headers().get(DEPRECATION).is_none());
+
+        let server_path = ServerPath {
+            deprecation: Some("next tuesday".to_string()),
+            ..ServerPath::test_local(temp_dir.path())
+        };
+        assert!(matches!(
+            server_path.deprecation(),
+            Err(Error::Configuration(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_dir_size_cache_upload_delete() {
+        use crate::views::delete::{delete_file_post, DeleteQuery};
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("test.txt"), b"hello").expect("Failed to write file");
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "test".to_string(),
+            ServerPath {
+                size_cache_secs: Some(3600),
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+        let used = || async {
+            let server_path = state
+                .configuration
+                .read()
+                .await
+                .server_paths
+                .get("test")
+                .cloned()
+                .expect("Missing server path");
+            let filekidfs = fs_from_serverpath(&server_path).expect("Failed to get fs");
+            state
+                .dir_sizes
+                .get("test", &server_path, filekidfs.as_ref())
+                .expect("Failed to get size")
+        };
+
+        let response = browse(
+            state.to_state(),
+            Path(("test".to_string(), None)),
+            Query(BrowseQuery::default()),
+            Some(test_user_claims()),
+            HeaderMap::new(),
+        )
+        .await
+        .expect("Failed to browse");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Failed to read body");
+        assert!(String::from_utf8_lossy(&body).contains("5 B of "));
+        assert_eq!(used().await, Some(5));
+
+        let multipart = test_multipart(&[("file", Some("new.txt"), b"0123456789")]).await;
+        let _ = upload_file(
+            state.to_state(),
+            Path(("test".to_string(), None)),
+            Some(test_user_claims()),
+            multipart,
+        )
+        .await
+        .expect("Failed to upload");
+        assert_eq!(used().await, Some(15));
+
+        // overwriting swaps the old size for the new one
+        let multipart = test_multipart(&[
+            ("file", Some("new.txt"), b"012"),
+            ("overwrite", None, b"on"),
+        ])
+        .await;
+        let _ = upload_file(
+            state.to_state(),
+            Path(("test".to_string(), None)),
+            Some(test_user_claims()),
+            multipart,
+        )
+        .await
+        .expect("Failed to upload");
+        assert_eq!(used().await, Some(8));
+
+        let _ = delete_file_post(
+            state.to_state(),
+            Some(test_user_claims()),
+            HeaderMap::new(),
+            Form(DeleteQuery {
+                server_path: "test".to_string(),
+                key: "test.txt".to_string(),
+                recursive: false,
+                permanent: None,
+                if_match: None,
+            }),
+        )
+        .await
+        .expect("Failed to delete");
+        assert_eq!(used().await, Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_backend() {
+        use axum::http::header::RETRY_AFTER;
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let mut config = Config::test_config();
+        config.retry_after_secs = 120;
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(temp_dir.path()));
+        config.server_paths.insert(
+            "gone".to_string(),
+            ServerPath::test_local(&temp_dir.path().join("unmounted")),
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let response = get_file(
+            state.to_state(),
+            Path(("gone".to_string(), "test.txt".to_string())),
+            Query(GetFileQuery::default()),
+            None,
+            HeaderMap::new(),
+        )
+        .await
+        .expect_err("Backend should be down")
+        .into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok()),
+            Some("120")
+        );
+
+        assert!(matches!(
+            browse(
+                state.to_state(),
+                Path(("gone".to_string(), None)),
+                Query(BrowseQuery::default()),
+                Some(test_user_claims()),
+                HeaderMap::new(),
+            )
+            .await,
+            Err(Error::ServiceUnavailable(_, 120))
+        ));
+
+        // a missing file on a working backend is still a 404
+        let response = get_file(
+            state.to_state(),
+            Path(("test".to_string(), "test.txt".to_string())),
+            Query(GetFileQuery::default()),
+            None,
+            HeaderMap::new(),
+        )
+        .await
+        .expect_err("File shouldn't exist")
+        .into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(response.headers().get(RETRY_AFTER).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_file_mime_overrides() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        for filename in ["README.md", "data.custom", "page.html"] {
+            std::fs::write(temp_dir.path().join(filename), b"hello").expect("Failed to write file");
+        }
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "test".to_string(),
+            ServerPath {
+                mime_overrides: [
+                    ("md".to_string(), "text/plain".to_string()),
+                    (".CUSTOM".to_string(), "application/x-custom".to_string()),
+                ]
+                .into(),
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+        let content_type = |filename: &str| {
+            let state = state.clone();
+            let filename = filename.to_string();
+            async move {
+                get_file(
+                    state.to_state(),
+                    Path(("test".to_string(), filename)),
+                    Query(GetFileQuery::default()),
+                    None,
+                    HeaderMap::new(),
+                )
+                .await
+                .expect("Failed to get file")
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+                .expect("No Content-Type")
+            }
+        };
+
+        assert_eq!(content_type("README.md").await, "text/plain");
+        assert_eq!(content_type("data.custom").await, "application/x-custom");
+        assert_eq!(content_type("page.html").await, "text/html");
+
+        let server_path = ServerPath {
+            mime_overrides: [("md".to_string(), "not a mime type".to_string())].into(),
+            ..ServerPath::test_local(temp_dir.path())
+        };
+        assert!(matches!(
+            server_path.check_mime_overrides(),
+            Err(Error::Configuration(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_get_file_text_encoding() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        // "こんにちは" in Shift-JIS
+        let shift_jis = [0x82, 0xb1, 0x82, 0xf1, 0x82, 0xc9, 0x82, 0xbf, 0x82, 0xcd];
+        std::fs::write(temp_dir.path().join("hello.txt"), shift_jis).expect("Failed to write file");
+
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "legacy".to_string(),
+            ServerPath {
+                text_encoding: Some("shift_jis".to_string()),
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        config
+            .server_paths
+            .insert("utf8".to_string(), ServerPath::test_local(temp_dir.path()));
+        let state = WebState::test_webstate_with_config(config).await;
+        let get = |server_path: &str| {
+            let state = state.clone();
+            let server_path = server_path.to_string();
+            async move {
+                let response = get_file(
+                    state.to_state(),
+                    Path((server_path, "hello.txt".to_string())),
+                    Query(GetFileQuery::default()),
+                    None,
+                    HeaderMap::new(),
+                )
+                .await
+                .expect("Failed to get file");
+                let content_type = response
+                    .headers()
+                    .get(CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_string);
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .expect("Failed to read body");
+                (content_type, String::from_utf8_lossy(&body).to_string())
+            }
+        };
+
+        let (content_type, body) = get("legacy").await;
+        assert_eq!(content_type.as_deref(), Some("text/plain; charset=utf-8"));
+        assert_eq!(body, "こんにちは");
+
+        let (_, body) = get("utf8").await;
+        assert_ne!(body, "こんにちは");
+        assert!(body.contains(char::REPLACEMENT_CHARACTER));
+
+        let server_path = ServerPath {
+            text_encoding: Some("klingon".to_string()),
+            ..ServerPath::test_local(temp_dir.path())
+        };
+        assert!(matches!(
+            server_path.text_encoding(),
+            Err(Error::Configuration(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_upload_file_streams() {
+        use axum::extract::DefaultBodyLimit;
+        use axum::routing::post;
+        use tower::ServiceExt;
+
+        const CHUNK: usize = 1024 * 1024;
+        const CHUNKS: usize = 8;
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        // like the real app, which leaves the size limits to the upload handlers
+        let app = axum::Router::new()
+            .route("/upload/{server_path}/", post(upload_nopath))
+            .layer(DefaultBodyLimit::disable())
+            .with_state(test_state(&temp_dir).await);
+        let data: Vec<u8> = (0..CHUNK * CHUNKS)
+            .map(|index| (index % 251) as u8)
+            .collect();
+
+        // how much of the upload is on disk, in the hidden staging files
+        let staged_bytes = |dir: std::path::PathBuf| {
+            std::fs::read_dir(dir)
+                .expect("Failed to read dir")
+                .filter_map(Result::ok)
+                .filter(|entry| entry.file_name().to_string_lossy().starts_with('.'))
+                .filter_map(|entry| entry.metadata().ok())
+                .map(|metadata| metadata.len())
+                .sum::<u64>()
+        };
+        let upload = |filename: &'static str, overwrite_after: bool| {
+            let boundary = "filekidtestboundary";
+            let data = data.clone();
+            let dir = temp_dir.path().to_path_buf();
+            let staged_when_last_chunk_read = Arc::new(AtomicU64::new(0));
+            let seen = staged_when_last_chunk_read.clone();
+            // chunks arrive a bit at a time, like they would over the network
+            let body = futures::stream::iter(0..=CHUNKS + 1).then(|index| async move {
+                tokio::task::yield_now().await;
+                index
+            });
+            let body = body.map(move |index| {
+                Ok::<_, std::io::Error>(Bytes::from(match index {
+                    0 => format!(
+                        "--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{filename}\"\r\n\r\n"
+                    )
+                    .into_bytes(),
+                    index if index <= CHUNKS => {
+                        if index == CHUNKS {
+                            seen.store(staged_bytes(dir.clone()), Ordering::Relaxed);
+                        }
+                        data[(index - 1) * CHUNK..index * CHUNK].to_vec()
+                    }
+                    _ => match overwrite_after {
+                        // the overwrite field can still come after the file
+                        true => format!(
+                            "\r\n--{boundary}\r\nContent-Disposition: form-data; name=\"overwrite\"\r\n\r\non\r\n--{boundary}--\r\n"
+                        ),
+                        false => format!("\r\n--{boundary}--\r\n"),
+                    }
+                    .into_bytes(),
+                }))
+            });
+            let app = app.clone();
+            async move {
+                let mut request = axum::http::Request::builder()
+                    .method("POST")
+                    .uri("/upload/test/")
+                    .header(
+                        CONTENT_TYPE,
+                        format!("multipart/form-data; boundary={boundary}"),
+                    )
+                    .body(axum::body::Body::from_stream(body))
+                    .expect("Failed to build request");
+                request.extensions_mut().insert(test_user_claims());
+                let response = app.oneshot(request).await.expect("Failed to make request");
+                (
+                    response.status(),
+                    staged_when_last_chunk_read.load(Ordering::Relaxed),
+                )
+            }
+        };
+
+        let (status, staged) = upload("big.bin", false).await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        // most of the file was already written out before the end of it arrived
+        assert!(
+            staged >= (CHUNK * (CHUNKS - 2)) as u64,
+            "only {staged} bytes staged"
+        );
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("big.bin")).expect("Failed to read upload"),
+            data
+        );
+        assert_eq!(staged_bytes(temp_dir.path().to_path_buf()), 0);
+
+        // a refused upload doesn't leave its staging file behind
+        std::fs::write(temp_dir.path().join("existing.bin"), b"original").expect("Failed to write");
+        let (status, _) = upload("existing.bin", false).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(staged_bytes(temp_dir.path().to_path_buf()), 0);
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("existing.bin")).expect("Failed to read file"),
+            b"original"
+        );
+
+        let (status, _) = upload("existing.bin", true).await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("existing.bin")).expect("Failed to read file"),
+            data
+        );
+        assert_eq!(staged_bytes(temp_dir.path().to_path_buf()), 0);
+    }
+
+    #[tokio::test]
+    async fn test_upload_file_max_file_bytes() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "test".to_string(),
+            ServerPath {
+                max_file_bytes: Some(8),
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let _ = upload_file(
+            state.to_state(),
+            Path(("test".to_string(), None)),
+            Some(test_user_claims()),
+            test_multipart(&[("file", Some("small.txt"), b"12345678")]).await,
+        )
+        .await
+        .expect("Failed to upload file under the limit");
+        assert!(temp_dir.path().join("small.txt").exists());
+
+        assert_eq!(
+            upload_file(
+                state.to_state(),
+                Path(("test".to_string(), None)),
+                Some(test_user_claims()),
+                test_multipart(&[("file", Some("big.txt"), b"123456789")]).await,
+            )
+            .await
+            .err(),
+            Some(Error::BadRequest(
+                "big.txt is 9 bytes, files here can be at most 8 bytes".to_string()
+            ))
+        );
+        assert!(!temp_dir.path().join("big.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_upload_file_tempdir() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("test.txt"), b"original")
+            .expect("Failed to write test file");
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "test".to_string(),
+            ServerPath {
+                type_: crate::fs::FileKidFsType::TempDir,
+                max_file_bytes: Some(8),
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+
+        // a failed upload's staged file gets cleaned up
+        assert!(upload_file(
+            state.to_state(),
+            Path(("test".to_string(), None)),
+            Some(test_user_claims()),
+            test_multipart(&[("file", Some("big.txt"), b"123456789")]).await,
+        )
+        .await
+        .is_err());
+        assert_eq!(
+            std::fs::read_dir(temp_dir.path())
+                .expect("Failed to list temp dir")
+                .count(),
+            1
+        );
+
+        let _ = upload_file(
+            state.to_state(),
+            Path(("test".to_string(), None)),
+            Some(test_user_claims()),
+            test_multipart(&[
+                ("file", Some("test.txt"), b"replaced"),
+                ("overwrite", None, b"true"),
+            ])
+            .await,
+        )
+        .await
+        .expect("Failed to overwrite file");
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("test.txt")).expect("Failed to read file"),
+            b"replaced"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upload_file_quota() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "test".to_string(),
+            ServerPath {
+                quota_bytes: Some(10),
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let _ = upload_file(
+            state.to_state(),
+            Path(("test".to_string(), None)),
+            Some(test_user_claims()),
+            test_multipart(&[("file", Some("small.txt"), b"12345678")]).await,
+        )
+        .await
+        .expect("Failed to upload file under the quota");
+        assert!(temp_dir.path().join("small.txt").exists());
+
+        assert_eq!(
+            upload_file(
+                state.to_state(),
+                Path(("test".to_string(), None)),
+                Some(test_user_claims()),
+                test_multipart(&[("file", Some("more.txt"), b"12345")]).await,
+            )
+            .await
+            .err(),
+            Some(Error::BadRequest("quota exceeded".to_string()))
+        );
+        assert!(!temp_dir.path().join("more.txt").exists());
+
+        // the file being replaced doesn't count
+        let _ = upload_file(
+            state.to_state(),
+            Path(("test".to_string(), None)),
+            Some(test_user_claims()),
+            test_multipart(&[
+                ("overwrite", None, b"true"),
+                ("file", Some("small.txt"), b"1234567890"),
+            ])
+            .await,
+        )
+        .await
+        .expect("Failed to overwrite file within the quota");
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("small.txt")).expect("Failed to read file"),
+            b"1234567890"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_file_precompressed() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("app.js"), b"plain").expect("Failed to write");
+        std::fs::write(temp_dir.path().join("app.js.br"), b"brotli!").expect("Failed to write");
+        std::fs::write(temp_dir.path().join("app.js.gz"), b"gzipped!!").expect("Failed to write");
+        std::fs::write(temp_dir.path().join("only.css"), b"plain").expect("Failed to write");
+        std::fs::write(temp_dir.path().join("only.css.gz"), b"gzipped!!").expect("Failed to write");
+        std::fs::write(temp_dir.path().join("bare.txt"), b"plain").expect("Failed to write");
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "test".to_string(),
+            ServerPath {
+                prefer_precompressed: true,
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let get = |filepath: &'static str, accept_encoding: Option<&'static str>| {
+            let state = state.clone();
+            async move {
+                let mut headers = HeaderMap::new();
+                if let Some(accept_encoding) = accept_encoding {
+                    headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(accept_encoding));
+                }
+                let response = get_file(
+                    state.to_state(),
+                    Path(("test".to_string(), filepath.to_string())),
+                    Query(GetFileQuery::default()),
+                    None,
+                    headers,
+                )
+                .await
+                .expect("Failed to get file");
+                let header = |name| {
+                    response
+                        .headers()
+                        .get(name)
+                        .and_then(|v| v.to_str().ok())
+                        .map(str::to_string)
+                };
+                let (encoding, content_type) = (header(CONTENT_ENCODING), header(CONTENT_TYPE));
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .expect("Failed to read body");
+                (encoding, content_type, body.to_vec())
+            }
+        };
+
+        // brotli's preferred when both are there and accepted
+        let (encoding, content_type, body) = get("app.js", Some("gzip, deflate, br")).await;
+        assert_eq!(encoding.as_deref(), Some("br"));
+        assert_eq!(content_type.as_deref(), Some("text/javascript"));
+        assert_eq!(body, b"brotli!");
+
+        // unless the client says otherwise
+        let (encoding, _, body) = get("app.js", Some("br;q=0.5, gzip")).await;
+        assert_eq!(encoding.as_deref(), Some("gzip"));
+        assert_eq!(body, b"gzipped!!");
+        let (encoding, content_type, body) = get("only.css", Some("br, gzip")).await;
+        assert_eq!(encoding.as_deref(), Some("gzip"));
+        assert_eq!(content_type.as_deref(), Some("text/css"));
+        assert_eq!(body, b"gzipped!!");
+
+        // no variant, or not accepted, gets the file itself
+        for (filepath, accept_encoding) in [
+            ("bare.txt", Some("br, gzip")),
+            ("app.js", None),
+            ("app.js", Some("br;q=0, gzip;q=0")),
+        ] {
+            let (encoding, _, body) = get(filepath, accept_encoding).await;
+            assert_eq!(encoding, None);
+            assert_eq!(body, b"plain");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_file_streamed_content_length() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let contents = vec![b'x'; 200_000];
+        std::fs::write(temp_dir.path().join("big.bin"), &contents).expect("Failed to write file");
+        let mut config = Config::test_config();
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(temp_dir.path()));
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let response = get_file(
+            state.to_state(),
+            Path(("test".to_string(), "big.bin".to_string())),
+            Query(GetFileQuery::default()),
+            None,
+            HeaderMap::new(),
+        )
+        .await
+        .expect("Failed to get file");
+        assert_eq!(
+            response
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok()),
+            Some("200000")
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Failed to read body");
+        assert_eq!(body.as_ref(), contents.as_slice());
+    }
+
+    #[tokio::test]
+    async fn test_head_file() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("test.txt"), b"hello world")
+            .expect("Failed to write file");
+        let mut config = Config::test_config();
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(temp_dir.path()));
+        config.server_paths.insert(
+            "immutable".to_string(),
+            ServerPath {
+                immutable: true,
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+        let request = |server_path: &str, filepath: &str, head: bool| {
+            let path = Path((server_path.to_string(), filepath.to_string()));
+            let state = state.to_state();
+            async move {
+                match head {
+                    true => {
+                        head_file(
+                            state,
+                            path,
+                            Query(GetFileQuery::default()),
+                            None,
+                            HeaderMap::new(),
+                        )
+                        .await
+                    }
+                    false => {
+                        get_file(
+                            state,
+                            path,
+                            Query(GetFileQuery::default()),
+                            None,
+                            HeaderMap::new(),
+                        )
+                        .await
+                    }
+                }
+            }
+        };
+
+        for server_path in ["test", "immutable"] {
+            let get = request(server_path, "test.txt", false)
+                .await
+                .expect("Failed to get file");
+            let head = request(server_path, "test.txt", true)
+                .await
+                .expect("Failed to head file");
+            assert_eq!(head.status(), StatusCode::OK);
+            for header in [CONTENT_TYPE, ACCEPT_RANGES, ETAG, LAST_MODIFIED] {
+                assert!(
+                    head.headers().get(&header).is_some(),
+                    "{server_path} {header}"
+                );
+                assert_eq!(head.headers().get(&header), get.headers().get(&header));
+            }
+            assert_eq!(
+                head.headers()
+                    .get(CONTENT_LENGTH)
+                    .and_then(|value| value.to_str().ok()),
+                Some("11")
+            );
+            let body = axum::body::to_bytes(head.into_body(), usize::MAX)
+                .await
+                .expect("Failed to read body");
+            assert!(body.is_empty());
+        }
+
+        let response = request("test", "nope.txt", true)
+            .await
+            .expect_err("Missing files aren't there")
+            .into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_get_file_conditional() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("test.txt"), b"hello").expect("Failed to write file");
+        let mut config = Config::test_config();
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(temp_dir.path()));
+        config.server_paths.insert(
+            "immutable".to_string(),
+            ServerPath {
+                immutable: true,
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+        let get = |server_path: &'static str, header: Option<(HeaderName, String)>| {
+            let state = state.clone();
+            async move {
+                let mut request_headers = HeaderMap::new();
+                if let Some((name, value)) = header {
+                    request_headers
+                        .insert(name, HeaderValue::from_str(&value).expect("Bad header"));
+                }
+                get_file(
+                    state.to_state(),
+                    Path((server_path.to_string(), "test.txt".to_string())),
+                    Query(GetFileQuery::default()),
+                    None,
+                    request_headers,
+                )
+                .await
+                .expect("Failed to get file")
+            }
+        };
+
+        let response = get("test", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .and_then(|v| v.to_str().ok())
+            .expect("Missing ETag")
+            .to_string();
+        assert!(etag.starts_with('"'));
+        let last_modified = response
+            .headers()
+            .get(LAST_MODIFIED)
+            .and_then(|v| v.to_str().ok())
+            .expect("Missing Last-Modified")
+            .to_string();
+
+        let response = get("test", Some((IF_NONE_MATCH, etag.clone()))).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(
+            response.headers().get(ETAG).and_then(|v| v.to_str().ok()),
+            Some(etag.as_str())
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Failed to read body");
+        assert!(body.is_empty());
+
+        // If-None-Match compares weakly, so the W/ doesn't matter
+        let response = get("test", Some((IF_NONE_MATCH, format!("W/{etag}")))).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let response = get("test", Some((IF_NONE_MATCH, "W/\"nope\"".to_string()))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = get("test", Some((IF_MODIFIED_SINCE, last_modified))).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let response = get(
+            "test",
+            Some((
+                IF_MODIFIED_SINCE,
+                "Sun, 06 Nov 1994 08:49:37 GMT".to_string(),
+            )),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // immutable paths use their content hash
+        let response = get("immutable", None).await;
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .and_then(|v| v.to_str().ok())
+            .expect("Missing ETag")
+            .to_string();
+        assert!(!etag.starts_with("W/"));
+        let response = get("immutable", Some((IF_NONE_MATCH, etag))).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn test_file_dir_mismatch_redirects() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::create_dir(temp_dir.path().join("somedir")).expect("Failed to create dir");
+        std::fs::write(temp_dir.path().join("somedir/file.txt"), b"hello")
+            .expect("Failed to write file");
+        let state = test_state(&temp_dir).await;
+        let location = |response: &Response| {
+            response
+                .headers()
+                .get(axum::http::header::LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+
+        let response = get_file(
+            state.to_state(),
+            Path(("test".to_string(), "somedir".to_string())),
+            Query(GetFileQuery::default()),
+            None,
+            HeaderMap::new(),
+        )
+        .await
+        .expect("Failed to get dir");
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(location(&response).as_deref(), Some("/browse/test/somedir"));
+
+        let response = browse(
+            state.to_state(),
+            Path(("test".to_string(), Some("somedir/file.txt".to_string()))),
+            Query(BrowseQuery::default()),
+            Some(test_user_claims()),
+            HeaderMap::new(),
+        )
+        .await
+        .expect("Failed to browse file");
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            location(&response).as_deref(),
+            Some("/get/test/somedir/file.txt")
+        );
+
+        // missing things are still missing
+        assert!(matches!(
+            browse(
+                state.to_state(),
+                Path(("test".to_string(), Some("nope".to_string()))),
+                Query(BrowseQuery::default()),
+                Some(test_user_claims()),
+                HeaderMap::new(),
+            )
+            .await,
+            Err(Error::NotFound(_))
+        ));
+        assert!(matches!(
+            get_file(
+                state.to_state(),
+                Path(("test".to_string(), "nope".to_string())),
+                Query(GetFileQuery::default()),
+                None,
+                HeaderMap::new(),
+            )
+            .await,
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_browse_serve_index() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::create_dir(temp_dir.path().join("site")).expect("Failed to create dir");
+        std::fs::write(temp_dir.path().join("site/index.html"), b"<h1>hello</h1>")
+            .expect("Failed to write index");
+        std::fs::create_dir(temp_dir.path().join("plain")).expect("Failed to create dir");
+        std::fs::write(temp_dir.path().join("plain/file.txt"), b"hello")
+            .expect("Failed to write file");
+
+        let browse_dir = |state: WebState, dir: &'static str| async move {
+            browse(
+                state.to_state(),
+                Path(("test".to_string(), Some(dir.to_string()))),
+                Query(BrowseQuery::default()),
+                Some(test_user_claims()),
+                HeaderMap::new(),
+            )
+            .await
+            .expect("Failed to browse")
+        };
+        let body = |response: Response| async move {
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .expect("Failed to read body");
+            String::from_utf8_lossy(&body).to_string()
+        };
+
+        // the flag's off by default, so directories are always listed
+        let state = test_state(&temp_dir).await;
+        let response = browse_dir(state.clone(), "site/").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(body(response).await.contains("index.html"));
+
+        let mut config = Config::test_config();
+        let mut server_path = ServerPath::test_local(temp_dir.path());
+        server_path.serve_index = true;
+        config.server_paths.insert("test".to_string(), server_path);
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let response = browse_dir(state.clone(), "site/").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok()),
+            Some("text/html")
+        );
+        assert_eq!(body(response).await, "<h1>hello</h1>");
+
+        // relative links in the index need the trailing slash
+        let response = browse_dir(state.clone(), "site").await;
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            response
+                .headers()
+                .get(axum::http::header::LOCATION)
+                .and_then(|v| v.to_str().ok()),
+            Some("/browse/test/site/")
+        );
+
+        // without an index it's the usual listing
+        let response = browse_dir(state.clone(), "plain/").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(body(response).await.contains("file.txt"));
+    }
+
+    #[tokio::test]
+    async fn test_browse_show_hidden() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("visible.txt"), b"hello").expect("Failed to write");
+        std::fs::write(temp_dir.path().join(".env"), b"hello").expect("Failed to write");
+
+        let browse_with = |state: WebState, show_hidden: Option<&'static str>| async move {
+            let response = browse(
+                state.to_state(),
+                Path(("test".to_string(), None)),
+                Query(BrowseQuery {
+                    show_hidden: show_hidden.map(str::to_string),
+                    ..Default::default()
+                }),
+                Some(test_user_claims()),
+                HeaderMap::new(),
+            )
+            .await
+            .expect("Failed to browse");
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .expect("Failed to read body");
+            String::from_utf8_lossy(&body).to_string()
+        };
+
+        let state = test_state(&temp_dir).await;
+        let body = browse_with(state.clone(), None).await;
+        assert!(body.contains("visible.txt"));
+        assert!(!body.contains(".env"));
+        assert!(browse_with(state.clone(), Some("1")).await.contains(".env"));
+
+        let mut config = Config::test_config();
+        let mut server_path = ServerPath::test_local(temp_dir.path());
+        server_path.show_hidden = true;
+        config.server_paths.insert("test".to_string(), server_path);
+        let state = WebState::test_webstate_with_config(config).await;
+        assert!(browse_with(state.clone(), None).await.contains(".env"));
+        assert!(!browse_with(state.clone(), Some("0")).await.contains(".env"));
+    }
+
+    #[tokio::test]
+    async fn test_browse_with_sizes() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::create_dir_all(temp_dir.path().join("docs/old")).expect("Failed to create dir");
+        std::fs::write(temp_dir.path().join("docs/a.txt"), b"hello").expect("Failed to write");
+        std::fs::write(temp_dir.path().join("docs/old/b.txt"), b"world").expect("Failed to write");
+        let state = test_state(&temp_dir).await;
+
+        let browse_with = |with_sizes: Option<&'static str>| {
+            let state = state.clone();
+            async move {
+                let response = browse(
+                    state.to_state(),
+                    Path(("test".to_string(), None)),
+                    Query(BrowseQuery {
+                        with_sizes: with_sizes.map(str::to_string),
+                        ..Default::default()
+                    }),
+                    Some(test_user_claims()),
+                    HeaderMap::new(),
+                )
+                .await
+                .expect("Failed to browse");
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .expect("Failed to read body");
+                String::from_utf8_lossy(&body).to_string()
+            }
+        };
+
+        let body = browse_with(None).await;
+        assert!(!body.contains("in 2 files"));
+        assert!(body.contains("with_sizes=1"));
+        assert!(browse_with(Some("1")).await.contains("10 B in 2 files"));
+    }
+
+    #[tokio::test]
+    async fn test_upload_audit() {
+        use crate::views::oidc::OIDC_TEST_USERNAME;
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let audit_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let audit_log = audit_dir.path().join("audit.log");
+        std::fs::create_dir(temp_dir.path().join("docs")).expect("Failed to create dir");
+        let mut config = Config::test_config();
+        config.audit_log = Some(audit_log.clone());
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(temp_dir.path()));
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let upload = || async {
+            upload_file(
+                state.to_state(),
+                Path(("test".to_string(), Some("docs".to_string()))),
+                Some(test_user_claims()),
+                test_multipart(&[("file", Some("report.pdf"), b"hello")]).await,
+            )
+            .await
+        };
+        let _ = upload().await.expect("Failed to upload");
+
+        let records = audit::read_records(&audit_log);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].username, OIDC_TEST_USERNAME);
+        assert_eq!(records[0].action, AuditAction::Upload);
+        assert_eq!(records[0].server_path, "test");
+        assert_eq!(records[0].key, "docs/report.pdf");
+        assert_eq!(records[0].result, "ok");
+
+        // refusals are recorded too
+        assert!(upload().await.is_err());
+        let records = audit::read_records(&audit_log);
+        assert_eq!(records.len(), 2);
+        assert_ne!(records[1].result, "ok");
+    }
+
+    #[tokio::test]
+    async fn test_upload_name_template() {
+        use crate::views::oidc::OIDC_TEST_USERNAME;
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "test".to_string(),
+            ServerPath {
+                upload_name_template: Some("{date}/{user}/{original}".to_string()),
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let _ = upload_file(
+            state.to_state(),
+            Path(("test".to_string(), None)),
+            Some(test_user_claims()),
+            test_multipart(&[("file", Some("report.pdf"), b"hello")]).await,
+        )
+        .await
+        .expect("Failed to upload");
+        let today = chrono::Utc::now().format("%Y-%m-%d").to_string();
+        assert_eq!(
+            std::fs::read(
+                temp_dir
+                    .path()
+                    .join(&today)
+                    .join(OIDC_TEST_USERNAME)
+                    .join("report.pdf")
+            )
+            .expect("Upload wasn't where the template said"),
+            b"hello"
+        );
+        assert!(!temp_dir.path().join("report.pdf").exists());
+
+        // values can't climb out or add directories, templates can't either
+        let server_path = ServerPath {
+            upload_name_template: Some("{user}/{id}-{original}".to_string()),
+            ..ServerPath::test_local(temp_dir.path())
+        };
+        let name = server_path
+            .upload_name("../../etc/passwd", "../bob")
+            .expect("Failed to render");
+        let (user, file) = name.split_once('/').expect("Missing user dir");
+        assert_eq!(user, ".._bob");
+        assert_eq!(file.len(), "12345678-.._.._etc_passwd".len());
+        assert!(file.ends_with("-.._.._etc_passwd"));
+
+        let server_path = ServerPath {
+            upload_name_template: Some("../{original}".to_string()),
+            ..ServerPath::test_local(temp_dir.path())
+        };
+        assert!(matches!(
+            server_path.upload_name("file.txt", "bob"),
+            Err(Error::BadRequest(_))
+        ));
+        let server_path = ServerPath {
+            upload_name_template: Some("{nope}/{original}".to_string()),
+            ..ServerPath::test_local(temp_dir.path())
+        };
+        assert!(matches!(
+            server_path.upload_name("file.txt", "bob"),
+            Err(Error::Configuration(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_upload_file_bad_filenames() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::create_dir(temp_dir.path().join("sub")).expect("Failed to create dir");
+        let state = test_state(&temp_dir).await;
+
+        for filename in [
+            "../escape.txt",
+            "sub/../../escape.txt",
+            "..",
+            "bad\u{1b}[31m.txt",
+        ] {
+            let result = upload_file(
+                state.to_state(),
+                Path(("test".to_string(), Some("sub".to_string()))),
+                Some(test_user_claims()),
+                test_multipart(&[("file", Some(filename), b"nope")]).await,
+            )
+            .await;
+            assert!(
+                matches!(result, Err(Error::BadRequest(_))),
+                "{filename:?} should have been rejected"
+            );
+        }
+        assert!(!temp_dir.path().join("escape.txt").exists());
+
+        let _ = upload_file(
+            state.to_state(),
+            Path(("test".to_string(), Some("sub".to_string()))),
+            Some(test_user_claims()),
+            test_multipart(&[("file", Some("café.txt"), b"yes")]).await,
+        )
+        .await
+        .expect("Unicode filenames should upload");
+        assert!(temp_dir.path().join("sub/café.txt").exists());
+    }
+}
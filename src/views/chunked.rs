@@ -0,0 +1,595 @@
+//! Chunked uploads, so a big upload that fails halfway can carry on from where it got to.
+//!
+//! `POST /uploads/init` starts one, each `PATCH /uploads/<id>` appends a `Content-Range` to a staging file,
+//! and `POST /uploads/<id>/complete` stores the whole thing. Uploads are tracked in the user's session,
+//! and staging files that stop getting chunks are cleaned up by [purge_stale_uploads_task].
+
+use std::io::SeekFrom;
+use std::path::{Path as FsPath, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use axum::body::Body;
+use axum::extract::Path;
+use axum::http::header::CONTENT_RANGE;
+use axum::http::HeaderMap;
+use axum::Json;
+use futures::StreamExt;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+use tracing::{info, warn};
+
+use super::put::read_body;
+use super::{check_login, prelude::*};
+use crate::audit::{self, AuditAction, AuditRecord};
+use crate::constants::CHUNKED_UPLOAD_SCAN_SECS;
+use crate::fs::{check_unique_basename, fs_for_user, key_escapes_base};
+use crate::SendableConfig;
+
+/// Where an upload's state lives in the session
+fn session_key(id: &str) -> String {
+    format!("chunked_upload:{id}")
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ChunkedInit {
+    pub(crate) server_path: String,
+    /// Where the file ends up, relative to the server path
+    pub(crate) path: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct ChunkedUpload {
+    pub(crate) id: String,
+    pub(crate) server_path: String,
+    pub(crate) key: String,
+    /// Bytes so far, the next chunk has to start here
+    pub(crate) received: u64,
+}
+
+async fn load_upload(session: &Session, id: &str) -> Result<ChunkedUpload, Error> {
+    session
+        .get::<ChunkedUpload>(&session_key(id))
+        .await
+        .map_err(|err| Error::Database(format!("Failed to load upload {id}: {err}")))?
+        .ok_or_else(|| Error::NotFound(format!("Upload {id}")))
+}
+
+async fn save_upload(session: &Session, upload: &ChunkedUpload) -> Result<(), Error> {
+    session
+        .insert(&session_key(&upload.id), upload)
+        .await
+        .map_err(|err| Error::Database(format!("Failed to save upload {}: {err}", upload.id)))
+}
+
+/// The start and (inclusive) end of `Content-Range: bytes <start>-<end>/<total or *>`, the total's not known up front
+fn parse_chunk_range(value: &str) -> Result<(u64, u64), Error> {
+    let invalid = || Error::BadRequest(format!("Invalid Content-Range header: {value}"));
+    let (start, end) = value
+        .trim()
+        .strip_prefix("bytes ")
+        .and_then(|value| value.split_once('/'))
+        .and_then(|(range, _)| range.split_once('-'))
+        .ok_or_else(invalid)?;
+    let start: u64 = start.parse().map_err(|_| invalid())?;
+    let end: u64 = end.parse().map_err(|_| invalid())?;
+    match start <= end {
+        true => Ok((start, end)),
+        false => Err(invalid()),
+    }
+}
+
+/// The checks every step makes, since the config can be reloaded halfway through an upload
+async fn checked_server_path(state: &WebState, server_path: &str) -> Result<ServerPath, Error> {
+    let server_reader = state.configuration.read().await;
+    server_reader.check_uploads_enabled()?;
+    let server_path_object = match server_reader.server_paths.get(server_path) {
+        None => {
+            error!("Couldn't find server path {}", server_path);
+            return Err(Error::NotFound(server_path.to_string()));
+        }
+        Some(p) => p.clone(),
+    };
+    server_path_object.check_method(&Method::POST)?;
+    server_path_object.check_writable()?;
+    Ok(server_path_object)
+}
+
+pub(crate) async fn chunked_init(
+    State(state): State<WebState>,
+    session: Session,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    Json(init): Json<ChunkedInit>,
+) -> Result<impl IntoResponse, Error> {
+    let user = check_login(claims)?;
+    let server_path_object = checked_server_path(&state, &init.server_path).await?;
+    let key = init.path.trim().trim_matches('/').to_string();
+    if key.is_empty() {
+        return Err(Error::BadRequest("Upload path is empty".to_string()));
+    }
+    if key_escapes_base(&key) {
+        return Err(Error::NotAuthorized(format!(
+            "{key} is outside of the base path"
+        )));
+    }
+    let filekidfs = fs_for_user(&server_path_object, Some(&user.username()))?;
+    if filekidfs.exists(&key)? {
+        return Err(Error::BadRequest(format!("{key} already exists")));
+    }
+    check_unique_basename(&server_path_object, filekidfs.as_ref(), &key)?;
+
+    let staging_dir = state.configuration.read().await.chunked_upload_dir();
+    tokio::fs::create_dir_all(&staging_dir).await?;
+    // the random staging filename doubles as the upload id
+    let (_, staging_file) = tempfile::Builder::new()
+        .prefix("upload-")
+        .tempfile_in(&staging_dir)?
+        .keep()
+        .map_err(|err| Error::Io(format!("Failed to create staging file: {err}")))?;
+    let id = staging_file
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .ok_or_else(|| Error::InternalServerError("Staging file has no name".to_string()))?;
+
+    let upload = ChunkedUpload {
+        id,
+        server_path: init.server_path,
+        key,
+        received: 0,
+    };
+    save_upload(&session, &upload).await?;
+    debug!(
+        "User {} started chunked upload {} of {} to {}",
+        user.username(),
+        upload.id,
+        upload.key,
+        upload.server_path
+    );
+    Ok((StatusCode::CREATED, Json(upload)))
+}
+
+/// Writes the next chunk, which has to start where the last one finished
+pub(crate) async fn chunked_append(
+    State(state): State<WebState>,
+    Path(id): Path<String>,
+    session: Session,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<ChunkedUpload>, Error> {
+    check_login(claims)?;
+    let mut upload = load_upload(&session, &id).await?;
+    let server_path_object = checked_server_path(&state, &upload.server_path).await?;
+
+    let (start, end) = parse_chunk_range(
+        headers
+            .get(CONTENT_RANGE)
+            .ok_or_else(|| Error::BadRequest("Chunks need a Content-Range header".to_string()))?
+            .to_str()
+            .map_err(|err| Error::BadRequest(format!("Invalid Content-Range header: {err}")))?,
+    )?;
+    if start != upload.received {
+        return Err(Error::BadRequest(format!(
+            "Upload {id} has {} bytes, the next chunk has to start there not at {start}",
+            upload.received
+        )));
+    }
+    let length = end - start + 1;
+    server_path_object.check_file_size(&upload.key, upload.received + length)?;
+
+    let max_bytes = state.configuration.read().await.max_upload_mb * 1024 * 1024;
+    let chunk = read_body(body, max_bytes).await?;
+    if chunk.len() as u64 != length {
+        return Err(Error::BadRequest(format!(
+            "Content-Range says {length} bytes but the chunk was {} bytes",
+            chunk.len()
+        )));
+    }
+
+    let staging_file = state
+        .configuration
+        .read()
+        .await
+        .chunked_upload_dir()
+        .join(&upload.id);
+    if !staging_file.is_file() {
+        return Err(Error::NotFound(format!("Upload {id}")));
+    }
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(&staging_file)
+        .await?;
+    // anything past `start` is left over from a chunk that failed partway, so it goes
+    file.set_len(start).await?;
+    file.seek(SeekFrom::Start(start)).await?;
+    file.write_all(&chunk).await?;
+    file.flush().await?;
+
+    upload.received += length;
+    save_upload(&session, &upload).await?;
+    Ok(Json(upload))
+}
+
+/// Stores the assembled file, the same way [crate::views::put::put_file] stores a whole one
+pub(crate) async fn chunked_complete(
+    State(state): State<WebState>,
+    Path(id): Path<String>,
+    session: Session,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+) -> Result<StatusCode, Error> {
+    let user = check_login(claims)?;
+    let upload = load_upload(&session, &id).await?;
+    let server_path_object = checked_server_path(&state, &upload.server_path).await?;
+    if key_escapes_base(&upload.key) {
+        return Err(Error::NotAuthorized(format!(
+            "{} is outside of the base path",
+            upload.key
+        )));
+    }
+
+    let staging_file = state
+        .configuration
+        .read()
+        .await
+        .chunked_upload_dir()
+        .join(&upload.id);
+    if !staging_file.is_file() {
+        return Err(Error::NotFound(format!("Upload {id}")));
+    }
+    // a chunk that failed partway can have left bytes past what was received
+    let staged = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(&staging_file)
+        .await?;
+    if staged.metadata().await?.len() < upload.received {
+        return Err(Error::InternalServerError(format!(
+            "Upload {id} is missing bytes it was sent"
+        )));
+    }
+    staged.set_len(upload.received).await?;
+    drop(staged);
+
+    let result = async {
+        let filekidfs = fs_for_user(&server_path_object, Some(&user.username()))?;
+        if filekidfs.exists(&upload.key)? {
+            return Err(Error::BadRequest(format!("{} already exists", upload.key)));
+        }
+        server_path_object.check_file_size(&upload.key, upload.received)?;
+        check_unique_basename(&server_path_object, filekidfs.as_ref(), &upload.key)?;
+        // the content checks need the whole file up front, so those paths don't stream
+        let stored = match filekidfs.has_stream_put_file()
+            && server_path_object.normalize_text_eol.is_none()
+            && !server_path_object.reject_executables
+        {
+            true => {
+                state
+                    .dir_sizes
+                    .check_quota(
+                        &upload.server_path,
+                        &server_path_object,
+                        Some(&user.username()),
+                        upload.received,
+                        0,
+                    )
+                    .await?;
+                let stream = ReaderStream::new(tokio::fs::File::open(&staging_file).await?);
+                filekidfs
+                    .stream_put_file(&upload.key, stream.boxed(), Some(upload.received), true)
+                    .await?
+            }
+            false => {
+                let contents = tokio::fs::read(&staging_file).await?;
+                server_path_object.check_not_executable(&upload.key, &contents)?;
+                let contents = server_path_object.normalize_upload(contents.into());
+                state
+                    .dir_sizes
+                    .check_quota(
+                        &upload.server_path,
+                        &server_path_object,
+                        Some(&user.username()),
+                        contents.len() as u64,
+                        0,
+                    )
+                    .await?;
+                filekidfs.put_file(&upload.key, &contents).await?;
+                contents.len() as u64
+            }
+        };
+        state.dir_sizes.add(&upload.server_path, stored);
+        if server_path_object.reject_executables {
+            filekidfs.strip_executable(&upload.key)?;
+        }
+        Ok(())
+    }
+    .await;
+    let audit_log = state.configuration.read().await.audit_log.clone();
+    audit::record(
+        audit_log.as_deref(),
+        AuditRecord::new(
+            user.username(),
+            AuditAction::Upload,
+            &upload.server_path,
+            &upload.key,
+            &result,
+        ),
+    )
+    .await;
+    result?;
+
+    tokio::fs::remove_file(&staging_file).await?;
+    session
+        .remove::<ChunkedUpload>(&session_key(&id))
+        .await
+        .map_err(|err| Error::Database(format!("Failed to remove upload {id}: {err}")))?;
+    debug!(
+        "User {} finished chunked upload {} of {} to {}",
+        user.username(),
+        id,
+        upload.key,
+        upload.server_path
+    );
+    Ok(StatusCode::CREATED)
+}
+
+/// Deletes staging files in `staging_dir` that haven't had a chunk for `expiry`, returning what was deleted
+pub(crate) fn purge_stale_uploads(
+    staging_dir: &FsPath,
+    expiry: Duration,
+) -> Result<Vec<PathBuf>, Error> {
+    if !staging_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let now = SystemTime::now();
+    let mut purged = Vec::new();
+    for entry in std::fs::read_dir(staging_dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        let age = now
+            .duration_since(metadata.modified()?)
+            .unwrap_or(Duration::ZERO);
+        if age < expiry {
+            continue;
+        }
+        std::fs::remove_file(entry.path())?;
+        purged.push(entry.path());
+    }
+    Ok(purged)
+}
+
+/// Runs forever, cleaning up abandoned chunked uploads every [CHUNKED_UPLOAD_SCAN_SECS]
+pub(crate) async fn purge_stale_uploads_task(configuration: SendableConfig) {
+    loop {
+        let config_reader = configuration.read().await;
+        let staging_dir = config_reader.chunked_upload_dir();
+        let expiry = Duration::from_secs(config_reader.chunked_upload_expiry_secs);
+        drop(config_reader);
+
+        match purge_stale_uploads(&staging_dir, expiry) {
+            Ok(purged) => {
+                for path in purged {
+                    info!("Removed abandoned chunked upload {}", path.display());
+                }
+            }
+            Err(err) => warn!(
+                "Failed to clean up chunked uploads in {}: {}",
+                staging_dir.display(),
+                err
+            ),
+        }
+        tokio::time::sleep(Duration::from_secs(CHUNKED_UPLOAD_SCAN_SECS)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::views::oidc::test_user_claims;
+    use axum::http::HeaderValue;
+    use tower_sessions::MemoryStore;
+
+    async fn test_state(base: &FsPath, staging: &FsPath) -> WebState {
+        let mut config = Config::test_config();
+        config.chunked_upload_dir = Some(staging.to_path_buf());
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(base));
+        WebState::test_webstate_with_config(config).await
+    }
+
+    async fn init(state: &WebState, session: &Session, path: &str) -> ChunkedUpload {
+        let response = chunked_init(
+            state.to_state(),
+            session.clone(),
+            Some(test_user_claims()),
+            Json(ChunkedInit {
+                server_path: "test".to_string(),
+                path: path.to_string(),
+            }),
+        )
+        .await
+        .expect("Failed to start upload")
+        .into_response();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Failed to read body");
+        serde_json::from_slice(&body).expect("Failed to parse upload")
+    }
+
+    async fn append(
+        state: &WebState,
+        session: &Session,
+        id: &str,
+        range: &'static str,
+        chunk: &'static [u8],
+    ) -> Result<Json<ChunkedUpload>, Error> {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_RANGE, HeaderValue::from_static(range));
+        chunked_append(
+            state.to_state(),
+            Path(id.to_string()),
+            session.clone(),
+            Some(test_user_claims()),
+            headers,
+            Body::from(chunk),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_chunked_upload() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let staging_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let state = test_state(temp_dir.path(), staging_dir.path()).await;
+        let session = Session::new(None, Arc::new(MemoryStore::default()), None);
+
+        let upload = init(&state, &session, "/big.txt").await;
+        assert_eq!(upload.key, "big.txt");
+        assert_eq!(upload.received, 0);
+
+        let Json(upload) = append(&state, &session, &upload.id, "bytes 0-5/*", b"hello ")
+            .await
+            .expect("Failed to append first chunk");
+        assert_eq!(upload.received, 6);
+        // chunks have to follow on from each other
+        assert!(matches!(
+            append(&state, &session, &upload.id, "bytes 0-4/11", b"world").await,
+            Err(Error::BadRequest(_))
+        ));
+        let Json(upload) = append(&state, &session, &upload.id, "bytes 6-10/11", b"world")
+            .await
+            .expect("Failed to append second chunk");
+        assert_eq!(upload.received, 11);
+        assert!(!temp_dir.path().join("big.txt").exists());
+
+        let status = chunked_complete(
+            state.to_state(),
+            Path(upload.id.clone()),
+            session.clone(),
+            Some(test_user_claims()),
+        )
+        .await
+        .expect("Failed to complete upload");
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("big.txt")).expect("Failed to read upload"),
+            b"hello world"
+        );
+        assert!(!staging_dir.path().join(&upload.id).exists());
+        assert_eq!(
+            load_upload(&session, &upload.id).await,
+            Err(Error::NotFound(format!("Upload {}", upload.id)))
+        );
+
+        // uploads stay inside the server path
+        assert!(matches!(
+            chunked_init(
+                state.to_state(),
+                session.clone(),
+                Some(test_user_claims()),
+                Json(ChunkedInit {
+                    server_path: "test".to_string(),
+                    path: "../escape.txt".to_string(),
+                }),
+            )
+            .await,
+            Err(Error::NotAuthorized(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_chunked_upload_partial_chunk() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let staging_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let state = test_state(temp_dir.path(), staging_dir.path()).await;
+        let session = Session::new(None, Arc::new(MemoryStore::default()), None);
+
+        let complete = |id: String| {
+            chunked_complete(
+                state.to_state(),
+                Path(id),
+                session.clone(),
+                Some(test_user_claims()),
+            )
+        };
+        // what a chunk that died partway through leaves behind
+        let write_partial = |id: &str| {
+            let mut file = std::fs::OpenOptions::new()
+                .append(true)
+                .open(staging_dir.path().join(id))
+                .expect("Failed to open staging file");
+            std::io::Write::write_all(&mut file, b"wor").expect("Failed to write");
+        };
+
+        let upload = init(&state, &session, "retried.txt").await;
+        let _ = append(&state, &session, &upload.id, "bytes 0-5/*", b"hello ")
+            .await
+            .expect("Failed to append first chunk");
+        write_partial(&upload.id);
+        // the retry goes where the client expects, not after the leftovers
+        let _ = append(&state, &session, &upload.id, "bytes 6-10/11", b"world")
+            .await
+            .expect("Failed to append second chunk");
+        assert_eq!(complete(upload.id.clone()).await, Ok(StatusCode::CREATED));
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("retried.txt")).expect("Failed to read upload"),
+            b"hello world"
+        );
+
+        // and leftovers never make it into the stored file
+        let upload = init(&state, &session, "short.txt").await;
+        let _ = append(&state, &session, &upload.id, "bytes 0-5/*", b"hello ")
+            .await
+            .expect("Failed to append chunk");
+        write_partial(&upload.id);
+        assert_eq!(complete(upload.id.clone()).await, Ok(StatusCode::CREATED));
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("short.txt")).expect("Failed to read upload"),
+            b"hello "
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chunked_upload_abandoned() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let staging_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let state = test_state(temp_dir.path(), staging_dir.path()).await;
+        let session = Session::new(None, Arc::new(MemoryStore::default()), None);
+
+        let upload = init(&state, &session, "abandoned.txt").await;
+        let _ = append(&state, &session, &upload.id, "bytes 0-4/*", b"hello")
+            .await
+            .expect("Failed to append chunk");
+        let staging_file = staging_dir.path().join(&upload.id);
+        assert!(staging_file.exists());
+
+        // recent uploads are left alone
+        assert_eq!(
+            purge_stale_uploads(staging_dir.path(), Duration::from_secs(3600))
+                .expect("Failed to purge"),
+            Vec::<PathBuf>::new()
+        );
+        assert_eq!(
+            purge_stale_uploads(staging_dir.path(), Duration::ZERO).expect("Failed to purge"),
+            vec![staging_file.clone()]
+        );
+        assert!(!staging_file.exists());
+
+        assert_eq!(
+            chunked_complete(
+                state.to_state(),
+                Path(upload.id.clone()),
+                session.clone(),
+                Some(test_user_claims()),
+            )
+            .await,
+            Err(Error::NotFound(format!("Upload {}", upload.id)))
+        );
+        assert!(!temp_dir.path().join("abandoned.txt").exists());
+    }
+}
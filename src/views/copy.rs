@@ -0,0 +1,248 @@
+//! Copy-file related things
+
+use super::{check_if_match, check_login, if_match_header, prelude::*};
+
+use crate::audit::{self, AuditAction, AuditRecord};
+use crate::fs::{check_unique_basename, fs_for_user};
+use axum::http::HeaderMap;
+use axum::response::Redirect;
+use axum::Form;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct CopyForm {
+    server_path: String,
+    key: String,
+    /// The full key of the new copy
+    destination: String,
+    #[serde(default)]
+    overwrite: bool,
+    /// The ETag of the destination being overwritten, the `If-Match` header wins if both are sent
+    #[serde(default)]
+    if_match: Option<String>,
+}
+
+impl CopyForm {
+    fn destination_parent_path(&self) -> String {
+        let path = self.destination.clone();
+        let mut path = path.split('/').collect::<Vec<&str>>();
+        path.pop();
+        path.join("/")
+    }
+}
+
+pub(crate) async fn copy_file_post(
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    headers: HeaderMap,
+    Form(form): Form<CopyForm>,
+) -> Result<Redirect, Error> {
+    let user = check_login(claims)?;
+
+    let server_reader = state.configuration.read().await;
+
+    let server_path_object = match server_reader.server_paths.get(&form.server_path) {
+        None => {
+            error!("Couldn't find server path {}", form.server_path);
+            return Err(Error::NotFound(form.server_path));
+        }
+        Some(p) => p,
+    };
+    server_path_object.check_method(&Method::POST)?;
+
+    server_path_object.check_writable()?;
+    let filekidfs = fs_for_user(server_path_object, Some(&user.username()))?;
+
+    if !filekidfs.exists(&form.key)? {
+        error!("Couldn't find file path {:?}", form.key);
+        return Err(Error::NotFound(form.key));
+    }
+
+    let result = async {
+        check_if_match(
+            if_match_header(&headers).or(form.if_match.as_deref()),
+            server_path_object,
+            filekidfs.as_ref(),
+            &form.destination,
+        )
+        .await?;
+        check_unique_basename(server_path_object, filekidfs.as_ref(), &form.destination)?;
+        let size = filekidfs.get_data(&form.key)?.size.unwrap_or(0);
+        let replaced_size = match form.overwrite && filekidfs.is_file(&form.destination) {
+            true => filekidfs.get_data(&form.destination)?.size.unwrap_or(0),
+            false => 0,
+        };
+        state
+            .dir_sizes
+            .check_quota(
+                &form.server_path,
+                server_path_object,
+                Some(&user.username()),
+                size,
+                replaced_size,
+            )
+            .await?;
+        filekidfs.copy_file(&form.key, &form.destination, form.overwrite)?;
+        state.dir_sizes.subtract(&form.server_path, replaced_size);
+        state.dir_sizes.add(&form.server_path, size);
+        Ok(())
+    }
+    .await;
+    audit::record(
+        server_reader.audit_log.as_deref(),
+        AuditRecord::new(
+            user.username(),
+            AuditAction::Copy,
+            &form.server_path,
+            &form.key,
+            &result,
+        )
+        .with_destination(&form.destination),
+    )
+    .await;
+    result?;
+    debug!(
+        "User {} copied {} to {} on {}",
+        user.username(),
+        form.key,
+        form.destination,
+        form.server_path
+    );
+
+    Ok(Redirect::to(&format!(
+        "{}/{}/{}",
+        Urls::Browse.as_ref(),
+        form.server_path,
+        form.destination_parent_path()
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::views::oidc::test_user_claims;
+
+    #[tokio::test]
+    async fn test_copy_file_post() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::create_dir(temp_dir.path().join("subdir")).expect("Failed to create subdir");
+        std::fs::write(temp_dir.path().join("test.txt"), b"Hello, world!")
+            .expect("Failed to write test file");
+
+        let mut config = Config::test_config();
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(temp_dir.path()));
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let form = |overwrite: bool| CopyForm {
+            server_path: "test".to_string(),
+            key: "test.txt".to_string(),
+            destination: "subdir/copy.txt".to_string(),
+            overwrite,
+            if_match: None,
+        };
+
+        let response = copy_file_post(
+            state.to_state(),
+            Some(test_user_claims()),
+            HeaderMap::new(),
+            Form(form(false)),
+        )
+        .await
+        .expect("Failed to copy file")
+        .into_response();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            response
+                .headers()
+                .get("location")
+                .expect("Failed to get location header"),
+            "/browse/test/subdir"
+        );
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("subdir/copy.txt")).expect("Failed to read copy"),
+            b"Hello, world!"
+        );
+
+        assert_eq!(
+            copy_file_post(
+                state.to_state(),
+                Some(test_user_claims()),
+                HeaderMap::new(),
+                Form(form(false))
+            )
+            .await
+            .err(),
+            Some(Error::BadRequest("destination exists".to_string()))
+        );
+        assert!(copy_file_post(
+            state.to_state(),
+            Some(test_user_claims()),
+            HeaderMap::new(),
+            Form(form(true))
+        )
+        .await
+        .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_copy_file_quota_and_if_match() {
+        use axum::http::header::IF_MATCH;
+        use axum::http::HeaderValue;
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("test.txt"), b"12345678")
+            .expect("Failed to write test file");
+
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "test".to_string(),
+            ServerPath {
+                quota_bytes: Some(20),
+                // long enough that only copies change the count
+                size_cache_secs: Some(3600),
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+        let copy = |destination: &str, overwrite: bool, if_match: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(if_match) = if_match {
+                headers.insert(
+                    IF_MATCH,
+                    HeaderValue::from_str(if_match).expect("Invalid header"),
+                );
+            }
+            copy_file_post(
+                state.to_state(),
+                Some(test_user_claims()),
+                headers,
+                Form(CopyForm {
+                    server_path: "test".to_string(),
+                    key: "test.txt".to_string(),
+                    destination: destination.to_string(),
+                    overwrite,
+                    if_match: None,
+                }),
+            )
+        };
+
+        assert!(copy("one.txt", false, None).await.is_ok());
+        // the first copy's counted, so a second doesn't fit
+        assert_eq!(
+            copy("two.txt", false, None).await.err(),
+            Some(Error::BadRequest("quota exceeded".to_string()))
+        );
+        assert!(!temp_dir.path().join("two.txt").exists());
+
+        assert_eq!(
+            copy("one.txt", true, Some(r#""0-0""#)).await.err(),
+            Some(Error::PreconditionFailed(
+                "one.txt has changed since it was loaded".to_string()
+            ))
+        );
+        // replacing a file only needs room for the difference
+        assert!(copy("one.txt", true, None).await.is_ok());
+    }
+}
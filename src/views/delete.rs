@@ -1,18 +1,28 @@
 //! Delete-file related things
 
-use super::{check_login, prelude::*};
+use super::{check_if_match, check_login, if_match_header, prelude::*, served_etag};
 
-use crate::fs::fs_from_serverpath;
+use crate::audit::{self, AuditAction, AuditRecord};
+use crate::fs::{fs_for_user, key_escapes_base, FileKidFs};
+use crate::trash::{self, in_trash, move_to_trash};
 use askama::Template;
 use axum::extract::{Query, State};
+use axum::http::HeaderMap;
 use axum::response::{Html, Redirect, Response};
-use axum::Form;
+use axum::{Form, Json};
 
 #[derive(Debug, Deserialize, Template)]
 #[template(path = "delete_form.html")]
 pub(crate) struct DeletePage {
     server_path: String,
     key: String,
+    /// Directories get a box to tick for deleting everything in them
+    is_dir: bool,
+    /// Whether this goes to the trash unless the permanent box is ticked
+    trash: bool,
+    permanent: bool,
+    /// Sent back with the confirmation, so a file that's changed in the meantime isn't deleted
+    etag: Option<String>,
     username: String,
 }
 
@@ -36,10 +46,23 @@ where
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct DeleteQuery {
-    server_path: String,
-    key: String,
+    pub(crate) server_path: String,
+    pub(crate) key: String,
+    /// Needed to delete a directory that isn't empty
+    #[serde(default)]
+    pub(crate) recursive: bool,
+    /// `permanent=1` skips the server path's trash
+    #[serde(default)]
+    pub(crate) permanent: Option<String>,
+    /// The file's ETag when it was loaded, the `If-Match` header wins if both are sent
+    #[serde(default)]
+    pub(crate) if_match: Option<String>,
 }
 impl DeleteQuery {
+    fn permanent(&self) -> bool {
+        matches!(self.permanent.as_deref(), Some("1" | "true"))
+    }
+
     fn parent_path(&self) -> String {
         let path = self.key.clone();
         let mut path = path.split('/').collect::<Vec<&str>>();
@@ -64,14 +87,25 @@ pub(crate) async fn delete_file_get(
         }
         Some(p) => p,
     };
+    server_path_object.check_method(&Method::GET)?;
 
-    let filekidfs = fs_from_serverpath(server_path_object)?;
+    server_path_object.check_deletable()?;
+    let filekidfs = fs_for_user(server_path_object, Some(&user.username()))?;
     if !filekidfs.exists(&query.key)? {
         error!("Couldn't find file path {:?}", query.key);
         return Err(Error::NotFound(query.key));
     }
 
     DeletePage {
+        is_dir: filekidfs.is_dir(&query.key),
+        trash: server_path_object
+            .trash()
+            .is_some_and(|trash_dir| !in_trash(trash_dir, &query.key)),
+        permanent: query.permanent(),
+        etag: match filekidfs.is_file(&query.key) {
+            true => served_etag(server_path_object, filekidfs.as_ref(), &query.key).await?,
+            false => None,
+        },
         server_path: query.server_path,
         key: query.key,
         username: user.username(),
@@ -79,10 +113,201 @@ pub(crate) async fn delete_file_get(
     .into()
 }
 
+/// Deletes a file or directory, keeping the directory size cache in step.
+///
+/// With a `trash_dir` it's moved in there instead, unless it's already in the trash.
+pub(crate) fn delete_key(
+    state: &WebState,
+    filekidfs: &dyn FileKidFs,
+    server_path: &str,
+    key: &str,
+    recursive: bool,
+    trash_dir: Option<&str>,
+) -> Result<(), Error> {
+    if key_escapes_base(key) {
+        return Err(Error::NotAuthorized(format!(
+            "{key} is outside of the base path"
+        )));
+    }
+    if !filekidfs.exists(key)? {
+        error!("Couldn't find file path {:?}", key);
+        return Err(Error::NotFound(key.to_string()));
+    }
+
+    // nothing's gone for good, so directories don't need the recursive box, and the space is still used
+    if let Some(trash_dir) = trash_dir.filter(|trash_dir| !in_trash(trash_dir, key)) {
+        let name = move_to_trash(filekidfs, trash_dir, key)?;
+        debug!("Moved {} to the trash as {}", key, name);
+        return Ok(());
+    }
+
+    if filekidfs.is_dir(key) {
+        filekidfs.delete_dir(key, recursive)?;
+        // working out what was in there costs more than recounting later
+        state.dir_sizes.forget(server_path);
+    } else {
+        let size = filekidfs.get_data(key)?.size.unwrap_or(0);
+        filekidfs.delete_file(key)?;
+        state.dir_sizes.subtract(server_path, size);
+    }
+    Ok(())
+}
+
 pub(crate) async fn delete_file_post(
     State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    headers: HeaderMap,
     Form(form): Form<DeleteQuery>,
 ) -> Result<impl IntoResponse, Error> {
+    let user = check_login(claims)?;
+
+    let server_reader = state.configuration.read().await;
+
+    let result = async {
+        let server_path_object = match server_reader.server_paths.get(&form.server_path) {
+            None => {
+                error!("Couldn't find server path {}", form.server_path);
+                return Err(Error::NotFound(form.server_path.clone()));
+            }
+            Some(p) => p,
+        };
+        server_path_object.check_method(&Method::POST)?;
+
+        server_path_object.check_deletable()?;
+        let filekidfs = fs_for_user(server_path_object, Some(&user.username()))?;
+        check_if_match(
+            if_match_header(&headers).or(form.if_match.as_deref()),
+            server_path_object,
+            filekidfs.as_ref(),
+            &form.key,
+        )
+        .await?;
+        delete_key(
+            &state,
+            filekidfs.as_ref(),
+            &form.server_path,
+            &form.key,
+            form.recursive,
+            server_path_object.trash().filter(|_| !form.permanent()),
+        )
+    }
+    .await;
+    audit::record(
+        server_reader.audit_log.as_deref(),
+        AuditRecord::new(
+            user.username(),
+            AuditAction::Delete,
+            &form.server_path,
+            &form.key,
+            &result,
+        ),
+    )
+    .await;
+    result?;
+
+    Ok(Redirect::to(&format!(
+        "{}/{}/{}",
+        Urls::Browse.as_ref(),
+        form.server_path,
+        form.parent_path()
+    )))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct BulkDeleteRequest {
+    pub(crate) server_path: String,
+    pub(crate) keys: Vec<String>,
+    /// Needed to delete directories that aren't empty
+    #[serde(default)]
+    pub(crate) recursive: bool,
+    /// Skips the server path's trash
+    #[serde(default)]
+    pub(crate) permanent: bool,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct BulkDeleteResult {
+    pub(crate) key: String,
+    pub(crate) deleted: bool,
+    /// Why it wasn't deleted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+}
+
+/// Deletes a batch of keys on one server path, carrying on past the ones that fail
+pub(crate) async fn bulk_delete_post(
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    Json(request): Json<BulkDeleteRequest>,
+) -> Result<Json<Vec<BulkDeleteResult>>, Error> {
+    let user = check_login(claims)?;
+
+    let server_reader = state.configuration.read().await;
+
+    let server_path_object = match server_reader.server_paths.get(&request.server_path) {
+        None => {
+            error!("Couldn't find server path {}", request.server_path);
+            return Err(Error::NotFound(request.server_path));
+        }
+        Some(p) => p,
+    };
+    server_path_object.check_method(&Method::POST)?;
+
+    server_path_object.check_deletable()?;
+    let filekidfs = fs_for_user(server_path_object, Some(&user.username()))?;
+
+    let mut results = Vec::with_capacity(request.keys.len());
+    for key in request.keys {
+        let result = delete_key(
+            &state,
+            filekidfs.as_ref(),
+            &request.server_path,
+            &key,
+            request.recursive,
+            server_path_object.trash().filter(|_| !request.permanent),
+        );
+        audit::record(
+            server_reader.audit_log.as_deref(),
+            AuditRecord::new(
+                user.username(),
+                AuditAction::Delete,
+                &request.server_path,
+                &key,
+                &result,
+            ),
+        )
+        .await;
+        results.push(BulkDeleteResult {
+            key,
+            deleted: result.is_ok(),
+            error: result.err().map(|err| err.to_string()),
+        });
+    }
+    debug!(
+        "User {} deleted {} of {} keys on {}",
+        user.username(),
+        results.iter().filter(|result| result.deleted).count(),
+        results.len(),
+        request.server_path
+    );
+    Ok(Json(results))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct RestoreForm {
+    pub(crate) server_path: String,
+    /// The name it's got in the trash directory
+    pub(crate) name: String,
+}
+
+/// Moves something from the server path's trash back to where it was deleted from
+pub(crate) async fn restore_file_post(
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    Form(form): Form<RestoreForm>,
+) -> Result<Redirect, Error> {
+    let user = check_login(claims)?;
+
     let server_reader = state.configuration.read().await;
 
     let server_path_object = match server_reader.server_paths.get(&form.server_path) {
@@ -92,20 +317,506 @@ pub(crate) async fn delete_file_post(
         }
         Some(p) => p,
     };
+    server_path_object.check_method(&Method::POST)?;
 
-    let filekidfs = fs_from_serverpath(server_path_object)?;
+    server_path_object.check_writable()?;
+    let trash_dir = server_path_object
+        .trash()
+        .ok_or_else(|| Error::BadRequest(format!("{} doesn't have a trash", form.server_path)))?;
+    let filekidfs = fs_for_user(server_path_object, Some(&user.username()))?;
 
-    if !filekidfs.exists(&form.key)? {
-        error!("Couldn't find file path {:?}", form.key);
-        return Err(Error::NotFound(form.key));
-    }
-
-    filekidfs.delete_file(&form.key)?;
+    let result = trash::restore(filekidfs.as_ref(), trash_dir, &form.name);
+    audit::record(
+        server_reader.audit_log.as_deref(),
+        AuditRecord::new(
+            user.username(),
+            AuditAction::Restore,
+            &form.server_path,
+            &format!("{trash_dir}/{}", form.name),
+            &result,
+        ),
+    )
+    .await;
+    let key = result?;
+    debug!(
+        "User {} restored {} on {} from the trash",
+        user.username(),
+        key,
+        form.server_path
+    );
 
+    let parent = key.rsplit_once('/').map_or("", |(parent, _)| parent);
     Ok(Redirect::to(&format!(
         "{}/{}/{}",
         Urls::Browse.as_ref(),
         form.server_path,
-        form.parent_path()
+        parent
     )))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::views::file_etag;
+    use crate::views::oidc::test_user_claims;
+
+    #[tokio::test]
+    async fn test_delete_read_only() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("test.txt"), b"hello").expect("Failed to write file");
+
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "test".to_string(),
+            ServerPath {
+                read_only: true,
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+        let query = || DeleteQuery {
+            server_path: "test".to_string(),
+            key: "test.txt".to_string(),
+            recursive: false,
+            permanent: None,
+            if_match: None,
+        };
+
+        assert_eq!(
+            delete_file_get(state.to_state(), Query(query()), Some(test_user_claims()))
+                .await
+                .err(),
+            Some(Error::NotAuthorized("server path is read-only".to_string()))
+        );
+        assert_eq!(
+            delete_file_post(
+                state.to_state(),
+                Some(test_user_claims()),
+                HeaderMap::new(),
+                Form(query())
+            )
+            .await
+            .err(),
+            Some(Error::NotAuthorized("server path is read-only".to_string()))
+        );
+        assert!(temp_dir.path().join("test.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_deletions_not_allowed() {
+        use crate::views::put::put_file;
+        use axum::extract::Path;
+        use axum::http::HeaderMap;
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("test.txt"), b"hello").expect("Failed to write file");
+
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "test".to_string(),
+            ServerPath {
+                deletions_allowed: Some(false),
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+        let query = || DeleteQuery {
+            server_path: "test".to_string(),
+            key: "test.txt".to_string(),
+            recursive: false,
+            permanent: None,
+            if_match: None,
+        };
+        let not_allowed = Some(Error::NotAuthorized(
+            "deleting files isn't allowed on this server path".to_string(),
+        ));
+
+        assert_eq!(
+            delete_file_get(state.to_state(), Query(query()), Some(test_user_claims()))
+                .await
+                .err(),
+            not_allowed
+        );
+        let err = delete_file_post(
+            state.to_state(),
+            Some(test_user_claims()),
+            HeaderMap::new(),
+            Form(query()),
+        )
+        .await
+        .err();
+        assert_eq!(err, not_allowed);
+        assert_eq!(
+            err.map(|err| err.into_response().status()),
+            Some(StatusCode::FORBIDDEN)
+        );
+        assert!(temp_dir.path().join("test.txt").exists());
+
+        // uploads carry on
+        let status = put_file(
+            state.to_state(),
+            Path(("test".to_string(), "new.txt".to_string())),
+            Some(test_user_claims()),
+            HeaderMap::new(),
+            axum::body::Body::from("hello"),
+        )
+        .await
+        .expect("Failed to upload");
+        assert!(status.is_success());
+        assert!(temp_dir.path().join("new.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_delete_dir() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::create_dir_all(temp_dir.path().join("empty")).expect("Failed to create dir");
+        std::fs::create_dir_all(temp_dir.path().join("full/sub")).expect("Failed to create dir");
+        std::fs::write(temp_dir.path().join("full/sub/test.txt"), b"hello")
+            .expect("Failed to write file");
+
+        let mut config = Config::test_config();
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(temp_dir.path()));
+        let state = WebState::test_webstate_with_config(config).await;
+        let query = |key: &str, recursive: bool| DeleteQuery {
+            server_path: "test".to_string(),
+            key: key.to_string(),
+            recursive,
+            permanent: None,
+            if_match: None,
+        };
+
+        // empty directories don't need the box ticked
+        delete_file_post(
+            state.to_state(),
+            Some(test_user_claims()),
+            HeaderMap::new(),
+            Form(query("empty", false)),
+        )
+        .await
+        .expect("Failed to delete empty dir");
+        assert!(!temp_dir.path().join("empty").exists());
+
+        assert_eq!(
+            delete_file_post(
+                state.to_state(),
+                Some(test_user_claims()),
+                HeaderMap::new(),
+                Form(query("full", false))
+            )
+            .await
+            .err(),
+            Some(Error::BadRequest("full isn't empty".to_string()))
+        );
+        assert!(temp_dir.path().join("full/sub/test.txt").exists());
+
+        delete_file_post(
+            state.to_state(),
+            Some(test_user_claims()),
+            HeaderMap::new(),
+            Form(query("full", true)),
+        )
+        .await
+        .expect("Failed to delete dir");
+        assert!(!temp_dir.path().join("full").exists());
+
+        assert!(matches!(
+            delete_file_post(
+                state.to_state(),
+                Some(test_user_claims()),
+                HeaderMap::new(),
+                Form(query("", true))
+            )
+            .await,
+            Err(Error::BadRequest(_))
+        ));
+        assert!(temp_dir.path().exists());
+    }
+
+    #[tokio::test]
+    async fn test_delete_if_match() {
+        use crate::fs::fs_from_serverpath;
+        use axum::http::header::IF_MATCH;
+        use axum::http::HeaderValue;
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("test.txt"), b"hello").expect("Failed to write file");
+
+        let mut config = Config::test_config();
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(temp_dir.path()));
+        let state = WebState::test_webstate_with_config(config).await;
+        let query = |if_match: Option<&str>| DeleteQuery {
+            server_path: "test".to_string(),
+            key: "test.txt".to_string(),
+            recursive: false,
+            permanent: None,
+            if_match: if_match.map(str::to_string),
+        };
+        let filekidfs =
+            fs_from_serverpath(&ServerPath::test_local(temp_dir.path())).expect("Failed to get fs");
+        let etag = file_etag(&filekidfs.get_data("test.txt").expect("Failed to get data"))
+            .expect("Missing ETag");
+
+        let stale = r#""0-0""#;
+        let mut headers = HeaderMap::new();
+        headers.insert(IF_MATCH, HeaderValue::from_static(stale));
+        let err = delete_file_post(
+            state.to_state(),
+            Some(test_user_claims()),
+            headers,
+            Form(query(None)),
+        )
+        .await
+        .err()
+        .expect("Stale ETag should have failed");
+        assert_eq!(
+            err,
+            Error::PreconditionFailed("test.txt has changed since it was loaded".to_string())
+        );
+        assert_eq!(
+            err.into_response().status(),
+            StatusCode::PRECONDITION_FAILED
+        );
+        assert!(temp_dir.path().join("test.txt").exists());
+
+        // the form field works too, for the confirmation page
+        assert!(delete_file_post(
+            state.to_state(),
+            Some(test_user_claims()),
+            HeaderMap::new(),
+            Form(query(Some(stale))),
+        )
+        .await
+        .is_err());
+        assert!(temp_dir.path().join("test.txt").exists());
+
+        delete_file_post(
+            state.to_state(),
+            Some(test_user_claims()),
+            HeaderMap::new(),
+            Form(query(Some(&etag))),
+        )
+        .await
+        .expect("Failed to delete with the current ETag");
+        assert!(!temp_dir.path().join("test.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_delete_audit() {
+        use crate::audit;
+        use crate::views::oidc::OIDC_TEST_USERNAME;
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let audit_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let audit_log = audit_dir.path().join("audit.log");
+        std::fs::write(temp_dir.path().join("test.txt"), b"hello").expect("Failed to write file");
+
+        let mut config = Config::test_config();
+        config.audit_log = Some(audit_log.clone());
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(temp_dir.path()));
+        let state = WebState::test_webstate_with_config(config).await;
+        let query = DeleteQuery {
+            server_path: "test".to_string(),
+            key: "test.txt".to_string(),
+            recursive: false,
+            permanent: None,
+            if_match: None,
+        };
+        delete_file_post(
+            state.to_state(),
+            Some(test_user_claims()),
+            HeaderMap::new(),
+            Form(query),
+        )
+        .await
+        .expect("Failed to delete");
+
+        let records = audit::read_records(&audit_log);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].username, OIDC_TEST_USERNAME);
+        assert_eq!(records[0].action, AuditAction::Delete);
+        assert_eq!(records[0].server_path, "test");
+        assert_eq!(records[0].key, "test.txt");
+        assert_eq!(records[0].result, "ok");
+    }
+
+    #[tokio::test]
+    async fn test_bulk_delete() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        for name in ["one.txt", "two.txt", "three.txt"] {
+            std::fs::write(temp_dir.path().join(name), b"hello").expect("Failed to write file");
+        }
+
+        let mut config = Config::test_config();
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(temp_dir.path()));
+        let state = WebState::test_webstate_with_config(config).await;
+        let request = |keys: &[&str]| BulkDeleteRequest {
+            server_path: "test".to_string(),
+            keys: keys.iter().map(|key| key.to_string()).collect(),
+            recursive: false,
+            permanent: false,
+        };
+
+        let Json(results) = bulk_delete_post(
+            state.to_state(),
+            Some(test_user_claims()),
+            Json(request(&["one.txt", "two.txt"])),
+        )
+        .await
+        .expect("Failed to bulk delete");
+        assert_eq!(
+            results,
+            vec![
+                BulkDeleteResult {
+                    key: "one.txt".to_string(),
+                    deleted: true,
+                    error: None,
+                },
+                BulkDeleteResult {
+                    key: "two.txt".to_string(),
+                    deleted: true,
+                    error: None,
+                },
+            ]
+        );
+        assert!(!temp_dir.path().join("one.txt").exists());
+        assert!(!temp_dir.path().join("two.txt").exists());
+
+        // one missing key doesn't stop the rest
+        let Json(results) = bulk_delete_post(
+            state.to_state(),
+            Some(test_user_claims()),
+            Json(request(&["missing.txt", "../escape.txt", "three.txt"])),
+        )
+        .await
+        .expect("Failed to bulk delete");
+        assert_eq!(
+            results
+                .iter()
+                .map(|result| (result.key.as_str(), result.deleted))
+                .collect::<Vec<_>>(),
+            vec![
+                ("missing.txt", false),
+                ("../escape.txt", false),
+                ("three.txt", true)
+            ]
+        );
+        assert_eq!(
+            results[0].error,
+            Some(Error::NotFound("missing.txt".to_string()).to_string())
+        );
+        assert!(!temp_dir.path().join("three.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_delete_to_trash() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::create_dir(temp_dir.path().join("docs")).expect("Failed to create dir");
+        std::fs::write(temp_dir.path().join("docs/test.txt"), b"hello")
+            .expect("Failed to write file");
+        std::fs::write(temp_dir.path().join("gone.txt"), b"bye").expect("Failed to write file");
+
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "test".to_string(),
+            ServerPath {
+                trash_dir: Some(".trash".to_string()),
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+        let query = |key: &str, permanent: Option<&str>| DeleteQuery {
+            server_path: "test".to_string(),
+            key: key.to_string(),
+            recursive: false,
+            permanent: permanent.map(str::to_string),
+            if_match: None,
+        };
+        let trashed = || {
+            std::fs::read_dir(temp_dir.path().join(".trash"))
+                .expect("Failed to read trash")
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        // soft deletes land in the trash
+        delete_file_post(
+            state.to_state(),
+            Some(test_user_claims()),
+            HeaderMap::new(),
+            Form(query("docs/test.txt", None)),
+        )
+        .await
+        .expect("Failed to delete");
+        assert!(!temp_dir.path().join("docs/test.txt").exists());
+        let names = trashed();
+        assert_eq!(names.len(), 1);
+        assert!(names[0].ends_with("-docs%2Ftest.txt"), "{}", names[0]);
+
+        // and come back out again
+        let response = restore_file_post(
+            state.to_state(),
+            Some(test_user_claims()),
+            Form(RestoreForm {
+                server_path: "test".to_string(),
+                name: names[0].clone(),
+            }),
+        )
+        .await
+        .expect("Failed to restore")
+        .into_response();
+        assert_eq!(
+            response
+                .headers()
+                .get("location")
+                .expect("Failed to get location header"),
+            "/browse/test/docs"
+        );
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("docs/test.txt")).expect("Failed to read file"),
+            b"hello"
+        );
+        assert!(trashed().is_empty());
+
+        // permanent deletes skip it
+        delete_file_post(
+            state.to_state(),
+            Some(test_user_claims()),
+            HeaderMap::new(),
+            Form(query("gone.txt", Some("1"))),
+        )
+        .await
+        .expect("Failed to delete");
+        assert!(!temp_dir.path().join("gone.txt").exists());
+        assert!(trashed().is_empty());
+
+        // as do deletes from the trash itself
+        delete_file_post(
+            state.to_state(),
+            Some(test_user_claims()),
+            HeaderMap::new(),
+            Form(query("docs/test.txt", None)),
+        )
+        .await
+        .expect("Failed to delete");
+        let name = trashed().remove(0);
+        delete_file_post(
+            state.to_state(),
+            Some(test_user_claims()),
+            HeaderMap::new(),
+            Form(query(&format!(".trash/{name}"), None)),
+        )
+        .await
+        .expect("Failed to delete from trash");
+        assert!(trashed().is_empty());
+    }
+}
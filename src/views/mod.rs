@@ -1,15 +1,32 @@
 //! Web views for FileKid.
 
+pub mod api;
+pub mod archive;
 pub mod browse;
+pub mod chunked;
+pub mod copy;
 pub mod delete;
 pub mod oidc;
 pub mod prelude;
+pub mod preview;
+pub mod put;
+pub mod rename;
+pub mod search;
+pub mod share;
+pub mod webdav;
 
 use std::cmp::Ordering;
 use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
 
+use axum::http::header::IF_MATCH;
+use axum::http::HeaderMap;
 use axum::response::{Html, Response};
 use prelude::*;
+use sha2::{Digest, Sha256};
+
+use crate::fs::{hex_digest, FileData, FileKidFs};
+use crate::text::to_utf8;
 
 use crate::oidc::check_login;
 
@@ -44,7 +61,11 @@ pub(crate) async fn home(
         .clone()
         .into_iter()
         .collect::<Vec<(String, ServerPath)>>();
-    server_paths.sort_by(|(a, _), (b, _)| a.cmp(b));
+    server_paths.sort_by(|(a_key, a), (b_key, b)| {
+        a.label(a_key)
+            .cmp(b.label(b_key))
+            .then_with(|| a_key.cmp(b_key))
+    });
 
     HomePage {
         server_paths,
@@ -53,7 +74,101 @@ pub(crate) async fn home(
     .into()
 }
 
-#[derive(Debug, Eq, PartialEq)]
+/// Formats a time as an HTTP-date, eg `Sun, 06 Nov 1994 08:49:37 GMT`
+pub(crate) fn http_date(time: impl Into<chrono::DateTime<chrono::Utc>>) -> String {
+    time.into().format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Formats a byte count for people, eg `1.5 MiB`
+pub(crate) fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{bytes} B"),
+        _ => format!("{size:.1} {}", UNITS[unit]),
+    }
+}
+
+/// The `"<size>-<mtime>"` ETag files are served with, if the backend knows both.
+///
+/// The mtime's in nanoseconds, so a same-sized rewrite within the same second still changes it.
+pub(crate) fn file_etag(data: &FileData) -> Option<String> {
+    match (data.size, data.modified) {
+        (Some(size), Some(modified)) => {
+            let modified = modified
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_nanos())
+                .unwrap_or_default();
+            Some(format!("\"{size:x}-{modified:x}\""))
+        }
+        _ => None,
+    }
+}
+
+/// The ETag immutable paths serve, a hash of the bytes that are sent
+pub(crate) fn content_etag(contents: &[u8]) -> String {
+    format!("\"{}\"", hex_digest(&Sha256::digest(contents)))
+}
+
+/// The ETag a GET of `key` is served with, see [file_etag] and [content_etag]
+pub(crate) async fn served_etag(
+    server_path_object: &ServerPath,
+    filekidfs: &dyn FileKidFs,
+    key: &str,
+) -> Result<Option<String>, Error> {
+    if !server_path_object.immutable {
+        return Ok(file_etag(&filekidfs.get_data(key)?));
+    }
+    let mut contents = filekidfs.get_file(key).await?;
+    // text's re-encoded before it's sent, and hashed after
+    if server_path_object.mime_type(key).starts_with("text/")
+        && let Some(encoding) = server_path_object.text_encoding()?
+    {
+        contents = to_utf8(&contents, encoding);
+    }
+    Ok(Some(content_etag(&contents)))
+}
+
+/// Checks an `If-Match` ETag list against the file as it is now, so a change made since the client looked isn't clobbered.
+///
+/// Comparison's strong, as RFC 9110 wants, so weak tags never match, and `*` only needs the file to exist.
+pub(crate) async fn check_if_match(
+    if_match: Option<&str>,
+    server_path_object: &ServerPath,
+    filekidfs: &dyn FileKidFs,
+    key: &str,
+) -> Result<(), Error> {
+    let Some(if_match) = if_match.map(str::trim).filter(|value| !value.is_empty()) else {
+        return Ok(());
+    };
+    let current = match filekidfs.exists(key)? {
+        true => served_etag(server_path_object, filekidfs, key).await?,
+        false => return Err(Error::PreconditionFailed(format!("{key} doesn't exist"))),
+    };
+    let matched = if_match.split(',').map(str::trim).any(|tag| {
+        tag == "*"
+            || (!tag.starts_with("W/") && current.as_deref().is_some_and(|current| tag == current))
+    });
+    match matched {
+        true => Ok(()),
+        false => Err(Error::PreconditionFailed(format!(
+            "{key} has changed since it was loaded"
+        ))),
+    }
+}
+
+/// The `If-Match` header's value, if it was sent
+pub(crate) fn if_match_header(headers: &HeaderMap) -> Option<&str> {
+    headers.get(IF_MATCH).and_then(|value| value.to_str().ok())
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
 pub enum FileType {
     Directory,
     File,
@@ -74,8 +189,8 @@ impl Ord for FileType {
                 FileType::File => Ordering::Less,
             },
             FileType::File => match other {
-                FileType::Directory => Ordering::Less,
-                FileType::File => Ordering::Greater,
+                FileType::Directory => Ordering::Greater,
+                FileType::File => Ordering::Equal,
             },
         }
     }
@@ -120,6 +235,38 @@ mod tests {
         .expect("Failed to render home page");
     }
 
+    #[tokio::test]
+    async fn test_home_display_name() {
+        let mut config = crate::config::Config::test_config();
+        config.server_paths.insert(
+            "zzz".to_string(),
+            ServerPath {
+                display_name: Some("Alpha Files".to_string()),
+                ..ServerPath::test_local(&PathBuf::from("/tmp"))
+            },
+        );
+        config.server_paths.insert(
+            "bbb".to_string(),
+            ServerPath::test_local(&PathBuf::from("/tmp")),
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let response = home(state.to_state(), Some(test_user_claims()))
+            .await
+            .expect("Failed to render home page");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Failed to read body");
+        let body = String::from_utf8_lossy(&body);
+
+        assert!(body.contains("/browse/zzz/"));
+        assert!(!body.contains("> zzz<"));
+        // sorted by what's shown rather than the key
+        let alpha = body.find("Alpha Files").expect("No display name");
+        let bbb = body.find("bbb").expect("No key");
+        assert!(alpha < bbb);
+    }
+
     #[test]
     fn test_filetype() {
         let file = PathBuf::from("Cargo.toml");
@@ -135,6 +282,7 @@ mod tests {
         );
 
         assert!(FileType::Directory < FileType::File);
+        assert_eq!(FileType::File.cmp(&FileType::File), Ordering::Equal);
 
         assert_eq!(FileType::Directory.icon(), "folder.svg");
         assert_eq!(FileType::File.icon(), "file.svg");
@@ -0,0 +1,134 @@
+//! Showing small text files as plain text, without downloading them
+
+use super::{check_login, prelude::*};
+
+use crate::constants::PREVIEW_BINARY_CHECK_BYTES;
+use crate::fs::fs_for_user;
+use axum::extract::Path;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::Response;
+
+/// Serves a text file as `text/plain`, as long as it's smaller than [crate::config::Config::max_preview_bytes] and doesn't look binary
+#[instrument(level = "debug", skip(state, claims))]
+pub(crate) async fn preview_get(
+    State(state): State<WebState>,
+    Path((server_path, filepath)): Path<(String, String)>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+) -> Result<Response, Error> {
+    let user = check_login(claims)?;
+
+    let server_reader = state.configuration.read().await;
+
+    let server_path_object = match server_reader.server_paths.get(&server_path) {
+        None => {
+            error!("Couldn't find server path {}", server_path);
+            return Err(Error::NotFound(server_path));
+        }
+        Some(p) => p,
+    };
+    server_path_object.check_method(&Method::GET)?;
+
+    let filekidfs = fs_for_user(server_path_object, Some(&user.username()))?;
+
+    if !filekidfs.is_file(&filepath) {
+        return Err(Error::NotFound(filepath));
+    }
+    let too_large = || Error::BadRequest("file too large to preview".to_string());
+    // check the size first so a huge file isn't read just to be refused
+    if filekidfs
+        .get_data(&filepath)?
+        .size
+        .is_some_and(|size| size > server_reader.max_preview_bytes)
+    {
+        return Err(too_large());
+    }
+
+    debug!(
+        "User {} previewed {} on {}",
+        user.username(),
+        filepath,
+        server_path
+    );
+    let contents = filekidfs.get_file(&filepath).await?;
+    if contents.len() as u64 > server_reader.max_preview_bytes {
+        return Err(too_large());
+    }
+    if contents
+        .iter()
+        .take(PREVIEW_BINARY_CHECK_BYTES)
+        .any(|byte| *byte == 0)
+    {
+        return Err(Error::BadRequest(
+            "binary files can't be previewed".to_string(),
+        ));
+    }
+
+    Ok((
+        StatusCode::OK,
+        [(CONTENT_TYPE, "text/plain; charset=utf-8")],
+        String::from_utf8_lossy(&contents).into_owned(),
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::views::oidc::test_user_claims;
+
+    #[tokio::test]
+    async fn test_preview_get() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(
+            temp_dir.path().join("config.ini"),
+            b"[section]\nkey = caf\xe9\n",
+        )
+        .expect("Failed to write file");
+        std::fs::write(temp_dir.path().join("big.txt"), vec![b'a'; 101])
+            .expect("Failed to write file");
+        std::fs::write(
+            temp_dir.path().join("image.png"),
+            b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR",
+        )
+        .expect("Failed to write file");
+
+        let mut config = Config::test_config();
+        config.max_preview_bytes = 100;
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(temp_dir.path()));
+        let state = WebState::test_webstate_with_config(config).await;
+        let preview = |filepath: &str| {
+            preview_get(
+                state.to_state(),
+                Path(("test".to_string(), filepath.to_string())),
+                Some(test_user_claims()),
+            )
+        };
+
+        let response = preview("config.ini").await.expect("Failed to preview");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok()),
+            Some("text/plain; charset=utf-8")
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Failed to read body");
+        assert_eq!(body, "[section]\nkey = caf\u{fffd}\n");
+
+        match preview("big.txt").await {
+            Err(Error::BadRequest(msg)) => assert_eq!(msg, "file too large to preview"),
+            other => panic!("Expected a bad request, got {other:?}"),
+        }
+        assert!(matches!(
+            preview("image.png").await,
+            Err(Error::BadRequest(_))
+        ));
+        assert!(matches!(preview("nope.txt").await, Err(Error::NotFound(_))));
+    }
+}
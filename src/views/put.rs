@@ -0,0 +1,904 @@
+//! Raw `PUT` uploads, including partial uploads using `Content-Range`
+
+use super::{check_if_match, check_login, if_match_header, prelude::*};
+
+use crate::audit::{self, AuditAction, AuditRecord};
+use crate::fs::{check_content_length, check_unique_basename, fs_for_user};
+use crate::oidc::User;
+use axum::body::{Body, Bytes};
+use axum::extract::Path;
+use axum::http::header::{CONTENT_LENGTH, CONTENT_RANGE};
+use axum::http::HeaderMap;
+use futures::{StreamExt, TryStreamExt};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A parsed `Content-Range: bytes <start>-<end>/<total>` header, `end` is inclusive.
+#[derive(Debug, PartialEq)]
+pub(crate) struct ContentRange {
+    pub start: u64,
+    pub end: u64,
+    pub total: u64,
+}
+
+impl ContentRange {
+    pub(crate) fn parse(value: &str) -> Result<Self, Error> {
+        let invalid = || Error::BadRequest(format!("Invalid Content-Range header: {value}"));
+
+        let (range, total) = value
+            .trim()
+            .strip_prefix("bytes ")
+            .and_then(|value| value.split_once('/'))
+            .ok_or_else(invalid)?;
+        let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+        let total: u64 = total.parse().map_err(|_| invalid())?;
+        let start: u64 = start.parse().map_err(|_| invalid())?;
+        let end: u64 = end.parse().map_err(|_| invalid())?;
+
+        if start > end || end >= total {
+            return Err(invalid());
+        }
+        Ok(Self { start, end, total })
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+/// Upload a file with a `PUT`, if a `Content-Range` header is sent then only that part of the file is written.
+///
+/// Whole-file uploads are streamed to backends that support it, unless line endings need normalizing,
+/// and the amount received is checked against `Content-Length` (see [Config::strict_content_length])
+/// and `max_upload_mb`.
+///
+/// Existing files are only replaced by an upload with an `If-Match` header holding their current ETag,
+/// a stale one gets `412 Precondition Failed`. Partial uploads send it with every range, the file's replaced when the last one arrives.
+///
+/// Returns `201 Created` once the file is complete, or `202 Accepted` while ranges are still outstanding.
+///
+/// [Config::strict_content_length]: crate::config::Config::strict_content_length
+pub(crate) async fn put_file(
+    State(state): State<WebState>,
+    Path((server_path, filepath)): Path<(String, String)>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<StatusCode, Error> {
+    let user = check_login(claims)?;
+    let result = store_put(
+        state.clone(),
+        server_path.clone(),
+        filepath.clone(),
+        &user,
+        headers,
+        body,
+    )
+    .await;
+    // a range that doesn't finish the file hasn't changed anything yet
+    if result != Ok(StatusCode::ACCEPTED) {
+        let audit_log = state.configuration.read().await.audit_log.clone();
+        audit::record(
+            audit_log.as_deref(),
+            AuditRecord::new(
+                user.username(),
+                AuditAction::Upload,
+                &server_path,
+                &filepath,
+                &result,
+            ),
+        )
+        .await;
+    }
+    result
+}
+
+async fn store_put(
+    state: WebState,
+    server_path: String,
+    filepath: String,
+    user: &User,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<StatusCode, Error> {
+    let server_reader = state.configuration.read().await;
+    server_reader.check_uploads_enabled()?;
+
+    let server_path_object = match server_reader.server_paths.get(&server_path) {
+        None => {
+            error!("Couldn't find server path {}", server_path);
+            return Err(Error::NotFound(server_path));
+        }
+        Some(p) => p.clone(),
+    };
+    server_path_object.check_method(&Method::PUT)?;
+    let max_bytes = server_reader.max_upload_mb * 1024 * 1024;
+    let strict = server_reader.strict_content_length;
+    // uploads can take a while, don't block config reloads
+    drop(server_reader);
+
+    server_path_object.check_writable()?;
+    let filekidfs = fs_for_user(&server_path_object, Some(&user.username()))?;
+
+    let declared_len = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+
+    check_unique_basename(&server_path_object, filekidfs.as_ref(), &filepath)?;
+
+    let if_match = if_match_header(&headers);
+    check_if_match(if_match, &server_path_object, filekidfs.as_ref(), &filepath).await?;
+
+    // an `If-Match` that passed means the client knows what it's replacing
+    let replaced_size = match (if_match, filekidfs.exists(&filepath)?) {
+        (_, false) => 0,
+        (Some(_), true) if filekidfs.is_file(&filepath) => {
+            filekidfs.get_data(&filepath)?.size.unwrap_or(0)
+        }
+        (_, true) => return Err(Error::BadRequest(format!("{filepath} already exists"))),
+    };
+
+    let Some(content_range) = headers.get(CONTENT_RANGE) else {
+        // the content checks need the whole file up front, so those paths don't stream
+        if filekidfs.has_stream_put_file()
+            && server_path_object.normalize_text_eol.is_none()
+            && !server_path_object.reject_executables
+        {
+            let quota_left = state
+                .dir_sizes
+                .quota_left_for(&server_path, &server_path_object, Some(&user.username()))
+                .await?;
+            let quota_left = quota_left.map(|left| left.saturating_add(replaced_size));
+            if let (Some(left), Some(declared)) = (quota_left, declared_len)
+                && declared > left
+            {
+                return Err(Error::BadRequest("quota exceeded".to_string()));
+            }
+            // streamed uploads aren't buffered, so the size limits are checked as bytes arrive
+            let over_quota = Arc::new(AtomicBool::new(false));
+            let mut received: usize = 0;
+            let stream = body
+                .into_data_stream()
+                .map_err(std::io::Error::other)
+                .and_then({
+                    let over_quota = over_quota.clone();
+                    move |chunk| {
+                        received += chunk.len();
+                        let result = if received > max_bytes {
+                            Err(std::io::Error::other(format!(
+                                "Upload is larger than the maximum of {max_bytes} bytes"
+                            )))
+                        } else if quota_left.is_some_and(|left| received as u64 > left) {
+                            over_quota.store(true, Ordering::Relaxed);
+                            Err(std::io::Error::other("quota exceeded"))
+                        } else {
+                            Ok(chunk)
+                        };
+                        futures::future::ready(result)
+                    }
+                })
+                .boxed();
+            let written = filekidfs
+                .stream_put_file(&filepath, stream, declared_len, strict)
+                .await
+                .map_err(|err| match over_quota.load(Ordering::Relaxed) {
+                    true => Error::BadRequest("quota exceeded".to_string()),
+                    false => err,
+                })?;
+            state.dir_sizes.subtract(&server_path, replaced_size);
+            state.dir_sizes.add(&server_path, written);
+        } else {
+            let body = read_body(body, max_bytes).await?;
+            check_content_length(declared_len, body.len() as u64, strict)?;
+            server_path_object.check_not_executable(&filepath, &body)?;
+            let body = server_path_object.normalize_upload(body);
+            state
+                .dir_sizes
+                .check_quota(
+                    &server_path,
+                    &server_path_object,
+                    Some(&user.username()),
+                    body.len() as u64,
+                    replaced_size,
+                )
+                .await?;
+            filekidfs.put_file(&filepath, &body).await?;
+            state.dir_sizes.subtract(&server_path, replaced_size);
+            state.dir_sizes.add(&server_path, body.len() as u64);
+            if server_path_object.reject_executables {
+                filekidfs.strip_executable(&filepath)?;
+            }
+        }
+        debug!(
+            "User {} uploaded {} to {}",
+            user.username(),
+            filepath,
+            server_path
+        );
+        return Ok(StatusCode::CREATED);
+    };
+
+    let range = ContentRange::parse(
+        content_range
+            .to_str()
+            .map_err(|err| Error::BadRequest(format!("Invalid Content-Range header: {err}")))?,
+    )?;
+
+    if range.total > max_bytes as u64 {
+        return Err(Error::BadRequest(format!(
+            "File size {} is larger than the maximum of {max_bytes} bytes",
+            range.total
+        )));
+    }
+    let body = read_body(body, max_bytes).await?;
+    if range.len() != body.len() as u64 {
+        return Err(Error::BadRequest(format!(
+            "Content-Range covers {} bytes but {} were sent",
+            range.len(),
+            body.len()
+        )));
+    }
+
+    // the whole file's size is known from the first range, so that's when the quota's checked
+    if range.start == 0 {
+        state
+            .dir_sizes
+            .check_quota(
+                &server_path,
+                &server_path_object,
+                Some(&user.username()),
+                range.total,
+                replaced_size,
+            )
+            .await?;
+    }
+
+    // only the first range has the start of the file in it
+    server_path_object.check_not_executable(
+        &filepath,
+        match range.start {
+            0 => &body,
+            _ => &[],
+        },
+    )?;
+
+    let complete = filekidfs
+        .put_file_range(
+            &filepath,
+            range.start,
+            range.total,
+            body,
+            if_match.is_some(),
+        )
+        .await?;
+    if complete {
+        state.dir_sizes.subtract(&server_path, replaced_size);
+        state.dir_sizes.add(&server_path, range.total);
+        if server_path_object.reject_executables {
+            filekidfs.strip_executable(&filepath)?;
+        }
+    }
+    debug!(
+        "User {} uploaded bytes {}-{}/{} of {} to {} (complete: {})",
+        user.username(),
+        range.start,
+        range.end,
+        range.total,
+        filepath,
+        server_path,
+        complete
+    );
+
+    match complete {
+        true => Ok(StatusCode::CREATED),
+        false => Ok(StatusCode::ACCEPTED),
+    }
+}
+
+/// Buffers a request body, for the cases where we need all of it at once
+pub(crate) async fn read_body(body: Body, max_bytes: usize) -> Result<Bytes, Error> {
+    axum::body::to_bytes(body, max_bytes).await.map_err(|err| {
+        error!("Failed to read upload body: {:?}", err);
+        Error::BadRequest("Failed to read upload body".to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::views::oidc::test_user_claims;
+    use crate::views::{content_etag, file_etag};
+    use axum::http::header::IF_MATCH;
+    use axum::http::HeaderValue;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn test_content_range_parse() {
+        assert_eq!(
+            ContentRange::parse("bytes 0-4/10").expect("Failed to parse"),
+            ContentRange {
+                start: 0,
+                end: 4,
+                total: 10
+            }
+        );
+        assert_eq!(
+            ContentRange::parse("bytes 5-9/10")
+                .expect("Failed to parse")
+                .len(),
+            5
+        );
+        for invalid in [
+            "bytes 0-10/10",
+            "bytes 5-4/10",
+            "bytes 0-4/*",
+            "bytes */10",
+            "0-4/10",
+            "bytes a-4/10",
+        ] {
+            assert!(ContentRange::parse(invalid).is_err(), "{invalid} parsed");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_put_file() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+
+        let mut config = Config::test_config();
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(temp_dir.path()));
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let put = |filepath: &str, range: Option<&'static str>, body: &'static [u8]| {
+            let mut headers = HeaderMap::new();
+            if let Some(range) = range {
+                headers.insert(CONTENT_RANGE, HeaderValue::from_static(range));
+            }
+            put_file(
+                state.to_state(),
+                Path(("test".to_string(), filepath.to_string())),
+                Some(test_user_claims()),
+                headers,
+                Body::from(body),
+            )
+        };
+
+        assert_eq!(
+            put("whole.txt", None, b"Hello, world!").await,
+            Ok(StatusCode::CREATED)
+        );
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("whole.txt")).expect("Failed to read file"),
+            b"Hello, world!"
+        );
+
+        assert_eq!(
+            put("parts.txt", Some("bytes 5-9/10"), b"world").await,
+            Ok(StatusCode::ACCEPTED)
+        );
+        assert!(!temp_dir.path().join("parts.txt").exists());
+        assert!(
+            put("parts.txt", Some("bytes 0-4/10"), b"hi").await.is_err(),
+            "short body should be rejected"
+        );
+        assert!(
+            put("huge.txt", Some("bytes 0-4/999999999999"), b"hello")
+                .await
+                .is_err(),
+            "total over the upload limit should be rejected"
+        );
+        assert_eq!(
+            put("parts.txt", Some("bytes 0-4/10"), b"hello").await,
+            Ok(StatusCode::CREATED)
+        );
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("parts.txt")).expect("Failed to read file"),
+            b"helloworld"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_uploads_disabled() {
+        use crate::views::browse::{get_file, GetFileQuery};
+        use crate::views::delete::{delete_file_post, DeleteQuery};
+        use axum::extract::Query;
+        use axum::Form;
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("test.txt"), b"hello").expect("Failed to write file");
+
+        let mut config = Config::test_config();
+        config.uploads_disabled = true;
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(temp_dir.path()));
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let response = put_file(
+            state.to_state(),
+            Path(("test".to_string(), "new.txt".to_string())),
+            Some(test_user_claims()),
+            HeaderMap::new(),
+            axum::body::Body::from("nope"),
+        )
+        .await
+        .expect_err("upload should be refused")
+        .into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(!temp_dir.path().join("new.txt").exists());
+
+        // downloads and deletes carry on
+        let response = get_file(
+            state.to_state(),
+            Path(("test".to_string(), "test.txt".to_string())),
+            Query(GetFileQuery::default()),
+            None,
+            HeaderMap::new(),
+        )
+        .await
+        .expect("Failed to download")
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let query = DeleteQuery {
+            server_path: "test".to_string(),
+            key: "test.txt".to_string(),
+            recursive: false,
+            permanent: None,
+            if_match: None,
+        };
+        assert!(delete_file_post(
+            state.to_state(),
+            Some(test_user_claims()),
+            HeaderMap::new(),
+            Form(query)
+        )
+        .await
+        .is_ok());
+        assert!(!temp_dir.path().join("test.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_put_file_if_match() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("test.txt"), b"original").expect("Failed to write");
+
+        let mut config = Config::test_config();
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(temp_dir.path()));
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let put = |if_match: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(if_match) = if_match {
+                headers.insert(
+                    IF_MATCH,
+                    HeaderValue::from_str(if_match).expect("Invalid header"),
+                );
+            }
+            put_file(
+                state.to_state(),
+                Path(("test".to_string(), "test.txt".to_string())),
+                Some(test_user_claims()),
+                headers,
+                Body::from("replaced"),
+            )
+        };
+
+        assert_eq!(
+            put(None).await,
+            Err(Error::BadRequest("test.txt already exists".to_string()))
+        );
+        assert_eq!(
+            put(Some(r#""0-0""#)).await,
+            Err(Error::PreconditionFailed(
+                "test.txt has changed since it was loaded".to_string()
+            ))
+        );
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("test.txt")).expect("Failed to read file"),
+            b"original"
+        );
+
+        let filekidfs =
+            fs_for_user(&ServerPath::test_local(temp_dir.path()), None).expect("Failed to get fs");
+        let etag = file_etag(&filekidfs.get_data("test.txt").expect("Failed to get data"))
+            .expect("Missing ETag");
+        assert_eq!(put(Some(&etag)).await, Ok(StatusCode::CREATED));
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("test.txt")).expect("Failed to read file"),
+            b"replaced"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_put_file_audit() {
+        use crate::audit;
+        use crate::views::oidc::OIDC_TEST_USERNAME;
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let audit_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let audit_log = audit_dir.path().join("audit.log");
+
+        let mut config = Config::test_config();
+        config.audit_log = Some(audit_log.clone());
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(temp_dir.path()));
+        let state = WebState::test_webstate_with_config(config).await;
+        let put = |range: Option<&'static str>, body: &'static str| {
+            let mut headers = HeaderMap::new();
+            if let Some(range) = range {
+                headers.insert(CONTENT_RANGE, HeaderValue::from_static(range));
+            }
+            put_file(
+                state.to_state(),
+                Path(("test".to_string(), "test.txt".to_string())),
+                Some(test_user_claims()),
+                headers,
+                Body::from(body),
+            )
+        };
+
+        assert_eq!(put(None, "hello").await, Ok(StatusCode::CREATED));
+        assert!(put(None, "again").await.is_err());
+        let records = audit::read_records(&audit_log);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].username, OIDC_TEST_USERNAME);
+        assert_eq!(records[0].action, AuditAction::Upload);
+        assert_eq!(records[0].server_path, "test");
+        assert_eq!(records[0].key, "test.txt");
+        assert_eq!(records[0].result, "ok");
+        assert_eq!(records[1].result, "Bad request: test.txt already exists");
+
+        // ranges are only recorded once the file's finished
+        std::fs::remove_file(temp_dir.path().join("test.txt")).expect("Failed to remove");
+        assert_eq!(
+            put(Some("bytes 0-1/4"), "he").await,
+            Ok(StatusCode::ACCEPTED)
+        );
+        assert_eq!(audit::read_records(&audit_log).len(), 2);
+        assert_eq!(
+            put(Some("bytes 2-3/4"), "ya").await,
+            Ok(StatusCode::CREATED)
+        );
+        let records = audit::read_records(&audit_log);
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[2].result, "ok");
+    }
+
+    #[tokio::test]
+    async fn test_put_file_if_match_same_second() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("test.txt");
+        // both writes land in the same second, only the sub-second part of the mtime differs
+        let second = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let write = |contents: &[u8], modified: SystemTime| {
+            std::fs::write(&path, contents).expect("Failed to write");
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .and_then(|file| file.set_modified(modified))
+                .expect("Failed to set mtime");
+        };
+        write(b"first", second + Duration::from_millis(100));
+
+        let mut config = Config::test_config();
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(temp_dir.path()));
+        let state = WebState::test_webstate_with_config(config).await;
+        let filekidfs =
+            fs_for_user(&ServerPath::test_local(temp_dir.path()), None).expect("Failed to get fs");
+        let etag = file_etag(&filekidfs.get_data("test.txt").expect("Failed to get data"))
+            .expect("Missing ETag");
+
+        write(b"other", second + Duration::from_millis(500));
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            IF_MATCH,
+            HeaderValue::from_str(&etag).expect("Invalid header"),
+        );
+        assert_eq!(
+            put_file(
+                state.to_state(),
+                Path(("test".to_string(), "test.txt".to_string())),
+                Some(test_user_claims()),
+                headers,
+                Body::from("third"),
+            )
+            .await,
+            Err(Error::PreconditionFailed(
+                "test.txt has changed since it was loaded".to_string()
+            ))
+        );
+        assert_eq!(std::fs::read(&path).expect("Failed to read file"), b"other");
+    }
+
+    #[tokio::test]
+    async fn test_put_file_if_match_immutable() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("test.txt"), b"original").expect("Failed to write");
+
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "test".to_string(),
+            ServerPath {
+                immutable: true,
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+        let put = |if_match: String| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                IF_MATCH,
+                HeaderValue::from_str(&if_match).expect("Invalid header"),
+            );
+            put_file(
+                state.to_state(),
+                Path(("test".to_string(), "test.txt".to_string())),
+                Some(test_user_claims()),
+                headers,
+                Body::from("replaced"),
+            )
+        };
+
+        // that's the ETag a GET hands out, and If-Match compares strongly
+        let etag = content_etag(b"original");
+        assert_eq!(
+            put(format!("W/{etag}")).await,
+            Err(Error::PreconditionFailed(
+                "test.txt has changed since it was loaded".to_string()
+            ))
+        );
+        assert_eq!(put(etag).await, Ok(StatusCode::CREATED));
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("test.txt")).expect("Failed to read file"),
+            b"replaced"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_put_file_range_if_match() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("test.txt"), b"original").expect("Failed to write");
+
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "test".to_string(),
+            ServerPath {
+                quota_bytes: Some(12),
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let put = |range: &'static str, body: &'static str, if_match: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_RANGE, HeaderValue::from_static(range));
+            if let Some(if_match) = if_match {
+                headers.insert(
+                    IF_MATCH,
+                    HeaderValue::from_str(if_match).expect("Invalid header"),
+                );
+            }
+            put_file(
+                state.to_state(),
+                Path(("test".to_string(), "test.txt".to_string())),
+                Some(test_user_claims()),
+                headers,
+                Body::from(body),
+            )
+        };
+
+        // a range can't replace a file any more than a whole upload can
+        assert_eq!(
+            put("bytes 0-7/8", "replaced", None).await,
+            Err(Error::BadRequest("test.txt already exists".to_string()))
+        );
+        assert_eq!(
+            put("bytes 0-7/8", "replaced", Some(r#""0-0""#)).await,
+            Err(Error::PreconditionFailed(
+                "test.txt has changed since it was loaded".to_string()
+            ))
+        );
+
+        let filekidfs =
+            fs_for_user(&ServerPath::test_local(temp_dir.path()), None).expect("Failed to get fs");
+        let etag = file_etag(&filekidfs.get_data("test.txt").expect("Failed to get data"))
+            .expect("Missing ETag");
+        // the file being replaced doesn't count against the quota
+        assert_eq!(
+            put("bytes 0-4/10", "repla", Some(&etag)).await,
+            Ok(StatusCode::ACCEPTED)
+        );
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("test.txt")).expect("Failed to read file"),
+            b"original"
+        );
+        assert_eq!(
+            put("bytes 5-9/10", "ced!!", Some(&etag)).await,
+            Ok(StatusCode::CREATED)
+        );
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("test.txt")).expect("Failed to read file"),
+            b"replaced!!"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_put_file_normalize_text_eol() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+
+        let mut config = Config::test_config();
+        let mut server_path = ServerPath::test_local(temp_dir.path());
+        server_path.normalize_text_eol = Some(crate::text::Eol::Lf);
+        config.server_paths.insert("test".to_string(), server_path);
+        let state = WebState::test_webstate_with_config(config).await;
+
+        for (filename, body) in [
+            ("text.txt", &b"line one\r\nline two\r\n"[..]),
+            ("binary.bin", &b"\x89PNG\r\n\x1a\n\0\0\r\n"[..]),
+        ] {
+            assert_eq!(
+                put_file(
+                    state.to_state(),
+                    Path(("test".to_string(), filename.to_string())),
+                    Some(test_user_claims()),
+                    HeaderMap::new(),
+                    Body::from(body.to_vec()),
+                )
+                .await,
+                Ok(StatusCode::CREATED)
+            );
+        }
+
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("text.txt")).expect("Failed to read file"),
+            b"line one\nline two\n"
+        );
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("binary.bin")).expect("Failed to read file"),
+            b"\x89PNG\r\n\x1a\n\0\0\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_put_file_content_length() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+
+        let mut config = Config::test_config();
+        config.strict_content_length = true;
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(temp_dir.path()));
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let put = |filepath: &str, content_length: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_LENGTH, HeaderValue::from_static(content_length));
+            put_file(
+                state.to_state(),
+                Path(("test".to_string(), filepath.to_string())),
+                Some(test_user_claims()),
+                headers,
+                Body::from("Hello, world!"),
+            )
+        };
+
+        assert_eq!(put("exact.txt", "13").await, Ok(StatusCode::CREATED));
+        assert!(matches!(
+            put("over.txt", "5").await,
+            Err(Error::BadRequest(_))
+        ));
+        assert!(matches!(
+            put("under.txt", "50").await,
+            Err(Error::BadRequest(_))
+        ));
+        assert!(temp_dir.path().join("exact.txt").exists());
+        assert!(!temp_dir.path().join("over.txt").exists());
+        assert!(!temp_dir.path().join("under.txt").exists());
+
+        // without strict mode, short uploads are only logged
+        state.configuration.write().await.strict_content_length = false;
+        assert_eq!(put("under.txt", "50").await, Ok(StatusCode::CREATED));
+        assert!(matches!(
+            put("over.txt", "5").await,
+            Err(Error::BadRequest(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_put_file_streamed_size_limit() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+
+        let mut config = Config::test_config();
+        config.max_upload_mb = 1;
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(temp_dir.path()));
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let put = |filepath: &str, size: usize| {
+            put_file(
+                state.to_state(),
+                Path(("test".to_string(), filepath.to_string())),
+                Some(test_user_claims()),
+                HeaderMap::new(),
+                Body::from(vec![0u8; size]),
+            )
+        };
+        assert_eq!(put("fits.bin", 1024 * 1024).await, Ok(StatusCode::CREATED));
+        assert!(put("too_big.bin", 1024 * 1024 + 1).await.is_err());
+        assert!(!temp_dir.path().join("too_big.bin").exists());
+    }
+
+    #[tokio::test]
+    async fn test_put_file_reject_executables() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "drop".to_string(),
+            ServerPath {
+                reject_executables: true,
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(temp_dir.path()));
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let put = |server_path: &str, filepath: &str, body: &'static [u8]| {
+            put_file(
+                state.to_state(),
+                Path((server_path.to_string(), filepath.to_string())),
+                Some(test_user_claims()),
+                HeaderMap::new(),
+                Body::from(body),
+            )
+        };
+        assert!(matches!(
+            put("drop", "script.txt", b"#!/bin/sh\nrm -rf /\n").await,
+            Err(Error::BadRequest(_))
+        ));
+        assert!(!temp_dir.path().join("script.txt").exists());
+        assert!(matches!(
+            put("drop", "setup.EXE", b"MZ").await,
+            Err(Error::BadRequest(_))
+        ));
+        assert_eq!(
+            put("drop", "notes.txt", b"hello #!").await,
+            Ok(StatusCode::CREATED)
+        );
+        // it's opt-in
+        assert_eq!(
+            put("test", "script.sh", b"#!/bin/sh\n").await,
+            Ok(StatusCode::CREATED)
+        );
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let script = temp_dir.path().join("script.sh");
+            std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755))
+                .expect("Failed to chmod");
+            crate::fs::fs_from_serverpath(&ServerPath::test_local(temp_dir.path()))
+                .expect("Failed to get fs")
+                .strip_executable("script.sh")
+                .expect("Failed to strip execute bits");
+            let mode = std::fs::metadata(&script)
+                .expect("Failed to stat")
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o644);
+        }
+    }
+}
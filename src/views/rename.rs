@@ -0,0 +1,247 @@
+//! Rename-file related things
+
+use super::{check_login, prelude::*};
+
+use crate::audit::{self, AuditAction, AuditRecord};
+use crate::fs::{check_unique_basename, fs_for_user, sanitize_filename};
+use axum::response::Redirect;
+use axum::Form;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct RenameForm {
+    server_path: String,
+    key: String,
+    new_name: String,
+}
+
+impl RenameForm {
+    fn parent_path(&self) -> String {
+        let path = self.key.clone();
+        let mut path = path.split('/').collect::<Vec<&str>>();
+        path.pop();
+        path.join("/")
+    }
+
+    /// The new key, which sits alongside the original file
+    fn destination(&self) -> Result<String, Error> {
+        let new_name = sanitize_filename(&self.new_name)?;
+        let parent_path = self.parent_path();
+        if parent_path.is_empty() {
+            Ok(new_name.to_string())
+        } else {
+            Ok(format!("{}/{}", parent_path, new_name))
+        }
+    }
+}
+
+pub(crate) async fn rename_file_post(
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    Form(form): Form<RenameForm>,
+) -> Result<Redirect, Error> {
+    let user = check_login(claims)?;
+
+    let server_reader = state.configuration.read().await;
+
+    let server_path_object = match server_reader.server_paths.get(&form.server_path) {
+        None => {
+            error!("Couldn't find server path {}", form.server_path);
+            return Err(Error::NotFound(form.server_path));
+        }
+        Some(p) => p,
+    };
+    server_path_object.check_method(&Method::POST)?;
+
+    server_path_object.check_writable()?;
+    let filekidfs = fs_for_user(server_path_object, Some(&user.username()))?;
+
+    if !filekidfs.exists(&form.key)? {
+        error!("Couldn't find file path {:?}", form.key);
+        return Err(Error::NotFound(form.key));
+    }
+
+    let destination = form.destination()?;
+    check_unique_basename(server_path_object, filekidfs.as_ref(), &destination)?;
+    let result = filekidfs.move_file(&form.key, &destination, false);
+    audit::record(
+        server_reader.audit_log.as_deref(),
+        AuditRecord::new(
+            user.username(),
+            AuditAction::Rename,
+            &form.server_path,
+            &form.key,
+            &result,
+        )
+        .with_destination(&destination),
+    )
+    .await;
+    result?;
+    debug!(
+        "User {} renamed {} to {} on {}",
+        user.username(),
+        form.key,
+        destination,
+        form.server_path
+    );
+
+    Ok(Redirect::to(&format!(
+        "{}/{}/{}",
+        Urls::Browse.as_ref(),
+        form.server_path,
+        form.parent_path()
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::views::oidc::test_user_claims;
+
+    #[tokio::test]
+    async fn test_rename_file_post() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::create_dir(temp_dir.path().join("subdir")).expect("Failed to create subdir");
+        std::fs::write(temp_dir.path().join("subdir/test.txt"), b"Hello, world!")
+            .expect("Failed to write test file");
+        std::fs::write(temp_dir.path().join("subdir/other.txt"), b"other")
+            .expect("Failed to write test file");
+
+        let mut config = Config::test_config();
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(temp_dir.path()));
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let form = |new_name: &str| RenameForm {
+            server_path: "test".to_string(),
+            key: "subdir/test.txt".to_string(),
+            new_name: new_name.to_string(),
+        };
+
+        assert!(matches!(
+            rename_file_post(
+                state.to_state(),
+                Some(test_user_claims()),
+                Form(form("other.txt"))
+            )
+            .await,
+            Err(Error::BadRequest(_))
+        ));
+        for bad_name in [
+            "../escape.txt",
+            "..\\escape.txt",
+            "nul\0.txt",
+            "bell\x07.txt",
+            "",
+        ] {
+            assert!(matches!(
+                rename_file_post(
+                    state.to_state(),
+                    Some(test_user_claims()),
+                    Form(form(bad_name))
+                )
+                .await,
+                Err(Error::BadRequest(_))
+            ));
+        }
+
+        let response = rename_file_post(
+            state.to_state(),
+            Some(test_user_claims()),
+            Form(form("renamed.txt")),
+        )
+        .await
+        .expect("Failed to rename file")
+        .into_response();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            response
+                .headers()
+                .get("location")
+                .expect("Failed to get location header"),
+            "/browse/test/subdir"
+        );
+        assert!(!temp_dir.path().join("subdir/test.txt").exists());
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("subdir/renamed.txt"))
+                .expect("Failed to read renamed file"),
+            b"Hello, world!"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rename_audit() {
+        use crate::audit;
+        use crate::views::oidc::OIDC_TEST_USERNAME;
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let audit_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let audit_log = audit_dir.path().join("audit.log");
+        std::fs::write(temp_dir.path().join("test.txt"), b"hello").expect("Failed to write file");
+
+        let mut config = Config::test_config();
+        config.audit_log = Some(audit_log.clone());
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(temp_dir.path()));
+        let state = WebState::test_webstate_with_config(config).await;
+        assert!(rename_file_post(
+            state.to_state(),
+            Some(test_user_claims()),
+            Form(RenameForm {
+                server_path: "test".to_string(),
+                key: "test.txt".to_string(),
+                new_name: "renamed.txt".to_string(),
+            }),
+        )
+        .await
+        .is_ok());
+
+        let records = audit::read_records(&audit_log);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].username, OIDC_TEST_USERNAME);
+        assert_eq!(records[0].action, AuditAction::Rename);
+        assert_eq!(records[0].server_path, "test");
+        assert_eq!(records[0].key, "test.txt");
+        assert_eq!(records[0].destination.as_deref(), Some("renamed.txt"));
+        assert_eq!(records[0].result, "ok");
+    }
+
+    #[tokio::test]
+    async fn test_rename_unique_basenames() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::create_dir(temp_dir.path().join("subdir")).expect("Failed to create subdir");
+        std::fs::write(temp_dir.path().join("test.txt"), b"hello").expect("Failed to write file");
+        std::fs::write(temp_dir.path().join("subdir/taken.txt"), b"taken")
+            .expect("Failed to write file");
+
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "test".to_string(),
+            ServerPath {
+                unique_basenames: true,
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+
+        assert_eq!(
+            rename_file_post(
+                state.to_state(),
+                Some(test_user_claims()),
+                Form(RenameForm {
+                    server_path: "test".to_string(),
+                    key: "test.txt".to_string(),
+                    new_name: "taken.txt".to_string(),
+                }),
+            )
+            .await
+            .err(),
+            Some(Error::BadRequest(
+                "taken.txt already exists at subdir/taken.txt".to_string()
+            ))
+        );
+        assert!(temp_dir.path().join("test.txt").exists());
+    }
+}
@@ -0,0 +1,154 @@
+//! Finding files by name within a server path
+
+use super::{check_login, prelude::*};
+
+use crate::constants::MAX_SEARCH_RESULTS;
+use crate::fs::fs_for_user;
+use crate::views::browse::FileEntry;
+use axum::extract::{Path, Query};
+use axum::response::{Html, Response};
+
+#[derive(Template)]
+#[template(path = "search.html")]
+pub(crate) struct SearchPage {
+    server_path: String,
+    query: String,
+    results: Vec<FileEntry>,
+    /// Set if the search stopped at [MAX_SEARCH_RESULTS]
+    truncated: bool,
+    username: String,
+}
+
+impl From<SearchPage> for Result<Response, Error>
+where
+    SearchPage: Template,
+{
+    fn from(page: SearchPage) -> Result<Response, Error> {
+        Ok(Html(page.render()?).into_response())
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct SearchQuery {
+    #[serde(default)]
+    q: String,
+    /// Only search under this directory
+    path: Option<String>,
+}
+
+/// Searches a server path for files and directories whose names contain `?q=`.
+#[instrument(level = "debug", skip(state, claims))]
+pub(crate) async fn search_get(
+    State(state): State<WebState>,
+    Path(server_path): Path<String>,
+    Query(query): Query<SearchQuery>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+) -> Result<Response, Error> {
+    let user = check_login(claims)?;
+
+    let server_reader = state.configuration.read().await;
+
+    let server_path_object = match server_reader.server_paths.get(&server_path) {
+        None => {
+            error!("Couldn't find server path {}", server_path);
+            return Err(Error::NotFound(server_path));
+        }
+        Some(p) => p.clone(),
+    };
+    drop(server_reader);
+    server_path_object.check_method(&Method::GET)?;
+
+    let filekidfs = fs_for_user(&server_path_object, Some(&user.username()))?;
+
+    debug!(
+        "User {} searched {} for {:?}",
+        user.username(),
+        server_path,
+        query.q
+    );
+    let root = query
+        .path
+        .map(|path| path.trim_matches('/').to_string())
+        .filter(|path| !path.is_empty());
+    // big trees take a while to walk, so keep it off the async workers
+    let mut results = tokio::task::spawn_blocking({
+        let q = query.q.clone();
+        move || filekidfs.search(root, &q, MAX_SEARCH_RESULTS)
+    })
+    .await
+    .map_err(|err| Error::InternalServerError(format!("Search task failed to run: {err}")))??;
+    let truncated = results.len() >= MAX_SEARCH_RESULTS;
+    results.retain(|entry| {
+        !entry
+            .fullpath
+            .split('/')
+            .any(|part| server_path_object.is_ignored(part))
+    });
+
+    SearchPage {
+        server_path,
+        query: query.q,
+        results,
+        truncated,
+        username: user.username(),
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::views::oidc::test_user_claims;
+
+    #[tokio::test]
+    async fn test_search_get() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::create_dir_all(temp_dir.path().join("docs/.git")).expect("Failed to create dirs");
+        for file in ["docs/Report.pdf", "docs/.git/report.orig", "notes.txt"] {
+            std::fs::write(temp_dir.path().join(file), b"").expect("Failed to write file");
+        }
+
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "test".to_string(),
+            ServerPath {
+                ignore_patterns: vec![".git".to_string()],
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let response = search_get(
+            state.to_state(),
+            Path("test".to_string()),
+            Query(SearchQuery {
+                q: "report".to_string(),
+                path: None,
+            }),
+            Some(test_user_claims()),
+        )
+        .await
+        .expect("Failed to search");
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Failed to read body");
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains("docs/Report.pdf"));
+        assert!(!body.contains("report.orig"));
+        assert!(!body.contains("notes.txt"));
+
+        assert_eq!(
+            search_get(
+                state.to_state(),
+                Path("nope".to_string()),
+                Query(SearchQuery::default()),
+                Some(test_user_claims()),
+            )
+            .await
+            .err(),
+            Some(Error::NotFound("nope".to_string()))
+        );
+    }
+}
@@ -0,0 +1,299 @@
+//! Time-limited links to a file for people who aren't logged in.
+//!
+//! The token is the link's details and an HMAC-SHA256 of them using [crate::config::Config::share_secret],
+//! so there's nothing to store and nothing in it can be changed.
+
+use super::browse::serve_file;
+use super::{check_login, prelude::*};
+
+use crate::audit::{self, AuditAction, AuditRecord};
+use crate::constants::{SHARE_LINK_DEFAULT_SECS, SHARE_LINK_MAX_SECS};
+use crate::fs::fs_for_user;
+use axum::extract::Path;
+use axum::http::HeaderMap;
+use axum::response::Response;
+use axum::{Form, Json};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ShareForm {
+    pub(crate) server_path: String,
+    pub(crate) key: String,
+    /// How long the link lasts, defaults to [SHARE_LINK_DEFAULT_SECS] and can't be more than [SHARE_LINK_MAX_SECS]
+    #[serde(default)]
+    pub(crate) expires_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ShareResponse {
+    /// Relative to the frontend URL
+    pub(crate) url: String,
+    pub(crate) expires: DateTime<Utc>,
+}
+
+/// What a share link points at, signed into its token
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct ShareClaims {
+    server_path: String,
+    key: String,
+    /// Per-user server paths need to know whose directory it's in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    /// Unix timestamp
+    expires: i64,
+}
+
+fn mac(secret: &str, payload: &[u8]) -> Result<HmacSha256, Error> {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes())
+        .map_err(|err| Error::InternalServerError(format!("Failed to set up HMAC: {err}")))?;
+    mac.update(payload);
+    Ok(mac)
+}
+
+fn sign(secret: &str, claims: &ShareClaims) -> Result<String, Error> {
+    let payload = serde_json::to_vec(claims).map_err(|err| {
+        Error::InternalServerError(format!("Failed to serialize share link: {err}"))
+    })?;
+    let signature = mac(secret, &payload)?.finalize().into_bytes();
+    Ok(format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(&payload),
+        URL_SAFE_NO_PAD.encode(signature)
+    ))
+}
+
+/// Checks the token's signature and that it hasn't expired, before trusting anything in it
+fn verify(secret: &str, token: &str, now: DateTime<Utc>) -> Result<ShareClaims, Error> {
+    let invalid = || Error::NotAuthorized("share link is invalid".to_string());
+    let (payload, signature) = token.split_once('.').ok_or_else(invalid)?;
+    let payload = URL_SAFE_NO_PAD.decode(payload).map_err(|_| invalid())?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| invalid())?;
+    // constant time, so the signature can't be guessed a byte at a time
+    mac(secret, &payload)?
+        .verify_slice(&signature)
+        .map_err(|_| invalid())?;
+    let claims: ShareClaims = serde_json::from_slice(&payload).map_err(|_| invalid())?;
+    if claims.expires <= now.timestamp() {
+        return Err(Error::NotAuthorized("share link has expired".to_string()));
+    }
+    Ok(claims)
+}
+
+/// Makes a link anyone can download the file from until it expires
+pub(crate) async fn share_post(
+    State(state): State<WebState>,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    Form(form): Form<ShareForm>,
+) -> Result<Json<ShareResponse>, Error> {
+    let user = check_login(claims)?;
+
+    let server_reader = state.configuration.read().await;
+    let Some(secret) = server_reader.share_secret.as_deref() else {
+        return Err(Error::NotFound(Urls::Share.as_ref().to_string()));
+    };
+
+    let result = async {
+        let server_path_object = match server_reader.server_paths.get(&form.server_path) {
+            None => {
+                error!("Couldn't find server path {}", form.server_path);
+                return Err(Error::NotFound(form.server_path.clone()));
+            }
+            Some(p) => p,
+        };
+        server_path_object.check_method(&Method::GET)?;
+
+        let filekidfs = fs_for_user(server_path_object, Some(&user.username()))?;
+        if !filekidfs.is_file(&form.key) {
+            return Err(Error::NotFound(form.key.clone()));
+        }
+
+        let expires_secs = form.expires_secs.unwrap_or(SHARE_LINK_DEFAULT_SECS);
+        if expires_secs == 0 || expires_secs > SHARE_LINK_MAX_SECS {
+            return Err(Error::BadRequest(format!(
+                "Share links can last between 1 and {SHARE_LINK_MAX_SECS} seconds"
+            )));
+        }
+        let expires = Utc::now() + chrono::Duration::seconds(expires_secs as i64);
+        let token = sign(
+            secret,
+            &ShareClaims {
+                server_path: form.server_path.clone(),
+                key: form.key.clone(),
+                username: server_path_object.is_per_user().then(|| user.username()),
+                expires: expires.timestamp(),
+            },
+        )?;
+        Ok(ShareResponse {
+            url: format!("{}/{}", Urls::Shared.as_ref(), token),
+            expires,
+        })
+    }
+    .await;
+    audit::record(
+        server_reader.audit_log.as_deref(),
+        AuditRecord::new(
+            user.username(),
+            AuditAction::Share,
+            &form.server_path,
+            &form.key,
+            &result,
+        ),
+    )
+    .await;
+    let response = result?;
+    debug!(
+        "User {} shared {} on {} until {}",
+        user.username(),
+        form.key,
+        form.server_path,
+        response.expires
+    );
+    Ok(Json(response))
+}
+
+/// Sends the file a share link points at, without a login, as long as the link's signed and current
+pub(crate) async fn shared_get(
+    State(state): State<WebState>,
+    Path(token): Path<String>,
+    request_headers: HeaderMap,
+) -> Result<Response, Error> {
+    let claims = match state.configuration.read().await.share_secret.as_deref() {
+        None => return Err(Error::NotFound(Urls::Shared.as_ref().to_string())),
+        Some(secret) => verify(secret, &token, Utc::now())?,
+    };
+    serve_file(
+        State(state),
+        Path((claims.server_path, claims.key)),
+        request_headers,
+        false,
+        false,
+        claims.username,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::views::oidc::test_user_claims;
+
+    async fn test_state(temp_dir: &tempfile::TempDir) -> WebState {
+        let mut config = Config::test_config();
+        config.share_secret = Some("hunter2".to_string());
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(temp_dir.path()));
+        WebState::test_webstate_with_config(config).await
+    }
+
+    async fn get(state: &WebState, token: &str) -> Result<Response, Error> {
+        shared_get(state.to_state(), Path(token.to_string()), HeaderMap::new()).await
+    }
+
+    #[tokio::test]
+    async fn test_share_link() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("report.txt"), b"hello").expect("Failed to write");
+        let state = test_state(&temp_dir).await;
+
+        let Json(share) = share_post(
+            state.to_state(),
+            Some(test_user_claims()),
+            Form(ShareForm {
+                server_path: "test".to_string(),
+                key: "report.txt".to_string(),
+                expires_secs: Some(60),
+            }),
+        )
+        .await
+        .expect("Failed to share");
+        let token = share
+            .url
+            .strip_prefix("/shared/")
+            .expect("Unexpected share URL");
+        assert!(share.expires > Utc::now());
+
+        let response = get(&state, token).await.expect("Failed to get shared file");
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Failed to read body");
+        assert_eq!(&body[..], b"hello");
+
+        // it's a login, not a link, that's needed to make one
+        assert!(share_post(
+            state.to_state(),
+            None,
+            Form(ShareForm {
+                server_path: "test".to_string(),
+                key: "report.txt".to_string(),
+                expires_secs: None,
+            }),
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_share_link_expired() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("report.txt"), b"hello").expect("Failed to write");
+        let state = test_state(&temp_dir).await;
+
+        let token = sign(
+            "hunter2",
+            &ShareClaims {
+                server_path: "test".to_string(),
+                key: "report.txt".to_string(),
+                username: None,
+                expires: Utc::now().timestamp() - 1,
+            },
+        )
+        .expect("Failed to sign");
+        let err = get(&state, &token).await.expect_err("Expired link worked");
+        assert_eq!(
+            err,
+            Error::NotAuthorized("share link has expired".to_string())
+        );
+        assert_eq!(err.into_response().status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_share_link_tampered() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(temp_dir.path().join("report.txt"), b"hello").expect("Failed to write");
+        std::fs::write(temp_dir.path().join("secret.txt"), b"nope").expect("Failed to write");
+        let state = test_state(&temp_dir).await;
+
+        let claims = |key: &str| ShareClaims {
+            server_path: "test".to_string(),
+            key: key.to_string(),
+            username: None,
+            expires: Utc::now().timestamp() + 60,
+        };
+        let token = sign("hunter2", &claims("report.txt")).expect("Failed to sign");
+        let (_, signature) = token.split_once('.').expect("No signature");
+
+        // pointing the signature at another file
+        let payload = serde_json::to_vec(&claims("secret.txt")).expect("Failed to serialize");
+        let forged = format!("{}.{}", URL_SAFE_NO_PAD.encode(payload), signature);
+        // or signing it with the wrong secret
+        let wrong_secret = sign("password1", &claims("secret.txt")).expect("Failed to sign");
+
+        for token in [forged.as_str(), wrong_secret.as_str(), "garbage", ""] {
+            let err = get(&state, token).await.expect_err("Tampered link worked");
+            assert_eq!(
+                err,
+                Error::NotAuthorized("share link is invalid".to_string())
+            );
+            assert_eq!(err.into_response().status(), StatusCode::FORBIDDEN);
+        }
+    }
+}
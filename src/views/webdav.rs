@@ -0,0 +1,529 @@
+//! A subset of WebDAV, enough to mount a server path as a network drive
+
+use std::fmt::Write;
+
+use axum::body::Body;
+use axum::extract::{Path, Query};
+use axum::http::header::{ALLOW, CONTENT_TYPE};
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use axum::response::Response;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+
+use super::browse::{get_file, FileEntry, GetFileQuery};
+use super::delete::delete_key;
+use super::prelude::*;
+use super::put::put_file;
+use super::{check_if_match, http_date, if_match_header, FileType};
+use crate::audit::{self, AuditAction, AuditRecord};
+use crate::fs::{fs_for_user, FileKidFs};
+use crate::oidc::User;
+
+/// Tells clients which WebDAV compliance class we speak
+const DAV: HeaderName = HeaderName::from_static("dav");
+const DEPTH: HeaderName = HeaderName::from_static("depth");
+const DAV_METHODS: &str = "OPTIONS, PROPFIND, GET, PUT, DELETE, MKCOL";
+
+/// Everything that can't go in a path segment of an href as-is
+const HREF_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+pub(crate) async fn dav_nopath(
+    state: State<WebState>,
+    Path(server_path): Path<String>,
+    method: Method,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, Error> {
+    dav(
+        state,
+        Path((server_path, String::new())),
+        method,
+        claims,
+        headers,
+        body,
+    )
+    .await
+}
+
+/// Translates WebDAV methods into [FileKidFs] calls, the path's `allowed_methods` apply to the WebDAV method names as-is
+#[instrument(level = "debug", skip(state, claims, headers, body))]
+pub(crate) async fn dav(
+    State(state): State<WebState>,
+    Path((server_path, filepath)): Path<(String, String)>,
+    method: Method,
+    claims: Option<OidcClaims<EmptyAdditionalClaims>>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, Error> {
+    let filepath = filepath.trim_matches('/').to_string();
+    let username = claims.clone().map(|claims| User::from(claims).username());
+    {
+        let server_reader = state.configuration.read().await;
+        let server_path_object = match server_reader.server_paths.get(&server_path) {
+            None => {
+                error!("Couldn't find server path {}", server_path);
+                return Err(Error::NotFound(server_path));
+            }
+            Some(p) => p,
+        };
+        server_path_object.check_method(&method)?;
+    }
+
+    match method.as_str() {
+        "OPTIONS" => Ok((
+            StatusCode::OK,
+            [
+                (DAV, HeaderValue::from_static("1")),
+                (ALLOW, HeaderValue::from_static(DAV_METHODS)),
+            ],
+        )
+            .into_response()),
+        "PROPFIND" => {
+            propfind(
+                &state,
+                &server_path,
+                &filepath,
+                &headers,
+                username.as_deref(),
+            )
+            .await
+        }
+        "GET" => {
+            get_file(
+                State(state),
+                Path((server_path, filepath)),
+                Query(GetFileQuery::default()),
+                claims,
+                headers,
+            )
+            .await
+        }
+        "PUT" => {
+            let status = put_file(
+                State(state),
+                Path((server_path, filepath)),
+                claims,
+                headers,
+                body,
+            )
+            .await?;
+            Ok(status.into_response())
+        }
+        "DELETE" => {
+            delete(
+                &state,
+                &server_path,
+                &filepath,
+                username.as_deref(),
+                if_match_header(&headers),
+            )
+            .await
+        }
+        "MKCOL" => mkcol(&state, &server_path, &filepath, username.as_deref()).await,
+        _ => Err(Error::MethodNotAllowed(dav_methods(|_| true))),
+    }
+}
+
+async fn propfind(
+    state: &WebState,
+    server_path: &str,
+    filepath: &str,
+    headers: &HeaderMap,
+    username: Option<&str>,
+) -> Result<Response, Error> {
+    // clients are meant to assume infinity without a header, which we don't do
+    let children = match headers.get(DEPTH).and_then(|value| value.to_str().ok()) {
+        Some("0") => false,
+        Some("1") => true,
+        depth => {
+            return Err(Error::BadRequest(format!(
+                "Only Depth 0 or 1 is supported, not {}",
+                depth.unwrap_or("infinity")
+            )));
+        }
+    };
+
+    let server_reader = state.configuration.read().await;
+    let server_path_object = server_reader
+        .server_paths
+        .get(server_path)
+        .ok_or_else(|| Error::NotFound(server_path.to_string()))?;
+    let filekidfs = fs_for_user(server_path_object, username)?;
+    if !filekidfs.exists(filepath)? {
+        return Err(Error::NotFound(filepath.to_string()));
+    }
+
+    let mut entries = vec![dav_entry(filekidfs.as_ref(), filepath)?];
+    if children && entries[0].filetype == FileType::Directory {
+        let key = (!filepath.is_empty()).then(|| filepath.to_string());
+        let mut listing = filekidfs
+            .list_dir(key, server_reader.max_list_entries)?
+            .into_iter()
+            .filter(|entry| !server_path_object.is_ignored(&entry.filename))
+            .collect::<Vec<FileEntry>>();
+        listing.sort_by(|a, b| a.filename.cmp(&b.filename));
+        entries.extend(listing);
+    }
+
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n",
+    );
+    for entry in &entries {
+        write_response(&mut xml, server_path, entry)?;
+    }
+    xml.push_str("</D:multistatus>\n");
+
+    Ok((
+        StatusCode::MULTI_STATUS,
+        [(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/xml; charset=utf-8"),
+        )],
+        xml,
+    )
+        .into_response())
+}
+
+/// The [FileEntry] for the thing PROPFIND was asked about, the root of the server path being `""`
+fn dav_entry(filekidfs: &dyn FileKidFs, filepath: &str) -> Result<FileEntry, Error> {
+    if filepath.is_empty() {
+        return Ok(FileEntry {
+            filename: String::new(),
+            fullpath: String::new(),
+            filetype: FileType::Directory,
+            size: None,
+            modified: None,
+            dir_usage: None,
+        });
+    }
+    let data = filekidfs.get_data(filepath)?;
+    let filetype = match filekidfs.is_dir(filepath) {
+        true => FileType::Directory,
+        false => FileType::File,
+    };
+    Ok(FileEntry {
+        filename: data.filename,
+        fullpath: filepath.to_string(),
+        size: (filetype == FileType::File).then_some(data.size).flatten(),
+        filetype,
+        modified: data.modified,
+        dir_usage: None,
+    })
+}
+
+fn write_response(xml: &mut String, server_path: &str, entry: &FileEntry) -> Result<(), Error> {
+    let mut href = format!(
+        "{}/{}/",
+        Urls::Dav.as_ref(),
+        utf8_percent_encode(server_path, HREF_SEGMENT)
+    );
+    for segment in entry.fullpath.split('/').filter(|s| !s.is_empty()) {
+        let _ = write!(href, "{}/", utf8_percent_encode(segment, HREF_SEGMENT));
+    }
+    if entry.filetype == FileType::File {
+        href.pop();
+    }
+
+    let mut props = format!(
+        "<D:displayname>{}</D:displayname>",
+        xml_escape(&entry.filename)
+    );
+    match entry.filetype {
+        FileType::Directory => props.push_str("<D:resourcetype><D:collection/></D:resourcetype>"),
+        FileType::File => {
+            props.push_str("<D:resourcetype/>");
+            let mime_type = mime_guess::from_path(&entry.filename).first_or_octet_stream();
+            let _ = write!(
+                props,
+                "<D:getcontenttype>{}</D:getcontenttype>",
+                xml_escape(mime_type.as_ref())
+            );
+            if let Some(size) = entry.size {
+                let _ = write!(props, "<D:getcontentlength>{size}</D:getcontentlength>");
+            }
+        }
+    }
+    if let Some(modified) = entry.modified {
+        let _ = write!(
+            props,
+            "<D:getlastmodified>{}</D:getlastmodified>",
+            http_date(modified)
+        );
+    }
+
+    writeln!(
+        xml,
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>{props}</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+        xml_escape(&href)
+    )
+    .map_err(|err| Error::InternalServerError(format!("Failed to write PROPFIND response: {err}")))
+}
+
+fn dav_methods(keep: impl Fn(&str) -> bool) -> Vec<String> {
+    DAV_METHODS
+        .split(", ")
+        .filter(|method| keep(method))
+        .map(str::to_string)
+        .collect()
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+async fn delete(
+    state: &WebState,
+    server_path: &str,
+    filepath: &str,
+    username: Option<&str>,
+    if_match: Option<&str>,
+) -> Result<Response, Error> {
+    let server_reader = state.configuration.read().await;
+    let server_path_object = server_reader
+        .server_paths
+        .get(server_path)
+        .ok_or_else(|| Error::NotFound(server_path.to_string()))?;
+    server_path_object.check_deletable()?;
+    let filekidfs = fs_for_user(server_path_object, username)?;
+
+    if filepath.is_empty() || !filekidfs.exists(filepath)? {
+        return Err(Error::NotFound(filepath.to_string()));
+    }
+    let result = async {
+        check_if_match(if_match, server_path_object, filekidfs.as_ref(), filepath).await?;
+        // the same as deleting from the browser, so it goes in the trash if there is one
+        delete_key(
+            state,
+            filekidfs.as_ref(),
+            server_path,
+            filepath,
+            false,
+            server_path_object.trash(),
+        )
+    }
+    .await;
+    audit::record(
+        server_reader.audit_log.as_deref(),
+        AuditRecord::new(
+            username.unwrap_or_default().to_string(),
+            AuditAction::Delete,
+            server_path,
+            filepath,
+            &result,
+        ),
+    )
+    .await;
+    result?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+async fn mkcol(
+    state: &WebState,
+    server_path: &str,
+    filepath: &str,
+    username: Option<&str>,
+) -> Result<Response, Error> {
+    let server_reader = state.configuration.read().await;
+    let server_path_object = server_reader
+        .server_paths
+        .get(server_path)
+        .ok_or_else(|| Error::NotFound(server_path.to_string()))?;
+    server_path_object.check_writable()?;
+    let filekidfs = fs_for_user(server_path_object, username)?;
+
+    // RFC 4918 says MKCOL on something that exists is a 405
+    if filepath.is_empty() || filekidfs.exists(filepath)? {
+        return Err(Error::MethodNotAllowed(dav_methods(|method| {
+            method != "MKCOL"
+        })));
+    }
+    let result = filekidfs.create_dir(filepath);
+    audit::record(
+        server_reader.audit_log.as_deref(),
+        AuditRecord::new(
+            username.unwrap_or_default().to_string(),
+            AuditAction::Mkdir,
+            server_path,
+            filepath,
+            &result,
+        ),
+    )
+    .await;
+    result?;
+    Ok(StatusCode::CREATED.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[tokio::test]
+    async fn test_propfind_multistatus() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::create_dir(temp_dir.path().join("sub")).expect("Failed to create dir");
+        std::fs::write(temp_dir.path().join("a & b.txt"), b"hello").expect("Failed to write file");
+        std::fs::write(temp_dir.path().join("sub/inner.txt"), b"hi").expect("Failed to write file");
+        std::fs::write(temp_dir.path().join("skip.tmp"), b"nope").expect("Failed to write file");
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "test".to_string(),
+            ServerPath {
+                ignore_patterns: vec!["*.tmp".to_string()],
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+        let propfind = |filepath: &str, depth: Option<&'static str>| {
+            let state = state.clone();
+            let filepath = filepath.to_string();
+            async move {
+                let mut headers = HeaderMap::new();
+                if let Some(depth) = depth {
+                    headers.insert(DEPTH, HeaderValue::from_static(depth));
+                }
+                dav(
+                    state.to_state(),
+                    Path(("test".to_string(), filepath)),
+                    Method::from_bytes(b"PROPFIND").expect("Failed to build method"),
+                    None,
+                    headers,
+                    Body::empty(),
+                )
+                .await
+            }
+        };
+        let hrefs = |xml: &str| {
+            let document = roxmltree::Document::parse(xml).expect("PROPFIND should be valid XML");
+            assert!(document
+                .root_element()
+                .has_tag_name(("DAV:", "multistatus")));
+            document
+                .descendants()
+                .filter(|node| node.has_tag_name(("DAV:", "href")))
+                .filter_map(|node| node.text().map(str::to_string))
+                .collect::<Vec<String>>()
+        };
+
+        let response = propfind("", Some("1")).await.expect("Failed to PROPFIND");
+        assert_eq!(response.status(), StatusCode::MULTI_STATUS);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Failed to read body");
+        let xml = String::from_utf8(body.to_vec()).expect("Body should be UTF-8");
+        assert_eq!(
+            hrefs(&xml),
+            vec!["/dav/test/", "/dav/test/a%20&%20b.txt", "/dav/test/sub/"]
+        );
+        assert!(xml.contains("<D:getcontentlength>5</D:getcontentlength>"));
+        assert!(xml.contains("<D:displayname>a &amp; b.txt</D:displayname>"));
+
+        let response = propfind("sub/", Some("0"))
+            .await
+            .expect("Failed to PROPFIND");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Failed to read body");
+        let xml = String::from_utf8(body.to_vec()).expect("Body should be UTF-8");
+        assert_eq!(hrefs(&xml), vec!["/dav/test/sub/"]);
+        assert!(xml.contains("<D:collection/>"));
+
+        assert!(matches!(
+            propfind("", None).await,
+            Err(Error::BadRequest(_))
+        ));
+        assert!(matches!(
+            propfind("missing", Some("0")).await,
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_mkcol() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let mut config = Config::test_config();
+        config
+            .server_paths
+            .insert("test".to_string(), ServerPath::test_local(temp_dir.path()));
+        let state = WebState::test_webstate_with_config(config).await;
+        let mkcol = || {
+            dav(
+                state.to_state(),
+                Path(("test".to_string(), "new".to_string())),
+                Method::from_bytes(b"MKCOL").expect("Failed to build method"),
+                None,
+                HeaderMap::new(),
+                Body::empty(),
+            )
+        };
+
+        let response = mkcol().await.expect("Failed to MKCOL");
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(temp_dir.path().join("new").is_dir());
+        assert!(matches!(mkcol().await, Err(Error::MethodNotAllowed(_))));
+    }
+
+    #[tokio::test]
+    async fn test_delete_to_trash() {
+        use crate::audit;
+        use crate::views::oidc::{test_user_claims, OIDC_TEST_USERNAME};
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let audit_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let audit_log = audit_dir.path().join("audit.log");
+        std::fs::write(temp_dir.path().join("test.txt"), b"hello").expect("Failed to write file");
+
+        let mut config = Config::test_config();
+        config.audit_log = Some(audit_log.clone());
+        config.server_paths.insert(
+            "test".to_string(),
+            ServerPath {
+                trash_dir: Some(".trash".to_string()),
+                ..ServerPath::test_local(temp_dir.path())
+            },
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let response = dav(
+            state.to_state(),
+            Path(("test".to_string(), "test.txt".to_string())),
+            Method::DELETE,
+            Some(test_user_claims()),
+            HeaderMap::new(),
+            Body::empty(),
+        )
+        .await
+        .expect("Failed to DELETE");
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(!temp_dir.path().join("test.txt").exists());
+        let trashed = std::fs::read_dir(temp_dir.path().join(".trash"))
+            .expect("Failed to read trash")
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(trashed.len(), 1);
+        assert!(trashed[0].ends_with("-test.txt"), "{}", trashed[0]);
+
+        let records = audit::read_records(&audit_log);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].username, OIDC_TEST_USERNAME);
+        assert_eq!(records[0].action, AuditAction::Delete);
+        assert_eq!(records[0].key, "test.txt");
+        assert_eq!(records[0].result, "ok");
+    }
+}
@@ -1,41 +1,176 @@
 //! Web UI things
 
-use axum::routing::{any, get, post};
-use axum_server::bind_rustls;
+use axum::routing::{any, get, patch, post};
 use axum_server::tls_rustls::RustlsConfig;
+use axum_server::{bind, bind_rustls};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::version::{TLS12, TLS13};
+use rustls::{ConfigBuilder, ServerConfig, SupportedProtocolVersion, WantsVerifier};
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::sync::RwLockReadGuard;
 use tower_http::services::ServeDir;
 use tower_sessions_sqlx_store::SqliteStore;
 
+use axum::body::Body;
 use axum::error_handling::HandleErrorLayer;
-use axum::extract::{DefaultBodyLimit, State};
-use axum::http::{StatusCode, Uri};
-use axum::response::{IntoResponse, Redirect};
-use axum::Router;
+use axum::extract::{DefaultBodyLimit, OriginalUri, Request, State};
+use axum::http::header::{CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::http::{Extensions, HeaderMap, HeaderName, HeaderValue, StatusCode, Uri, Version};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::{Json, Router};
 use axum_oidc::error::MiddlewareError;
 use axum_oidc::{
     handle_oidc_redirect, EmptyAdditionalClaims, OidcAuthLayer, OidcClient, OidcLoginLayer,
 };
+use serde::Serialize;
 use tower::ServiceBuilder;
+use tower_http::compression::predicate::{DefaultPredicate, Predicate};
+use tower_http::compression::CompressionLayer;
 use tower_http::limit::RequestBodyLimitLayer;
+use tower_http::set_header::SetResponseHeaderLayer;
 
 use tower_sessions::SessionManagerLayer;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use utoipa_swagger_ui::SwaggerUi;
 
-use crate::constants::WEB_SERVER_DEFAULT_STATIC_PATH;
-use crate::oidc::OidcErrorHandler;
-use crate::views::browse::{browse, browse_nopath, get_file, upload_file, upload_nopath};
-use crate::views::delete::{delete_file_get, delete_file_post};
+use crate::config::TlsVersion;
+use crate::constants::{
+    ASSET_EXTENSIONS, COMPRESSIBLE_CONTENT_TYPES, WEB_SERVER_DEFAULT_STATIC_PATH,
+};
+use crate::error::negotiate_error_format;
+use crate::fs::tempdir::LiveTempDirs;
+use crate::fs::{check_available, fs_from_serverpath};
+use crate::idle::{idle_shutdown_task, track_activity};
+use crate::metrics::{metrics_get, record_metrics};
+use crate::oidc::{
+    basic_auth_fallback, refresh_task, BasicAuth, OidcErrorHandler, RefreshableLayer,
+};
+use crate::views::api::{
+    checksum_get, disk_space_get, list_get, list_nopath, openapi_get, require_api_docs, stat_get,
+    tree_get, tree_nopath,
+};
+use crate::views::archive::{zip_get, zip_nopath};
+use crate::views::browse::{
+    browse, browse_nopath, create_dir_post, get_file, head_file, upload_file, upload_nopath,
+};
+use crate::views::chunked::{
+    chunked_append, chunked_complete, chunked_init, purge_stale_uploads_task,
+};
+use crate::views::copy::copy_file_post;
+use crate::views::delete::{
+    bulk_delete_post, delete_file_get, delete_file_post, restore_file_post,
+};
+use crate::views::preview::preview_get;
+use crate::views::put::put_file;
+use crate::views::rename::rename_file_post;
+use crate::views::search::search_get;
+use crate::views::share::{share_post, shared_get};
+use crate::views::webdav::{dav, dav_nopath};
 use crate::{views, Config, Error, SendableConfig, WebServerControl, WebState};
 
-pub(crate) async fn handler_404() -> (StatusCode, &'static str) {
+pub(crate) async fn handler_404(OriginalUri(uri): OriginalUri) -> (StatusCode, &'static str) {
+    match is_asset_path(uri.path()) {
+        true => debug!("No asset at {}", uri.path()),
+        false => info!("Nothing found at {}", uri.path()),
+    }
     (StatusCode::NOT_FOUND, "nothing to see here")
 }
 
+/// Whether a path looks like something a browser went looking for by itself, like an icon or a source map
+fn is_asset_path(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            ASSET_EXTENSIONS
+                .iter()
+                .any(|asset| asset.eq_ignore_ascii_case(extension))
+        })
+}
+
+/// Whether a response is a page or API response worth compressing, downloads have a `Content-Disposition` and are often compressed already
+fn compressible(_: StatusCode, _: Version, headers: &HeaderMap, _: &Extensions) -> bool {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase());
+    !headers.contains_key(CONTENT_DISPOSITION)
+        && content_type
+            .is_some_and(|content_type| COMPRESSIBLE_CONTENT_TYPES.contains(&content_type.as_str()))
+}
+
+/// The goat, for when the static directory doesn't have a `favicon.ico`
+const DEFAULT_FAVICON: &[u8] = include_bytes!("../static/goaticon.png");
+
+/// Serves `favicon.ico` from the static directory, browsers ask for it whether the pages link to it or not
+pub(crate) async fn favicon(State(state): State<WebState>) -> Response {
+    let config_reader = state.configuration.read().await;
+    let favicon_path = config_reader
+        .static_path
+        .clone()
+        .unwrap_or(PathBuf::from(WEB_SERVER_DEFAULT_STATIC_PATH))
+        .join("favicon.ico");
+    let cache_control = format!("public, max-age={}", config_reader.static_max_age_secs);
+    drop(config_reader);
+
+    let (content_type, body) = match tokio::fs::read(&favicon_path).await {
+        Ok(contents) => ("image/x-icon", Body::from(contents)),
+        Err(_) => ("image/png", Body::from(DEFAULT_FAVICON)),
+    };
+    (
+        [
+            (CONTENT_TYPE, content_type),
+            (CACHE_CONTROL, &cache_control),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+/// Carries the ID of the request the response is for, see [request_id]
+pub(crate) const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Gives every request a new ID, which everything logged while handling it is recorded under and the response carries in `X-Request-Id`.
+///
+/// Once the request's done, its method, path, status and how long it took are logged.
+pub(crate) async fn request_id(request: Request, next: Next) -> Response {
+    let id = uuid::Uuid::new_v4().to_string();
+    // not the query string, it's not always safe to log
+    let (method, path) = (request.method().clone(), request.uri().path().to_string());
+
+    let started = Instant::now();
+    let span = info_span!("request", request_id = %id);
+    let mut response = next.run(request).instrument(span).await;
+    info!(
+        "{} {} {} {}ms request_id={}",
+        method,
+        path,
+        response.status().as_u16(),
+        started.elapsed().as_millis(),
+        id
+    );
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(X_REQUEST_ID, value);
+    }
+    response
+}
+
+/// Sends `/browse/{server_path}` and friends on to the `/`-terminated route that serves them, 308 keeps the method and body
+pub(crate) async fn add_trailing_slash(OriginalUri(uri): OriginalUri) -> Redirect {
+    let location = match uri.query() {
+        Some(query) => format!("{}/?{}", uri.path(), query),
+        None => format!("{}/", uri.path()),
+    };
+    Redirect::permanent(&location)
+}
+
 pub(crate) enum Urls {
     GetFile,
     Browse,
@@ -44,9 +179,44 @@ pub(crate) enum Urls {
     Index,
     RpLogout,
     HealthCheck,
+    /// Only OK if every server path's backend is reachable
+    Ready,
+    /// Prometheus metrics, if they're turned on
+    Metrics,
+    /// Directories as zip files
+    Zip,
+    /// WebDAV, for mounting server paths as network drives
+    Dav,
+    /// Uploads sent in chunks, see [crate::views::chunked]
+    ChunkedUpload,
     Static,
+    /// Served from the static directory, with a default if there isn't one there
+    Favicon,
     Delete,
+    /// Deleting lots of keys at once, see [crate::views::delete::bulk_delete_post]
+    BulkDelete,
+    /// Taking things back out of a server path's trash
+    Restore,
     Upload,
+    Rename,
+    Copy,
+    ApiTree,
+    ApiList,
+    ApiDiskSpace,
+    /// The OpenAPI description of the JSON API, if it's turned on
+    ApiDocs,
+    /// Swagger UI for [Urls::ApiDocs]
+    ApiDocsUi,
+    Checksum,
+    Stat,
+    Search,
+    CreateDir,
+    /// Small text files shown as plain text
+    Preview,
+    /// Makes a [Urls::Shared] link to a file
+    Share,
+    /// Signed, time-limited links to a file that don't need a login
+    Shared,
 }
 
 impl Urls {
@@ -59,9 +229,31 @@ impl Urls {
             Urls::Logout => "/logout",
             Urls::RpLogout => "/rp_logout",
             Urls::HealthCheck => "/healthy",
+            Urls::Ready => "/ready",
+            Urls::Metrics => "/metrics",
+            Urls::Zip => "/zip",
+            Urls::Dav => "/dav",
+            Urls::ChunkedUpload => "/uploads",
             Urls::Static => "/static",
+            Urls::Favicon => "/favicon.ico",
             Urls::Delete => "/delete",
+            Urls::BulkDelete => "/delete/bulk",
+            Urls::Restore => "/restore",
             Urls::Upload => "/upload",
+            Urls::Rename => "/rename",
+            Urls::Copy => "/copy",
+            Urls::ApiTree => "/api/tree",
+            Urls::ApiList => "/api/list",
+            Urls::ApiDiskSpace => "/api/space",
+            Urls::ApiDocs => "/api/openapi.json",
+            Urls::ApiDocsUi => "/api/docs",
+            Urls::Checksum => "/checksum",
+            Urls::Stat => "/stat",
+            Urls::Search => "/search",
+            Urls::Preview => "/preview",
+            Urls::CreateDir => "/mkdir",
+            Urls::Share => "/share",
+            Urls::Shared => "/shared",
         }
     }
 }
@@ -70,6 +262,84 @@ async fn up(State(_state): State<WebState>) -> impl IntoResponse {
     (StatusCode::OK, "OK")
 }
 
+#[derive(Debug, Serialize)]
+pub(crate) struct ReadyResponse {
+    ready: bool,
+    /// The server paths that aren't available
+    failed: Vec<String>,
+}
+
+/// Checks every server path can actually serve files, unlike [Urls::HealthCheck] which only says the process is up
+pub(crate) async fn ready(State(state): State<WebState>) -> Result<impl IntoResponse, Error> {
+    let (server_paths, timeout) = {
+        let config = state.configuration.read().await;
+        (config.server_paths.clone(), config.available_timeout())
+    };
+    // everything's checked at once, so a slow backend only costs one timeout
+    let checks = server_paths
+        .iter()
+        // per-user directories are created when they're first used
+        .filter(|(_, server_path)| !server_path.is_per_user())
+        .map(|(name, server_path)| async move {
+            let available = match fs_from_serverpath(server_path) {
+                Ok(filekidfs) => check_available(filekidfs.as_ref(), timeout).await,
+                Err(err) => Err(err),
+            };
+            match available {
+                Ok(true) => None,
+                Ok(false) => {
+                    warn!("Server path {} isn't available", name);
+                    Some(name.to_owned())
+                }
+                Err(err) => {
+                    warn!("Failed to check server path {}: {:?}", name, err);
+                    Some(name.to_owned())
+                }
+            }
+        });
+    let mut failed: Vec<String> = futures::future::join_all(checks)
+        .await
+        .into_iter()
+        .flatten()
+        .collect();
+    failed.sort();
+
+    let status = match failed.is_empty() {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    Ok((
+        status,
+        Json(ReadyResponse {
+            ready: failed.is_empty(),
+            failed,
+        }),
+    ))
+}
+
+/// Discovers the IdP and builds the auth layer from what it says, at startup and then every `oidc_refresh_secs`
+async fn discover_oidc_auth_layer(
+    redirect_url: Uri,
+    client_id: String,
+    client_secret: Option<String>,
+    issuer: String,
+) -> Result<OidcAuthLayer<EmptyAdditionalClaims>, Error> {
+    let mut oidc_client = OidcClient::builder()
+        .with_default_http_client()
+        .add_scope("openid")
+        .add_scope("groups")
+        .with_redirect_url(redirect_url)
+        .with_client_id(client_id);
+
+    if let Some(secret) = client_secret {
+        oidc_client = oidc_client.with_client_secret(secret);
+    }
+
+    let oidc_client: OidcClient<EmptyAdditionalClaims> =
+        oidc_client.discover(issuer).await?.build();
+    Ok(OidcAuthLayer::<EmptyAdditionalClaims>::new(oidc_client))
+}
+
 pub(crate) async fn build_app(
     state: WebState,
     session_layer: SessionManagerLayer<SqliteStore>,
@@ -80,7 +350,9 @@ pub(crate) async fn build_app(
     let oidc_issuer = config_reader.oidc_issuer.clone();
     let oidc_client_id = config_reader.oidc_client_id.clone();
     let oidc_client_secret = config_reader.oidc_client_secret.clone();
+    let oidc_refresh_secs = config_reader.oidc_refresh_secs;
     let frontend_url = config_reader.frontend_url.clone();
+    let basic_auth = config_reader.basic_auth.clone();
     drop(config_reader);
 
     let frontend_url = Uri::from_str(&frontend_url)
@@ -96,19 +368,51 @@ pub(crate) async fn build_app(
         .layer(OidcLoginLayer::<EmptyAdditionalClaims>::new());
 
     let ui = Router::new()
+        .route(
+            &format!("{}/{{server_path}}", Urls::Upload.as_ref()),
+            post(add_trailing_slash),
+        )
         .route(
             &format!("{}/{{server_path}}/", Urls::Upload.as_ref()),
             post(upload_nopath),
         )
         .route(
             &format!("{}/{{server_path}}/{{*filepath}}", Urls::Upload.as_ref()),
-            post(upload_file),
+            post(upload_file).put(put_file),
+        )
+        .route(
+            &format!("{}/init", Urls::ChunkedUpload.as_ref()),
+            post(chunked_init),
+        )
+        .route(
+            &format!("{}/{{id}}", Urls::ChunkedUpload.as_ref()),
+            patch(chunked_append),
+        )
+        .route(
+            &format!("{}/{{id}}/complete", Urls::ChunkedUpload.as_ref()),
+            post(chunked_complete),
+        )
+        .route(
+            &format!("{}/{{server_path}}", Urls::Dav.as_ref()),
+            any(dav_nopath),
+        )
+        .route(
+            &format!("{}/{{server_path}}/", Urls::Dav.as_ref()),
+            any(dav_nopath),
+        )
+        .route(
+            &format!("{}/{{server_path}}/{{*filepath}}", Urls::Dav.as_ref()),
+            any(dav),
         )
         // TODO: this is pretty janky but it works for now
         .layer(DefaultBodyLimit::disable())
         .layer(RequestBodyLimitLayer::new(
             state.configuration.read().await.max_upload_mb * 1024 * 1024,
         ))
+        .route(
+            &format!("{}/{{server_path}}", Urls::Browse.as_ref()),
+            get(add_trailing_slash),
+        )
         .route(
             &format!("{}/{{server_path}}/", Urls::Browse.as_ref()),
             get(browse_nopath),
@@ -121,9 +425,56 @@ pub(crate) async fn build_app(
             Urls::Delete.as_ref(),
             get(delete_file_get).post(delete_file_post),
         )
+        .route(Urls::BulkDelete.as_ref(), post(bulk_delete_post))
+        .route(Urls::Restore.as_ref(), post(restore_file_post))
+        .route(Urls::ApiDiskSpace.as_ref(), get(disk_space_get))
+        .route(
+            &format!("{}/{{server_path}}/{{*filepath}}", Urls::Checksum.as_ref()),
+            get(checksum_get),
+        )
+        .route(
+            &format!("{}/{{server_path}}/{{*filepath}}", Urls::Stat.as_ref()),
+            get(stat_get),
+        )
+        .route(
+            &format!("{}/{{server_path}}/", Urls::ApiTree.as_ref()),
+            get(tree_nopath),
+        )
+        .route(
+            &format!("{}/{{server_path}}/{{*filepath}}", Urls::ApiTree.as_ref()),
+            get(tree_get),
+        )
+        .route(
+            &format!("{}/{{server_path}}/", Urls::ApiList.as_ref()),
+            get(list_nopath),
+        )
+        .route(
+            &format!("{}/{{server_path}}/{{*filepath}}", Urls::ApiList.as_ref()),
+            get(list_get),
+        )
+        .route(
+            &format!("{}/{{server_path}}/{{*filepath}}", Urls::Preview.as_ref()),
+            get(preview_get),
+        )
+        .route(
+            &format!("{}/{{server_path}}", Urls::Search.as_ref()),
+            get(search_get),
+        )
+        .route(Urls::Rename.as_ref(), post(rename_file_post))
+        .route(Urls::CreateDir.as_ref(), post(create_dir_post))
+        .route(Urls::Copy.as_ref(), post(copy_file_post))
+        .route(Urls::Share.as_ref(), post(share_post))
         .route(
             &format!("{}/{{server_path}}/{{*filepath}}", Urls::GetFile.as_ref()),
-            get(get_file),
+            get(get_file).head(head_file),
+        )
+        .route(
+            &format!("{}/{{server_path}}/", Urls::Zip.as_ref()),
+            get(zip_nopath),
+        )
+        .route(
+            &format!("{}/{{server_path}}/{{*filepath}}", Urls::Zip.as_ref()),
+            get(zip_get),
         )
         .route(Urls::Index.as_ref(), get(views::home));
 
@@ -136,7 +487,7 @@ pub(crate) async fn build_app(
 
     let app: Router<WebState> = match state.configuration.read().await.oauth2_disabled {
         true => app.merge(ui),
-        false => {
+        false => 'oidc: {
             let tail = match frontend_url.path().ends_with("/") {
                 true => "",
                 false => "/",
@@ -147,23 +498,42 @@ pub(crate) async fn build_app(
                     Error::Configuration(format!("Failed to parse frontend URL: {err:?}"))
                 })?;
 
-            let mut oidc_client = OidcClient::builder()
-                .with_default_http_client()
-                .add_scope("openid")
-                .add_scope("groups")
-                .with_redirect_url(redirect_url)
-                .with_client_id(oidc_client_id);
-
-            if let Some(secret) = oidc_client_secret {
-                oidc_client = oidc_client.with_client_secret(secret);
+            let discovered = discover_oidc_auth_layer(
+                redirect_url.clone(),
+                oidc_client_id.clone(),
+                oidc_client_secret.clone(),
+                oidc_issuer.clone(),
+            )
+            .await;
+            let oidc_auth_layer = match (discovered, basic_auth.clone()) {
+                (Ok(layer), _) => RefreshableLayer::new(layer),
+                (Err(err), Some(credentials)) => {
+                    error!(
+                        "OIDC discovery failed, only HTTP Basic logins will work until the server's reloaded: {}",
+                        err
+                    );
+                    break 'oidc app.merge(ui).layer(axum::middleware::from_fn_with_state(
+                        BasicAuth::new(credentials, true),
+                        basic_auth_fallback,
+                    ));
+                }
+                (Err(err), None) => return Err(err),
+            };
+            if oidc_refresh_secs > 0 {
+                tokio::task::spawn(refresh_task(
+                    oidc_auth_layer.clone(),
+                    Duration::from_secs(oidc_refresh_secs),
+                    move || {
+                        discover_oidc_auth_layer(
+                            redirect_url.clone(),
+                            oidc_client_id.clone(),
+                            oidc_client_secret.clone(),
+                            oidc_issuer.clone(),
+                        )
+                    },
+                ));
             }
 
-            let oidc_client: OidcClient<EmptyAdditionalClaims> =
-                oidc_client.discover(oidc_issuer).await?.build();
-
-            let oidc_auth_layer: OidcAuthLayer<EmptyAdditionalClaims> =
-                OidcAuthLayer::<EmptyAdditionalClaims>::new(oidc_client);
-
             let oidc_auth_service = ServiceBuilder::new()
                 .layer(HandleErrorLayer::new(|e: MiddlewareError| async move {
                     if let MiddlewareError::SessionNotFound = e {
@@ -177,43 +547,131 @@ pub(crate) async fn build_app(
                 }))
                 .layer(oidc_auth_layer);
 
-            app.merge(ui)
-                .layer(oidc_login_service)
-                .route(
-                    "/auth/login",
-                    any(handle_oidc_redirect::<EmptyAdditionalClaims>),
-                )
-                .layer(oidc_auth_service)
+            let app = app.merge(ui).layer(oidc_login_service);
+            // between the two OIDC layers, so it only sees requests OIDC didn't log in
+            let app = match basic_auth {
+                Some(credentials) => app.layer(axum::middleware::from_fn_with_state(
+                    BasicAuth::new(credentials, false),
+                    basic_auth_fallback,
+                )),
+                None => app,
+            };
+            app.route(
+                "/auth/login",
+                any(handle_oidc_redirect::<EmptyAdditionalClaims>),
+            )
+            .layer(oidc_auth_service)
         }
     };
-    // after here, the routers don't *require* auth
-    let app = app
-        // after here, the URLs cannot have auth
-        .route(Urls::HealthCheck.as_ref(), get(up))
-        .route(Urls::Logout.as_ref(), get(views::oidc::logout))
-        .nest_service(
-            Urls::Static.as_ref(),
+    let config_reader = state.configuration.read().await;
+    let static_cache_control = HeaderValue::from_str(&format!(
+        "public, max-age={}",
+        config_reader.static_max_age_secs
+    ))
+    .map_err(|err| Error::Configuration(format!("Invalid static max age: {err:?}")))?;
+    let static_service = ServiceBuilder::new()
+        // missing files shouldn't stick around in caches
+        .layer(SetResponseHeaderLayer::overriding(
+            CACHE_CONTROL,
+            move |response: &Response<_>| {
+                response
+                    .status()
+                    .is_success()
+                    .then(|| static_cache_control.clone())
+            },
+        ))
+        .service(
             ServeDir::new(
-                state
-                    .configuration
-                    .read()
-                    .await
+                config_reader
                     .static_path
                     .clone()
                     .unwrap_or(PathBuf::from(WEB_SERVER_DEFAULT_STATIC_PATH)),
             )
+            // this sets `Vary: Accept-Encoding` too
             .precompressed_br(),
+        );
+    drop(config_reader);
+
+    // after here, the routers don't *require* auth
+    let app = app
+        // after here, the URLs cannot have auth
+        .route(Urls::HealthCheck.as_ref(), get(up))
+        .route(Urls::Ready.as_ref(), get(ready))
+        .route(Urls::Metrics.as_ref(), get(metrics_get))
+        .route(Urls::ApiDocs.as_ref(), get(openapi_get))
+        // the signature's the authentication
+        .route(
+            &format!("{}/{{token}}", Urls::Shared.as_ref()),
+            get(shared_get),
+        )
+        .merge(
+            Router::from(
+                SwaggerUi::new(Urls::ApiDocsUi.as_ref())
+                    .config(utoipa_swagger_ui::Config::from(Urls::ApiDocs.as_ref())),
+            )
+            .route_layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                require_api_docs,
+            )),
         )
+        .route(Urls::Favicon.as_ref(), get(favicon))
+        .route(Urls::Logout.as_ref(), get(views::oidc::logout))
+        .nest_service(Urls::Static.as_ref(), static_service)
         .fallback(handler_404)
         // .layer(TraceLayer::new_for_http())
-        .layer(session_layer);
+        .layer(session_layer)
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            track_activity,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            record_metrics,
+        ))
+        .layer(axum::middleware::from_fn(negotiate_error_format));
+    let app = match state.configuration.read().await.compress_responses {
+        true => app.layer(
+            CompressionLayer::new().compress_when(DefaultPredicate::new().and(compressible)),
+        ),
+        false => app,
+    };
+    let app = app.layer(axum::middleware::from_fn(request_id));
     // here... we... go!
     Ok(app.with_state(state))
 }
 
-fn check_certs_exist(
-    config_reader: &RwLockReadGuard<'_, Config>,
-) -> Result<(PathBuf, PathBuf), Error> {
+/// How the web server accepts connections
+#[derive(Debug, PartialEq)]
+pub(crate) enum Listener {
+    Plain,
+    Tls {
+        cert_file: PathBuf,
+        cert_key: PathBuf,
+        min_version: TlsVersion,
+        cipher_suites: Option<Vec<String>>,
+    },
+}
+
+impl Listener {
+    /// The certificates and TLS policy are only checked if TLS is turned on
+    pub(crate) fn from_config(config: &Config) -> Result<Self, Error> {
+        match config.tls_enabled {
+            true => {
+                let (cert_file, cert_key) = check_certs_exist(config)?;
+                tls_policy(config.tls_min_version, config.tls_cipher_suites.as_deref())?;
+                Ok(Self::Tls {
+                    cert_file,
+                    cert_key,
+                    min_version: config.tls_min_version,
+                    cipher_suites: config.tls_cipher_suites.clone(),
+                })
+            }
+            false => Ok(Self::Plain),
+        }
+    }
+}
+
+fn check_certs_exist(config_reader: &Config) -> Result<(PathBuf, PathBuf), Error> {
     let cert_file = config_reader.cert_file.clone();
     let cert_key = config_reader.cert_key.clone();
     if !cert_file.exists() {
@@ -230,50 +688,250 @@ fn check_certs_exist(
     Ok((cert_file, cert_key))
 }
 
+/// A rustls config builder that only offers `min_version` and newer, and `cipher_suites` if they're set
+pub(crate) fn tls_policy(
+    min_version: TlsVersion,
+    cipher_suites: Option<&[String]>,
+) -> Result<ConfigBuilder<ServerConfig, WantsVerifier>, Error> {
+    let versions: &[&'static SupportedProtocolVersion] = match min_version {
+        TlsVersion::Tls12 => &[&TLS13, &TLS12],
+        TlsVersion::Tls13 => &[&TLS13],
+    };
+    let mut provider = rustls::crypto::aws_lc_rs::default_provider();
+    if let Some(cipher_suites) = cipher_suites {
+        if let Some(unknown) = cipher_suites.iter().find(|name| {
+            !provider
+                .cipher_suites
+                .iter()
+                .any(|suite| format!("{:?}", suite.suite()).eq_ignore_ascii_case(name))
+        }) {
+            return Err(Error::Configuration(format!(
+                "Unknown TLS cipher suite {unknown:?}"
+            )));
+        }
+        provider.cipher_suites.retain(|suite| {
+            let name = format!("{:?}", suite.suite());
+            cipher_suites
+                .iter()
+                .any(|want| want.eq_ignore_ascii_case(&name))
+        });
+    }
+    provider
+        .cipher_suites
+        .retain(|suite| versions.contains(&suite.version()));
+
+    ServerConfig::builder_with_provider(Arc::new(provider))
+        .with_protocol_versions(versions)
+        .map_err(|err| {
+            Error::Configuration(format!(
+                "TLS minimum version {min_version:?} doesn't work with cipher suites {cipher_suites:?}: {err}"
+            ))
+        })
+}
+
+/// Loads the certificate chain and key and applies the TLS policy from [tls_policy]
+fn tls_server_config(
+    min_version: TlsVersion,
+    cipher_suites: Option<&[String]>,
+    cert_file: &Path,
+    cert_key: &Path,
+) -> Result<ServerConfig, Error> {
+    let certs = CertificateDer::pem_file_iter(cert_file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| {
+            Error::Configuration(format!(
+                "Failed to load certificates from {}: {err}",
+                cert_file.display()
+            ))
+        })?;
+    let key = PrivateKeyDer::from_pem_file(cert_key).map_err(|err| {
+        Error::Configuration(format!(
+            "Failed to load private key from {}: {err}",
+            cert_key.display()
+        ))
+    })?;
+    let mut server_config = tls_policy(min_version, cipher_suites)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| Error::Configuration(format!("Failed to load TLS config: {err}")))?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(server_config)
+}
+
 /// Start and run the web server
 pub async fn start_web_server(configuration: SendableConfig, app: Router) -> Result<(), Error> {
     let configuration_reader = configuration.read().await;
 
     let listen_address = configuration_reader.listen_addr();
-    let (cert_file, cert_key) = check_certs_exist(&configuration_reader)?;
+    let listener = Listener::from_config(&configuration_reader)?;
     drop(configuration_reader);
 
-    let tls_config = RustlsConfig::from_pem_file(&cert_file.as_path(), &cert_key.as_path())
-        .await
-        .map_err(|err| Error::Generic(format!("Failed to load TLS config: {err:?}")))?;
-    bind_rustls(
-        listen_address.parse::<SocketAddr>().map_err(|err| {
-            Error::Generic(format!(
-                "Failed to parse listen address {listen_address}: {err:?}"
-            ))
-        })?,
-        tls_config,
-    )
-    .serve(app.into_make_service())
-    .await
+    let listen_address = listen_address.parse::<SocketAddr>().map_err(|err| {
+        Error::Generic(format!(
+            "Failed to parse listen address {listen_address}: {err:?}"
+        ))
+    })?;
+
+    match listener {
+        Listener::Plain => {
+            warn!(
+                "TLS is disabled, serving plain HTTP on {} - only do this behind a TLS-terminating proxy!",
+                listen_address
+            );
+            bind(listen_address)
+                .serve(app.into_make_service())
+                .await
+        }
+        Listener::Tls {
+            cert_file,
+            cert_key,
+            min_version,
+            cipher_suites,
+        } => {
+            let tls_config = RustlsConfig::from_config(Arc::new(tls_server_config(
+                min_version,
+                cipher_suites.as_deref(),
+                &cert_file,
+                &cert_key,
+            )?));
+            bind_rustls(listen_address, tls_config)
+                .serve(app.into_make_service())
+                .await
+        }
+    }
     .map_err(|err| Error::Generic(format!("Web server failed: {err:?}")))
 }
 
+/// Re-reads the config file and swaps it in for the running one, new server paths and settings take effect straight away.
+///
+/// Anything baked in at startup (the listener, sessions, upload body limit) still needs a restart.
+/// If the new config doesn't load or fails [Config::startup_check] the old one is kept.
+pub(crate) async fn reload_config(
+    config_filepath: &Path,
+    configuration: &SendableConfig,
+    live_tempdirs: &mut LiveTempDirs,
+) -> Result<(), Error> {
+    let mut new_config = Config::from_file_and_env(&config_filepath.to_path_buf())?;
+    new_config.startup_check().await?;
+
+    let mut config_writer = configuration.write().await;
+    // these can be switched on from the command line, which a reload shouldn't undo
+    new_config.debug |= config_writer.debug;
+    new_config.oauth2_disabled |= config_writer.oauth2_disabled;
+    live_tempdirs.sync(&mut new_config, Some(&config_writer))?;
+    *config_writer = new_config;
+    info!("Reloaded configuration from {}", config_filepath.display());
+    Ok(())
+}
+
+/// How long a reload request asks to wait, or [None] if it's not a reload request
+fn reload_delay(message: &WebServerControl) -> Option<Duration> {
+    match message {
+        WebServerControl::Reload => Some(Duration::from_secs(1)),
+        WebServerControl::ReloadAfter(millis) => Some(Duration::from_millis(*millis)),
+        WebServerControl::Stop | WebServerControl::StopAfter(_) => None,
+    }
+}
+
+/// What happened while waiting for a burst of reload requests to settle down
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Debounced {
+    /// It went quiet, time to reload
+    Reload,
+    /// Something other than a reload request turned up, or the channel closed
+    Interrupted(Option<WebServerControl>),
+}
+
+/// Waits `delay` (or `quiet` if that's longer) after a reload request, starting the wait again whenever another one arrives so a burst turns into a single reload.
+pub(crate) async fn debounce_reloads(
+    web_server_controller: &mut Receiver<WebServerControl>,
+    delay: Duration,
+    quiet: Duration,
+) -> Debounced {
+    let mut wait = delay.max(quiet);
+    loop {
+        match tokio::time::timeout(wait, web_server_controller.recv()).await {
+            Err(_) => return Debounced::Reload,
+            Ok(message) => match message.as_ref().and_then(reload_delay) {
+                Some(delay) => {
+                    debug!("Coalescing reload request {:?}", message);
+                    wait = delay.max(quiet);
+                }
+                None => return Debounced::Interrupted(message),
+            },
+        }
+    }
+}
+
+/// Throws away reload requests that queued up while a reload was running, returning the first message that isn't one
+pub(crate) fn drain_reloads(
+    web_server_controller: &mut Receiver<WebServerControl>,
+) -> Option<WebServerControl> {
+    while let Ok(message) = web_server_controller.try_recv() {
+        if reload_delay(&message).is_none() {
+            return Some(message);
+        }
+        debug!(
+            "Ignoring reload request {:?} that arrived during a reload",
+            message
+        );
+    }
+    None
+}
+
+/// Sends a [WebServerControl::Reload] whenever the process gets a SIGHUP, like most daemons.
+///
+/// The handler's installed before this returns, so signals sent after that won't kill the process.
+#[cfg(unix)]
+pub fn reload_on_sighup(
+    web_tx: Sender<WebServerControl>,
+) -> Result<tokio::task::JoinHandle<()>, Error> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    Ok(tokio::task::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("Got SIGHUP, reloading configuration");
+            if web_tx.send(WebServerControl::Reload).await.is_err() {
+                error!("Web server controller channel closed, no longer listening for SIGHUP");
+                break;
+            }
+        }
+    }))
+}
+
 /// Starts up the web server
 pub async fn run_web_server(
     config_filepath: PathBuf,
     configuration: SendableConfig,
-    session_db_path: Option<String>,
+    // overrides [Config::session_db_path] with a database URL, mostly for tests
+    session_db_url: Option<String>,
     // db: Arc<DatabaseConnection>,
     // registry: Arc<Registry>,
     web_tx: Sender<WebServerControl>,
     mut web_server_controller: Receiver<WebServerControl>,
 ) -> Result<(), Error> {
-    let (_deletion_task, session_layer) = crate::session_store::build(session_db_path).await?;
+    let session_config = crate::session_store::SessionConfig::from(&*configuration.read().await);
+    let (_deletion_task, session_layer) =
+        crate::session_store::build(session_db_url, &session_config).await?;
+    let _trash_task = tokio::task::spawn(crate::trash::purge_trash_task(configuration.clone()));
+    let _chunked_upload_task = tokio::task::spawn(purge_stale_uploads_task(configuration.clone()));
 
     let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
 
-    let app = build_app(
-        // TODO web_tx impl
-        WebState::new(web_tx.clone(), configuration.clone(), config_filepath).await?,
-        session_layer,
-    )
-    .await?;
+    let mut live_tempdirs = LiveTempDirs::default();
+    live_tempdirs.sync(&mut *configuration.write().await, None)?;
+
+    // TODO web_tx impl
+    let state_config_filepath = config_filepath.clone();
+    let state = WebState::new(web_tx.clone(), configuration.clone(), config_filepath).await?;
+    let _idle_task = tokio::task::spawn(idle_shutdown_task(
+        configuration.clone(),
+        state.activity.clone(),
+        web_tx.clone(),
+    ));
+
+    let mut app = build_app(state.clone(), session_layer.clone()).await?;
 
     let frontend_url = configuration.read().await.frontend_url.clone();
 
@@ -283,50 +941,85 @@ pub async fn run_web_server(
         configuration.read().await.listen_addr()
     );
 
-    loop {
+    // it's a task so it keeps serving while reloads are debounced and loaded
+    let mut server = tokio::task::spawn(start_web_server(configuration.clone(), app.clone()));
+    // a message that turned up while reloads were being handled, dealt with before anything else
+    let mut pending: Option<WebServerControl> = None;
+    let result = loop {
         tokio::select! {
-            server_result = start_web_server(configuration.clone(), app.clone()) => {
-                match server_result {Ok(_) => {
-                    error!("Web server exited cleanly");
-                },
-                Err(err) => {
-                    error!("Web server failed: {:?}", err);
-                    return Err(err)
-                }}
+            server_result = &mut server => {
+                match server_result {
+                    Ok(Ok(_)) => {
+                        error!("Web server exited cleanly");
+                    },
+                    Ok(Err(err)) => {
+                        error!("Web server failed: {:?}", err);
+                        break Err(err)
+                    },
+                    Err(err) => {
+                        error!("Web server task failed: {:?}", err);
+                        break Err(Error::InternalServerError(format!("Web server task failed: {err}")))
+                    }
+                }
+                server = tokio::task::spawn(start_web_server(configuration.clone(), app.clone()));
             },
-            server_message = web_server_controller.recv() => {
+            server_message = async {
+                match pending.take() {
+                    Some(message) => Some(message),
+                    None => web_server_controller.recv().await,
+                }
+            } => {
                 match server_message {
                     Some(WebServerControl::Stop) => {
                         tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
                         info!("Web server stopping");
-                        return Ok(());
+                        break Ok(());
                     },
                     Some(WebServerControl::StopAfter(millis)) => {
                         tokio::time::sleep(tokio::time::Duration::from_millis(millis)).await;
                         info!("Web server stopping");
-                        return Ok(());
-                    },
-                    Some(WebServerControl::Reload) => {
-                        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-                        info!("Web server reloading");
+                        break Ok(());
                     },
-                    Some(WebServerControl::ReloadAfter(millis)) => {
-                        tokio::time::sleep(tokio::time::Duration::from_secs(millis)).await;
-                        info!("Web server reloading");
+                    Some(message @ (WebServerControl::Reload | WebServerControl::ReloadAfter(_))) => {
+                        let quiet = Duration::from_millis(configuration.read().await.reload_quiet_millis);
+                        match debounce_reloads(&mut web_server_controller, reload_delay(&message).unwrap_or_default(), quiet).await {
+                            Debounced::Reload => {
+                                info!("Web server reloading");
+                                match reload_config(&state_config_filepath, &configuration, &mut live_tempdirs).await {
+                                    // the router has settings baked in, so it's rebuilt and the listener restarted with it
+                                    Ok(()) => match build_app(state.clone(), session_layer.clone()).await {
+                                        Ok(rebuilt) => {
+                                            app = rebuilt;
+                                            server.abort();
+                                            // the old listener has to be gone before the new one binds
+                                            let _ = (&mut server).await;
+                                            server = tokio::task::spawn(start_web_server(configuration.clone(), app.clone()));
+                                        }
+                                        Err(err) => error!("Failed to rebuild the web server after reloading config, it's still running with the old settings: {:?}", err),
+                                    },
+                                    Err(err) => error!("Failed to reload config, keeping the old one: {:?}", err),
+                                }
+                                pending = drain_reloads(&mut web_server_controller);
+                            }
+                            Debounced::Interrupted(message) => pending = message,
+                        }
                     },
                     None => {
                         error!("Web server controller channel closed");
-                        return Ok(())
+                        break Ok(())
                     }
                 }
             }
         }
-    }
+    };
+    server.abort();
+    result
 }
 
 #[cfg(test)]
 mod tests {
 
+    use std::collections::HashMap;
     use std::sync::Arc;
 
     use tokio::sync::{mpsc, RwLock};
@@ -356,4 +1049,719 @@ mod tests {
             "Web server should start without errors"
         );
     }
+
+    #[test]
+    fn test_listener_from_config() {
+        // the test config's certificates don't exist
+        let mut config = Config::test_config();
+        assert!(Listener::from_config(&config).is_err());
+
+        config.tls_enabled = false;
+        assert_eq!(Listener::from_config(&config), Ok(Listener::Plain));
+    }
+
+    #[tokio::test]
+    async fn test_tls_policy() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+            .expect("Failed to generate certificate");
+        let cert_file = temp_dir.path().join("cert.pem");
+        let cert_key = temp_dir.path().join("key.pem");
+        std::fs::write(&cert_file, cert.cert.pem()).expect("Failed to write cert");
+        std::fs::write(&cert_key, cert.signing_key.serialize_pem()).expect("Failed to write key");
+
+        let server_config = tls_server_config(TlsVersion::Tls13, None, &cert_file, &cert_key)
+            .expect("TLS 1.3 only should work");
+        assert!(!server_config.crypto_provider().cipher_suites.is_empty());
+        assert!(server_config
+            .crypto_provider()
+            .cipher_suites
+            .iter()
+            .all(|suite| suite.version() == &TLS13));
+
+        let suites = vec!["TLS13_AES_256_GCM_SHA384".to_string()];
+        let server_config =
+            tls_server_config(TlsVersion::Tls12, Some(&suites), &cert_file, &cert_key)
+                .expect("A single TLS 1.3 suite should work");
+        assert_eq!(server_config.crypto_provider().cipher_suites.len(), 1);
+
+        // TLS 1.3 can't use TLS 1.2 suites, and typos are caught
+        let mut config = Config::test_config();
+        config.cert_file = cert_file;
+        config.cert_key = cert_key;
+        config.tls_min_version = TlsVersion::Tls13;
+        config.tls_cipher_suites = Some(vec!["TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256".to_string()]);
+        assert!(matches!(
+            config.startup_check().await,
+            Err(Error::Configuration(_))
+        ));
+        assert!(Listener::from_config(&config).is_err());
+        config.tls_cipher_suites = Some(vec!["TLS13_NOPE".to_string()]);
+        assert!(matches!(
+            config.startup_check().await,
+            Err(Error::Configuration(_))
+        ));
+        config.tls_cipher_suites = None;
+        assert!(config.startup_check().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_start_web_server_plain_http() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // find a free port
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("Failed to find a free port")
+            .port();
+        let mut config = Config::test_config();
+        config.tls_enabled = false;
+        config.port = std::num::NonZeroU16::new(port).expect("Port was zero");
+        let configuration = Arc::new(RwLock::new(config));
+
+        let app = Router::new().route(Urls::HealthCheck.as_ref(), get(|| async { "OK" }));
+        let server = tokio::spawn(start_web_server(configuration, app));
+
+        let mut response = String::new();
+        for _ in 0..50 {
+            if let Ok(mut stream) = tokio::net::TcpStream::connect(("127.0.0.1", port)).await {
+                stream
+                    .write_all(
+                        b"GET /healthy HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                    )
+                    .await
+                    .expect("Failed to send request");
+                stream
+                    .read_to_string(&mut response)
+                    .await
+                    .expect("Failed to read response");
+                break;
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        }
+        server.abort();
+        assert!(response.starts_with("HTTP/1.1 200"), "got {response:?}");
+    }
+
+    #[tokio::test]
+    async fn test_reload_config() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("Failed to find a free port")
+            .port();
+        let static_dir = tempfile::tempdir().expect("Failed to create static dir");
+        std::fs::write(static_dir.path().join("filekid.css"), "body {}")
+            .expect("Failed to write static file");
+        let mut config = Config::test_config();
+        config.tls_enabled = false;
+        config.oauth2_disabled = true;
+        config.port = std::num::NonZeroU16::new(port).expect("Port was zero");
+        config.static_path = Some(static_dir.path().to_path_buf());
+
+        // the static file's Cache-Control header, once the server's answering
+        let cache_control = || async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            for _ in 0..50 {
+                if let Ok(mut stream) = tokio::net::TcpStream::connect(("127.0.0.1", port)).await {
+                    let mut response = String::new();
+                    stream
+                        .write_all(
+                            b"GET /static/filekid.css HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                        )
+                        .await
+                        .expect("Failed to send request");
+                    stream
+                        .read_to_string(&mut response)
+                        .await
+                        .expect("Failed to read response");
+                    return response
+                        .lines()
+                        .find_map(|line| line.strip_prefix("cache-control: "))
+                        .map(str::to_string);
+                }
+                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+            }
+            None
+        };
+
+        let config_file = tempfile::NamedTempFile::new().expect("Failed to create config file");
+        let write_config = |config: &Config| {
+            std::fs::write(
+                config_file.path(),
+                serde_json::to_string(config).expect("Failed to serialize config"),
+            )
+            .expect("Failed to write config")
+        };
+        write_config(&config);
+
+        let configuration = Arc::new(RwLock::new(
+            Config::from_file(&config_file.path().to_path_buf()).expect("Failed to load config"),
+        ));
+        let (web_tx, web_rx) = mpsc::channel(10);
+        let server = tokio::spawn(run_web_server(
+            config_file.path().to_path_buf(),
+            configuration.clone(),
+            Some(crate::session_store::SQLITE_MEMORY.to_string()),
+            web_tx.clone(),
+            web_rx,
+        ));
+        assert_eq!(
+            cache_control().await.as_deref(),
+            Some("public, max-age=86400")
+        );
+
+        config.max_upload_mb = 5;
+        config.uploads_disabled = true;
+        config.static_max_age_secs = 60;
+        write_config(&config);
+        web_tx
+            .send(WebServerControl::ReloadAfter(0))
+            .await
+            .expect("Failed to send a message");
+        for _ in 0..100 {
+            if configuration.read().await.max_upload_mb == 5 {
+                break;
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        }
+        assert_eq!(configuration.read().await.max_upload_mb, 5);
+        assert!(configuration.read().await.uploads_disabled);
+        // the command line setting survives
+        assert!(configuration.read().await.oauth2_disabled);
+        // and the router's rebuilt with the new settings
+        let mut header = None;
+        for _ in 0..50 {
+            header = cache_control().await;
+            if header.as_deref() == Some("public, max-age=60") {
+                break;
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        }
+        assert_eq!(header.as_deref(), Some("public, max-age=60"));
+
+        // a broken file leaves things as they were
+        std::fs::write(config_file.path(), "{ not json").expect("Failed to write config");
+        web_tx
+            .send(WebServerControl::ReloadAfter(0))
+            .await
+            .expect("Failed to send a message");
+        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+        assert_eq!(configuration.read().await.max_upload_mb, 5);
+        assert_eq!(cache_control().await.as_deref(), Some("public, max-age=60"));
+
+        web_tx
+            .send(WebServerControl::StopAfter(0))
+            .await
+            .expect("Failed to send a message");
+        assert_eq!(
+            server.await.expect("Web server task failed"),
+            Ok(()),
+            "Web server should stop cleanly"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_debounce_reloads() {
+        let quiet = Duration::from_millis(50);
+        let (web_tx, mut web_rx) = mpsc::channel(10);
+        for _ in 0..5 {
+            web_tx
+                .send(WebServerControl::ReloadAfter(0))
+                .await
+                .expect("Failed to send a message");
+        }
+        let mut reloads = 0;
+        while let Ok(Some(message)) =
+            tokio::time::timeout(Duration::from_millis(200), web_rx.recv()).await
+        {
+            let delay = reload_delay(&message).expect("Not a reload request");
+            assert_eq!(
+                debounce_reloads(&mut web_rx, delay, quiet).await,
+                Debounced::Reload
+            );
+            reloads += 1;
+        }
+        assert_eq!(
+            reloads, 1,
+            "A burst of reload requests should only reload once"
+        );
+
+        // anything queued up during a reload is dropped, but a stop still gets through
+        web_tx
+            .send(WebServerControl::Reload)
+            .await
+            .expect("Failed to send a message");
+        web_tx
+            .send(WebServerControl::StopAfter(0))
+            .await
+            .expect("Failed to send a message");
+        web_tx
+            .send(WebServerControl::Reload)
+            .await
+            .expect("Failed to send a message");
+        assert_eq!(
+            drain_reloads(&mut web_rx),
+            Some(WebServerControl::StopAfter(0))
+        );
+        assert_eq!(drain_reloads(&mut web_rx), None);
+
+        // stopping in the middle of a burst wins
+        web_tx
+            .send(WebServerControl::Stop)
+            .await
+            .expect("Failed to send a message");
+        assert_eq!(
+            debounce_reloads(&mut web_rx, Duration::ZERO, quiet).await,
+            Debounced::Interrupted(Some(WebServerControl::Stop))
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_reload_on_sighup() {
+        let (web_tx, mut web_rx) = mpsc::channel(10);
+        let task = reload_on_sighup(web_tx).expect("Failed to install the SIGHUP handler");
+
+        let status = std::process::Command::new("kill")
+            .args(["-HUP", &std::process::id().to_string()])
+            .status()
+            .expect("Failed to run kill");
+        assert!(status.success());
+
+        let message = tokio::time::timeout(tokio::time::Duration::from_secs(5), web_rx.recv())
+            .await
+            .expect("Timed out waiting for the reload message");
+        assert_eq!(message, Some(WebServerControl::Reload));
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        use tower::ServiceExt;
+
+        let mut config = Config::test_config();
+        config.oauth2_disabled = true;
+        let state = WebState::test_webstate_with_config(config).await;
+        let (_deletion_task, session_layer) = crate::session_store::build(
+            Some(crate::session_store::SQLITE_MEMORY.to_string()),
+            &crate::session_store::SessionConfig::from(&*state.configuration.read().await),
+        )
+        .await
+        .expect("Failed to build session store");
+        let app = build_app(state, session_layer)
+            .await
+            .expect("Failed to build app");
+
+        let mut ids = Vec::new();
+        for uri in [Urls::HealthCheck.as_ref(), "/nothing-here"] {
+            let response = app
+                .clone()
+                .oneshot(
+                    axum::http::Request::builder()
+                        .uri(uri)
+                        .body(axum::body::Body::empty())
+                        .expect("Failed to build request"),
+                )
+                .await
+                .expect("Failed to make request");
+            let id = response
+                .headers()
+                .get(X_REQUEST_ID)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+                .expect("No X-Request-Id");
+            assert!(uuid::Uuid::parse_str(&id).is_ok(), "{id}");
+            ids.push(id);
+        }
+        assert_ne!(ids[0], ids[1]);
+    }
+
+    #[tokio::test]
+    async fn test_basic_auth_when_oidc_down() {
+        use base64::engine::general_purpose::STANDARD;
+        use base64::Engine;
+        use tower::ServiceExt;
+
+        let oidc_down = || {
+            let mut config = Config::test_config();
+            config.oauth2_disabled = false;
+            // nothing's listening here, so discovery fails
+            config.oidc_issuer = "https://127.0.0.1:1/oauth2/openid/filekid".to_string();
+            config
+        };
+        let state = WebState::test_webstate_with_config(oidc_down()).await;
+        let (_deletion_task, session_layer) = crate::session_store::build(
+            Some(crate::session_store::SQLITE_MEMORY.to_string()),
+            &crate::session_store::SessionConfig::from(&*state.configuration.read().await),
+        )
+        .await
+        .expect("Failed to build session store");
+        assert!(build_app(state, session_layer).await.is_err());
+
+        let mut config = oidc_down();
+        config.basic_auth = Some(HashMap::from([(
+            "alice".to_string(),
+            crate::oidc::test_password_hash("hunter2"),
+        )]));
+        let state = WebState::test_webstate_with_config(config).await;
+        let (_deletion_task, session_layer) = crate::session_store::build(
+            Some(crate::session_store::SQLITE_MEMORY.to_string()),
+            &crate::session_store::SessionConfig::from(&*state.configuration.read().await),
+        )
+        .await
+        .expect("Failed to build session store");
+        let app = build_app(state, session_layer)
+            .await
+            .expect("Failed to build app");
+
+        for (authorization, status) in [
+            (Some("alice:hunter2"), StatusCode::OK),
+            (Some("alice:hunter3"), StatusCode::UNAUTHORIZED),
+            (None, StatusCode::UNAUTHORIZED),
+        ] {
+            let mut request = axum::http::Request::builder().uri(Urls::Index.as_ref());
+            if let Some(authorization) = authorization {
+                request = request.header(
+                    axum::http::header::AUTHORIZATION,
+                    format!("Basic {}", STANDARD.encode(authorization)),
+                );
+            }
+            let response = app
+                .clone()
+                .oneshot(
+                    request
+                        .body(Body::empty())
+                        .expect("Failed to build request"),
+                )
+                .await
+                .expect("Failed to make request");
+            assert_eq!(response.status(), status, "{authorization:?}");
+        }
+
+        // the unauthenticated endpoints don't ask
+        let response = app
+            .oneshot(
+                axum::http::Request::builder()
+                    .uri(Urls::HealthCheck.as_ref())
+                    .body(Body::empty())
+                    .expect("Failed to build request"),
+            )
+            .await
+            .expect("Failed to make request");
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_static_cache_control() {
+        use axum::http::header::VARY;
+        use tower::ServiceExt;
+
+        let static_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(static_dir.path().join("style.css"), b"body {}")
+            .expect("Failed to write file");
+        let mut config = Config::test_config();
+        config.oauth2_disabled = true;
+        config.static_path = Some(static_dir.path().to_path_buf());
+        config.static_max_age_secs = 600;
+        let state = WebState::test_webstate_with_config(config).await;
+        let (_deletion_task, session_layer) = crate::session_store::build(
+            Some(crate::session_store::SQLITE_MEMORY.to_string()),
+            &crate::session_store::SessionConfig::from(&*state.configuration.read().await),
+        )
+        .await
+        .expect("Failed to build session store");
+        let app = build_app(state, session_layer)
+            .await
+            .expect("Failed to build app");
+        let get = |uri: &'static str| {
+            app.clone().oneshot(
+                axum::http::Request::builder()
+                    .uri(uri)
+                    .body(axum::body::Body::empty())
+                    .expect("Failed to build request"),
+            )
+        };
+
+        let response = get("/static/style.css")
+            .await
+            .expect("Failed to make request");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(CACHE_CONTROL),
+            Some(&HeaderValue::from_static("public, max-age=600"))
+        );
+        assert_eq!(
+            response.headers().get(VARY),
+            Some(&HeaderValue::from_static("accept-encoding"))
+        );
+
+        let response = get("/static/missing.css")
+            .await
+            .expect("Failed to make request");
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers().get(CACHE_CONTROL), None);
+    }
+
+    #[tokio::test]
+    async fn test_favicon() {
+        use tower::ServiceExt;
+
+        let mut config = Config::test_config();
+        config.oauth2_disabled = true;
+        let state = WebState::test_webstate_with_config(config).await;
+        let (_deletion_task, session_layer) = crate::session_store::build(
+            Some(crate::session_store::SQLITE_MEMORY.to_string()),
+            &crate::session_store::SessionConfig::from(&*state.configuration.read().await),
+        )
+        .await
+        .expect("Failed to build session store");
+        let app = build_app(state, session_layer)
+            .await
+            .expect("Failed to build app");
+
+        let response = app
+            .oneshot(
+                axum::http::Request::builder()
+                    .uri(Urls::Favicon.as_ref())
+                    .body(axum::body::Body::empty())
+                    .expect("Failed to build request"),
+            )
+            .await
+            .expect("Failed to make request");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE),
+            Some(&HeaderValue::from_static("image/png"))
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Failed to read body");
+        assert_eq!(body, DEFAULT_FAVICON);
+
+        assert!(is_asset_path("/apple-touch-icon.png"));
+        assert!(is_asset_path("/robots.TXT"));
+        assert!(!is_asset_path("/wp-login.php"));
+        assert!(!is_asset_path("/nothing-here"));
+    }
+
+    #[tokio::test]
+    async fn test_api_docs() {
+        use tower::ServiceExt;
+
+        let app = |api_docs_enabled: bool| async move {
+            let mut config = Config::test_config();
+            config.oauth2_disabled = true;
+            config.api_docs_enabled = api_docs_enabled;
+            let state = WebState::test_webstate_with_config(config).await;
+            let (_deletion_task, session_layer) = crate::session_store::build(
+                Some(crate::session_store::SQLITE_MEMORY.to_string()),
+                &crate::session_store::SessionConfig::from(&*state.configuration.read().await),
+            )
+            .await
+            .expect("Failed to build session store");
+            build_app(state, session_layer)
+                .await
+                .expect("Failed to build app")
+        };
+        let get = |uri: &str| {
+            axum::http::Request::builder()
+                .uri(uri)
+                .body(axum::body::Body::empty())
+                .expect("Failed to build request")
+        };
+
+        // off by default
+        let disabled = app(false).await;
+        for uri in [Urls::ApiDocs.as_ref(), "/api/docs/"] {
+            let response = disabled
+                .clone()
+                .oneshot(get(uri))
+                .await
+                .expect("Failed to make request");
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{uri}");
+        }
+
+        let enabled = app(true).await;
+        let response = enabled
+            .clone()
+            .oneshot(get(Urls::ApiDocs.as_ref()))
+            .await
+            .expect("Failed to make request");
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Failed to read body");
+        let spec: serde_json::Value = serde_json::from_slice(&body).expect("Spec isn't JSON");
+        assert!(spec["openapi"]
+            .as_str()
+            .is_some_and(|v| v.starts_with("3.")));
+        for path in [
+            "/api/tree/{server_path}/",
+            "/api/tree/{server_path}/{filepath}",
+            "/api/list/{server_path}/",
+            "/api/list/{server_path}/{filepath}",
+            "/api/space",
+            "/checksum/{server_path}/{filepath}",
+            "/stat/{server_path}/{filepath}",
+        ] {
+            assert!(spec["paths"][path]["get"].is_object(), "{path} is missing");
+        }
+        assert!(spec["components"]["schemas"]["ListResponse"].is_object());
+
+        let response = enabled
+            .oneshot(get("/api/docs/"))
+            .await
+            .expect("Failed to make request");
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_compress_responses() {
+        use axum::http::header::{ACCEPT_ENCODING, CONTENT_ENCODING};
+        use tower::ServiceExt;
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        for index in 0..20 {
+            std::fs::write(
+                temp_dir.path().join(format!("file{index}.txt")),
+                "some text ".repeat(20),
+            )
+            .expect("Failed to write file");
+        }
+
+        for compress_responses in [true, false] {
+            let mut config = Config::test_config();
+            config.oauth2_disabled = true;
+            config.compress_responses = compress_responses;
+            config.server_paths.insert(
+                "test".to_string(),
+                crate::ServerPath::test_local(temp_dir.path()),
+            );
+            let state = WebState::test_webstate_with_config(config).await;
+            let (_deletion_task, session_layer) = crate::session_store::build(
+                Some(crate::session_store::SQLITE_MEMORY.to_string()),
+                &crate::session_store::SessionConfig::from(&*state.configuration.read().await),
+            )
+            .await
+            .expect("Failed to build session store");
+            let app = build_app(state, session_layer)
+                .await
+                .expect("Failed to build app");
+            let get = |uri: &'static str| {
+                let mut request = axum::http::Request::builder()
+                    .uri(uri)
+                    .header(ACCEPT_ENCODING, "gzip")
+                    .body(axum::body::Body::empty())
+                    .expect("Failed to build request");
+                request
+                    .extensions_mut()
+                    .insert(crate::views::oidc::test_user_claims());
+                app.clone().oneshot(request)
+            };
+
+            let response = get("/browse/test/").await.expect("Failed to make request");
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers().get(CONTENT_ENCODING),
+                compress_responses.then_some(&HeaderValue::from_static("gzip"))
+            );
+
+            // downloads are left alone
+            let response = get("/get/test/file0.txt")
+                .await
+                .expect("Failed to make request");
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers().get(CONTENT_ENCODING), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trailing_slash_redirects() {
+        use axum::http::Method;
+        use tower::ServiceExt;
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let mut config = Config::test_config();
+        config.oauth2_disabled = true;
+        config.server_paths.insert(
+            "test".to_string(),
+            crate::ServerPath::test_local(temp_dir.path()),
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+        let (_deletion_task, session_layer) = crate::session_store::build(
+            Some(crate::session_store::SQLITE_MEMORY.to_string()),
+            &crate::session_store::SessionConfig::from(&*state.configuration.read().await),
+        )
+        .await
+        .expect("Failed to build session store");
+        let app = build_app(state, session_layer)
+            .await
+            .expect("Failed to build app");
+
+        for (method, uri, location) in [
+            (Method::GET, "/browse/test", "/browse/test/"),
+            (
+                Method::GET,
+                "/browse/test?sort=size",
+                "/browse/test/?sort=size",
+            ),
+            (Method::POST, "/upload/test", "/upload/test/"),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(
+                    axum::http::Request::builder()
+                        .method(method)
+                        .uri(uri)
+                        .body(axum::body::Body::empty())
+                        .expect("Failed to build request"),
+                )
+                .await
+                .expect("Failed to make request");
+            assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT, "{uri}");
+            assert_eq!(
+                response
+                    .headers()
+                    .get(axum::http::header::LOCATION)
+                    .and_then(|value| value.to_str().ok()),
+                Some(location)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ready() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let mut config = Config::test_config();
+        config.server_paths.insert(
+            "here".to_string(),
+            crate::ServerPath::test_local(temp_dir.path()),
+        );
+        let state = WebState::test_webstate_with_config(config).await;
+
+        let response = ready(state.to_state())
+            .await
+            .expect("Failed to check readiness")
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        state.configuration.write().await.server_paths.insert(
+            "gone".to_string(),
+            crate::ServerPath::test_local(&temp_dir.path().join("doesnt_exist")),
+        );
+        let response = ready(state.to_state())
+            .await
+            .expect("Failed to check readiness")
+            .into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Failed to read body");
+        let body: serde_json::Value = serde_json::from_slice(&body).expect("Failed to parse JSON");
+        assert_eq!(
+            body,
+            serde_json::json!({"ready": false, "failed": ["gone"]})
+        );
+    }
 }